@@ -1,64 +1,622 @@
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Result};
+use device_interfaces::{Display, NetworkInterface};
 
 use r2_core::{
-    bus::{Bus, RAM_START},
+    bus::{Bus, Layout, FRAMEBUFFER, RAM_START, RTC, RTC_IRQ},
     clint::Clint,
+    elf::{self, Symbols},
+    fdt::DeviceTree,
+    framebuffer::Framebuffer,
+    htif::Htif,
+    mmio::MmioDevice,
+    overlay::{self, Overlay},
+    profiler::Profiler,
+    rtc::{self, Rtc},
     start,
+    virtio::{
+        blk::Block,
+        console::Console,
+        net::{Net, DEFAULT_MAC},
+        p9::P9,
+        rng::{Rng, Seeded},
+        vsock::{Vsock, DEFAULT_GUEST_CID},
+        VirtioMmio,
+    },
+    Options,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    /// Path to image file.
-    image_file_path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required = true)]
+    /// Path to image file. ELF32 images are loaded at their physical addresses and started
+    /// at their entry point, anything else is copied to the start of RAM.
+    image_file_path: Option<PathBuf>,
 
     #[arg(short, long)]
-    /// Path to dtb file.
+    /// Path to dtb file. Devices are mapped at the addresses it declares. One describing the
+    /// emulated machine is generated when omitted.
     dtb_file_path: Option<PathBuf>,
 
     #[arg(short, long, default_value = "67108864")]
     /// RAM size. default 64 * 1024 * 1024.
     ram_size: usize,
+
+    #[arg(long)]
+    /// Print execution statistics to stderr on exit.
+    stats: bool,
+
+    #[arg(long)]
+    /// Write execution statistics as JSON to this file on exit.
+    stats_json: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Sample the guest PC and write folded stacks for flamegraphs to this file on exit.
+    profile: Option<PathBuf>,
+
+    #[arg(long, default_value = "1000")]
+    /// Number of instructions between two PC samples.
+    profile_interval: u64,
+
+    #[arg(long)]
+    /// ELF file or Linux System.map used to symbolize the profile. Defaults to the symbols
+    /// of an ELF image.
+    symbols: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Service RISC-V semihosting calls. This gives the guest access to host files.
+    semihosting: bool,
+
+    #[arg(long)]
+    /// Act as SBI firmware so S-mode kernels boot without OpenSBI. Raw images with a RISC-V
+    /// Image header are loaded at their text offset.
+    sbi: bool,
+
+    #[arg(long)]
+    /// Kernel command line. Replaces `bootargs` in `/chosen` of the DTB.
+    append: Option<String>,
+
+    #[arg(long)]
+    /// Initramfs to load into RAM and pass to the kernel through `/chosen`.
+    initrd: Option<PathBuf>,
+
+    #[arg(long, value_parser = parse_drive)]
+    /// Disk image exposed as a virtio-blk device, as
+    /// `file=<path>[,readonly=on][,sync=on][,overlay=<path>]`. `sync=on` makes every write
+    /// durable before the guest sees it complete. With `overlay`, the image is left untouched
+    /// and writes go to a copy-on-write overlay file, created if missing. Can be repeated.
+    drive: Vec<Drive>,
+
+    #[arg(long)]
+    /// Keep disk writes in memory, leaving images and overlays untouched.
+    snapshot: bool,
+
+    #[arg(long, value_parser = parse_net)]
+    /// Network card exposed as a virtio-net device, as `user`, `socket=<path>,peer=<path>` or
+    /// `pcap=<path>`, followed by `[,mac=<mac>][,capture=<path>]`. `user` gives the guest
+    /// 10.0.2.15 over DHCP and forwards TCP and UDP sent to 10.0.2.2 to the same port on the
+    /// host's localhost. `socket` links two emulators through Unix datagram sockets. `pcap`
+    /// only records what the guest sends, which `capture` does for the other two. Can be
+    /// repeated.
+    net: Vec<NetSpec>,
+
+    #[arg(long)]
+    /// Connect the terminal to a virtio console, `hvc0` in the guest, which follows the size
+    /// of the terminal. The UART still prints what the guest writes to it but gets no input.
+    virtio_console: bool,
+
+    #[arg(long, value_parser = parse_console_port)]
+    /// Named virtio console port backed by a Unix socket, as `<name>=<path>`. The guest sees
+    /// it as `/dev/virtio-ports/<name>`. Can be repeated.
+    console_port: Vec<(String, PathBuf)>,
+
+    #[arg(long, value_parser = parse_share)]
+    /// Host directory shared with the guest over virtio-9p, as
+    /// `<dir>:<tag>[,readonly=on]`. The guest mounts it with
+    /// `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <mountpoint>`. Can be repeated.
+    share: Vec<Share>,
+
+    #[arg(long, value_parser = parse_vsock)]
+    /// Add a virtio-vsock device, as `<path>[,cid=<cid>]`. Guest connections to host port
+    /// `P` go to the Unix socket `<path>_P`. Host programs reach guest port `P` by
+    /// connecting to `<path>` and sending `CONNECT P\n`, answered by `OK <port>\n`. The
+    /// guest context ID defaults to 3.
+    vsock: Option<(PathBuf, u64)>,
+
+    #[arg(long)]
+    /// Add a virtio-rng device feeding host entropy to the guest.
+    rng: bool,
+
+    #[arg(long, conflicts_with = "rng")]
+    /// Add a virtio-rng device that is seeded instead of fed host entropy, so that runs
+    /// are reproducible. The guest then has no real randomness.
+    rng_seed: Option<u64>,
+
+    #[arg(long)]
+    /// Start the real-time clock at this many seconds since the UNIX epoch instead of the
+    /// host's date, advancing one microsecond per instruction or idle step, so that runs are
+    /// reproducible.
+    rtc_epoch: Option<u64>,
+
+    #[arg(long, value_parser = parse_resolution)]
+    /// Add a linear framebuffer of `<width>x<height>` pixels, which the guest finds as a
+    /// `simple-framebuffer` in the device tree.
+    framebuffer: Option<(u32, u32)>,
+
+    #[arg(long, value_parser = parse_screenshot, requires = "framebuffer")]
+    /// Save the framebuffer as a PNG file on exit, as `<path>[,every=<ms>]`. With `every`,
+    /// numbered screenshots are also saved next to it at that interval while the screen
+    /// changes.
+    screenshot: Option<(PathBuf, Option<Duration>)>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage copy-on-write overlays made with `--drive file=<image>,overlay=<path>`.
+    Overlay {
+        #[command(subcommand)]
+        action: OverlayAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum OverlayAction {
+    /// Write the changes held by an overlay into its base image, then empty the overlay.
+    Commit { overlay: PathBuf },
+    /// Drop the changes held by an overlay.
+    Discard { overlay: PathBuf },
+}
+
+/// A disk image given with `--drive`.
+#[derive(Debug, Clone)]
+struct Drive {
+    file: PathBuf,
+    read_only: bool,
+    sync: bool,
+    overlay: Option<PathBuf>,
+}
+
+fn parse_drive(spec: &str) -> Result<Drive, String> {
+    let mut file = None;
+    let mut overlay = None;
+    let (mut read_only, mut sync) = (false, false);
+    for option in spec.split(',') {
+        let (key, value) = option.split_once('=').unwrap_or((option, "on"));
+        let flag = || match value {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(format!("`{key}` must be `on` or `off`")),
+        };
+        match key {
+            "file" => file = Some(PathBuf::from(value)),
+            "readonly" => read_only = flag()?,
+            "sync" => sync = flag()?,
+            "overlay" => overlay = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown drive option `{key}`")),
+        }
+    }
+    Ok(Drive {
+        file: file.ok_or("missing `file=<path>`")?,
+        read_only,
+        sync,
+        overlay,
+    })
+}
+
+/// A directory given with `--share`.
+#[derive(Debug, Clone)]
+struct Share {
+    dir: PathBuf,
+    tag: String,
+    read_only: bool,
+}
+
+fn parse_share(spec: &str) -> Result<Share, String> {
+    let mut options = spec.split(',');
+    let share = options.next().unwrap_or_default();
+    // Split at the last colon, so that directories may contain some.
+    let Some((dir, tag)) = share
+        .rsplit_once(':')
+        .filter(|(d, t)| !d.is_empty() && !t.is_empty())
+    else {
+        return Err("expected `<dir>:<tag>`".to_string());
+    };
+    let mut read_only = false;
+    for option in options {
+        read_only = match option {
+            "readonly" | "readonly=on" => true,
+            "readonly=off" => false,
+            _ => return Err(format!("unknown share option `{option}`")),
+        };
+    }
+    Ok(Share {
+        dir: PathBuf::from(dir),
+        tag: tag.to_string(),
+        read_only,
+    })
+}
+
+/// A network card given with `--net`.
+#[derive(Debug, Clone)]
+struct NetSpec {
+    backend: NetBackend,
+    mac: Option<[u8; 6]>,
+    capture: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+enum NetBackend {
+    User,
+    Socket { path: PathBuf, peer: PathBuf },
+    Pcap,
+}
+
+fn parse_net(spec: &str) -> Result<NetSpec, String> {
+    let (mut user, mut path, mut peer) = (false, None, None);
+    let (mut mac, mut capture, mut pcap) = (None, None, None);
+    for option in spec.split(',') {
+        match option.split_once('=') {
+            None if option == "user" => user = true,
+            Some(("socket", value)) => path = Some(PathBuf::from(value)),
+            Some(("peer", value)) => peer = Some(PathBuf::from(value)),
+            Some(("pcap", value)) => pcap = Some(PathBuf::from(value)),
+            Some(("capture", value)) => capture = Some(PathBuf::from(value)),
+            Some(("mac", value)) => mac = Some(parse_mac(value)?),
+            _ => return Err(format!("unknown net option `{option}`")),
+        }
+    }
+    let backend = match (user, path, peer, pcap) {
+        (true, None, None, None) => NetBackend::User,
+        (false, Some(path), Some(peer), None) => NetBackend::Socket { path, peer },
+        (false, Some(_), None, None) => return Err("missing `peer=<path>`".to_string()),
+        (false, None, None, Some(file)) if capture.is_none() => {
+            capture = Some(file);
+            NetBackend::Pcap
+        }
+        _ => return Err("expected one of `user`, `socket=<path>` or `pcap=<path>`".to_string()),
+    };
+    Ok(NetSpec {
+        backend,
+        mac,
+        capture,
+    })
+}
+
+fn parse_vsock(spec: &str) -> Result<(PathBuf, u64), String> {
+    let mut options = spec.split(',');
+    let path = PathBuf::from(options.next().unwrap_or_default());
+    let mut cid = DEFAULT_GUEST_CID;
+    for option in options {
+        match option.split_once('=') {
+            // 0 to 2 are reserved for the hypervisor and the host.
+            Some(("cid", value)) => match value.parse() {
+                Ok(value) if value > 2 && value < u32::MAX as u64 => cid = value,
+                _ => return Err(format!("`{value}` is not a guest context ID")),
+            },
+            _ => return Err(format!("unknown vsock option `{option}`")),
+        }
+    }
+    Ok((path, cid))
+}
+
+fn parse_resolution(spec: &str) -> Result<(u32, u32), String> {
+    let error = || format!("`{spec}` is not a size like 640x480");
+    let (width, height) = spec.split_once('x').ok_or_else(error)?;
+    let (width, height) = (
+        width.parse().map_err(|_| error())?,
+        height.parse().map_err(|_| error())?,
+    );
+    // Keeps the pixels below the 1 GiB between the framebuffer base and RAM.
+    if width == 0 || height == 0 || width > 8192 || height > 8192 {
+        return Err(format!("`{spec}` is not between 1x1 and 8192x8192"));
+    }
+    Ok((width, height))
+}
+
+fn parse_screenshot(spec: &str) -> Result<(PathBuf, Option<Duration>), String> {
+    let mut options = spec.split(',');
+    let path = PathBuf::from(options.next().unwrap_or_default());
+    let mut every = None;
+    for option in options {
+        match option.split_once('=') {
+            Some(("every", value)) => match value.parse() {
+                Ok(ms) if ms > 0 => every = Some(Duration::from_millis(ms)),
+                _ => return Err(format!("`{value}` is not a number of milliseconds")),
+            },
+            _ => return Err(format!("unknown screenshot option `{option}`")),
+        }
+    }
+    Ok((path, every))
+}
+
+fn parse_console_port(spec: &str) -> Result<(String, PathBuf), String> {
+    match spec.split_once('=') {
+        Some((name, path)) if !name.is_empty() => Ok((name.to_string(), PathBuf::from(path))),
+        _ => Err("expected `<name>=<path>`".to_string()),
+    }
+}
+
+fn parse_mac(value: &str) -> Result<[u8; 6], String> {
+    let error = || format!("`{value}` is not a MAC address like 52:54:00:12:34:56");
+    let mut mac = [0u8; 6];
+    let mut octets = value.split(':');
+    for octet in &mut mac {
+        let text = octets.next().ok_or_else(error)?;
+        *octet = u8::from_str_radix(text, 16).map_err(|_| error())?;
+    }
+    if octets.next().is_some() {
+        return Err(error());
+    }
+    Ok(mac)
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    if let Some(Command::Overlay { action }) = args.command {
+        return match action {
+            OverlayAction::Commit { overlay } => {
+                let clusters = overlay::commit(&overlay)?;
+                println!(
+                    "committed {clusters} clusters to {}",
+                    overlay::base_path(&overlay)?.display()
+                );
+                Ok(())
+            }
+            OverlayAction::Discard { overlay } => Ok(overlay::discard(&overlay)?),
+        };
+    }
 
     let ram_size = args.ram_size;
 
     let mut ram = vec![0u8; ram_size];
 
-    let mut f = File::open(args.image_file_path)?;
+    // Only optional for subcommands.
+    let image = std::fs::read(args.image_file_path.as_ref().expect("required by clap"))?;
 
-    let len = f.metadata()?.len();
-    if len > ram_size as u64 {
-        bail!("Insufficient RAM capacity. Please increase RAM capacity with `-r` option.")
-    }
+    // `image_end` is the end of the loaded image, as an address.
+    let (pc, image_end, image_symbols) = if elf::is_elf(&image) {
+        let elf = elf::Elf::parse(&image)?;
+        elf.load(&mut ram, RAM_START)?;
+        (elf.entry, elf.end().unwrap_or(RAM_START), elf.symbols()?)
+    } else {
+        // The offset comes from the image header, so it can be anything.
+        let end = usize::try_from(image_text_offset(&image))
+            .ok()
+            .and_then(|offset| offset.checked_add(image.len()))
+            .filter(|end| *end <= ram_size);
+        let Some(end) = end else {
+            bail!("Insufficient RAM capacity. Please increase RAM capacity with `-r` option.")
+        };
+        let offset = end - image.len();
+        ram[offset..end].copy_from_slice(&image);
+        let start = RAM_START + offset as u32;
+        (start, start + image.len() as u32, Symbols::default())
+    };
 
-    f.read_exact(&mut ram[..len as usize])?;
+    // A user supplied DTB decides where the devices are mapped. Its warnings have to be
+    // printed before the UART puts the terminal into raw mode.
+    let user_dtb = match &args.dtb_file_path {
+        Some(path) => {
+            let dtb = std::fs::read(path)?;
+            let (layout, warnings) = Layout::from_device_tree(&DeviceTree::parse(&dtb)?);
+            for warning in warnings {
+                eprintln!("warning: {warning}");
+            }
+            Some((dtb, layout))
+        }
+        None => None,
+    };
 
-    let dtb_ref = if let Some(dtb) = args.dtb_file_path {
-        let mut f = File::open(dtb)?;
-        let len = f.metadata()?.len();
-        let ptr = ram_size as u64 - len;
-        f.read_exact(&mut ram[(ptr as usize)..(ptr + len) as usize])?;
-        ptr as u32 + RAM_START
+    let clint = Clint::new(devices::timer::Timer::default());
+    let uart = if args.virtio_console {
+        devices::uart::Uart::output_only()
     } else {
-        0
+        devices::uart::Uart::new()
     };
+    let mut bus = Bus::new(ram, clint, uart);
+    if let Some((_, layout)) = &user_dtb {
+        bus.set_layout(*layout)?;
+    }
+    for drive in &args.drive {
+        // Behind an overlay the image itself is only ever read.
+        let copy_on_write = args.snapshot || drive.overlay.is_some();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!drive.read_only && !copy_on_write)
+            .open(&drive.file)?;
+        let (read_only, sync) = (drive.read_only, drive.sync);
+        let device: Box<dyn MmioDevice> = match &drive.overlay {
+            // What the overlay holds is part of the disk, but it is left as it is too.
+            Some(path) if args.snapshot && path.exists() => {
+                let base = std::fs::canonicalize(&drive.file)?;
+                let overlay = Overlay::open_read_only(file, &base, path)?;
+                let disk = Overlay::in_memory(overlay)?;
+                Box::new(VirtioMmio::new(Block::new(disk, read_only, sync)?))
+            }
+            _ if args.snapshot => Box::new(VirtioMmio::new(Block::new(
+                Overlay::in_memory(file)?,
+                read_only,
+                sync,
+            )?)),
+            Some(path) => {
+                // Recorded in the overlay, so it has to work from any directory.
+                let base = std::fs::canonicalize(&drive.file)?;
+                let overlay = Overlay::open(file, &base, path)?;
+                Box::new(VirtioMmio::new(Block::new(overlay, read_only, sync)?))
+            }
+            None => Box::new(VirtioMmio::new(Block::new(file, read_only, sync)?)),
+        };
+        bus.map_virtio(device)?;
+    }
+    for (index, net) in args.net.iter().enumerate() {
+        let backend: Box<dyn NetworkInterface> = match &net.backend {
+            NetBackend::User => Box::new(devices::net::Nat::new()),
+            NetBackend::Socket { path, peer } => Box::new(devices::net::Socket::bind(path, peer)?),
+            NetBackend::Pcap => Box::new(devices::net::Unplugged),
+        };
+        let backend: Box<dyn NetworkInterface> = match &net.capture {
+            Some(path) => Box::new(devices::net::Pcap::create(path, backend)?),
+            None => backend,
+        };
+        // Cards without an address get consecutive ones, so they can share a link.
+        let mut mac = DEFAULT_MAC;
+        mac[5] = mac[5].wrapping_add(index as u8);
+        let net = Net::new(backend, net.mac.unwrap_or(mac));
+        bus.map_virtio(Box::new(VirtioMmio::new(net)))?;
+    }
 
-    let clint = Clint::new(devices::timer::Timer::default());
-    let uart = devices::uart::Uart::new();
-    let bus = Bus::new(ram, clint, uart);
+    if args.virtio_console || !args.console_port.is_empty() {
+        let mut console = if args.virtio_console {
+            Console::new(Box::new(devices::console::Terminal::new()))
+        } else {
+            Console::new(Box::new(devices::console::Unconnected))
+        };
+        for (name, path) in &args.console_port {
+            console.add_port(name, Box::new(devices::console::Socket::listen(path)?));
+        }
+        bus.map_virtio(Box::new(VirtioMmio::new(console)))?;
+    }
+
+    for share in &args.share {
+        let server = devices::p9::Share::new(&share.dir, share.read_only)?;
+        bus.map_virtio(Box::new(VirtioMmio::new(P9::new(server, &share.tag))))?;
+    }
+
+    if let Some((path, cid)) = &args.vsock {
+        let host = devices::vsock::UnixVsock::listen(path)?;
+        bus.map_virtio(Box::new(VirtioMmio::new(Vsock::new(host, *cid))))?;
+    }
+
+    // Without it, guests that need randomness early wait for entropy to trickle in.
+    if let Some(seed) = args.rng_seed {
+        bus.map_virtio(Box::new(VirtioMmio::new(Rng::new(Seeded::new(seed)))))?;
+    } else if args.rng {
+        let entropy = devices::entropy::HostEntropy::open()?;
+        bus.map_virtio(Box::new(VirtioMmio::new(Rng::new(entropy))))?;
+    }
+
+    let rtc: Box<dyn MmioDevice> = match args.rtc_epoch {
+        Some(epoch) => Box::new(Rtc::new(rtc::Fixed::new(epoch))),
+        None => Box::new(Rtc::new(devices::clock::HostClock)),
+    };
+    bus.map_device(RTC.base, rtc, Some(RTC_IRQ))?;
 
-    start(bus, RAM_START, dtb_ref, &std::thread::sleep);
+    if let Some((width, height)) = args.framebuffer {
+        let display: Box<dyn Display> = match &args.screenshot {
+            Some((path, every)) => Box::new(devices::display::Screenshots::new(path, *every)),
+            None => Box::new(devices::display::Headless),
+        };
+        let framebuffer = Framebuffer::new(width, height, display)?;
+        bus.map_device(FRAMEBUFFER, Box::new(framebuffer), None)?;
+    }
+
+    let initrd = match &args.initrd {
+        Some(path) => {
+            let initrd = std::fs::read(path)?;
+            match bus.load_initrd(&initrd) {
+                Some(region) if region.base >= image_end => Some(region),
+                _ => bail!(
+                    "Insufficient RAM capacity for the initrd. Please increase RAM capacity with `-r` option."
+                ),
+            }
+        }
+        None => None,
+    };
+
+    // Without a DTB one is generated from the bus, so it always matches the hardware.
+    let mut dtb = match user_dtb {
+        Some((dtb, _)) => dtb,
+        None => bus.platform().to_dtb(),
+    };
+    if args.append.is_some() || initrd.is_some() {
+        let mut tree = DeviceTree::parse(&dtb)?;
+        if let Some(bootargs) = &args.append {
+            tree.set_bootargs(bootargs);
+        }
+        if let Some(initrd) = initrd {
+            tree.set_initrd(initrd);
+        }
+        dtb = tree.to_dtb();
+    }
+    let dtb_ref = match bus.load_dtb(&dtb) {
+        // It goes at the top of RAM, which an ELF image may reach too.
+        Some(addr) if addr >= image_end && initrd.is_none_or(|i| addr >= i.base + i.size) => addr,
+        _ => bail!(
+            "Insufficient RAM capacity for the DTB. Please increase RAM capacity with `-r` option."
+        ),
+    };
 
+    // Test programs built for riscv-tests and riscv-arch-test report through HTIF.
+    if let Some(tohost) = image_symbols.addr_of("tohost") {
+        bus.attach_htif(Htif::new(tohost, image_symbols.addr_of("fromhost")));
+    }
+
+    // Load symbols up front so a bad path is reported before the guest runs.
+    let symbols = match &args.symbols {
+        Some(path) => load_symbols(path)?,
+        None => image_symbols,
+    };
+    let profiler = args
+        .profile
+        .as_ref()
+        .map(|_| Profiler::new(args.profile_interval));
+
+    let options = Options {
+        profiler,
+        semihosting: args.semihosting,
+        sbi: args.sbi,
+    };
+
+    let started = Instant::now();
+    let exit = start(bus, pc, dtb_ref, options, &std::thread::sleep);
+    let elapsed = started.elapsed();
+    let stats = exit.stats;
+    if let Some(fault) = exit.fault {
+        eprintln!("the guest got stuck on {fault}");
+    }
+
+    if args.stats {
+        eprint!("{}", stats.report(elapsed));
+    }
+    if let Some(path) = args.stats_json {
+        std::fs::write(path, stats.to_json(elapsed))?;
+    }
+    if let (Some(path), Some(profile)) = (args.profile, &stats.profile) {
+        profile.write_folded(&symbols, File::create(path)?)?;
+    }
+
+    if exit.code != 0 {
+        std::process::exit(exit.code as i32);
+    }
     Ok(())
 }
+
+fn load_symbols(path: &Path) -> Result<Symbols> {
+    let bytes = std::fs::read(path)?;
+    if elf::is_elf(&bytes) {
+        Ok(elf::Elf::parse(&bytes)?.symbols()?)
+    } else {
+        Ok(Symbols::from_system_map(&String::from_utf8_lossy(&bytes)))
+    }
+}
+
+/// Offset from the start of RAM a raw image wants to be loaded at. Linux `Image` files carry
+/// it in their header, next to the "RISCV" magic.
+/// @See https://docs.kernel.org/arch/riscv/boot-image-header.html
+fn image_text_offset(image: &[u8]) -> u64 {
+    match (image.get(0x30..0x38), image.get(8..16)) {
+        (Some(b"RISCV\0\0\0"), Some(offset)) => {
+            u64::from_le_bytes(offset.try_into().unwrap_or_default())
+        }
+        _ => 0,
+    }
+}
@@ -1,21 +1,258 @@
+use std::cell::{Cell, RefCell};
+
 use crate::{
     bus_interface::{BusController, BusException, BusReader, BusWriter},
-    clint::Clint,
+    clint::{Clint, TIMEBASE_FREQUENCY},
+    fdt::{DeviceNode, DeviceTree, Platform},
+    htif::{Htif, Response},
+    mmio::{GuestMemory, MapError, MmioDevice, Width, TICK_INTERVAL},
+    plic::{self, Plic},
+    stats::MmioCount,
+    uart::Uart,
 };
 
 pub const RAM_START: u32 = 0x8000_0000;
 
+pub const UART: Region = Region::new(0x1000_0000, 0x100);
+pub const CLINT: Region = Region::new(0x1100_0000, 0x1_0000);
+pub const SYSCON: Region = Region::new(0x1110_0000, 0x1000);
+pub const PLIC: Region = Region::new(0x0c00_0000, 0x400_0000);
+/// Accesses around the default devices read as zero and ignore writes, as some guests
+/// probe there.
+const MMIO_HOLE: Region = Region::new(0x1000_0000, 0x200_0000);
+
+/// Interrupt line of the UART, the one it has on QEMU's `virt` machine.
+pub const UART_IRQ: u32 = 10;
+/// First of the virtio-mmio slots. Like on QEMU's `virt` machine, slot `n` is mapped
+/// `n` pages further and interrupts through line `VIRTIO_IRQ + n`.
+pub const VIRTIO: Region = Region::new(0x1000_1000, 0x1000);
+pub const VIRTIO_IRQ: u32 = 1;
+const VIRTIO_SLOTS: u32 = 8;
+/// The RTC and its interrupt line, which are those of QEMU's `virt` machine relative to the
+/// UART.
+pub const RTC: Region = Region::new(0x1010_0000, 0x1000);
+pub const RTC_IRQ: u32 = 11;
+/// Where the framebuffer goes, clear of RAM and the other devices.
+pub const FRAMEBUFFER: u32 = 0x4000_0000;
+
+const UART_CLOCK_FREQUENCY: u32 = 0x100_0000;
+const SYSCON_POWEROFF: u32 = 0x5555;
+const SYSCON_REBOOT: u32 = 0x7777;
+/// Low half of a `sifive,test` finisher write, which says what to do. The high half is an exit
+/// code, only used by failures.
+const FINISHER_STATUS: u32 = 0xffff;
+/// Status that ends the run as failed.
+const FINISHER_FAIL: u32 = 0x3333;
+/// Granularity at which the boot image is saved.
+const BOOT_IMAGE_PAGE: usize = 0x1000;
+
+/// A block of the physical address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub const fn new(base: u32, size: u32) -> Self {
+        Self { base, size }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < self.size
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        let end = |r: &Region| r.base as u64 + r.size as u64;
+        (self.base as u64) < end(other) && (other.base as u64) < end(self)
+    }
+}
+
+/// Where the devices of the bus are mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub uart: Region,
+    pub clint: Region,
+    pub syscon: Region,
+    pub plic: Region,
+    /// PLIC source the UART interrupts through.
+    pub uart_irq: u32,
+    /// Value that powers the machine off when written to the syscon. Like every syscon
+    /// write, only its low half counts.
+    pub poweroff_value: u32,
+    /// Value that reboots the machine when written to the syscon.
+    pub reboot_value: u32,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            uart: UART,
+            clint: CLINT,
+            syscon: SYSCON,
+            plic: PLIC,
+            uart_irq: UART_IRQ,
+            poweroff_value: SYSCON_POWEROFF,
+            reboot_value: SYSCON_REBOOT,
+        }
+    }
+}
+
+impl Layout {
+    /// Maps the devices at the addresses a device tree declares, translated through the
+    /// `ranges` of their buses. Devices it does not mention keep their default address.
+    /// Nodes r2 cannot emulate as described, such as virtio-mmio slots elsewhere than r2's,
+    /// are returned as warnings.
+    pub fn from_device_tree(tree: &DeviceTree) -> (Self, Vec<String>) {
+        let mut layout = Self::default();
+        let mut warnings = Vec::new();
+        let mut mapped = Vec::new();
+        for node in tree.nodes() {
+            let path = node.path.as_str();
+            let compatible = node.strings("compatible");
+            let Some(first) = compatible.first() else {
+                continue;
+            };
+            let region = || -> Option<Region> {
+                let (base, size) = *node.reg()?.first()?;
+                let base = tree.translate(path, base)?;
+                Some(Region::new(base.try_into().ok()?, size.try_into().ok()?))
+            };
+            let is = |names: &[&str]| compatible.iter().any(|c| names.contains(c));
+            let device = if is(&["ns16550a", "ns16550", "ns16850", "ns8250"]) {
+                if let Some(irq) = node.u32_property("interrupts") {
+                    layout.uart_irq = irq;
+                }
+                let reg_shift = node.u32_property("reg-shift").unwrap_or(0);
+                let reg_io_width = node.u32_property("reg-io-width").unwrap_or(1);
+                if reg_shift != 0 || reg_io_width != 1 {
+                    warnings.push(format!(
+                        "{path}: the UART has byte registers one apart, not reg-shift \
+                         {reg_shift} and reg-io-width {reg_io_width}"
+                    ));
+                }
+                Some(("UART", &mut layout.uart))
+            } else if is(&["sifive,clint0", "riscv,clint0"]) {
+                Some(("CLINT", &mut layout.clint))
+            } else if is(&["sifive,plic-1.0.0", "riscv,plic0"]) {
+                Some(("PLIC", &mut layout.plic))
+            } else if is(&["syscon", "sifive,test0", "sifive,test1"]) {
+                Some(("syscon", &mut layout.syscon))
+            } else {
+                None
+            };
+            if let Some((name, slot)) = device {
+                match region() {
+                    _ if mapped.contains(&name) => {
+                        warnings.push(format!("{path}: only one {name} is emulated"))
+                    }
+                    Some(region) => {
+                        *slot = region;
+                        mapped.push(name);
+                    }
+                    None => warnings.push(format!(
+                        "{path}: unusable reg, keeping the {name} at {:#x}",
+                        slot.base
+                    )),
+                }
+            } else if is(&["syscon-poweroff"]) {
+                if let Some(value) = node.u32_property("value") {
+                    layout.poweroff_value = value;
+                }
+            } else if is(&["syscon-reboot"]) {
+                if let Some(value) = node.u32_property("value") {
+                    layout.reboot_value = value;
+                }
+            } else if path.is_empty() || is(&["simple-bus", "riscv", "riscv,cpu-intc"]) {
+                // The root node and containers need no emulation.
+            } else if is(&["virtio,mmio"]) {
+                // Slots are filled by the devices attached on the command line, always at
+                // the same addresses.
+                let slot = region().and_then(|region| {
+                    let slot = region.base.checked_sub(VIRTIO.base)? / VIRTIO.size;
+                    let base = VIRTIO.base + slot * VIRTIO.size;
+                    (slot < VIRTIO_SLOTS && region.base == base).then_some(slot)
+                });
+                let irq = node.u32_property("interrupts");
+                match slot {
+                    Some(slot) if irq.is_none_or(|irq| irq == VIRTIO_IRQ + slot) => {}
+                    _ => warnings.push(format!(
+                        "{path}: virtio-mmio slots are at {:#x} onward, a page apart, and \
+                         interrupt from line {VIRTIO_IRQ} onward",
+                        VIRTIO.base
+                    )),
+                }
+            } else {
+                warnings.push(format!("{path}: {first} is not emulated"));
+            }
+        }
+        (layout, warnings)
+    }
+}
+
 pub struct Bus<T, S> {
     pub ram: Vec<u8>,
     pub clint: Clint<T>,
-    pub serial: S,
-    /// 8250 line control register. Its top bit (DLAB) turns registers 0 and 1 into the
-    /// baud rate divisor latches, so it has to be tracked to keep the guest from
-    /// mistaking a divisor for a character.
-    lcr: u8,
-    divisor: u16,
+    /// Reading UART registers changes its state, but reads only borrow the bus.
+    uart: RefCell<Uart<S>>,
     pub power_off: bool,
     pub reboot: bool,
+    /// Exit code of a failure reported through the syscon.
+    finisher_code: Option<u32>,
+    /// Host-target interface watching `tohost`, for test programs that use it.
+    htif: Option<Htif>,
+    /// Reading the claim register changes state, but reads only borrow the bus.
+    plic: RefCell<Plic>,
+    layout: Layout,
+    /// Address map of the memory-mapped devices, searched in order.
+    mappings: Vec<Mapping>,
+    /// Number of virtio-mmio slots taken.
+    virtio_slots: u32,
+    /// Pages of RAM that were not blank at boot, as offsets and contents, for reboots.
+    boot_image: Vec<(usize, Box<[u8]>)>,
+    /// Steps since the plugged-in devices were last ticked.
+    untick_steps: u32,
+    /// Whether the guest accessed a plugged-in device since the last tick, which may have
+    /// given it work. Reads only borrow the bus, hence the cell.
+    device_accessed: Cell<bool>,
+    /// Interrupt lines the plugged-in devices asserted at the last tick.
+    device_levels: u64,
+}
+
+/// What an entry of the address map is wired to.
+enum Target {
+    Clint,
+    Uart,
+    Syscon,
+    Plic,
+    /// Reads as zero and ignores writes.
+    Hole,
+    /// A device plugged in with [`Bus::map_device`]. Reads only borrow the bus, hence the
+    /// cell.
+    Device(RefCell<Box<dyn MmioDevice>>),
+}
+
+struct Mapping {
+    name: String,
+    region: Region,
+    target: Target,
+    /// Interrupt line the device drives, if it has one.
+    irq: Option<u32>,
+    reads: Cell<u64>,
+    writes: u64,
+}
+
+impl Mapping {
+    fn new(name: &str, region: Region, target: Target) -> Self {
+        Self {
+            name: name.to_string(),
+            region,
+            target,
+            irq: None,
+            reads: Cell::new(0),
+            writes: 0,
+        }
+    }
 }
 
 impl<T, S> Bus<T, S> {
@@ -23,14 +260,199 @@ impl<T, S> Bus<T, S> {
         Self {
             ram,
             clint,
-            serial,
-            lcr: 0,
-            divisor: 0,
+            uart: RefCell::new(Uart::new(serial)),
             power_off: false,
             reboot: false,
+            finisher_code: None,
+            htif: None,
+            plic: RefCell::new(Plic::new()),
+            layout: Layout::default(),
+            mappings: vec![
+                Mapping::new("plic", PLIC, Target::Plic),
+                Mapping::new("clint", CLINT, Target::Clint),
+                Mapping {
+                    irq: Some(UART_IRQ),
+                    ..Mapping::new("uart", UART, Target::Uart)
+                },
+                Mapping::new("syscon", SYSCON, Target::Syscon),
+                // Always last, so that devices mapped inside it take precedence.
+                Mapping::new("unmapped", MMIO_HOLE, Target::Hole),
+            ],
+            virtio_slots: 0,
+            boot_image: Vec::new(),
+            untick_steps: 0,
+            device_accessed: Cell::new(false),
+            device_levels: 0,
+        }
+    }
+
+    /// Maps the devices at the addresses in `layout` instead of the default ones. A layout
+    /// whose devices overlap each other, RAM or a plugged-in device is refused and the bus
+    /// is left as it was.
+    pub fn set_layout(&mut self, layout: Layout) -> Result<(), MapError> {
+        if !(1..plic::SOURCES).contains(&layout.uart_irq) {
+            return Err(MapError::InvalidIrq {
+                device: "uart".to_string(),
+                irq: layout.uart_irq,
+            });
+        }
+        let region = |mapping: &Mapping| match mapping.target {
+            Target::Clint => layout.clint,
+            Target::Uart => layout.uart,
+            Target::Plic => layout.plic,
+            Target::Syscon => layout.syscon,
+            Target::Hole | Target::Device(_) => mapping.region,
+        };
+        let regions: Vec<(&str, Region)> = self
+            .mappings
+            .iter()
+            .filter(|m| !matches!(m.target, Target::Hole))
+            .map(|m| (m.name.as_str(), region(m)))
+            .collect();
+        for (i, (name, region)) in regions.iter().enumerate() {
+            if let Some(other) = self.overlap(region, regions[..i].iter().copied()) {
+                return Err(MapError::Overlap {
+                    device: name.to_string(),
+                    other: other.to_string(),
+                });
+            }
+        }
+
+        self.layout = layout;
+        for mapping in &mut self.mappings {
+            mapping.region = region(mapping);
+            if let Target::Uart = mapping.target {
+                mapping.irq = Some(layout.uart_irq);
+            }
+        }
+        Ok(())
+    }
+
+    /// The first of `others`, or RAM, that `region` overlaps.
+    fn overlap<'a>(
+        &self,
+        region: &Region,
+        others: impl Iterator<Item = (&'a str, Region)>,
+    ) -> Option<&'a str> {
+        let ram = Region::new(RAM_START, self.ram.len() as u32);
+        others
+            .chain([("RAM", ram)])
+            .find(|(_, other)| other.overlaps(region))
+            .map(|(name, _)| name)
+    }
+
+    /// Maps `device` at `base`. Its interrupt output drives `irq`, if given.
+    pub fn map_device(
+        &mut self,
+        base: u32,
+        device: Box<dyn MmioDevice>,
+        irq: Option<u32>,
+    ) -> Result<(), MapError> {
+        let region = Region::new(base, device.size());
+        let name = device.name().to_string();
+        if let Some(irq) = irq.filter(|irq| !(1..plic::SOURCES).contains(irq)) {
+            return Err(MapError::InvalidIrq { device: name, irq });
+        }
+        let others = self
+            .mappings
+            .iter()
+            .filter(|m| !matches!(m.target, Target::Hole))
+            .map(|m| (m.name.as_str(), m.region));
+        if let Some(other) = self.overlap(&region, others) {
+            return Err(MapError::Overlap {
+                device: name,
+                other: other.to_string(),
+            });
+        }
+        let mut mapping = Mapping::new(&name, region, Target::Device(RefCell::new(device)));
+        mapping.irq = irq;
+        self.mappings.insert(self.mappings.len() - 1, mapping);
+        // Its interrupt output is sampled from the next step on.
+        self.device_accessed.set(true);
+        Ok(())
+    }
+
+    /// Maps a virtio-mmio device in the next free slot and returns where it went.
+    pub fn map_virtio(&mut self, device: Box<dyn MmioDevice>) -> Result<Region, MapError> {
+        let slot = self.virtio_slots;
+        if slot == VIRTIO_SLOTS {
+            return Err(MapError::NoSlot {
+                device: device.name().to_string(),
+            });
+        }
+        let base = VIRTIO.base + slot * VIRTIO.size;
+        let size = device.size();
+        self.map_device(base, device, Some(VIRTIO_IRQ + slot))?;
+        self.virtio_slots += 1;
+        Ok(Region::new(base, size))
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The hardware this bus emulates, for generating a device tree. `bootargs` defaults
+    /// to a console on the UART.
+    pub fn platform(&self) -> Platform {
+        Platform {
+            ram: Region::new(RAM_START, self.ram.len() as u32),
+            harts: 1,
+            isa: "rv32ima".to_string(),
+            mmu_type: "riscv,sv32".to_string(),
+            timebase_frequency: TIMEBASE_FREQUENCY,
+            uart: self.layout.uart,
+            uart_clock_frequency: UART_CLOCK_FREQUENCY,
+            clint: self.layout.clint,
+            plic: self.layout.plic,
+            plic_sources: plic::SOURCES - 1,
+            uart_irq: self.layout.uart_irq,
+            syscon: self.layout.syscon,
+            poweroff_value: self.layout.poweroff_value,
+            reboot_value: self.layout.reboot_value,
+            devices: self
+                .mappings
+                .iter()
+                .filter_map(|m| match &m.target {
+                    Target::Device(device) => {
+                        let device = device.borrow();
+                        Some(DeviceNode {
+                            name: m.name.clone(),
+                            compatible: device.compatible()?.to_string(),
+                            region: m.region,
+                            irq: m.irq,
+                            properties: device.properties(),
+                        })
+                    }
+                    _ => None,
+                })
+                .collect(),
+            bootargs: format!(
+                "earlycon=uart8250,mmio,{:#x},1000000 console=ttyS0",
+                self.layout.uart.base
+            ),
         }
     }
 
+    /// Copies a device tree blob to the top of RAM and returns its guest address, or `None`
+    /// if it does not fit.
+    pub fn load_dtb(&mut self, dtb: &[u8]) -> Option<u32> {
+        // The kernel expects the blob to be 8-byte aligned.
+        let offset = self.ram.len().checked_sub(dtb.len())? & !7;
+        self.ram[offset..offset + dtb.len()].copy_from_slice(dtb);
+        Some(RAM_START + offset as u32)
+    }
+
+    /// Copies an initramfs into RAM and returns where it went, or `None` if it does not fit.
+    /// Like QEMU it goes halfway into RAM, or 128 MiB in on larger machines, far enough that
+    /// the kernel does not overwrite it while starting up.
+    pub fn load_initrd(&mut self, initrd: &[u8]) -> Option<Region> {
+        let offset = (self.ram.len() / 2).min(128 * 1024 * 1024);
+        self.ram
+            .get_mut(offset..offset + initrd.len())?
+            .copy_from_slice(initrd);
+        Some(Region::new(RAM_START + offset as u32, initrd.len() as u32))
+    }
+
     pub fn clint(&self) -> &Clint<T> {
         &self.clint
     }
@@ -39,6 +461,15 @@ impl<T, S> Bus<T, S> {
         std::mem::replace(&mut self.ram, ram)
     }
 
+    /// Starts watching the `tohost`/`fromhost` words of `htif`.
+    pub fn attach_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    pub fn htif(&self) -> Option<&Htif> {
+        self.htif.as_ref()
+    }
+
     /// Translates a physical address into a RAM offset, rejecting anything the guest
     /// should not be able to reach. Stray accesses have to be reported to the guest as a
     /// fault rather than taking the emulator down with it.
@@ -47,34 +478,92 @@ impl<T, S> Bus<T, S> {
         (offset + size as usize <= self.ram.len()).then_some(offset)
     }
 
-    fn dlab(&self) -> bool {
-        self.lcr & 0x80 != 0
+    /// Index of the mapping `addr` falls in.
+    fn mapping(&self, addr: u32) -> Option<usize> {
+        self.mappings.iter().position(|m| m.region.contains(addr))
+    }
+
+    /// Lets HTIF see a word stored to RAM and completes the command it finishes, if any.
+    fn htif_store(&mut self, addr: u32, v: u32) {
+        let Some(htif) = &mut self.htif else {
+            return;
+        };
+        let Response::Ack(reply) = htif.write(addr, v) else {
+            return;
+        };
+        let (tohost, fromhost) = (htif.tohost, htif.fromhost);
+        if let Some(offset) = self.ram_offset(tohost, 8) {
+            self.ram[offset..offset + 8].fill(0);
+        }
+        if let (Some(fromhost), Some(reply)) = (fromhost, reply) {
+            if let Some(offset) = self.ram_offset(fromhost, 8) {
+                self.ram[offset..offset + 8].copy_from_slice(&reply.to_le_bytes());
+            }
+        }
     }
 }
 
-const UART_RBR_THR: u32 = 0x0;
-const UART_IER: u32 = 0x1;
-const UART_LCR: u32 = 0x3;
+impl<T, S> Bus<T, S>
+where
+    T: device_interfaces::TimerDriver,
+    S: device_interfaces::SerialInterface,
+{
+    /// Interrupt lines currently asserted by the UART and plugged-in devices.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> + '_ {
+        self.mappings.iter().filter_map(|m| match &m.target {
+            Target::Uart if self.uart.borrow().interrupt() => m.irq,
+            Target::Device(device) if device.borrow().interrupt() => m.irq,
+            _ => None,
+        })
+    }
 
-impl<T, S: device_interfaces::SerialInterface> Bus<T, S> {
-    fn serial_read(&self, reg: u32) -> u8 {
-        match reg {
-            UART_RBR_THR if self.dlab() => self.divisor as u8,
-            UART_IER if self.dlab() => (self.divisor >> 8) as u8,
-            _ => self.serial.read(reg),
-        }
+    /// Reads a device register, or returns `None` if `addr` is not MMIO.
+    fn mmio_read(&self, addr: u32, width: Width) -> Option<u32> {
+        let mapping = &self.mappings[self.mapping(addr)?];
+        mapping.reads.set(mapping.reads.get() + 1);
+        let offset = addr - mapping.region.base;
+        Some(match &mapping.target {
+            Target::Clint => self.clint.read(offset),
+            Target::Plic => self.plic.borrow_mut().read(offset),
+            Target::Uart => self.uart.borrow_mut().read(offset & 0x7) as u32,
+            Target::Syscon | Target::Hole => 0,
+            Target::Device(device) => {
+                self.device_accessed.set(true);
+                device.borrow_mut().read(offset, width)
+            }
+        })
     }
 
-    fn serial_write(&mut self, reg: u32, v: u8) {
-        match reg {
-            UART_RBR_THR if self.dlab() => self.divisor = (self.divisor & 0xff00) | v as u16,
-            UART_IER if self.dlab() => self.divisor = (self.divisor & 0x00ff) | ((v as u16) << 8),
-            UART_LCR => {
-                self.lcr = v;
-                self.serial.write(reg, v as u32);
+    /// Writes a device register. Returns false if `addr` is not MMIO.
+    fn mmio_write(&mut self, addr: u32, v: u32, width: Width) -> bool {
+        let Some(idx) = self.mapping(addr) else {
+            return false;
+        };
+        let mapping = &mut self.mappings[idx];
+        mapping.writes += 1;
+        let offset = addr - mapping.region.base;
+        let status = v & FINISHER_STATUS;
+        match &mapping.target {
+            Target::Clint => self.clint.write(offset, v),
+            Target::Plic => self.plic.get_mut().write(offset, v),
+            Target::Uart => self.uart.get_mut().write(offset & 0x7, v as u8),
+            Target::Syscon if status == self.layout.poweroff_value & FINISHER_STATUS => {
+                self.power_off = true
+            }
+            Target::Syscon if status == self.layout.reboot_value & FINISHER_STATUS => {
+                self.reboot = true
+            }
+            // A failure with code 0 would read as a pass, so it exits with 1.
+            Target::Syscon if status == FINISHER_FAIL => {
+                self.finisher_code = Some((v >> 16).max(1))
+            }
+            Target::Syscon | Target::Hole => {}
+            Target::Device(device) => {
+                self.device_accessed.set(true);
+                device.borrow_mut().write(offset, v, width)
             }
-            _ => self.serial.write(reg, v as u32),
         }
+        true
     }
 }
 
@@ -85,15 +574,101 @@ where
 {
     fn step(&mut self, mip: &mut u32) {
         self.clint.step(mip);
+        let uart = self.uart.get_mut();
+        uart.step();
+        // Going through every device on each instruction is too slow, so they are ticked
+        // on an interval, and right away when the guest may have handed them work.
+        self.untick_steps += 1;
+        if self.untick_steps == TICK_INTERVAL || self.device_accessed.take() {
+            let steps = std::mem::take(&mut self.untick_steps);
+            self.device_levels = 0;
+            let mut memory = GuestMemory::new(RAM_START, &mut self.ram);
+            for mapping in &self.mappings {
+                let Target::Device(device) = &mapping.target else {
+                    continue;
+                };
+                let mut device = device.borrow_mut();
+                device.tick(steps, &mut memory);
+                if let Some(irq) = mapping.irq.filter(|_| device.interrupt()) {
+                    self.device_levels |= 1u64.checked_shl(irq).unwrap_or(0);
+                }
+            }
+        }
+        let mut levels = self.device_levels;
+        if uart.interrupt() {
+            levels |= 1u64.checked_shl(self.layout.uart_irq).unwrap_or(0);
+        }
+        self.plic.get_mut().step(levels, mip);
     }
 
     fn power_off(&self) -> bool {
-        self.power_off
+        self.power_off || self.uart.borrow().serial().quit_requested()
     }
 
     fn reboot(&self) -> bool {
         self.reboot
     }
+
+    fn exit_code(&self) -> Option<u32> {
+        self.finisher_code
+            .or_else(|| self.htif.as_ref().and_then(Htif::exit_code))
+    }
+
+    fn mmio_stats(&self) -> Vec<MmioCount> {
+        self.mappings
+            .iter()
+            .map(|m| MmioCount {
+                device: m.name.clone(),
+                base: m.region.base,
+                reads: m.reads.get(),
+                writes: m.writes,
+            })
+            .collect()
+    }
+
+    fn time(&self) -> u64 {
+        self.clint.mtime
+    }
+
+    fn console_write(&mut self, c: u8) {
+        self.uart.get_mut().transmit(c);
+    }
+
+    fn console_read(&mut self) -> Option<u8> {
+        self.uart.get_mut().receive()
+    }
+
+    fn save_boot_image(&mut self) {
+        // Most of RAM is blank at boot, so only the pages holding something are kept.
+        self.boot_image = self
+            .ram
+            .chunks(BOOT_IMAGE_PAGE)
+            .enumerate()
+            .filter(|(_, page)| page.iter().any(|&b| b != 0))
+            .map(|(i, page)| (i * BOOT_IMAGE_PAGE, page.into()))
+            .collect();
+    }
+
+    fn reset(&mut self) {
+        self.power_off = false;
+        self.reboot = false;
+        self.finisher_code = None;
+        self.clint.reset();
+        self.uart.get_mut().reset();
+        self.plic.get_mut().reset();
+        for mapping in &mut self.mappings {
+            if let Target::Device(device) = &mut mapping.target {
+                device.get_mut().reset();
+            }
+        }
+        self.untick_steps = 0;
+        self.device_accessed.set(true);
+        self.device_levels = 0;
+        self.ram.fill(0);
+        for (offset, page) in &self.boot_image {
+            self.ram[*offset..*offset + page.len()].copy_from_slice(page);
+        }
+    }
 }
 
 impl<T, S> BusReader for Bus<T, S>
@@ -102,59 +677,43 @@ where
     S: device_interfaces::SerialInterface,
 {
     fn read8(&self, addr: u32) -> Result<u8, BusException> {
-        match addr {
-            0x1100bffc => Ok(self.clint.read(addr & 0xffff) as u8),
-            0x1100bff8 => Ok(self.clint.read(addr & 0xffff) as u8),
-            0x10000000..=0x100000ff => Ok(self.serial_read(addr & 0x7)),
-            0x10000100..=0x12000000 => Ok(0),
-            _ => {
-                let offset = self
-                    .ram_offset(addr, 1)
-                    .ok_or(BusException::LoadAccessFault)?;
-                Ok(self.ram[offset])
-            }
-        }
+        let Some(offset) = self.ram_offset(addr, 1) else {
+            return self
+                .mmio_read(addr, Width::Byte)
+                .map(|v| v as u8)
+                .ok_or(BusException::LoadAccessFault);
+        };
+        Ok(self.ram[offset])
     }
 
     fn read16(&self, addr: u32) -> Result<u16, BusException> {
         if addr & 1 != 0 {
             return Err(BusException::LoadAddressMisaligned);
         }
-        match addr {
-            0x1100bffc => Ok(self.clint.read(addr & 0xffff) as u16),
-            0x1100bff8 => Ok(self.clint.read(addr & 0xffff) as u16),
-            0x10000000..=0x100000ff => Ok(self.serial_read(addr & 0x7) as u16),
-            0x10000100..=0x12000000 => Ok(0),
-            _ => {
-                let offset = self
-                    .ram_offset(addr, 2)
-                    .ok_or(BusException::LoadAccessFault)?;
-                Ok(u16::from_le_bytes([self.ram[offset], self.ram[offset + 1]]))
-            }
-        }
+        let Some(offset) = self.ram_offset(addr, 2) else {
+            return self
+                .mmio_read(addr, Width::Half)
+                .map(|v| v as u16)
+                .ok_or(BusException::LoadAccessFault);
+        };
+        Ok(u16::from_le_bytes([self.ram[offset], self.ram[offset + 1]]))
     }
 
     fn read32(&self, addr: u32) -> Result<u32, BusException> {
         if addr & 3 != 0 {
             return Err(BusException::LoadAddressMisaligned);
         }
-        match addr {
-            0x1100bffc => Ok(self.clint.read(addr & 0xffff)),
-            0x1100bff8 => Ok(self.clint.read(addr & 0xffff)),
-            0x10000000..=0x100000ff => Ok(self.serial_read(addr & 0x7) as u32),
-            0x10000100..=0x12000000 => Ok(0),
-            _ => {
-                let offset = self
-                    .ram_offset(addr, 4)
-                    .ok_or(BusException::LoadAccessFault)?;
-                Ok(u32::from_le_bytes([
-                    self.ram[offset],
-                    self.ram[offset + 1],
-                    self.ram[offset + 2],
-                    self.ram[offset + 3],
-                ]))
-            }
-        }
+        let Some(offset) = self.ram_offset(addr, 4) else {
+            return self
+                .mmio_read(addr, Width::Word)
+                .ok_or(BusException::LoadAccessFault);
+        };
+        Ok(u32::from_le_bytes([
+            self.ram[offset],
+            self.ram[offset + 1],
+            self.ram[offset + 2],
+            self.ram[offset + 3],
+        ]))
     }
 }
 
@@ -164,21 +723,13 @@ where
     S: device_interfaces::SerialInterface,
 {
     fn write8(&mut self, addr: u32, v: u8) -> Result<(), BusException> {
-        match addr {
-            // msip
-            0x11100000 => self.clint.write(addr & 0xffff, v as u32),
-            // mtime
-            0x11004004 => self.clint.write(addr & 0xffff, v as u32),
-            0x11004000 => self.clint.write(addr & 0xffff, v as u32),
-            0x10000000..=0x100000ff => self.serial_write(addr & 0x7, v),
-            0x10000100..=0x12000000 => {}
-            _ => {
-                let offset = self
-                    .ram_offset(addr, 1)
-                    .ok_or(BusException::StoreAccessFault)?;
-                self.ram[offset] = v;
-            }
+        let Some(offset) = self.ram_offset(addr, 1) else {
+            return self
+                .mmio_write(addr, v as u32, Width::Byte)
+                .then_some(())
+                .ok_or(BusException::StoreAccessFault);
         };
+        self.ram[offset] = v;
         Ok(())
     }
 
@@ -186,24 +737,13 @@ where
         if addr & 1 != 0 {
             return Err(BusException::StoreAddressMisaligned);
         }
-        match addr {
-            // syscon
-            0x11100000 if v == 0x5555 => self.power_off = true,
-            0x11100000 if v == 0x7777 => self.reboot = true,
-            // msip
-            0x11100000 => self.clint.write(addr & 0xffff, v as u32),
-            // mtime
-            0x11004004 => self.clint.write(addr & 0xffff, v as u32),
-            0x11004000 => self.clint.write(addr & 0xffff, v as u32),
-            0x10000000..=0x100000ff => self.serial_write(addr & 0x7, v as u8),
-            0x10000100..=0x12000000 => {}
-            _ => {
-                let offset = self
-                    .ram_offset(addr, 2)
-                    .ok_or(BusException::StoreAccessFault)?;
-                self.ram[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
-            }
+        let Some(offset) = self.ram_offset(addr, 2) else {
+            return self
+                .mmio_write(addr, v as u32, Width::Half)
+                .then_some(())
+                .ok_or(BusException::StoreAccessFault);
         };
+        self.ram[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
         Ok(())
     }
 
@@ -211,24 +751,14 @@ where
         if addr & 3 != 0 {
             return Err(BusException::StoreAddressMisaligned);
         }
-        match addr {
-            // syscon
-            0x11100000 if v == 0x5555 => self.power_off = true,
-            0x11100000 if v == 0x7777 => self.reboot = true,
-            // msip
-            0x11100000 => self.clint.write(addr & 0xffff, v),
-            // mtime
-            0x11004004 => self.clint.write(addr & 0xffff, v),
-            0x11004000 => self.clint.write(addr & 0xffff, v),
-            0x10000000..=0x100000ff => self.serial_write(addr & 0x7, v as u8),
-            0x10000100..=0x12000000 => {}
-            _ => {
-                let offset = self
-                    .ram_offset(addr, 4)
-                    .ok_or(BusException::StoreAccessFault)?;
-                self.ram[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
-            }
+        let Some(offset) = self.ram_offset(addr, 4) else {
+            return self
+                .mmio_write(addr, v, Width::Word)
+                .then_some(())
+                .ok_or(BusException::StoreAccessFault);
         };
+        self.ram[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+        self.htif_store(addr, v);
         Ok(())
     }
 }
@@ -1,5 +1,7 @@
 use std::error::Error;
 
+use crate::stats::MmioCount;
+
 #[derive(Debug, Clone, Copy)]
 pub enum BusException {
     LoadAddressMisaligned,
@@ -29,6 +31,30 @@ pub trait BusController {
     fn step(&mut self, mip: &mut u32);
     fn power_off(&self) -> bool;
     fn reboot(&self) -> bool;
+    /// Exit code a device asked the emulator to stop with.
+    fn exit_code(&self) -> Option<u32> {
+        None
+    }
+    /// Per-device MMIO access counts, for buses that keep them.
+    fn mmio_stats(&self) -> Vec<MmioCount> {
+        Vec::new()
+    }
+    /// Platform time in timer ticks, as seen by the `time` CSR and the SBI timer.
+    fn time(&self) -> u64 {
+        0
+    }
+    /// Writes a byte to the console, for firmware calls.
+    fn console_write(&mut self, _c: u8) {}
+    /// Reads a byte from the console if one is waiting, for firmware calls.
+    fn console_read(&mut self) -> Option<u8> {
+        None
+    }
+    /// Remembers what RAM holds now, kernel and device tree included, as what a reset
+    /// restores.
+    fn save_boot_image(&mut self) {}
+    /// Puts the devices back in their power-on state and RAM back to the saved boot image,
+    /// for a reboot.
+    fn reset(&mut self) {}
 }
 
 pub trait BusReader {
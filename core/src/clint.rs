@@ -1,5 +1,8 @@
 use device_interfaces::TimerDriver;
 
+/// Frequency mtime counts at: the timer drivers report microseconds.
+pub const TIMEBASE_FREQUENCY: u32 = 1_000_000;
+
 /// Core-Local Interruptor (CLINT)
 /// https://sifive.cdn.prismic.io/sifive%2Fc89f6e5a-cf9e-44c3-a3db-04420702dcc1_sifive+e31+manual+v19.08.pdf
 /// https://chromitem-soc.readthedocs.io/en/latest/clint.html
@@ -31,6 +34,13 @@ impl<T: TimerDriver> Clint<T> {
         }
     }
 
+    /// Clears the registers, as at power-on.
+    pub fn reset(&mut self) {
+        self.msip = 0;
+        self.mtimecmp = 0;
+        self.mtime = 0;
+    }
+
     pub fn step(&mut self, mip: &mut u32) {
         self.mtime += self.timer.as_micros();
         // Handle Elasped interrupt.
@@ -51,7 +61,7 @@ impl<T: TimerDriver> Clint<T> {
             0x4004 => self.mtimecmp.wrapping_shr(32) as u32,
             0xbff8 => self.mtime as u32,
             0xbffc => self.mtime.wrapping_shr(32) as u32,
-            _ => unreachable!(),
+            _ => 0,
         }
     }
 
@@ -68,7 +78,7 @@ impl<T: TimerDriver> Clint<T> {
             // MTIME registers 8 bytes
             0xbff8 => self.mtime = (self.mtime & !0xffffffff) | (value as u64),
             0xbffc => self.mtime = (self.mtime & !(0xffffffff << 32)) | ((value as u64) << 32),
-            _ => unreachable!(),
+            _ => {}
         };
     }
 }
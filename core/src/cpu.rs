@@ -1,4 +1,11 @@
+mod mmu;
+mod sbi;
+
 use crate::bus_interface::{BusController, BusException, BusReader, BusWriter};
+use crate::profiler::Profiler;
+use crate::semihosting::{self, Outcome, Semihosting};
+use crate::stats::{OpcodeClass, Stats};
+use mmu::{Access, Tlb};
 
 #[derive(Debug, Default)]
 pub struct Cpu<B> {
@@ -43,17 +50,73 @@ pub struct Cpu<B> {
     /// trap. Otherwise, mcause is never written by the implementation, though it may be explicitly
     /// written by software.
     mcause: u32,
+    /// Delegates exceptions taken below M-mode to S-mode, one bit per cause.
+    medeleg: u32,
+    /// Delegates interrupts taken below M-mode to S-mode, one bit per cause.
+    mideleg: u32,
+    /// The stvec register holds the S-mode trap vector, formatted like mtvec.
+    stvec: u32,
+    /// S-mode counterpart of mscratch.
+    sscratch: u32,
+    /// S-mode counterpart of mepc.
+    sepc: u32,
+    /// S-mode counterpart of mcause.
+    scause: u32,
+    /// S-mode counterpart of mtval.
+    stval: u32,
+    /// The satp register controls S-mode address translation: MODE (bit 31), ASID and the
+    /// physical page number of the root page table.
+    satp: u32,
+    /// Translations cached from the page tables.
+    tlb: Tlb,
     /// Exception code recoder.
-    exception: u32,
+    exception: Option<u32>,
     /// The Wait for Interrupt instruction (WFI) provides a hint to the implementation that
     /// the current hart can be stalled until an interrupt might need servicing.
     wait_for_interrupt: bool,
-    /// Previous privilege mode.
-    previous_mode: PrivilegeMode,
+    /// Privilege mode the hart is running in.
+    mode: PrivilegeMode,
     /// This is used to reserve addresses for LR/SC
     reserved_load_addresses: std::collections::HashMap<u32, u32>,
     /// It is used to record exception reason for mtval
     cause: u32,
+    /// Execution statistics.
+    stats: Stats,
+    /// PC sampler, when profiling was requested.
+    profiler: Option<Profiler>,
+    /// Host services for semihosting calls, when enabled.
+    semihosting: Option<Semihosting>,
+    /// Exit code the guest asked the emulator to stop with.
+    exit_code: Option<u32>,
+    /// Whether S-mode `ecall`s are serviced by the built-in SBI implementation.
+    sbi: bool,
+    /// Deadline of the SBI timer, compared against the platform time.
+    stimecmp: u64,
+    /// Whether the guest asked for a reboot through SBI.
+    reboot: bool,
+    /// Exception the hart got stuck on, if it did.
+    fault: Option<Fault>,
+}
+
+/// An exception the hart cannot get out of: it was raised by the first instruction of the
+/// trap handler it leads to, in the same privilege mode, so the hart would take it forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// Value written to `mcause` or `scause`.
+    pub cause: u32,
+    pub pc: u32,
+    /// Value written to `mtval` or `stval`.
+    pub tval: u32,
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "exception {:#x} at {:#010x} (tval {:#010x}) in its own trap handler",
+            self.cause, self.pc, self.tval
+        )
+    }
 }
 
 impl<B: BusController + BusReader + BusWriter> Cpu<B> {
@@ -70,12 +133,29 @@ impl<B: BusController + BusReader + BusWriter> Cpu<B> {
             mepc: 0,
             mtval: 0,
             mcause: 0,
+            medeleg: 0,
+            mideleg: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            tlb: Tlb::default(),
             bus,
-            exception: 0,
+            exception: None,
             wait_for_interrupt: false,
-            previous_mode: PrivilegeMode::Machine,
+            mode: PrivilegeMode::Machine,
             reserved_load_addresses: std::collections::HashMap::new(),
             cause: 0,
+            stats: Stats::default(),
+            profiler: None,
+            semihosting: None,
+            exit_code: None,
+            sbi: false,
+            stimecmp: u64::MAX,
+            reboot: false,
+            fault: None,
         }
     }
 
@@ -93,6 +173,29 @@ impl<B: BusController + BusReader + BusWriter> Cpu<B> {
         self.pc = pc;
         self
     }
+
+    pub fn profiler(&mut self, profiler: Option<Profiler>) -> &mut Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Services semihosting calls instead of raising breakpoint exceptions for them.
+    pub fn semihosting(&mut self, enabled: bool) -> &mut Self {
+        self.semihosting = enabled.then(Semihosting::default);
+        self
+    }
+
+    /// Acts as SBI firmware: the hart starts in S-mode with traps delegated the way
+    /// OpenSBI sets them up, and S-mode `ecall`s are serviced by the emulator.
+    pub fn sbi(&mut self, enabled: bool) -> &mut Self {
+        self.sbi = enabled;
+        if enabled {
+            self.mode = PrivilegeMode::SuperVisor;
+            self.medeleg = sbi::DELEGATED_EXCEPTIONS;
+            self.mideleg = sbi::DELEGATED_INTERRUPTS;
+        }
+        self
+    }
 }
 
 // @See https://github.com/riscv/riscv-isa-manual/releases/download/Priv-v1.12/riscv-privileged-20211203.pdf p39
@@ -148,33 +251,48 @@ impl From<Exception> for u32 {
 
 /// @See https://www.five-embeddev.com/riscv-isa-manual/latest/machine.html#sec:mcause
 #[derive(Debug, PartialEq, Clone, Copy)]
-#[allow(dead_code)]
+#[allow(clippy::enum_variant_names)]
 enum Interrupt {
-    MachineTimerInterrupt,
+    SupervisorSoftwareInterrupt = 1,
+    MachineSoftwareInterrupt = 3,
+    SupervisorTimerInterrupt = 5,
+    MachineTimerInterrupt = 7,
+    SupervisorExternalInterrupt = 9,
+    MachineExternalInterrupt = 11,
+}
+
+impl Interrupt {
+    /// In decreasing priority order.
+    const ALL: [Interrupt; 6] = [
+        Self::MachineExternalInterrupt,
+        Self::MachineSoftwareInterrupt,
+        Self::MachineTimerInterrupt,
+        Self::SupervisorExternalInterrupt,
+        Self::SupervisorSoftwareInterrupt,
+        Self::SupervisorTimerInterrupt,
+    ];
+
+    /// Bit in mip/mie.
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
 }
 
 impl From<Interrupt> for u32 {
     fn from(value: Interrupt) -> Self {
-        match value {
-            Interrupt::MachineTimerInterrupt => 0x8000_0007,
-        }
+        0x8000_0000 | value as u32
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
 pub enum PrivilegeMode {
     User,
     SuperVisor,
     Reserved,
+    #[default]
     Machine,
 }
 
-impl Default for PrivilegeMode {
-    fn default() -> Self {
-        Self::Machine
-    }
-}
-
 impl From<PrivilegeMode> for u32 {
     fn from(value: PrivilegeMode) -> Self {
         match value {
@@ -197,6 +315,25 @@ impl From<u32> for PrivilegeMode {
     }
 }
 
+/// mstatus fields.
+mod mstatus {
+    pub(crate) const SIE: u32 = 1 << 1;
+    pub(crate) const MIE: u32 = 1 << 3;
+    pub(crate) const SPIE: u32 = 1 << 5;
+    pub(crate) const MPIE: u32 = 1 << 7;
+    pub(crate) const SPP: u32 = 1 << 8;
+    pub(crate) const MPP: u32 = 0b11 << 11;
+    pub(crate) const MPRV: u32 = 1 << 17;
+    pub(crate) const SUM: u32 = 1 << 18;
+    pub(crate) const MXR: u32 = 1 << 19;
+    /// Fields visible through sstatus: SIE, SPIE, SPP, VS, FS, XS, SUM, MXR and SD.
+    pub(crate) const SSTATUS: u32 = 0x800de722;
+}
+
+/// mip bits software may write. The timer and external bits of M-mode are driven by
+/// devices.
+const MIP_WRITABLE: u32 = 0x222;
+
 mod helpers {
     pub(crate) fn rd(ir: u32) -> usize {
         ((ir >> 7) & 0x1f) as usize
@@ -207,6 +344,36 @@ mod helpers {
     }
 }
 
+fn opcode_class(ir: u32) -> OpcodeClass {
+    match ir & 0x7f {
+        0b0110111 => OpcodeClass::Lui,
+        0b0010111 => OpcodeClass::Auipc,
+        0b1101111 => OpcodeClass::Jal,
+        0b1100111 => OpcodeClass::Jalr,
+        0b1100011 => OpcodeClass::Branch,
+        0b0000011 => OpcodeClass::Load,
+        0b0100011 => OpcodeClass::Store,
+        0b0110011 if (ir & 0x02000000) != 0 && (ir & 0b100000) != 0 => OpcodeClass::MulDiv,
+        0b0010011 | 0b0110011 => OpcodeClass::Alu,
+        0b0001111 => OpcodeClass::Fence,
+        0b1110011 if (ir >> 12) & 0b111 == 0 => OpcodeClass::System,
+        0b1110011 => OpcodeClass::Csr,
+        0b0101111 => OpcodeClass::Atomic,
+        _ => OpcodeClass::Illegal,
+    }
+}
+
+/// Address of the handler for `cause` given the BASE and MODE fields of a trap vector.
+/// In vectored mode interrupts jump to BASE + 4 * cause.
+fn trap_vector(tvec: u32, cause: u32) -> u32 {
+    let base = tvec & !0b11;
+    if tvec & 0b11 == 1 && cause & 0x8000_0000 != 0 {
+        base + 4 * (cause & 0x1f)
+    } else {
+        base
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum CpuState {
     Idle,
@@ -222,40 +389,120 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
         &self.bus
     }
 
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Exit code requested by the guest, through semihosting or a bus device, if it asked
+    /// to stop.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code.or_else(|| self.bus.exit_code())
+    }
+
+    /// Whether the guest asked for a reboot, through SBI or a bus device.
+    pub fn reboot_requested(&self) -> bool {
+        self.reboot || self.bus.reboot()
+    }
+
+    /// Puts the hart and the bus back in their power-on state, for a reboot. The
+    /// statistics, the profiler and the features chosen with the builders carry over. The
+    /// boot registers (`a0`, `a1` and `pc`) have to be set again.
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.x = [0; 32];
+        self.pc = 0;
+        self.mstatus = 0;
+        self.cycle = 0;
+        self.mscratch = 0;
+        self.mtvec = 0;
+        self.mie = 0;
+        self.mip = 0;
+        self.mepc = 0;
+        self.mtval = 0;
+        self.mcause = 0;
+        self.medeleg = 0;
+        self.mideleg = 0;
+        self.stvec = 0;
+        self.sscratch = 0;
+        self.sepc = 0;
+        self.scause = 0;
+        self.stval = 0;
+        self.satp = 0;
+        self.tlb = Tlb::default();
+        self.exception = None;
+        self.wait_for_interrupt = false;
+        self.mode = PrivilegeMode::Machine;
+        self.reserved_load_addresses.clear();
+        self.cause = 0;
+        self.exit_code = None;
+        self.stimecmp = u64::MAX;
+        self.reboot = false;
+        self.fault = None;
+        let (semihosting, sbi) = (self.semihosting.is_some(), self.sbi);
+        self.semihosting(semihosting).sbi(sbi);
+    }
+
+    /// The exception the hart got stuck on, if it did.
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    /// Address of the next instruction.
+    pub fn program_counter(&self) -> u32 {
+        self.pc
+    }
+
+    /// Records time spent parked in WFI.
+    pub fn add_idle(&mut self, duration: std::time::Duration) {
+        self.stats.idle += duration;
+    }
+
+    /// Snapshot of the execution statistics, including the bus's MMIO counters.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            mmio: self.bus.mmio_stats(),
+            profile: self.profiler.clone(),
+            ..self.stats.clone()
+        }
+    }
+
     pub fn step(&mut self) -> CpuState {
         // Drive bus state
         self.bus.step(&mut self.mip);
+        if self.sbi {
+            self.sbi_timer();
+        }
 
-        // If interrupted
-        // MTIP（bit 7）：Machine timer interrupt pending
-        if self.mip & 0x80 != 0 {
+        // A pending and enabled interrupt wakes the hart even if it cannot be taken yet.
+        if self.mip & self.mie != 0 {
             self.wait_for_interrupt = false;
         } else if self.wait_for_interrupt {
             return CpuState::Idle;
         }
 
-        // bit3 in mstatus is MIE: Machine Interrupt Enable
-        if (self.mip & 0x80 != 0) && (self.mie & 0x80 != 0) && (self.mstatus & 0x8 != 0) {
-            self.exception = Interrupt::MachineTimerInterrupt.into();
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.exception = Some(interrupt.into());
             self.process_exception();
             return CpuState::Active;
         }
 
         self.cycle = self.cycle.wrapping_add(1);
 
-        let ir = match self.bus.read32(self.pc) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.tick(self.pc, self.mode);
+        }
+
+        let ir = match self.fetch() {
             Ok(ir) => ir,
             Err(e) => {
-                let e = match e {
-                    BusException::LoadAddressMisaligned => Exception::InstructionAddressMisaligned,
-                    _ => Exception::InstructionAccessFault,
-                };
                 self.record_exception(e, self.pc);
                 self.process_exception();
                 return CpuState::Active;
             }
         };
 
+        self.stats.record_opcode(opcode_class(ir));
+
         match ir & 0x7f {
             0b0110111 => self.write_back(helpers::rd(ir), ir & 0xfffff000), // LUI
             0b0010111 => {
@@ -278,6 +525,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
                 if op == 0 {
                     self.system(ir);
                     if self.wait_for_interrupt {
+                        self.stats.instructions += 1;
                         return CpuState::Idle;
                     }
                 } else {
@@ -291,11 +539,12 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             }
         }
 
-        if self.exception != 0 {
+        if self.exception.is_some() {
             self.process_exception();
             return CpuState::Active;
         }
 
+        self.stats.instructions += 1;
         self.pc = self.pc.wrapping_add(4);
         self.process_exception();
         CpuState::Active
@@ -307,28 +556,106 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
         // On an illegal instruction trap, mtval may be written with the first XLEN or ILEN bits of the faulting instruction
         // as described below. For other traps, mtval is set to zero, but a future standard may redefine
         // mtval’s setting for other traps.
-        self.exception = e.into();
+        self.exception = Some(e.into());
         self.cause = cause;
     }
 
+    /// Highest priority interrupt that is pending, enabled and not masked by the current
+    /// privilege mode.
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.mip & self.mie;
+        if pending == 0 {
+            return None;
+        }
+        // Interrupts for a more privileged mode are always enabled, those for a less
+        // privileged one never are.
+        let m_enabled = self.mode != PrivilegeMode::Machine || self.mstatus & mstatus::MIE != 0;
+        let s_enabled = match self.mode {
+            PrivilegeMode::User => true,
+            PrivilegeMode::SuperVisor => self.mstatus & mstatus::SIE != 0,
+            _ => false,
+        };
+        let m_pending = if m_enabled { pending & !self.mideleg } else { 0 };
+        let s_pending = if s_enabled { pending & self.mideleg } else { 0 };
+        let takeable = if m_pending != 0 { m_pending } else { s_pending };
+        Interrupt::ALL
+            .into_iter()
+            .find(|interrupt| takeable & interrupt.bit() != 0)
+    }
+
     fn process_exception(&mut self) {
-        if self.exception != 0 {
-            // Interrupt
-            if self.exception & 0x80000000 != 0 {
-                self.mcause = self.exception;
-                self.mtval = 0;
-            } else {
-                // Exception
-                self.mcause = self.exception;
-                self.mtval = self.cause;
-            }
+        let Some(cause) = self.exception.take() else {
+            return;
+        };
+        self.stats.record_trap(cause);
+        let interrupt = cause & 0x8000_0000 != 0;
+        let tval = if interrupt { 0 } else { self.cause };
+        let delegation = if interrupt { self.mideleg } else { self.medeleg };
+        let code = cause & 0x1f;
+        let (pc, mode) = (self.pc, self.mode);
+
+        if self.mode != PrivilegeMode::Machine && (delegation >> code) & 1 != 0 {
+            self.scause = cause;
+            self.sepc = self.pc;
+            self.stval = tval;
+            let sie = (self.mstatus & mstatus::SIE) << 4;
+            let spp = if self.mode == PrivilegeMode::SuperVisor { mstatus::SPP } else { 0 };
+            self.mstatus =
+                (self.mstatus & !(mstatus::SIE | mstatus::SPIE | mstatus::SPP)) | sie | spp;
+            self.pc = trap_vector(self.stvec, cause);
+            self.mode = PrivilegeMode::SuperVisor;
+        } else {
+            self.mcause = cause;
             self.mepc = self.pc;
-            let prev: u32 = self.previous_mode.into();
-            self.mstatus = (((self.mstatus) & 0x08) << 4) | (prev << 11);
-            self.pc = self.mtvec;
-            self.previous_mode = PrivilegeMode::Machine;
-            self.exception = 0;
+            self.mtval = tval;
+            let mie = (self.mstatus & mstatus::MIE) << 4;
+            let mpp = u32::from(self.mode) << 11;
+            self.mstatus =
+                (self.mstatus & !(mstatus::MIE | mstatus::MPIE | mstatus::MPP)) | mie | mpp;
+            self.pc = trap_vector(self.mtvec, cause);
+            self.mode = PrivilegeMode::Machine;
         }
+        if !interrupt && self.pc == pc && self.mode == mode {
+            self.fault = Some(Fault { cause, pc, tval });
+        }
+    }
+
+    fn fetch(&mut self) -> Result<u32, Exception> {
+        let addr = self.translate(self.pc, Access::Fetch)?;
+        self.bus.read32(addr).map_err(|e| match e {
+            BusException::LoadAddressMisaligned => Exception::InstructionAddressMisaligned,
+            _ => Exception::InstructionAccessFault,
+        })
+    }
+
+    fn read8(&mut self, addr: u32) -> Result<u8, Exception> {
+        let addr = self.translate(addr, Access::Load)?;
+        Ok(self.bus.read8(addr)?)
+    }
+
+    fn read16(&mut self, addr: u32) -> Result<u16, Exception> {
+        let addr = self.translate(addr, Access::Load)?;
+        Ok(self.bus.read16(addr)?)
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, Exception> {
+        let addr = self.translate(addr, Access::Load)?;
+        Ok(self.bus.read32(addr)?)
+    }
+
+    fn write8(&mut self, addr: u32, v: u8) -> Result<(), Exception> {
+        let addr = self.translate(addr, Access::Store)?;
+        Ok(self.bus.write8(addr, v)?)
+    }
+
+    fn write16(&mut self, addr: u32, v: u16) -> Result<(), Exception> {
+        let addr = self.translate(addr, Access::Store)?;
+        Ok(self.bus.write16(addr, v)?)
+    }
+
+    fn write32(&mut self, addr: u32, v: u32) -> Result<(), Exception> {
+        let addr = self.translate(addr, Access::Store)?;
+        Ok(self.bus.write32(addr, v)?)
     }
 
     fn write_back(&mut self, rd: usize, v: u32) {
@@ -393,25 +720,25 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
 
         match (ir >> 12) & 0x7 {
             // LB, LH, LW, LBU, LHU
-            0b000 => match self.bus.read8(rsval) {
+            0b000 => match self.read8(rsval) {
                 Ok(v) => self.write_back(rd, (v as i8) as u32),
-                Err(e) => self.record_exception(e.into(), rsval),
+                Err(e) => self.record_exception(e, rsval),
             },
-            0b001 => match self.bus.read16(rsval) {
+            0b001 => match self.read16(rsval) {
                 Ok(v) => self.write_back(rd, (v as i16) as u32),
-                Err(e) => self.record_exception(e.into(), rsval),
+                Err(e) => self.record_exception(e, rsval),
             },
-            0b010 => match self.bus.read32(rsval) {
+            0b010 => match self.read32(rsval) {
                 Ok(v) => self.write_back(rd, v),
-                Err(e) => self.record_exception(e.into(), rsval),
+                Err(e) => self.record_exception(e, rsval),
             },
-            0b100 => match self.bus.read8(rsval) {
+            0b100 => match self.read8(rsval) {
                 Ok(v) => self.write_back(rd, v as u32),
-                Err(e) => self.record_exception(e.into(), rsval),
+                Err(e) => self.record_exception(e, rsval),
             },
-            0b101 => match self.bus.read16(rsval) {
+            0b101 => match self.read16(rsval) {
                 Ok(v) => self.write_back(rd, v as u32),
-                Err(e) => self.record_exception(e.into(), rsval),
+                Err(e) => self.record_exception(e, rsval),
             },
             _ => {
                 self.record_exception(Exception::IllegalInstruction, ir);
@@ -431,19 +758,16 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
         match (ir >> 12) & 0x7 {
             // SB, SH, SW
             0b000 => {
-                self.bus
-                    .write8(addr, rs2 as u8)
-                    .unwrap_or_else(|e| self.record_exception(e.into(), addr));
+                self.write8(addr, rs2 as u8)
+                    .unwrap_or_else(|e| self.record_exception(e, addr));
             }
             0b001 => {
-                self.bus
-                    .write16(addr, rs2 as u16)
-                    .unwrap_or_else(|e| self.record_exception(e.into(), addr));
+                self.write16(addr, rs2 as u16)
+                    .unwrap_or_else(|e| self.record_exception(e, addr));
             }
             0b010 => {
-                self.bus
-                    .write32(addr, rs2)
-                    .unwrap_or_else(|e| self.record_exception(e.into(), addr));
+                self.write32(addr, rs2)
+                    .unwrap_or_else(|e| self.record_exception(e, addr));
             }
             _ => {
                 self.record_exception(Exception::IllegalInstruction, ir);
@@ -458,7 +782,16 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
         let mut rs2 = self.x[((ir >> 20) & 0x1f) as usize];
         let f = (ir >> 27) & 0x1f;
 
-        let v = match self.bus.read32(rs1) {
+        // Everything but LR.W writes, so faults are reported as store faults.
+        let access = if f == 0b00010 { Access::Load } else { Access::Store };
+        let addr = match self.translate(rs1, access) {
+            Ok(addr) => addr,
+            Err(e) => {
+                self.record_exception(e, rs1);
+                return;
+            }
+        };
+        let v = match self.bus.read32(addr) {
             Ok(v) => v,
             Err(e) => {
                 self.record_exception(e.into(), rs1);
@@ -479,7 +812,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
                 if let Some(val) = self.reserved_load_addresses.get(&rs1) {
                     if *val == v {
                         self.bus
-                            .write32(rs1, rs2)
+                            .write32(addr, rs2)
                             .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                         self.write_back(rd, 0);
                     } else {
@@ -492,14 +825,14 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             // AMOSWAP.W
             0b00001 => {
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v);
             }
             0b00000 => {
                 rs2 = rs2.wrapping_add(v);
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -507,7 +840,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b00100 => {
                 rs2 ^= v;
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -515,7 +848,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b01100 => {
                 rs2 &= v;
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -523,7 +856,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b01000 => {
                 rs2 |= v;
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -531,7 +864,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b10000 => {
                 rs2 = if (rs2 as i32) < (v as i32) { rs2 } else { v };
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -539,7 +872,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b10100 => {
                 rs2 = if (rs2 as i32) > (v as i32) { rs2 } else { v };
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -547,7 +880,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b11000 => {
                 rs2 = if rs2 < v { rs2 } else { v };
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -555,7 +888,7 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
             0b11100 => {
                 rs2 = if rs2 > v { rs2 } else { v };
                 self.bus
-                    .write32(rs1, rs2)
+                    .write32(addr, rs2)
                     .unwrap_or_else(|e| self.record_exception(e.into(), rs1));
                 self.write_back(rd, v)
             }
@@ -584,10 +917,10 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
                 // DIV
                 v = if rs2 == 0 { !0 } else { (rs1 as i32).wrapping_div(rs2 as i32) as u32 }
             }
-            0b101 => v = if rs2 == 0 { u32::MAX } else { rs1 / rs2 }, // DIVU
-            0b110 if rs2 == 0 => v = 0,                               // REM
+            0b101 => v = rs1.checked_div(rs2).unwrap_or(u32::MAX), // DIVU
+            0b110 if rs2 == 0 => v = rs1,                          // REM
             0b110 => v = (rs1 as i32).wrapping_rem(rs2 as i32) as u32, // REM
-            0b111 => v = if rs2 == 0 { rs1 } else { rs1 % rs2 },      // REMU
+            0b111 => v = if rs2 == 0 { rs1 } else { rs1 % rs2 },   // REMU
             _ => {
                 self.record_exception(Exception::IllegalInstruction, ir);
             }
@@ -626,80 +959,178 @@ impl<B: BusController + BusWriter + BusReader> Cpu<B> {
     fn zicsr(&mut self, ir: u32) {
         // Zicsr
         let rd = helpers::rd(ir);
-        let mut v = 0;
         let csr = ir >> 20;
         let op = (ir >> 12) & 0b111;
-        if (op & 3) != 0 {
-            let rs1imm = (ir >> 15) & 0x1f;
-            let rs1 = self.x[rs1imm as usize];
-            let mut val = rs1;
-            // https://raw.githubusercontent.com/riscv/virtual-memory/main/specs/663-Svpbmt.pdf
-            // Generally, support for Zicsr
-            match csr {
-                0x340 => v = self.mscratch,
-                0x305 => v = self.mtvec,
-                0x304 => v = self.mie,
-                0xC00 => v = self.cycle as u32,
-                0x341 => v = self.mepc,
-                0x300 => v = self.mstatus,
-                0x342 => v = self.mcause,
-                0x343 => v = self.mtval,
-                0xf11 => v = 0x00000000, // mvendorid
-                0x301 => v = 0x00000000, // misa
-                _ => {}
-            }
+        if (op & 3) == 0 {
+            self.record_exception(Exception::IllegalInstruction, ir);
+            return;
+        }
+        let rs1imm = (ir >> 15) & 0x1f;
+        // CSRRS and CSRRC with x0 (or a zero immediate) only read.
+        let writes = op & 3 == 0b01 || rs1imm != 0;
+        // Bits 9:8 of the address give the lowest privilege level that may access the CSR,
+        // and 0b11 in bits 11:10 makes it read-only.
+        if u32::from(self.mode) < (csr >> 8) & 0b11 || (writes && csr >> 10 == 0b11) {
+            self.record_exception(Exception::IllegalInstruction, ir);
+            return;
+        }
+        let rs1 = if op & 0b100 != 0 { rs1imm } else { self.x[rs1imm as usize] };
+        let v = self.read_csr(csr);
+        let val = match op & 3 {
+            0b01 => rs1,     // CSRRW(I)
+            0b10 => v | rs1, // CSRRS(I)
+            _ => v & !rs1,   // CSRRC(I)
+        };
+        if writes {
+            self.write_csr(csr, val);
+        }
+        self.write_back(rd, v);
+    }
 
-            match op {
-                0b001 => val = rs1,         //CSRRW
-                0b010 => val = v | rs1,     //CSRRS
-                0b011 => val = v & !rs1,    //CSRRC
-                0b101 => val = rs1imm,      //CSRRWI
-                0b110 => val = v | rs1imm,  //CSRRSI
-                0b111 => val = v & !rs1imm, //CSRRCI
-                _ => {}
-            }
+    // https://raw.githubusercontent.com/riscv/virtual-memory/main/specs/663-Svpbmt.pdf
+    // Generally, support for Zicsr
+    fn read_csr(&self, csr: u32) -> u32 {
+        match csr {
+            0x100 => self.mstatus & mstatus::SSTATUS,
+            0x104 => self.mie & self.mideleg,
+            0x105 => self.stvec,
+            0x140 => self.sscratch,
+            0x141 => self.sepc,
+            0x142 => self.scause,
+            0x143 => self.stval,
+            0x144 => self.mip & self.mideleg,
+            0x180 => self.satp,
+            0x340 => self.mscratch,
+            0x305 => self.mtvec,
+            0x304 => self.mie,
+            0x344 => self.mip,
+            0x302 => self.medeleg,
+            0x303 => self.mideleg,
+            0xC00 => self.cycle as u32,
+            0xC80 => (self.cycle >> 32) as u32,
+            0xC01 => self.bus.time() as u32,
+            0xC81 => (self.bus.time() >> 32) as u32,
+            0xC02 => self.stats.instructions as u32,
+            0xC82 => (self.stats.instructions >> 32) as u32,
+            0x341 => self.mepc,
+            0x300 => self.mstatus,
+            0x342 => self.mcause,
+            0x343 => self.mtval,
+            0xf11 => 0x00000000, // mvendorid
+            0xf14 => 0x00000000, // mhartid
+            0x301 => 0x00000000, // misa
+            _ => 0,
+        }
+    }
 
-            match csr {
-                0x340 => self.mscratch = val,
-                0x305 => self.mtvec = val,
-                0x304 => self.mie = val,
-                0x344 => self.mip = val,
-                0x341 => self.mepc = val,
-                0x300 => self.mstatus = val,
-                0x342 => self.mcause = val,
-                0x343 => self.mtval = val,
-                _ => {}
+    fn write_csr(&mut self, csr: u32, val: u32) {
+        match csr {
+            0x100 => self.mstatus = (self.mstatus & !mstatus::SSTATUS) | (val & mstatus::SSTATUS),
+            0x104 => self.mie = (self.mie & !self.mideleg) | (val & self.mideleg),
+            0x105 => self.stvec = val,
+            0x140 => self.sscratch = val,
+            0x141 => self.sepc = val,
+            0x142 => self.scause = val,
+            0x143 => self.stval = val,
+            // Only SSIP can be written from S-mode.
+            0x144 => {
+                let writable = Interrupt::SupervisorSoftwareInterrupt.bit() & self.mideleg;
+                self.mip = (self.mip & !writable) | (val & writable);
             }
-        } else {
-            self.record_exception(Exception::IllegalInstruction, ir);
+            0x180 => {
+                self.satp = val;
+                self.tlb.flush();
+            }
+            0x340 => self.mscratch = val,
+            0x305 => self.mtvec = val,
+            0x304 => self.mie = val,
+            0x344 => self.mip = (self.mip & !MIP_WRITABLE) | (val & MIP_WRITABLE),
+            0x302 => self.medeleg = val,
+            0x303 => self.mideleg = val & MIP_WRITABLE,
+            0x341 => self.mepc = val,
+            0x300 => self.mstatus = val,
+            0x342 => self.mcause = val,
+            0x343 => self.mtval = val,
+            _ => {}
         }
-        self.write_back(rd, v);
     }
 
     // system
     fn system(&mut self, ir: u32) {
         let csr = ir >> 20;
-        if csr == 0x105 {
+        if ir >> 25 == 0b0001001 {
+            // SFENCE.VMA
+            self.tlb.flush();
+        } else if csr == 0x105 {
             //WFI
             self.mstatus |= 8;
             self.wait_for_interrupt = true; //Inform environment we want to go to sleep.
             self.pc = self.pc.wrapping_add(4);
-        } else if (csr & 0xff) == 0x02 {
+        } else if csr == 0x302 && self.mode != PrivilegeMode::Machine
+            || csr == 0x102 && self.mode == PrivilegeMode::User
+        {
+            // MRET and SRET from a less privileged mode than the one they return from.
+            self.record_exception(Exception::IllegalInstruction, ir);
+        } else if csr == 0x302 {
             // MRET
-            let prev_mstatus = self.mstatus;
-            let prev_mode: u32 = self.previous_mode.into();
-            self.mstatus = ((prev_mstatus & 0x80) >> 4) | (prev_mode << 11) | 0x80;
-            self.previous_mode = PrivilegeMode::from((prev_mstatus >> 11) & 0b11);
+            let mpie = (self.mstatus & mstatus::MPIE) >> 4;
+            let mode = PrivilegeMode::from((self.mstatus & mstatus::MPP) >> 11);
+            let mprv =
+                if mode == PrivilegeMode::Machine { self.mstatus & mstatus::MPRV } else { 0 };
+            self.mstatus = (self.mstatus & !(mstatus::MIE | mstatus::MPP | mstatus::MPRV))
+                | mpie
+                | mstatus::MPIE
+                | mprv;
+            self.mode = mode;
             self.pc = self.mepc.wrapping_sub(4);
+        } else if csr == 0x102 {
+            // SRET
+            let spie = (self.mstatus & mstatus::SPIE) >> 4;
+            self.mode = if self.mstatus & mstatus::SPP != 0 {
+                PrivilegeMode::SuperVisor
+            } else {
+                PrivilegeMode::User
+            };
+            self.mstatus = (self.mstatus & !(mstatus::SIE | mstatus::SPP | mstatus::MPRV))
+                | spie
+                | mstatus::SPIE;
+            self.pc = self.sepc.wrapping_sub(4);
         } else {
             match csr {
-                0 if self.previous_mode != PrivilegeMode::User => {
-                    self.exception = Exception::EnvironmentCallMmode.into()
-                }
-                0 => self.exception = Exception::EnvironmentCallUmode.into(),
-                1 => self.exception = Exception::Breakpoint.into(),
+                // ecall leaves the trap value at zero and ebreak sets it to its own address.
+                0 => match self.mode {
+                    PrivilegeMode::User => {
+                        self.record_exception(Exception::EnvironmentCallUmode, 0)
+                    }
+                    PrivilegeMode::SuperVisor if self.sbi => self.sbi_call(),
+                    PrivilegeMode::SuperVisor => {
+                        self.record_exception(Exception::EnvironmentCallSmode, 0)
+                    }
+                    _ => self.record_exception(Exception::EnvironmentCallMmode, 0),
+                },
+                1 if self.is_semihosting_call() => self.semihosting_call(),
+                1 => self.record_exception(Exception::Breakpoint, self.pc),
                 _ => self.record_exception(Exception::IllegalInstruction, ir),
             }
         }
     }
+
+    /// Whether the `ebreak` at pc is wrapped in the semihosting entry and exit markers.
+    fn is_semihosting_call(&self) -> bool {
+        self.semihosting.is_some()
+            && self.bus.read32(self.pc.wrapping_sub(4)).ok() == Some(semihosting::ENTRY_NOP)
+            && self.bus.read32(self.pc.wrapping_add(4)).ok() == Some(semihosting::EXIT_NOP)
+    }
+
+    fn semihosting_call(&mut self) {
+        let Some(semihosting) = &mut self.semihosting else {
+            return;
+        };
+        let (op, arg) = (self.x[10], self.x[11]);
+        match semihosting.call(op, arg, &mut self.bus) {
+            Ok(Outcome::Return(v)) => self.x[10] = v,
+            Ok(Outcome::Exit(code)) => self.exit_code = Some(code),
+            Err(e) => self.record_exception(e.into(), arg),
+        }
+    }
 }
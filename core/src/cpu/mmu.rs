@@ -0,0 +1,187 @@
+//! Sv32 virtual memory.
+//! @See https://github.com/riscv/riscv-isa-manual/releases/download/Priv-v1.12/riscv-privileged-20211203.pdf p80
+
+use super::{mstatus, Cpu, Exception, PrivilegeMode};
+use crate::bus_interface::{BusController, BusReader, BusWriter};
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+/// satp.MODE selecting Sv32 translation.
+pub(super) const SATP_SV32: u32 = 1 << 31;
+
+const PAGE_SHIFT: u32 = 12;
+const TLB_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self) -> Exception {
+        match self {
+            Self::Fetch => Exception::InstructionPageFault,
+            Self::Load => Exception::LoadPageFault,
+            Self::Store => Exception::StoreAmoPageFault,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct TlbEntry {
+    valid: bool,
+    vpn: u32,
+    /// Physical page number of the 4 KiB page, even for superpage mappings.
+    ppn: u32,
+    /// Leaf PTE, for permission checks.
+    pte: u32,
+}
+
+/// Direct-mapped cache of leaf translations. Permissions are checked on every hit, so
+/// only `satp` writes and `sfence.vma` have to flush it.
+#[derive(Debug)]
+pub(super) struct Tlb {
+    entries: [TlbEntry; TLB_ENTRIES],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self {
+            entries: [TlbEntry::default(); TLB_ENTRIES],
+        }
+    }
+}
+
+impl Tlb {
+    pub(super) fn flush(&mut self) {
+        self.entries = [TlbEntry::default(); TLB_ENTRIES];
+    }
+}
+
+impl<B: BusController + BusReader + BusWriter> Cpu<B> {
+    /// Translates a virtual address into a physical one for `access`.
+    pub(super) fn translate(&mut self, vaddr: u32, access: Access) -> Result<u32, Exception> {
+        // MPRV makes loads and stores use the privilege in MPP.
+        let mode = if access != Access::Fetch && self.mstatus & mstatus::MPRV != 0 {
+            PrivilegeMode::from((self.mstatus & mstatus::MPP) >> 11)
+        } else {
+            self.mode
+        };
+        if mode == PrivilegeMode::Machine || self.satp & SATP_SV32 == 0 {
+            return Ok(vaddr);
+        }
+
+        let vpn = vaddr >> PAGE_SHIFT;
+        let slot = vpn as usize % TLB_ENTRIES;
+        let cached = self.tlb.entries[slot];
+        let (ppn, pte) = if cached.valid
+            && cached.vpn == vpn
+            && (access != Access::Store || cached.pte & PTE_D != 0)
+        {
+            (cached.ppn, cached.pte)
+        } else {
+            let (ppn, pte) = self.walk(vaddr, mode, access)?;
+            self.tlb.entries[slot] = TlbEntry {
+                valid: true,
+                vpn,
+                ppn,
+                pte,
+            };
+            (ppn, pte)
+        };
+
+        if !self.permitted(pte, mode, access) {
+            return Err(access.page_fault());
+        }
+        Ok((ppn << PAGE_SHIFT) | (vaddr & 0xfff))
+    }
+
+    /// Walks the two-level page table and returns the physical page number and leaf PTE,
+    /// setting the accessed and dirty bits on the way.
+    fn walk(
+        &mut self,
+        vaddr: u32,
+        mode: PrivilegeMode,
+        access: Access,
+    ) -> Result<(u32, u32), Exception> {
+        let vpn = [(vaddr >> 12) & 0x3ff, (vaddr >> 22) & 0x3ff];
+        let mut table = (self.satp & 0x3f_ffff) << PAGE_SHIFT;
+        for level in (0..2).rev() {
+            let pte_addr = table + vpn[level] * 4;
+            let pte = self
+                .bus
+                .read32(pte_addr)
+                .map_err(|_| access_fault(access))?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault());
+            }
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Pointer to the next level.
+                table = (pte >> 10) << PAGE_SHIFT;
+                continue;
+            }
+
+            let ppn = pte >> 10;
+            let ppn = if level == 1 {
+                // A misaligned superpage.
+                if ppn & 0x3ff != 0 {
+                    return Err(access.page_fault());
+                }
+                ppn | vpn[0]
+            } else {
+                ppn
+            };
+            if !self.permitted(pte, mode, access) {
+                return Err(access.page_fault());
+            }
+
+            let mut updated = pte | PTE_A;
+            if access == Access::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                self.bus
+                    .write32(pte_addr, updated)
+                    .map_err(|_| access_fault(access))?;
+            }
+            return Ok((ppn, updated));
+        }
+        Err(access.page_fault())
+    }
+
+    fn permitted(&self, pte: u32, mode: PrivilegeMode, access: Access) -> bool {
+        let user_page = pte & PTE_U != 0;
+        match mode {
+            PrivilegeMode::User if !user_page => return false,
+            PrivilegeMode::SuperVisor
+                if user_page && (access == Access::Fetch || self.mstatus & mstatus::SUM == 0) =>
+            {
+                return false
+            }
+            _ => {}
+        }
+        match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => {
+                pte & PTE_R != 0 || (self.mstatus & mstatus::MXR != 0 && pte & PTE_X != 0)
+            }
+            Access::Store => pte & PTE_W != 0,
+        }
+    }
+}
+
+fn access_fault(access: Access) -> Exception {
+    match access {
+        Access::Fetch => Exception::InstructionAccessFault,
+        Access::Load => Exception::LoadAccessFault,
+        Access::Store => Exception::StoreAmoAccessFault,
+    }
+}
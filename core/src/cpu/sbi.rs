@@ -0,0 +1,202 @@
+//! Built-in SBI implementation.
+//!
+//! With `--sbi` the emulator plays the role OpenSBI normally does: the hart starts in
+//! S-mode and `ecall`s from S-mode are serviced here instead of trapping into M-mode.
+//! @See https://github.com/riscv-non-isa/riscv-sbi-doc/releases/download/v2.0/riscv-sbi.pdf
+
+use super::{Cpu, Interrupt};
+use crate::bus_interface::{BusController, BusReader, BusWriter};
+
+/// Exceptions OpenSBI hands to S-mode: misaligned fetches, breakpoints, U-mode `ecall`s
+/// and page faults.
+pub(super) const DELEGATED_EXCEPTIONS: u32 = 0xb109;
+/// The supervisor software, timer and external interrupts.
+pub(super) const DELEGATED_INTERRUPTS: u32 = 0x222;
+
+const EXT_BASE: u32 = 0x10;
+const EXT_TIME: u32 = 0x5449_4d45;
+const EXT_IPI: u32 = 0x0073_5049;
+const EXT_RFENCE: u32 = 0x5246_4e43;
+const EXT_HSM: u32 = 0x0048_534d;
+const EXT_SRST: u32 = 0x5352_5354;
+const EXT_DBCN: u32 = 0x4442_434e;
+
+/// Extensions reported by `sbi_probe_extension`, besides the legacy ones.
+const EXTENSIONS: [u32; 7] = [
+    EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN,
+];
+
+const LEGACY_SET_TIMER: u32 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u32 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u32 = 0x02;
+const LEGACY_CLEAR_IPI: u32 = 0x03;
+const LEGACY_SEND_IPI: u32 = 0x04;
+const LEGACY_SHUTDOWN: u32 = 0x08;
+
+/// SBI specification version 2.0.
+const SPEC_VERSION: u32 = 0x0200_0000;
+/// Implementation ID reported by `sbi_get_impl_id`. Not a registered one.
+const IMPL_ID: u32 = 0x5232;
+
+const SUCCESS: i32 = 0;
+const ERR_FAILED: i32 = -1;
+const ERR_NOT_SUPPORTED: i32 = -2;
+const ERR_INVALID_PARAM: i32 = -3;
+const ERR_ALREADY_AVAILABLE: i32 = -6;
+
+/// `hart_get_status` value of a running hart.
+const HART_STARTED: u32 = 0;
+
+/// Result of an SBI function: an error code in `a0` and a value in `a1`.
+type SbiResult = Result<u32, i32>;
+
+impl<B: BusController + BusReader + BusWriter> Cpu<B> {
+    /// Raises the supervisor timer interrupt once the deadline set through SBI passed.
+    pub(super) fn sbi_timer(&mut self) {
+        if self.bus.time() >= self.stimecmp {
+            self.mip |= Interrupt::SupervisorTimerInterrupt.bit();
+        }
+    }
+
+    /// Services an `ecall` from S-mode. The extension is selected by `a7` and the function
+    /// by `a6`.
+    pub(super) fn sbi_call(&mut self) {
+        let (eid, fid) = (self.x[17], self.x[16]);
+        let result = match eid {
+            LEGACY_SET_TIMER..=LEGACY_SHUTDOWN => {
+                // Legacy extensions only return an error code, or the character for getchar.
+                self.x[10] = self.sbi_legacy(eid) as u32;
+                return;
+            }
+            EXT_BASE => self.sbi_base(fid),
+            EXT_TIME if fid == 0 => {
+                self.set_timer(((self.x[11] as u64) << 32) | self.x[10] as u64);
+                Ok(0)
+            }
+            EXT_IPI if fid == 0 => {
+                self.send_ipi(self.x[10], self.x[11]);
+                Ok(0)
+            }
+            EXT_RFENCE => {
+                // A single hart with no ASIDs: every remote fence is a full local flush.
+                self.tlb.flush();
+                Ok(0)
+            }
+            EXT_HSM => self.sbi_hsm(fid),
+            EXT_SRST if fid == 0 => self.system_reset(self.x[10], self.x[11]),
+            EXT_DBCN => self.sbi_dbcn(fid),
+            _ => Err(ERR_NOT_SUPPORTED),
+        };
+        let (error, value) = match result {
+            Ok(value) => (SUCCESS, value),
+            Err(error) => (error, 0),
+        };
+        self.x[10] = error as u32;
+        self.x[11] = value;
+    }
+
+    fn sbi_base(&mut self, fid: u32) -> SbiResult {
+        match fid {
+            0 => Ok(SPEC_VERSION),
+            1 => Ok(IMPL_ID),
+            2 => Ok(0),
+            3 => {
+                let eid = self.x[10];
+                Ok((eid <= LEGACY_SHUTDOWN || EXTENSIONS.contains(&eid)) as u32)
+            }
+            // mvendorid, marchid and mimpid.
+            4..=6 => Ok(0),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn sbi_hsm(&mut self, fid: u32) -> SbiResult {
+        let hart = self.x[10];
+        match fid {
+            // hart_start
+            0 if hart == 0 => Err(ERR_ALREADY_AVAILABLE),
+            // hart_stop
+            1 => Err(ERR_FAILED),
+            // hart_get_status
+            2 if hart == 0 => Ok(HART_STARTED),
+            0 | 2 => Err(ERR_INVALID_PARAM),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn system_reset(&mut self, kind: u32, reason: u32) -> SbiResult {
+        match kind {
+            // Shutdown. Any reason other than "no reason" is a system failure.
+            0 => self.exit_code = Some((reason != 0) as u32),
+            // Cold and warm reboot.
+            1 | 2 => self.reboot = true,
+            _ => return Err(ERR_INVALID_PARAM),
+        }
+        Ok(0)
+    }
+
+    fn sbi_dbcn(&mut self, fid: u32) -> SbiResult {
+        let (len, addr, addr_hi) = (self.x[10], self.x[11], self.x[12]);
+        match fid {
+            // console_write
+            0 if addr_hi == 0 => {
+                for i in 0..len {
+                    let c = self
+                        .bus
+                        .read8(addr.wrapping_add(i))
+                        .map_err(|_| ERR_INVALID_PARAM)?;
+                    self.bus.console_write(c);
+                }
+                Ok(len)
+            }
+            // console_read
+            1 if addr_hi == 0 => {
+                let mut read = 0;
+                while read < len {
+                    let Some(c) = self.bus.console_read() else {
+                        break;
+                    };
+                    self.bus
+                        .write8(addr.wrapping_add(read), c)
+                        .map_err(|_| ERR_INVALID_PARAM)?;
+                    read += 1;
+                }
+                Ok(read)
+            }
+            0 | 1 => Err(ERR_INVALID_PARAM),
+            // console_write_byte
+            2 => {
+                self.bus.console_write(len as u8);
+                Ok(0)
+            }
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    fn sbi_legacy(&mut self, eid: u32) -> i32 {
+        match eid {
+            LEGACY_SET_TIMER => self.set_timer(((self.x[11] as u64) << 32) | self.x[10] as u64),
+            LEGACY_CONSOLE_PUTCHAR => self.bus.console_write(self.x[10] as u8),
+            LEGACY_CONSOLE_GETCHAR => return self.bus.console_read().map_or(-1, i32::from),
+            LEGACY_CLEAR_IPI => self.mip &= !Interrupt::SupervisorSoftwareInterrupt.bit(),
+            // The hart mask is a pointer here, and this hart is the only one there is.
+            LEGACY_SEND_IPI => self.mip |= Interrupt::SupervisorSoftwareInterrupt.bit(),
+            LEGACY_SHUTDOWN => self.exit_code = Some(0),
+            // remote_fence_i, remote_sfence_vma and remote_sfence_vma_asid.
+            _ => self.tlb.flush(),
+        }
+        SUCCESS
+    }
+
+    fn set_timer(&mut self, deadline: u64) {
+        self.stimecmp = deadline;
+        self.mip &= !Interrupt::SupervisorTimerInterrupt.bit();
+    }
+
+    /// Only hart 0 exists, so it is the only one that can be targeted.
+    fn send_ipi(&mut self, mask: u32, base: u32) {
+        if base == u32::MAX || (base == 0 && mask & 1 != 0) {
+            self.mip |= Interrupt::SupervisorSoftwareInterrupt.bit();
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Minimal ELF32 little-endian RISC-V reader.
+//!
+//! Only the parts r2 needs are decoded: the file header, program headers, section headers
+//! and the symbol table. Everything is read straight out of the byte slice, so malformed
+//! files are reported as [`ElfError`] instead of panicking.
+//! @See https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+
+use std::error::Error;
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    UnsupportedClass,
+    UnsupportedEndian,
+    UnsupportedMachine(u16),
+    Truncated,
+    /// A `PT_LOAD` segment does not fit in guest RAM.
+    SegmentOutOfRam {
+        addr: u32,
+        size: u32,
+    },
+}
+
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotElf => write!(f, "not an ELF file"),
+            Self::UnsupportedClass => write!(f, "only ELF32 is supported"),
+            Self::UnsupportedEndian => write!(f, "only little-endian ELF is supported"),
+            Self::UnsupportedMachine(m) => write!(f, "unsupported ELF machine {m}"),
+            Self::Truncated => write!(f, "ELF file is truncated"),
+            Self::SegmentOutOfRam { addr, size } => write!(
+                f,
+                "segment at {addr:#010x} ({size:#x} bytes) does not fit in RAM"
+            ),
+        }
+    }
+}
+
+impl Error for ElfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Whether `bytes` starts with the ELF magic.
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x7fELF")
+}
+
+fn u16_at(bytes: &[u8], off: usize) -> Result<u16, ElfError> {
+    let b = bytes.get(off..offset(off, 2)?).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(bytes: &[u8], off: usize) -> Result<u32, ElfError> {
+    let b = bytes.get(off..offset(off, 4)?).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// `base + delta`, for offsets taken from the file. They can point anywhere, so going past
+/// the end of the address space means the file is malformed.
+fn offset(base: usize, delta: usize) -> Result<usize, ElfError> {
+    base.checked_add(delta).ok_or(ElfError::Truncated)
+}
+
+/// Offset of entry `i` of a table of `entsize`-byte entries at `base`.
+fn table_entry(base: usize, i: usize, entsize: usize) -> Result<usize, ElfError> {
+    offset(base, i.checked_mul(entsize).ok_or(ElfError::Truncated)?)
+}
+
+fn c_str_at(bytes: &[u8], off: usize) -> Result<&str, ElfError> {
+    let tail = bytes.get(off..).ok_or(ElfError::Truncated)?;
+    let len = tail
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(ElfError::Truncated)?;
+    Ok(std::str::from_utf8(&tail[..len]).unwrap_or(""))
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Physical load address.
+    pub paddr: u32,
+    /// Offset of the initialized bytes in the file.
+    pub offset: u32,
+    /// Bytes present in the file.
+    pub filesz: u32,
+    /// Bytes occupied in memory. Anything past `filesz` is zero-filled (`.bss`).
+    pub memsz: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SectionHeader {
+    kind: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    entsize: u32,
+}
+
+/// A parsed ELF32 image borrowing the underlying bytes.
+#[derive(Debug)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    /// Program entry point.
+    pub entry: u32,
+    segments: Vec<Segment>,
+    sections: Vec<SectionHeader>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if !is_elf(bytes) {
+            return Err(ElfError::NotElf);
+        }
+        if bytes.len() < 52 {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[4] != ELFCLASS32 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if bytes[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedEndian);
+        }
+        let machine = u16_at(bytes, 18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+        let entry = u32_at(bytes, 24)?;
+        let phoff = u32_at(bytes, 28)? as usize;
+        let phentsize = u16_at(bytes, 42)? as usize;
+        let phnum = u16_at(bytes, 44)? as usize;
+        let shoff = u32_at(bytes, 32)? as usize;
+        let shentsize = u16_at(bytes, 46)? as usize;
+        let shnum = u16_at(bytes, 48)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let base = table_entry(phoff, i, phentsize)?;
+            // Empty segments occupy no memory and may carry any address.
+            if u32_at(bytes, base)? != PT_LOAD || u32_at(bytes, offset(base, 20)?)? == 0 {
+                continue;
+            }
+            let segment = Segment {
+                offset: u32_at(bytes, offset(base, 4)?)?,
+                paddr: u32_at(bytes, offset(base, 12)?)?,
+                filesz: u32_at(bytes, offset(base, 16)?)?,
+                memsz: u32_at(bytes, offset(base, 20)?)?,
+            };
+            let end = offset(segment.offset as usize, segment.filesz as usize)?;
+            if end > bytes.len() || segment.filesz > segment.memsz {
+                return Err(ElfError::Truncated);
+            }
+            if segment.paddr.checked_add(segment.memsz).is_none() {
+                return Err(ElfError::SegmentOutOfRam {
+                    addr: segment.paddr,
+                    size: segment.memsz,
+                });
+            }
+            segments.push(segment);
+        }
+
+        let sections = (0..shnum)
+            .map(|i| {
+                let base = table_entry(shoff, i, shentsize)?;
+                Ok(SectionHeader {
+                    kind: u32_at(bytes, offset(base, 4)?)?,
+                    offset: u32_at(bytes, offset(base, 16)?)?,
+                    size: u32_at(bytes, offset(base, 20)?)?,
+                    link: u32_at(bytes, offset(base, 24)?)?,
+                    entsize: u32_at(bytes, offset(base, 36)?)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        Ok(Self {
+            bytes,
+            entry,
+            segments,
+            sections,
+        })
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Address just past the highest segment, or `None` if there is none.
+    pub fn end(&self) -> Option<u32> {
+        // `parse` made sure that no segment wraps around.
+        self.segments.iter().map(|s| s.paddr + s.memsz).max()
+    }
+
+    /// Copies every `PT_LOAD` segment to its physical address in `ram`, which is mapped at
+    /// `ram_start`. Nothing is written unless all segments fit.
+    pub fn load(&self, ram: &mut [u8], ram_start: u32) -> Result<(), ElfError> {
+        let ranges = self
+            .segments
+            .iter()
+            .map(|s| {
+                let out_of_ram = ElfError::SegmentOutOfRam {
+                    addr: s.paddr,
+                    size: s.memsz,
+                };
+                let start = s.paddr.checked_sub(ram_start).ok_or(out_of_ram.clone())? as usize;
+                match start.checked_add(s.memsz as usize) {
+                    Some(end) if end <= ram.len() => {}
+                    _ => return Err(out_of_ram),
+                }
+                Ok((s, start))
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        for (segment, start) in ranges {
+            let file = &self.bytes[segment.offset as usize..][..segment.filesz as usize];
+            ram[start..start + file.len()].copy_from_slice(file);
+            ram[start + file.len()..start + segment.memsz as usize].fill(0);
+        }
+        Ok(())
+    }
+
+    /// Function, object and label symbols from `.symtab`. Files that have been stripped yield an
+    /// empty table.
+    pub fn symbols(&self) -> Result<Symbols, ElfError> {
+        let mut symbols = Vec::new();
+        for symtab in self.sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+            let strtab = self
+                .sections
+                .get(symtab.link as usize)
+                .ok_or(ElfError::Truncated)?;
+            let entsize = (symtab.entsize as usize).max(16);
+            for i in 0..symtab.size as usize / entsize {
+                let base = table_entry(symtab.offset as usize, i, entsize)?;
+                let name = u32_at(self.bytes, base)?;
+                let value = u32_at(self.bytes, offset(base, 4)?)?;
+                let size = u32_at(self.bytes, offset(base, 8)?)?;
+                let info = *self
+                    .bytes
+                    .get(offset(base, 12)?)
+                    .ok_or(ElfError::Truncated)?;
+                let shndx = u16_at(self.bytes, offset(base, 14)?)?;
+                let kind = info & 0xf;
+                if shndx == SHN_UNDEF || !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) {
+                    continue;
+                }
+                let name = c_str_at(self.bytes, offset(strtab.offset as usize, name as usize)?)?;
+                // `$x`/`$d` are mapping symbols and `.L` prefixed ones are assembler locals.
+                if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    addr: value,
+                    size,
+                    name: name.to_string(),
+                    function: kind == STT_FUNC,
+                });
+            }
+        }
+        Ok(Symbols::new(symbols))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub addr: u32,
+    /// Size in bytes, or 0 when unknown (assembler labels, `System.map` entries).
+    pub size: u32,
+    pub name: String,
+    /// Whether the symbol is known to be a function.
+    pub function: bool,
+}
+
+/// Address-sorted symbol table used to turn guest addresses into names.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        // Functions win over plain labels at the same address.
+        symbols.sort_by(|a, b| a.addr.cmp(&b.addr).then(b.function.cmp(&a.function)));
+        symbols.dedup_by_key(|s| s.addr);
+        Self { symbols }
+    }
+
+    /// Parses a Linux `System.map` (`<addr> <type> <name>` per line), keeping text symbols.
+    pub fn from_system_map(text: &str) -> Self {
+        let symbols = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let addr = u32::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                matches!(kind, "T" | "t" | "W" | "w").then(|| Symbol {
+                    addr,
+                    size: 0,
+                    name: name.to_string(),
+                    function: true,
+                })
+            })
+            .collect();
+        Self::new(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Address of the symbol called `name`.
+    pub fn addr_of(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    /// Symbol covering `addr`. Unsized symbols extend up to the next symbol, except the last
+    /// one, which would otherwise swallow the rest of the address space.
+    pub fn lookup(&self, addr: u32) -> Option<&Symbol> {
+        let idx = self
+            .symbols
+            .partition_point(|s| s.addr <= addr)
+            .checked_sub(1)?;
+        let symbol = &self.symbols[idx];
+        let covers = if symbol.size != 0 {
+            addr - symbol.addr < symbol.size
+        } else {
+            idx + 1 < self.symbols.len()
+        };
+        covers.then_some(symbol)
+    }
+}
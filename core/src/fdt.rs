@@ -0,0 +1,660 @@
+//! Flattened device tree generation.
+//!
+//! The device tree handed to the kernel is built from the [`Platform`] the bus describes, so
+//! it always matches the emulated hardware. Blobs, generated or supplied by the user, can be
+//! decoded into a [`DeviceTree`] to patch `/chosen` before boot.
+//! @See https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::bus::Region;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const VERSION: u32 = 17;
+const LAST_COMP_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+const RESERVATION_SIZE: usize = 16;
+
+/// Hardware the device tree describes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub ram: Region,
+    pub harts: u32,
+    /// `riscv,isa` of every hart.
+    pub isa: String,
+    /// `mmu-type` of every hart.
+    pub mmu_type: String,
+    /// Frequency `time` counts at, in Hz.
+    pub timebase_frequency: u32,
+    pub uart: Region,
+    pub uart_clock_frequency: u32,
+    pub clint: Region,
+    pub plic: Region,
+    /// Number of PLIC interrupt sources, not counting source 0.
+    pub plic_sources: u32,
+    pub uart_irq: u32,
+    /// Register the poweroff and reboot values are written to.
+    pub syscon: Region,
+    pub poweroff_value: u32,
+    pub reboot_value: u32,
+    /// Devices plugged into the bus.
+    pub devices: Vec<DeviceNode>,
+    /// Kernel command line, written to `/chosen/bootargs`.
+    pub bootargs: String,
+}
+
+/// A device advertised by a node of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceNode {
+    pub name: String,
+    pub compatible: String,
+    pub region: Region,
+    /// PLIC source it interrupts through.
+    pub irq: Option<u32>,
+    /// Further properties the driver needs.
+    pub properties: Vec<(String, Property)>,
+}
+
+/// Value of a device-specific property.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Property {
+    U32(u32),
+    Str(String),
+}
+
+impl Platform {
+    /// Serializes the platform as a flattened device tree blob.
+    pub fn to_dtb(&self) -> Vec<u8> {
+        let mut fdt = FdtWriter::default();
+        let mut phandles = 0;
+        let mut phandle = || {
+            phandles += 1;
+            phandles
+        };
+
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_str("compatible", "riscv-r2");
+        fdt.property_str("model", "riscv-r2");
+
+        fdt.begin_node("chosen");
+        fdt.property_str("bootargs", &self.bootargs);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{:x}", self.ram.base));
+        fdt.property_str("device_type", "memory");
+        fdt.property_cells("reg", &reg(self.ram));
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", self.timebase_frequency);
+        let mut intcs = Vec::new();
+        for hart in 0..self.harts {
+            fdt.begin_node(&format!("cpu@{hart:x}"));
+            fdt.property_str("device_type", "cpu");
+            fdt.property_u32("reg", hart);
+            fdt.property_str("status", "okay");
+            fdt.property_str("compatible", "riscv");
+            fdt.property_str("riscv,isa", &self.isa);
+            fdt.property_str("mmu-type", &self.mmu_type);
+            fdt.begin_node("interrupt-controller");
+            let intc = phandle();
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_empty("interrupt-controller");
+            fdt.property_str("compatible", "riscv,cpu-intc");
+            fdt.property_u32("phandle", intc);
+            fdt.end_node();
+            fdt.end_node();
+            intcs.push(intc);
+        }
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_str("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+
+        let plic = phandle();
+        fdt.begin_node(&format!("plic@{:x}", self.plic.base));
+        fdt.property_u32("phandle", plic);
+        // Machine and supervisor external interrupts of every hart.
+        let interrupts: Vec<u32> = intcs
+            .iter()
+            .flat_map(|intc| [*intc, 11, *intc, 9])
+            .collect();
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.property_u32("riscv,ndev", self.plic_sources);
+        fdt.property_cells("reg", &reg(self.plic));
+        fdt.property_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_u32("#address-cells", 0);
+        fdt.end_node();
+
+        fdt.begin_node(&format!("uart@{:x}", self.uart.base));
+        fdt.property_u32("interrupt-parent", plic);
+        fdt.property_u32("interrupts", self.uart_irq);
+        fdt.property_u32("clock-frequency", self.uart_clock_frequency);
+        fdt.property_cells("reg", &reg(self.uart));
+        fdt.property_str("compatible", "ns16550a");
+        fdt.end_node();
+
+        let syscon = phandle();
+        fdt.begin_node(&format!("syscon@{:x}", self.syscon.base));
+        fdt.property_u32("phandle", syscon);
+        fdt.property_cells("reg", &reg(self.syscon));
+        // Also the finisher of QEMU's `virt` machine, which test programs write 0x3333 to
+        // with an exit code above it to report a failure.
+        fdt.property_strs("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.end_node();
+
+        for (name, value) in [
+            ("poweroff", self.poweroff_value),
+            ("reboot", self.reboot_value),
+        ] {
+            fdt.begin_node(name);
+            fdt.property_u32("value", value);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("regmap", syscon);
+            fdt.property_str("compatible", &format!("syscon-{name}"));
+            fdt.end_node();
+        }
+
+        for device in &self.devices {
+            fdt.begin_node(&format!("{}@{:x}", device.name, device.region.base));
+            if let Some(irq) = device.irq {
+                fdt.property_u32("interrupt-parent", plic);
+                fdt.property_u32("interrupts", irq);
+            }
+            fdt.property_cells("reg", &reg(device.region));
+            fdt.property_str("compatible", &device.compatible);
+            for (name, value) in &device.properties {
+                match value {
+                    Property::U32(v) => fdt.property_u32(name, *v),
+                    Property::Str(v) => fdt.property_str(name, v),
+                }
+            }
+            fdt.end_node();
+        }
+
+        fdt.begin_node(&format!("clint@{:x}", self.clint.base));
+        // Machine software and timer interrupts of every hart.
+        let interrupts: Vec<u32> = intcs.iter().flat_map(|intc| [*intc, 3, *intc, 7]).collect();
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.property_cells("reg", &reg(self.clint));
+        fdt.property_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.end_node();
+        fdt.finish(&[], 0)
+    }
+}
+
+/// `reg` cells of a region with two address and two size cells.
+fn reg(region: Region) -> [u32; 4] {
+    [0, region.base, 0, region.size]
+}
+
+#[derive(Debug, Default)]
+struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        let len = self.structure.len().next_multiple_of(4);
+        self.structure.resize(len, 0);
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad();
+    }
+
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    /// Offset of `name` in the strings block, adding it if it is not there yet.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let name = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(name);
+        self.structure.extend_from_slice(value);
+        self.pad();
+    }
+
+    fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    fn property_u32(&mut self, name: &str, v: u32) {
+        self.property(name, &v.to_be_bytes());
+    }
+
+    fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    fn property_str(&mut self, name: &str, s: &str) {
+        self.property_strs(name, &[s]);
+    }
+
+    fn property_strs(&mut self, name: &str, strs: &[&str]) {
+        let value: Vec<u8> = strs
+            .iter()
+            .flat_map(|s| [s.as_bytes(), &[0]].concat())
+            .collect();
+        self.property(name, &value);
+    }
+
+    fn finish(mut self, reservations: &[Reservation], boot_cpuid: u32) -> Vec<u8> {
+        self.token(FDT_END);
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + (reservations.len() + 1) * RESERVATION_SIZE;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            VERSION,
+            LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut out: Vec<u8> = header.iter().flat_map(|v| v.to_be_bytes()).collect();
+        for reservation in reservations {
+            out.extend_from_slice(&reservation.address.to_be_bytes());
+            out.extend_from_slice(&reservation.size.to_be_bytes());
+        }
+        // The map ends with an empty entry.
+        out.resize(off_struct, 0);
+        out.extend_from_slice(&self.structure);
+        out.extend_from_slice(&self.strings);
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    UnexpectedToken(u32),
+}
+
+impl std::fmt::Display for FdtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a flattened device tree"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported device tree version {v}"),
+            Self::Truncated => write!(f, "device tree is truncated"),
+            Self::UnexpectedToken(t) => write!(f, "unexpected device tree token {t:#x}"),
+        }
+    }
+}
+
+impl Error for FdtError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// An entry of the memory reservation block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub address: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Node {
+    /// Node name including the unit address, empty for the root.
+    pub name: String,
+    /// Properties in the order they appear in the blob.
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Replaces the property called `name`, or appends it.
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.properties.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.properties.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    /// The child called `name`, created if there is none.
+    pub fn child_mut(&mut self, name: &str) -> &mut Node {
+        let idx = match self.children.iter().position(|c| c.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.children.push(Node::new(name));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[idx]
+    }
+
+    pub fn u32_property(&self, name: &str) -> Option<u32> {
+        let value = self.property(name)?;
+        Some(u32::from_be_bytes(value.try_into().ok()?))
+    }
+
+    /// A string list property such as `compatible`. Missing properties yield no strings.
+    pub fn strings(&self, name: &str) -> Vec<&str> {
+        self.property(name)
+            .map(|value| {
+                value
+                    .split(|b| *b == 0)
+                    .filter(|s| !s.is_empty())
+                    .filter_map(|s| std::str::from_utf8(s).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn write(&self, fdt: &mut FdtWriter) {
+        fdt.begin_node(&self.name);
+        for (name, value) in &self.properties {
+            fdt.property(name, value);
+        }
+        for child in &self.children {
+            child.write(fdt);
+        }
+        fdt.end_node();
+    }
+}
+
+/// A node along with where it sits in the tree.
+#[derive(Debug, Clone)]
+pub struct NodeRef<'a> {
+    /// Full path, empty for the root.
+    pub path: String,
+    pub node: &'a Node,
+    /// `#address-cells` of the parent.
+    pub address_cells: u32,
+    /// `#size-cells` of the parent.
+    pub size_cells: u32,
+}
+
+impl std::ops::Deref for NodeRef<'_> {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        self.node
+    }
+}
+
+impl NodeRef<'_> {
+    /// `(address, size)` pairs of the `reg` property.
+    pub fn reg(&self) -> Option<Vec<(u64, u64)>> {
+        let value = self.property("reg")?;
+        let cells: Vec<u32> = value
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        let (address_cells, size_cells) = (self.address_cells as usize, self.size_cells as usize);
+        let stride = address_cells + size_cells;
+        if stride == 0 || address_cells > 2 || size_cells > 2 || !cells.len().is_multiple_of(stride)
+        {
+            return None;
+        }
+        let number = |cells: &[u32]| cells.iter().fold(0u64, |n, c| (n << 32) | *c as u64);
+        Some(
+            cells
+                .chunks_exact(stride)
+                .map(|c| (number(&c[..address_cells]), number(&c[address_cells..])))
+                .collect(),
+        )
+    }
+}
+
+/// A device tree decoded from a blob, which can be edited and serialized again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceTree {
+    pub reservations: Vec<Reservation>,
+    pub boot_cpuid: u32,
+    pub root: Node,
+}
+
+impl DeviceTree {
+    pub fn parse(bytes: &[u8]) -> Result<Self, FdtError> {
+        if bytes.len() < HEADER_SIZE || u32_at(bytes, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let off_struct = u32_at(bytes, 8)? as usize;
+        let off_strings = u32_at(bytes, 12)? as usize;
+        let off_rsvmap = u32_at(bytes, 16)? as usize;
+        let version = u32_at(bytes, 20)?;
+        if version < LAST_COMP_VERSION {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let boot_cpuid = u32_at(bytes, 28)?;
+
+        let mut reservations = Vec::new();
+        for entry in (off_rsvmap..).step_by(RESERVATION_SIZE) {
+            let address = u64_at(bytes, entry)?;
+            let size = u64_at(bytes, entry + 8)?;
+            if address == 0 && size == 0 {
+                break;
+            }
+            reservations.push(Reservation { address, size });
+        }
+
+        let mut offset = off_struct;
+        let mut stack: Vec<Node> = Vec::new();
+        loop {
+            let token = u32_at(bytes, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str_at(bytes, offset)?;
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    stack.push(Node::new(name));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or(FdtError::UnexpectedToken(token))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => {
+                            return Ok(Self {
+                                reservations,
+                                boot_cpuid,
+                                root: node,
+                            })
+                        }
+                    }
+                }
+                FDT_PROP => {
+                    let len = u32_at(bytes, offset)? as usize;
+                    let name = c_str_at(bytes, off_strings + u32_at(bytes, offset + 4)? as usize)?;
+                    let value = bytes
+                        .get(offset + 8..offset + 8 + len)
+                        .ok_or(FdtError::Truncated)?;
+                    let node = stack.last_mut().ok_or(FdtError::UnexpectedToken(token))?;
+                    node.properties.push((name.to_string(), value.to_vec()));
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                }
+                FDT_NOP => {}
+                _ => return Err(FdtError::UnexpectedToken(token)),
+            }
+        }
+    }
+
+    /// Every node in depth-first order, parents first.
+    pub fn nodes(&self) -> Vec<NodeRef<'_>> {
+        fn visit<'a>(node: NodeRef<'a>, out: &mut Vec<NodeRef<'a>>) {
+            // Children interpret their `reg` with the cells declared by their parent.
+            let address_cells = node.u32_property("#address-cells").unwrap_or(2);
+            let size_cells = node.u32_property("#size-cells").unwrap_or(1);
+            let children: Vec<_> = node
+                .node
+                .children
+                .iter()
+                .map(|child| NodeRef {
+                    path: format!("{}/{}", node.path, child.name),
+                    node: child,
+                    address_cells,
+                    size_cells,
+                })
+                .collect();
+            out.push(node);
+            for child in children {
+                visit(child, out);
+            }
+        }
+        let mut out = Vec::new();
+        let root = NodeRef {
+            path: String::new(),
+            node: &self.root,
+            address_cells: 2,
+            size_cells: 1,
+        };
+        visit(root, &mut out);
+        out
+    }
+
+    /// Translates `address`, from the `reg` of the node at `path`, to a CPU address through
+    /// the `ranges` of the buses above it. An empty `ranges` maps addresses one to one. A bus
+    /// without `ranges`, or an address none of them covers, cannot be reached.
+    pub fn translate(&self, path: &str, mut address: u64) -> Option<u64> {
+        // The buses below the root down to the parent of the node, each with the
+        // `#address-cells` of its own parent.
+        let mut buses = Vec::new();
+        let mut node = &self.root;
+        let mut address_cells = 2;
+        for name in path.split('/').skip(1) {
+            if !std::ptr::eq(node, &self.root) {
+                buses.push((node, address_cells));
+            }
+            address_cells = node.u32_property("#address-cells").unwrap_or(2);
+            node = node.child(name)?;
+        }
+        for (bus, parent_cells) in buses.into_iter().rev() {
+            let value = bus.property("ranges")?;
+            if value.is_empty() {
+                continue;
+            }
+            let child_cells = bus.u32_property("#address-cells").unwrap_or(2) as usize;
+            let size_cells = bus.u32_property("#size-cells").unwrap_or(1) as usize;
+            let parent_cells = parent_cells as usize;
+            let stride = child_cells + parent_cells + size_cells;
+            if stride == 0 || child_cells > 2 || parent_cells > 2 || size_cells > 2 {
+                return None;
+            }
+            let cells: Vec<u32> = value
+                .chunks_exact(4)
+                .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            let number = |cells: &[u32]| cells.iter().fold(0u64, |n, c| (n << 32) | *c as u64);
+            address = cells
+                .chunks_exact(stride)
+                .map(|c| {
+                    let (child, rest) = c.split_at(child_cells);
+                    let (parent, size) = rest.split_at(parent_cells);
+                    (number(child), number(parent), number(size))
+                })
+                .find(|(child, _, size)| address.wrapping_sub(*child) < *size)
+                .map(|(child, parent, _)| parent.wrapping_add(address - child))?;
+        }
+        Some(address)
+    }
+
+    pub fn to_dtb(&self) -> Vec<u8> {
+        let mut fdt = FdtWriter::default();
+        self.root.write(&mut fdt);
+        fdt.finish(&self.reservations, self.boot_cpuid)
+    }
+
+    /// Sets the kernel command line.
+    pub fn set_bootargs(&mut self, bootargs: &str) {
+        self.root
+            .child_mut("chosen")
+            .set_property("bootargs", [bootargs.as_bytes(), &[0]].concat());
+    }
+
+    /// Points the kernel at an initramfs loaded at `initrd`.
+    pub fn set_initrd(&mut self, initrd: Region) {
+        let start = initrd.base as u64;
+        let end = start + initrd.size as u64;
+        let chosen = self.root.child_mut("chosen");
+        chosen.set_property("linux,initrd-start", start.to_be_bytes().to_vec());
+        chosen.set_property("linux,initrd-end", end.to_be_bytes().to_vec());
+    }
+}
+
+fn u32_at(bytes: &[u8], off: usize) -> Result<u32, FdtError> {
+    let b = bytes.get(off..off + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn u64_at(bytes: &[u8], off: usize) -> Result<u64, FdtError> {
+    Ok(((u32_at(bytes, off)? as u64) << 32) | u32_at(bytes, off + 4)? as u64)
+}
+
+fn c_str_at(bytes: &[u8], off: usize) -> Result<&str, FdtError> {
+    let tail = bytes.get(off..).ok_or(FdtError::Truncated)?;
+    let len = tail
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(FdtError::Truncated)?;
+    Ok(std::str::from_utf8(&tail[..len]).unwrap_or(""))
+}
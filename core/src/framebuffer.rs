@@ -0,0 +1,142 @@
+//! Linear framebuffer, described to the guest as a `simple-framebuffer` node.
+//!
+//! The kernel (`CONFIG_FB_SIMPLE` or `CONFIG_DRM_SIMPLEDRM`) takes the geometry from the
+//! device tree and draws straight into the region. Pixels are `x8r8g8b8`: 32-bit
+//! little-endian words with blue in the low byte.
+//! @See https://www.kernel.org/doc/Documentation/devicetree/bindings/display/simple-framebuffer.yaml
+
+use std::error::Error;
+
+use device_interfaces::Display;
+
+use crate::fdt::Property;
+use crate::mmio::{GuestMemory, MmioDevice, Width};
+
+const BYTES_PER_PIXEL: u32 = 4;
+
+/// Bus steps between two looks at whether the guest drew something.
+const REFRESH_INTERVAL: u32 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramebufferError {
+    /// The pixels would not fit in the 32-bit address space.
+    TooLarge { width: u32, height: u32 },
+}
+
+impl std::fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { width, height } => {
+                write!(f, "a {width}x{height} framebuffer does not fit in memory")
+            }
+        }
+    }
+}
+
+impl Error for FramebufferError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+pub struct Framebuffer<D> {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    display: D,
+    /// Whether the guest wrote to the pixels since the last frame.
+    dirty: bool,
+    /// Bus steps until the next frame, if the guest drew.
+    refresh_countdown: u32,
+}
+
+impl<D: Display> Framebuffer<D> {
+    /// A black screen of `width` by `height` pixels.
+    pub fn new(width: u32, height: u32, display: D) -> Result<Self, FramebufferError> {
+        // The region the device maps is rounded up to whole pages.
+        let len = width
+            .checked_mul(BYTES_PER_PIXEL)
+            .and_then(|stride| stride.checked_mul(height))
+            .filter(|len| len.checked_next_multiple_of(0x1000).is_some())
+            .ok_or(FramebufferError::TooLarge { width, height })?;
+        Ok(Self {
+            width,
+            height,
+            pixels: vec![0; len as usize],
+            display,
+            // The host shows the black screen until the guest draws.
+            dirty: true,
+            refresh_countdown: 1,
+        })
+    }
+
+    /// The current picture as RGBA rows from the top.
+    pub fn rgba(&self) -> Vec<u8> {
+        self.pixels
+            .chunks_exact(BYTES_PER_PIXEL as usize)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], 0xff])
+            .collect()
+    }
+
+    fn stride(&self) -> u32 {
+        self.width * BYTES_PER_PIXEL
+    }
+}
+
+impl<D: Display> MmioDevice for Framebuffer<D> {
+    fn name(&self) -> &str {
+        "framebuffer"
+    }
+
+    fn size(&self) -> u32 {
+        (self.pixels.len() as u32).next_multiple_of(0x1000)
+    }
+
+    fn read(&mut self, offset: u32, width: Width) -> u32 {
+        let start = offset as usize;
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().take(width as usize).enumerate() {
+            *byte = self.pixels.get(start + i).copied().unwrap_or(0);
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    fn write(&mut self, offset: u32, v: u32, width: Width) {
+        let start = offset as usize;
+        let bytes = v.to_le_bytes();
+        if let Some(pixels) = self.pixels.get_mut(start..start + width as usize) {
+            pixels.copy_from_slice(&bytes[..width as usize]);
+            self.dirty = true;
+        }
+    }
+
+    fn tick(&mut self, steps: u32, _memory: &mut GuestMemory) {
+        self.refresh_countdown = self.refresh_countdown.saturating_sub(steps);
+        if self.refresh_countdown != 0 {
+            return;
+        }
+        self.refresh_countdown = REFRESH_INTERVAL;
+        if std::mem::take(&mut self.dirty) {
+            let rgba = self.rgba();
+            self.display.refresh(self.width, self.height, &rgba);
+        }
+    }
+
+    fn compatible(&self) -> Option<&str> {
+        Some("simple-framebuffer")
+    }
+
+    fn properties(&self) -> Vec<(String, Property)> {
+        vec![
+            ("width".to_string(), Property::U32(self.width)),
+            ("height".to_string(), Property::U32(self.height)),
+            ("stride".to_string(), Property::U32(self.stride())),
+            ("format".to_string(), Property::Str("x8r8g8b8".to_string())),
+        ]
+    }
+
+    fn reset(&mut self) {
+        self.pixels.fill(0);
+        self.dirty = true;
+    }
+}
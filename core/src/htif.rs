@@ -0,0 +1,83 @@
+//! Berkeley Host-Target Interface (HTIF).
+//!
+//! Test programs such as `riscv-tests` and `riscv-arch-test` report their result by writing
+//! a 64-bit command to the `tohost` symbol: `(code << 1) | 1` ends the run with `code` (0
+//! means pass). Device 1 command 1 prints the character in the low byte, which is
+//! acknowledged through `fromhost`.
+//!
+//! RV32 guests write the command as two words, low half first, so a command is only acted
+//! on once its upper half arrives.
+
+use std::io::Write;
+
+const DEVICE_CONSOLE: u32 = 1;
+const CONSOLE_PUTCHAR: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Htif {
+    /// Address of the `tohost` symbol.
+    pub tohost: u32,
+    /// Address of the `fromhost` symbol, if the program has one.
+    pub fromhost: Option<u32>,
+    /// Lower half of the command being written.
+    low: u32,
+    exit_code: Option<u32>,
+}
+
+/// What the bus has to do after a command was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Response {
+    /// Clear `tohost`, and write the value to `fromhost` if there is one.
+    Ack(Option<u64>),
+    /// Leave memory alone, the command is not complete yet.
+    Pending,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            low: 0,
+            exit_code: None,
+        }
+    }
+
+    /// Exit code of the program, once it reported one.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    /// Handles a word the guest stored at `addr`.
+    pub(crate) fn write(&mut self, addr: u32, v: u32) -> Response {
+        if addr == self.tohost {
+            self.low = v;
+            return Response::Pending;
+        }
+        if addr != self.tohost + 4 {
+            return Response::Pending;
+        }
+        let command = ((v as u64) << 32) | self.low as u64;
+        if command == 0 {
+            return Response::Pending;
+        }
+        let device = (command >> 56) as u32;
+        let cmd = ((command >> 48) & 0xff) as u32;
+        let payload = command & 0xffff_ffff_ffff;
+        match (device, cmd) {
+            (0, _) if payload & 1 != 0 => {
+                self.exit_code = Some((payload >> 1) as u32);
+                Response::Ack(None)
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&[payload as u8]).ok();
+                stdout.flush().ok();
+                Response::Ack(Some(command & !0xffff_ffff_ffff))
+            }
+            // Unknown devices (system calls of the proxy kernel, console reads) are just
+            // acknowledged so the guest does not spin forever.
+            _ => Response::Ack(Some(command & !0xffff_ffff_ffff)),
+        }
+    }
+}
@@ -2,35 +2,83 @@ pub mod bus;
 pub mod bus_interface;
 pub mod clint;
 pub mod cpu;
+pub mod elf;
+pub mod fdt;
+pub mod framebuffer;
+pub mod htif;
+pub mod machine;
+pub mod mmio;
+pub mod overlay;
+pub mod plic;
+pub mod profiler;
+pub mod rtc;
+mod semihosting;
+pub mod stats;
+pub mod uart;
+pub mod virtio;
 
 use bus_interface::{BusController, BusReader, BusWriter};
-use cpu::{Cpu, CpuState};
+use cpu::Fault;
+use machine::{ExitReason, Machine};
+use profiler::Profiler;
+use stats::Stats;
 
+/// Optional features of a run. The defaults are what a plain Linux boot needs.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Samples the guest PC while running.
+    pub profiler: Option<Profiler>,
+    /// Services RISC-V semihosting calls, which gives the guest access to host files.
+    pub semihosting: bool,
+    /// Starts the hart in S-mode and services SBI calls, so S-mode kernels run without
+    /// firmware.
+    pub sbi: bool,
+}
+
+/// How a run ended.
+#[derive(Debug)]
+pub struct Exit {
+    /// Exit code requested by the guest, 0 for a plain power-off and 1 if the hart got
+    /// stuck.
+    pub code: u32,
+    pub stats: Stats,
+    /// The exception the hart got stuck on, if that is what ended the run.
+    pub fault: Option<Fault>,
+}
+
+/// Runs the guest until it powers off, asks to exit or gets stuck. A reboot resets the
+/// machine and boots the images RAM held at the start again.
 pub fn start<B: BusController + BusReader + BusWriter>(
     bus: B,
     pc: u32,
     dtb_ref: u32,
+    options: Options,
     sleep: &dyn Fn(std::time::Duration),
-) {
-    'reboot: {
-        let mut core = Cpu::new(bus);
-
-        // https://github.com/torvalds/linux/blob/89d77f71f493a3663b10fa812d17f472935d24be/arch/riscv/kernel/head.S#LL153C1-L153C1
-        // Pass hart id and ref to dtb.
-        core.a0(0x00) // hart id
-            .a1(dtb_ref) // ref to dtb
-            .pc(pc);
-
-        loop {
-            match core.step() {
-                CpuState::Active if core.bus().power_off() => return,
-                CpuState::Active if core.bus().reboot() => break 'reboot,
-                CpuState::Idle => {
-                    sleep(core::time::Duration::from_micros(100));
-                    core.add_cycles(1);
+) -> Exit {
+    let mut machine = Machine::new(bus, pc, dtb_ref, options);
+    loop {
+        match machine.run_for(u64::MAX) {
+            ExitReason::PowerOff(code) => {
+                return Exit {
+                    code,
+                    stats: machine.stats(),
+                    fault: None,
+                }
+            }
+            ExitReason::Fault(fault) => {
+                return Exit {
+                    code: 1,
+                    stats: machine.stats(),
+                    fault: Some(fault),
                 }
-                _ => {}
             }
+            ExitReason::Reboot => machine.reboot(),
+            ExitReason::Idle => {
+                let idle = core::time::Duration::from_micros(100);
+                sleep(idle);
+                machine.add_idle(idle);
+            }
+            ExitReason::BudgetExhausted | ExitReason::Breakpoint(_) => {}
         }
     }
 }
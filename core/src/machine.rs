@@ -0,0 +1,164 @@
+//! A guest that runs in bounded slices, for hosts that interleave emulation with their own
+//! work.
+//!
+//! [`start`](crate::start) runs a guest to the end. A [`Machine`] runs it for a number of
+//! instructions or until a deadline, then tells why it stopped, so that test harnesses can
+//! poke at the bus between slices and applications can run it from their own event loop.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use crate::bus_interface::{BusController, BusReader, BusWriter};
+use crate::cpu::{Cpu, CpuState, Fault};
+use crate::stats::Stats;
+use crate::Options;
+
+/// Steps between two looks at the clock in [`Machine::run_until`].
+const DEADLINE_CHECK_INTERVAL: u64 = 4096;
+
+/// Why a run stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The instructions given to [`Machine::run_for`] are done, or the deadline given to
+    /// [`Machine::run_until`] passed.
+    BudgetExhausted,
+    /// The hart waits for an interrupt. Running again right away is fine; hosts that do
+    /// not want to spin wait a little first and report it with [`Machine::add_idle`].
+    Idle,
+    /// The guest powered off, with the exit code it asked for, 0 for a plain power-off.
+    PowerOff(u32),
+    /// The guest asked for a reboot, which [`Machine::reboot`] carries out.
+    Reboot,
+    /// The hart is about to execute the instruction at this address, which has a
+    /// breakpoint. Running again executes it.
+    Breakpoint(u32),
+    /// The hart is stuck and will not make progress anymore.
+    Fault(Fault),
+}
+
+pub struct Machine<B> {
+    core: Cpu<B>,
+    /// Entry point and device tree address the hart boots with.
+    pc: u32,
+    dtb_ref: u32,
+    breakpoints: BTreeSet<u32>,
+    /// Breakpoint the last run stopped at, which the next run steps over.
+    stopped_at: Option<u32>,
+}
+
+impl<B: BusController + BusReader + BusWriter> Machine<B> {
+    /// A machine ready to boot the images in RAM at `pc`, passing it `dtb_ref` as the
+    /// device tree. Those images are also what a reboot restores.
+    pub fn new(mut bus: B, pc: u32, dtb_ref: u32, options: Options) -> Self {
+        bus.save_boot_image();
+        let mut core = Cpu::new(bus);
+        core.profiler(options.profiler)
+            .semihosting(options.semihosting)
+            .sbi(options.sbi);
+        let mut machine = Self {
+            core,
+            pc,
+            dtb_ref,
+            breakpoints: BTreeSet::new(),
+            stopped_at: None,
+        };
+        machine.boot();
+        machine
+    }
+
+    fn boot(&mut self) {
+        // https://github.com/torvalds/linux/blob/89d77f71f493a3663b10fa812d17f472935d24be/arch/riscv/kernel/head.S#LL153C1-L153C1
+        // Pass hart id and ref to dtb.
+        self.core
+            .a0(0x00) // hart id
+            .a1(self.dtb_ref) // ref to dtb
+            .pc(self.pc);
+    }
+
+    /// Runs at most `instructions` steps. A step executes an instruction or takes an
+    /// interrupt.
+    pub fn run_for(&mut self, instructions: u64) -> ExitReason {
+        for _ in 0..instructions {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        ExitReason::BudgetExhausted
+    }
+
+    /// Runs until `deadline`, which is checked every few thousand steps.
+    pub fn run_until(&mut self, deadline: Instant) -> ExitReason {
+        while Instant::now() < deadline {
+            match self.run_for(DEADLINE_CHECK_INTERVAL) {
+                ExitReason::BudgetExhausted => {}
+                reason => return reason,
+            }
+        }
+        ExitReason::BudgetExhausted
+    }
+
+    fn step(&mut self) -> Option<ExitReason> {
+        let pc = self.core.program_counter();
+        if self.stopped_at.take() != Some(pc) && self.breakpoints.contains(&pc) {
+            self.stopped_at = Some(pc);
+            return Some(ExitReason::Breakpoint(pc));
+        }
+        let state = self.core.step();
+        // The guest may ask to stop with the same instruction that parks the hart, or while
+        // it is parked.
+        if self.core.bus().power_off() {
+            return Some(ExitReason::PowerOff(0));
+        }
+        if self.core.reboot_requested() {
+            return Some(ExitReason::Reboot);
+        }
+        if let Some(code) = self.core.exit_code() {
+            return Some(ExitReason::PowerOff(code));
+        }
+        match state {
+            CpuState::Active => self.core.fault().map(ExitReason::Fault),
+            CpuState::Idle => {
+                self.core.add_cycles(1);
+                Some(ExitReason::Idle)
+            }
+        }
+    }
+
+    /// Resets the hart and the devices and boots the original images again.
+    pub fn reboot(&mut self) {
+        self.core.reset();
+        self.boot();
+        self.stopped_at = None;
+    }
+
+    /// Stops runs before the instruction at `pc`, a virtual address when paging is on.
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Records time the host waited after an [`ExitReason::Idle`].
+    pub fn add_idle(&mut self, duration: Duration) {
+        self.core.add_idle(duration);
+    }
+
+    pub fn cpu(&self) -> &Cpu<B> {
+        &self.core
+    }
+
+    pub fn bus(&self) -> &B {
+        self.core.bus()
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        self.core.bus_mut()
+    }
+
+    /// Execution statistics since the machine was created, across reboots.
+    pub fn stats(&self) -> Stats {
+        self.core.stats()
+    }
+}
@@ -0,0 +1,123 @@
+//! Memory-mapped peripherals that can be plugged into the [`Bus`](crate::bus::Bus).
+//!
+//! A device only sees offsets into its own region. The bus takes care of finding the device
+//! an address belongs to, counting accesses and routing its interrupt output.
+
+use std::error::Error;
+
+use crate::fdt::Property;
+
+/// Bus steps between two device ticks, unless the guest accessed a device in between.
+pub const TICK_INTERVAL: u32 = 64;
+
+/// Width of an access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+}
+
+pub trait MmioDevice {
+    /// Short name used in statistics and error messages.
+    fn name(&self) -> &str;
+    /// Size in bytes of the region the device decodes.
+    fn size(&self) -> u32;
+    /// Reads the register at `offset`. Only the low `width` bytes are used.
+    fn read(&mut self, offset: u32, width: Width) -> u32;
+    /// Writes the low `width` bytes of `v` to the register at `offset`.
+    fn write(&mut self, offset: u32, v: u32, width: Width);
+    /// Called every [`TICK_INTERVAL`] bus steps, and on the step after the guest accessed
+    /// any device, before the hart executes an instruction. `steps` is the number of bus
+    /// steps since the previous tick, which is what intervals have to be counted in. Devices
+    /// doing DMA access guest RAM through `memory`. The interrupt output is only sampled
+    /// after a tick.
+    fn tick(&mut self, _steps: u32, _memory: &mut GuestMemory) {}
+    /// Level of the device's interrupt output.
+    fn interrupt(&self) -> bool {
+        false
+    }
+    /// `compatible` string of the node advertising the device in the generated device
+    /// tree. Devices without one are left out of it.
+    fn compatible(&self) -> Option<&str> {
+        None
+    }
+    /// Properties of its device tree node besides `reg`, `compatible` and the interrupt.
+    fn properties(&self) -> Vec<(String, Property)> {
+        Vec::new()
+    }
+    /// Returns the device to its power-on state when the machine reboots. What lives on the
+    /// host side, like disk contents or open connections, is kept.
+    fn reset(&mut self) {}
+}
+
+/// Guest RAM as devices see it for DMA, addressed by physical address.
+pub struct GuestMemory<'a> {
+    base: u32,
+    ram: &'a mut [u8],
+}
+
+impl<'a> GuestMemory<'a> {
+    pub fn new(base: u32, ram: &'a mut [u8]) -> Self {
+        Self { base, ram }
+    }
+
+    /// The `len` bytes at `addr`, or `None` if they are not all in RAM.
+    pub fn slice(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let offset = self.offset(addr, len)?;
+        Some(&self.ram[offset..offset + len])
+    }
+
+    pub fn slice_mut(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        let offset = self.offset(addr, len)?;
+        Some(&mut self.ram[offset..offset + len])
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Option<u16> {
+        Some(u16::from_le_bytes(self.slice(addr, 2)?.try_into().ok()?))
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Option<u32> {
+        Some(u32::from_le_bytes(self.slice(addr, 4)?.try_into().ok()?))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Option<u64> {
+        Some(u64::from_le_bytes(self.slice(addr, 8)?.try_into().ok()?))
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Option<()> {
+        self.slice_mut(addr, data.len())?.copy_from_slice(data);
+        Some(())
+    }
+
+    fn offset(&self, addr: u64, len: usize) -> Option<usize> {
+        let offset = usize::try_from(addr.checked_sub(self.base as u64)?).ok()?;
+        (offset.checked_add(len)? <= self.ram.len()).then_some(offset)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The device would overlap `other`, which is already mapped, or RAM.
+    Overlap { device: String, other: String },
+    /// The interrupt line is not a PLIC source.
+    InvalidIrq { device: String, irq: u32 },
+    /// Every virtio-mmio slot is taken.
+    NoSlot { device: String },
+}
+
+impl std::fmt::Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overlap { device, other } => write!(f, "{device} overlaps {other}"),
+            Self::InvalidIrq { device, irq } => write!(f, "{device}: no interrupt line {irq}"),
+            Self::NoSlot { device } => write!(f, "no virtio-mmio slot left for {device}"),
+        }
+    }
+}
+
+impl Error for MapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
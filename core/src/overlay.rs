@@ -0,0 +1,413 @@
+//! Copy-on-write overlays of disk images.
+//!
+//! An [`Overlay`] presents a base image the guest can write to without changing it. Reads
+//! of clusters that were never written fall through to the base image; the first write to
+//! a cluster copies it into the overlay, which serves it from then on.
+//!
+//! Overlay files start with a header naming the base image, followed by a bitmap of the
+//! clusters they hold. Each cluster has a fixed place after that, so clusters that were
+//! never written stay holes in a sparse file.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::virtio::blk::Disk;
+
+const MAGIC: &[u8; 8] = b"R2COW\0\0\0";
+const VERSION: u32 = 1;
+/// Granularity of copy-on-write.
+pub const CLUSTER_SIZE: u64 = 4096;
+/// Magic, version, cluster size, image size and length of the base path.
+const HEADER_SIZE: u64 = 28;
+/// Longest base path an overlay can name, Linux's `PATH_MAX`.
+const MAX_BASE_PATH: usize = 4096;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Header of an overlay file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Header {
+    cluster_size: u64,
+    /// Size of the base image, which is the size of the disk.
+    size: u64,
+    base: PathBuf,
+}
+
+impl Header {
+    fn read(file: &mut File) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(invalid("not an overlay".to_string()));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let version = u32_at(8);
+        if version != VERSION {
+            return Err(invalid(format!("unsupported overlay version {version}")));
+        }
+        let cluster_size = u32_at(12) as u64;
+        if cluster_size != CLUSTER_SIZE {
+            return Err(invalid(format!("unsupported cluster size {cluster_size}")));
+        }
+        let size = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let base_len = u32_at(24) as usize;
+        if base_len > MAX_BASE_PATH {
+            return Err(invalid(format!(
+                "base path of {base_len} bytes is too long"
+            )));
+        }
+        let mut base = vec![0u8; base_len];
+        file.read_exact(&mut base)?;
+        let base = String::from_utf8(base).map_err(|_| invalid("bad base path".to_string()))?;
+        Ok(Self {
+            cluster_size,
+            size,
+            base: PathBuf::from(base),
+        })
+    }
+
+    fn write(&self, file: &mut File) -> io::Result<()> {
+        let base = self
+            .base
+            .to_str()
+            .ok_or_else(|| invalid("base path is not UTF-8".to_string()))?;
+        if base.len() > MAX_BASE_PATH {
+            return Err(invalid(format!(
+                "base path of {} bytes is too long",
+                base.len()
+            )));
+        }
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize + base.len());
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.cluster_size as u32).to_le_bytes());
+        header.extend_from_slice(&self.size.to_le_bytes());
+        header.extend_from_slice(&(base.len() as u32).to_le_bytes());
+        header.extend_from_slice(base.as_bytes());
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)
+    }
+
+    fn clusters(&self) -> u64 {
+        self.size.div_ceil(self.cluster_size)
+    }
+
+    fn bitmap_start(&self) -> u64 {
+        (HEADER_SIZE + self.base.as_os_str().len() as u64).next_multiple_of(8)
+    }
+
+    fn data_start(&self) -> u64 {
+        (self.bitmap_start() + self.clusters().div_ceil(8)).next_multiple_of(self.cluster_size)
+    }
+}
+
+/// Whether `a` and `b` name the same file. Paths that cannot be resolved have to match as
+/// they are.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Where written clusters are kept.
+enum Store {
+    File {
+        file: File,
+        bitmap_start: u64,
+        data_start: u64,
+    },
+    /// Lost when the overlay is dropped, like QEMU's `-snapshot`.
+    Memory(HashMap<u64, Vec<u8>>),
+}
+
+/// A disk image seen through a copy-on-write overlay.
+pub struct Overlay<B> {
+    base: B,
+    base_len: u64,
+    /// Clusters held by the overlay, one bit each.
+    present: Vec<u8>,
+    store: Store,
+    pos: u64,
+}
+
+impl<B: Read + Seek> Overlay<B> {
+    /// Keeps every write in memory.
+    pub fn in_memory(mut base: B) -> io::Result<Self> {
+        let base_len = base.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            base,
+            base_len,
+            present: vec![0; base_len.div_ceil(CLUSTER_SIZE).div_ceil(8) as usize],
+            store: Store::Memory(HashMap::new()),
+            pos: 0,
+        })
+    }
+
+    /// Keeps writes in the overlay file at `path`, which is created for `base_path` if it
+    /// does not exist.
+    pub fn open(base: B, base_path: &Path, path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Self::with_file(base, base_path, path, file)
+    }
+
+    /// Reads the existing overlay file at `path` without ever changing it. Writes fail, so
+    /// this is meant to go under an [`Overlay::in_memory`], for a snapshot of the disk as
+    /// the overlay left it.
+    pub fn open_read_only(base: B, base_path: &Path, path: &Path) -> io::Result<Self> {
+        Self::with_file(base, base_path, path, File::open(path)?)
+    }
+
+    fn with_file(mut base: B, base_path: &Path, path: &Path, mut file: File) -> io::Result<Self> {
+        let base_len = base.seek(SeekFrom::End(0))?;
+        let header = if file.metadata()?.len() == 0 {
+            let header = Header {
+                cluster_size: CLUSTER_SIZE,
+                size: base_len,
+                base: base_path.to_path_buf(),
+            };
+            header.write(&mut file)?;
+            file.set_len(header.data_start())?;
+            header
+        } else {
+            Header::read(&mut file)?
+        };
+        if !same_file(&header.base, base_path) {
+            return Err(invalid(format!(
+                "{} was made for {}, not {}",
+                path.display(),
+                header.base.display(),
+                base_path.display()
+            )));
+        }
+        if header.size != base_len {
+            return Err(invalid(format!(
+                "{} was made for a {} byte image, not {base_len} bytes",
+                path.display(),
+                header.size
+            )));
+        }
+        let mut present = vec![0; header.clusters().div_ceil(8) as usize];
+        file.seek(SeekFrom::Start(header.bitmap_start()))?;
+        file.read_exact(&mut present)?;
+        Ok(Self {
+            base,
+            base_len,
+            present,
+            store: Store::File {
+                file,
+                bitmap_start: header.bitmap_start(),
+                data_start: header.data_start(),
+            },
+            pos: 0,
+        })
+    }
+
+    fn is_present(&self, cluster: u64) -> bool {
+        self.present[(cluster / 8) as usize] & (1 << (cluster % 8)) != 0
+    }
+
+    /// Reads `buf.len()` bytes at `offset` within `cluster` from the overlay.
+    fn read_stored(&mut self, cluster: u64, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match &mut self.store {
+            Store::File {
+                file, data_start, ..
+            } => {
+                file.seek(SeekFrom::Start(
+                    *data_start + cluster * CLUSTER_SIZE + offset,
+                ))?;
+                file.read_exact(buf)
+            }
+            Store::Memory(clusters) => {
+                let data = &clusters[&cluster];
+                buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `data` at `offset` within `cluster`, which has to be in the overlay already
+    /// unless the whole cluster is written.
+    fn write_stored(&mut self, cluster: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        match &mut self.store {
+            Store::File {
+                file, data_start, ..
+            } => {
+                file.seek(SeekFrom::Start(
+                    *data_start + cluster * CLUSTER_SIZE + offset,
+                ))?;
+                file.write_all(data)
+            }
+            Store::Memory(clusters) => {
+                let stored = clusters
+                    .entry(cluster)
+                    .or_insert_with(|| vec![0; CLUSTER_SIZE as usize]);
+                stored[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads from the base image. The last cluster may extend past its end, which reads as
+    /// zeros.
+    fn read_base(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        let available = self.base_len.saturating_sub(pos).min(buf.len() as u64) as usize;
+        self.base.seek(SeekFrom::Start(pos))?;
+        self.base.read_exact(&mut buf[..available])?;
+        buf[available..].fill(0);
+        Ok(())
+    }
+
+    /// Copies `cluster` from the base image into the overlay, with `data` written at
+    /// `offset`.
+    fn copy_up(&mut self, cluster: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut contents = vec![0u8; CLUSTER_SIZE as usize];
+        self.read_base(cluster * CLUSTER_SIZE, &mut contents)?;
+        contents[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        self.write_stored(cluster, 0, &contents)?;
+
+        let byte = (cluster / 8) as usize;
+        self.present[byte] |= 1 << (cluster % 8);
+        if let Store::File {
+            file, bitmap_start, ..
+        } = &mut self.store
+        {
+            file.seek(SeekFrom::Start(*bitmap_start + byte as u64))?;
+            file.write_all(&self.present[byte..byte + 1])?;
+        }
+        Ok(())
+    }
+
+    /// Cluster `pos` falls in, the offset within it and how many of `len` bytes from there
+    /// stay within both the cluster and the disk.
+    fn span(&self, len: usize) -> (u64, u64, usize) {
+        let (cluster, offset) = (self.pos / CLUSTER_SIZE, self.pos % CLUSTER_SIZE);
+        let n = (len as u64)
+            .min(CLUSTER_SIZE - offset)
+            .min(self.base_len.saturating_sub(self.pos));
+        (cluster, offset, n as usize)
+    }
+}
+
+impl<B: Read + Seek> Read for Overlay<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (cluster, offset, n) = self.span(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        if self.is_present(cluster) {
+            self.read_stored(cluster, offset, &mut buf[..n])?;
+        } else {
+            self.read_base(self.pos, &mut buf[..n])?;
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<B: Read + Seek> Write for Overlay<B> {
+    /// Writes past the end of the base image are refused: the disk cannot grow.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (cluster, offset, n) = self.span(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        if self.is_present(cluster) {
+            self.write_stored(cluster, offset, &buf[..n])?;
+        } else {
+            self.copy_up(cluster, offset, &buf[..n])?;
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.store {
+            Store::File { file, .. } => file.flush(),
+            Store::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl<B: Read + Seek> Seek for Overlay<B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.base_len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+impl<B: Read + Seek> Disk for Overlay<B> {
+    fn sync(&mut self) -> io::Result<()> {
+        match &mut self.store {
+            Store::File { file, .. } => file.sync_data(),
+            Store::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// Base image the overlay file at `path` was made for.
+pub fn base_path(path: &Path) -> io::Result<PathBuf> {
+    Ok(Header::read(&mut File::open(path)?)?.base)
+}
+
+/// Writes the clusters held by the overlay file at `path` back into its base image, then
+/// empties the overlay. Returns the number of clusters written.
+pub fn commit(path: &Path) -> io::Result<u64> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let header = Header::read(&mut file)?;
+    let mut base = OpenOptions::new().write(true).open(&header.base)?;
+    if base.metadata()?.len() != header.size {
+        return Err(invalid(format!(
+            "{} changed size since the overlay was made",
+            header.base.display()
+        )));
+    }
+    let mut present = vec![0u8; header.clusters().div_ceil(8) as usize];
+    file.seek(SeekFrom::Start(header.bitmap_start()))?;
+    file.read_exact(&mut present)?;
+
+    let mut committed = 0;
+    let mut data = vec![0u8; CLUSTER_SIZE as usize];
+    for cluster in
+        (0..header.clusters()).filter(|c| present[(c / 8) as usize] & (1 << (c % 8)) != 0)
+    {
+        let start = cluster * CLUSTER_SIZE;
+        let len = CLUSTER_SIZE.min(header.size - start) as usize;
+        file.seek(SeekFrom::Start(header.data_start() + start))?;
+        file.read_exact(&mut data[..len])?;
+        base.seek(SeekFrom::Start(start))?;
+        base.write_all(&data[..len])?;
+        committed += 1;
+    }
+    base.sync_data()?;
+    discard(path)?;
+    Ok(committed)
+}
+
+/// Drops every change held by the overlay file at `path`.
+pub fn discard(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let header = Header::read(&mut file)?;
+    file.seek(SeekFrom::Start(header.bitmap_start()))?;
+    file.write_all(&vec![0u8; header.clusters().div_ceil(8) as usize])?;
+    // Punch out the data as well, so the file goes back to its initial size.
+    file.set_len(header.bitmap_start() + header.clusters().div_ceil(8))?;
+    file.set_len(header.data_start())?;
+    file.sync_all()
+}
@@ -0,0 +1,180 @@
+/// Number of interrupt sources, including source 0 which means "no interrupt".
+pub const SOURCES: u32 = 64;
+/// Context of the hart's M-mode and S-mode, in that order.
+const CONTEXTS: usize = 2;
+/// Sources are prioritized from 1 to 7. Priority 0 never interrupts.
+const MAX_PRIORITY: u32 = 7;
+
+const PRIORITY_BASE: u32 = 0x0;
+const PENDING_BASE: u32 = 0x1000;
+const ENABLE_BASE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT_BASE: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+/// External interrupt bit of mip each context drives: MEIP and SEIP.
+const MIP_BITS: [u32; CONTEXTS] = [0x800, 0x200];
+
+/// Platform-Level Interrupt Controller (PLIC)
+/// https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+///
+/// Every source is level-triggered: it is pending while its line is high and it is not
+/// being serviced.
+#[derive(Debug)]
+pub struct Plic {
+    /// Priority of each source.
+    priority: [u32; SOURCES as usize],
+    /// Level of each interrupt line, one bit per source.
+    levels: u64,
+    /// Sources claimed and not completed yet. They do not become pending again until
+    /// completed.
+    claimed: u64,
+    /// Sources each context is interrupted by.
+    enable: [u64; CONTEXTS],
+    /// Priority a source must exceed to interrupt each context.
+    threshold: [u32; CONTEXTS],
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: [0; SOURCES as usize],
+            levels: 0,
+            claimed: 0,
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+        }
+    }
+
+    /// Disables every source and forgets claims, as at power-on.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Latches the interrupt lines, one bit per source, and drives the external interrupt
+    /// bits of `mip` accordingly.
+    pub fn step(&mut self, levels: u64, mip: &mut u32) {
+        // Source 0 does not exist.
+        self.levels = levels & !1;
+        if self.levels == 0 {
+            *mip &= !(MIP_BITS[0] | MIP_BITS[1]);
+            return;
+        }
+        for (context, bit) in MIP_BITS.iter().enumerate() {
+            if self.best(context) != 0 {
+                *mip |= bit;
+            } else {
+                *mip &= !bit;
+            }
+        }
+    }
+
+    /// Read register content.
+    pub fn read(&mut self, addr: u32) -> u32 {
+        match addr {
+            PRIORITY_BASE..PENDING_BASE => self.priority.get(addr as usize / 4).map_or(0, |p| *p),
+            PENDING_BASE..ENABLE_BASE => word(self.pending(), addr - PENDING_BASE),
+            ENABLE_BASE..CONTEXT_BASE => match Self::context(addr - ENABLE_BASE, ENABLE_STRIDE) {
+                Some((context, offset)) => word(self.enable[context], offset),
+                None => 0,
+            },
+            _ => match Self::context(addr.wrapping_sub(CONTEXT_BASE), CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context],
+                Some((context, 4)) => self.claim(context),
+                _ => 0,
+            },
+        }
+    }
+
+    /// Write.
+    pub fn write(&mut self, addr: u32, value: u32) {
+        match addr {
+            PRIORITY_BASE..PENDING_BASE => {
+                // Source 0 has no priority to set.
+                if let Some(priority) = self
+                    .priority
+                    .get_mut(addr as usize / 4)
+                    .filter(|_| addr >= 4)
+                {
+                    *priority = value.min(MAX_PRIORITY);
+                }
+            }
+            // Pending bits are read-only.
+            PENDING_BASE..ENABLE_BASE => {}
+            ENABLE_BASE..CONTEXT_BASE => {
+                if let Some((context, offset)) = Self::context(addr - ENABLE_BASE, ENABLE_STRIDE) {
+                    if offset < 8 {
+                        let shift = offset * 8;
+                        let enable = &mut self.enable[context];
+                        *enable = (*enable & !(0xffff_ffff << shift)) | ((value as u64) << shift);
+                        *enable &= !1;
+                    }
+                }
+            }
+            _ => match Self::context(addr.wrapping_sub(CONTEXT_BASE), CONTEXT_STRIDE) {
+                Some((context, 0)) => self.threshold[context] = value.min(MAX_PRIORITY),
+                Some((context, 4)) => self.complete(context, value),
+                _ => {}
+            },
+        }
+    }
+
+    /// Splits an offset into a block of per-context registers into the context and the
+    /// offset within its registers.
+    fn context(offset: u32, stride: u32) -> Option<(usize, u32)> {
+        let context = (offset / stride) as usize;
+        (context < CONTEXTS).then_some((context, offset % stride))
+    }
+
+    fn pending(&self) -> u64 {
+        self.levels & !self.claimed
+    }
+
+    /// The pending source with the highest priority that can interrupt `context`, the lowest
+    /// numbered one among equals, or 0 if there is none.
+    fn best(&self, context: usize) -> u32 {
+        let mut candidates = self.pending() & self.enable[context];
+        let mut best = (0, self.threshold[context]);
+        while candidates != 0 {
+            let source = candidates.trailing_zeros();
+            candidates &= candidates - 1;
+            let priority = self.priority[source as usize];
+            if priority > best.1 {
+                best = (source, priority);
+            }
+        }
+        best.0
+    }
+
+    /// Takes the best pending source for `context` out of the pending set.
+    fn claim(&mut self, context: usize) -> u32 {
+        let source = self.best(context);
+        if source != 0 {
+            self.claimed |= 1 << source;
+        }
+        source
+    }
+
+    /// Finishes servicing `source`. Sources not enabled for `context` are ignored, as the
+    /// specification allows.
+    fn complete(&mut self, context: usize, source: u32) {
+        if source < SOURCES && self.enable[context] & (1 << source) != 0 {
+            self.claimed &= !(1 << source);
+        }
+    }
+}
+
+/// The 32 bits of a per-source bitmap at byte `offset`.
+fn word(bits: u64, offset: u32) -> u32 {
+    match offset {
+        0 => bits as u32,
+        4 => (bits >> 32) as u32,
+        _ => 0,
+    }
+}
@@ -0,0 +1,74 @@
+//! Statistical profiler that samples the guest program counter.
+//!
+//! Every `interval` instructions the current PC and privilege mode are recorded. The
+//! samples are later resolved through a [`Symbols`] table and written as folded stacks,
+//! the input format of `flamegraph.pl` and `inferno`.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::cpu::PrivilegeMode;
+use crate::elf::Symbols;
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    interval: u64,
+    /// Instructions left until the next sample.
+    countdown: u64,
+    samples: HashMap<(PrivilegeMode, u32), u64>,
+}
+
+impl Profiler {
+    /// Samples once every `interval` instructions.
+    pub fn new(interval: u64) -> Self {
+        let interval = interval.max(1);
+        Self {
+            interval,
+            countdown: interval,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn tick(&mut self, pc: u32, mode: PrivilegeMode) {
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.interval;
+            *self.samples.entry((mode, pc)).or_default() += 1;
+        }
+    }
+
+    /// Number of samples taken so far.
+    pub fn total(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    /// Writes one `<mode>;<function> <count>` line per distinct frame. Addresses without a
+    /// symbol are printed in hex so they can still be told apart.
+    pub fn write_folded(&self, symbols: &Symbols, mut w: impl Write) -> std::io::Result<()> {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for ((mode, pc), count) in &self.samples {
+            let frame = match symbols.lookup(*pc) {
+                Some(symbol) => symbol.name.clone(),
+                None => format!("{pc:#010x}"),
+            };
+            *folded
+                .entry(format!("{};{}", mode_name(*mode), frame))
+                .or_default() += count;
+        }
+        let mut folded: Vec<_> = folded.into_iter().collect();
+        folded.sort();
+        for (stack, count) in folded {
+            writeln!(w, "{stack} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+fn mode_name(mode: PrivilegeMode) -> &'static str {
+    match mode {
+        PrivilegeMode::User => "user",
+        PrivilegeMode::SuperVisor => "supervisor",
+        PrivilegeMode::Reserved => "reserved",
+        PrivilegeMode::Machine => "machine",
+    }
+}
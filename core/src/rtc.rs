@@ -0,0 +1,166 @@
+//! Goldfish real-time clock, which gives the guest the date (`CONFIG_RTC_DRV_GOLDFISH`).
+//!
+//! Time is a 64-bit count of nanoseconds since the UNIX epoch. Reading the low half latches
+//! the high half, so that a low-then-high read is consistent. Writing the high half then the
+//! low half sets the time. An alarm raises the interrupt once the time reaches it.
+//! @See https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+
+use device_interfaces::WallClock;
+
+use crate::mmio::{GuestMemory, MmioDevice, Width};
+
+const TIME_LOW: u32 = 0x00;
+const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const CLEAR_ALARM: u32 = 0x14;
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+/// Bus steps between two looks at the clock.
+const POLL_INTERVAL: u32 = 256;
+
+pub struct Rtc<C> {
+    clock: C,
+    /// Time of the clock at the last poll.
+    now: u64,
+    /// What the guest set the time to, relative to the clock.
+    offset: u64,
+    /// High half latched by the last read of the low half, or written ahead of the low half.
+    time_high: u32,
+    alarm: u64,
+    alarm_running: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    /// Bus steps until the clock is polled.
+    poll_countdown: u32,
+}
+
+impl<C: WallClock> Rtc<C> {
+    pub fn new(mut clock: C) -> Self {
+        Self {
+            now: clock.now(),
+            clock,
+            offset: 0,
+            time_high: 0,
+            alarm: 0,
+            alarm_running: false,
+            irq_enabled: false,
+            irq_pending: false,
+            poll_countdown: POLL_INTERVAL,
+        }
+    }
+
+    fn time(&self) -> u64 {
+        self.now.wrapping_add(self.offset)
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm_running && self.time() >= self.alarm {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl<C: WallClock> MmioDevice for Rtc<C> {
+    fn name(&self) -> &str {
+        "rtc"
+    }
+
+    fn size(&self) -> u32 {
+        0x1000
+    }
+
+    fn read(&mut self, offset: u32, _width: Width) -> u32 {
+        match offset {
+            TIME_LOW => {
+                let time = self.time();
+                self.time_high = (time >> 32) as u32;
+                time as u32
+            }
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm_running as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, v: u32, _width: Width) {
+        match offset {
+            TIME_LOW => {
+                let time = (self.time_high as u64) << 32 | v as u64;
+                self.offset = time.wrapping_sub(self.now);
+            }
+            TIME_HIGH => self.time_high = v,
+            // Setting the low half arms the alarm.
+            ALARM_LOW => {
+                self.alarm = self.alarm & !0xffff_ffff | v as u64;
+                self.alarm_running = true;
+                self.check_alarm();
+            }
+            ALARM_HIGH => self.alarm = self.alarm & 0xffff_ffff | (v as u64) << 32,
+            IRQ_ENABLED => self.irq_enabled = v & 1 != 0,
+            CLEAR_ALARM => self.alarm_running = false,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, steps: u32, _memory: &mut GuestMemory) {
+        self.clock.advance(steps as u64);
+        self.poll_countdown = self.poll_countdown.saturating_sub(steps);
+        if self.poll_countdown != 0 {
+            return;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+        self.now = self.clock.now();
+        self.check_alarm();
+    }
+
+    fn interrupt(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn compatible(&self) -> Option<&str> {
+        Some("google,goldfish-rtc")
+    }
+
+    /// Keeps the time, as a battery-backed clock would, but drops the alarm.
+    fn reset(&mut self) {
+        self.alarm = 0;
+        self.alarm_running = false;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+    }
+}
+
+/// A clock that starts at a given date and advances one microsecond per bus step, which is
+/// one per instruction or per step the hart waits in WFI, so that runs see the same times
+/// whenever they happen. The guest sees it move on every 256 steps.
+#[derive(Debug, Clone)]
+pub struct Fixed {
+    now: u64,
+}
+
+impl Fixed {
+    /// Starts at `epoch`, in seconds since the UNIX epoch.
+    pub fn new(epoch: u64) -> Self {
+        Self {
+            now: epoch.saturating_mul(1_000_000_000),
+        }
+    }
+}
+
+impl WallClock for Fixed {
+    fn now(&mut self) -> u64 {
+        self.now
+    }
+
+    fn advance(&mut self, steps: u64) {
+        self.now = self.now.wrapping_add(steps.wrapping_mul(1000));
+    }
+}
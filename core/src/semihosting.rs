@@ -0,0 +1,539 @@
+//! RISC-V semihosting.
+//!
+//! A guest requests a host service with the magic sequence
+//!
+//! ```text
+//! slli x0, x0, 0x1f
+//! ebreak
+//! srai x0, x0, 7
+//! ```
+//!
+//! with the operation number in `a0` and a pointer to its parameter block in `a1`. The
+//! result is returned in `a0`. Operation numbers and semantics follow the Arm semihosting
+//! specification the RISC-V convention is built upon.
+//! @See https://github.com/riscv-non-isa/riscv-semihosting/blob/main/riscv-semihosting.adoc
+//! @See https://github.com/ARM-software/abi-aa/blob/main/semihosting/semihosting.rst
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
+
+use crate::bus_interface::{BusException, BusReader, BusWriter};
+
+/// `slli x0, x0, 0x1f`, the instruction right before the `ebreak`.
+pub(crate) const ENTRY_NOP: u32 = 0x01f01013;
+/// `srai x0, x0, 7`, the instruction right after the `ebreak`.
+pub(crate) const EXIT_NOP: u32 = 0x40705013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_ISERROR: u32 = 0x08;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0a;
+const SYS_FLEN: u32 = 0x0c;
+const SYS_REMOVE: u32 = 0x0e;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Reason code of a normal program exit.
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// `:tt` is the special file name for the host console.
+const CONSOLE: &str = ":tt";
+
+/// Returned in `a0` when an operation fails.
+const FAILED: u32 = u32::MAX;
+
+// errno values shared by newlib and Linux.
+const EBADF: i32 = 9;
+const EINVAL: i32 = 22;
+const ENAMETOOLONG: i32 = 36;
+
+/// Longest file name accepted, as on Linux.
+const PATH_MAX: u32 = 4096;
+
+/// Bytes moved between the guest and the host at a time. Lengths come from the guest, so
+/// they cannot size host buffers.
+const CHUNK: u32 = 64 * 1024;
+
+/// What the hart should do once an operation has been serviced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// Write the value to `a0` and resume.
+    Return(u32),
+    /// Stop the emulator with the given exit code.
+    Exit(u32),
+}
+
+#[derive(Debug)]
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Semihosting {
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    /// errno of the last failed operation, for `SYS_ERRNO`.
+    errno: i32,
+    /// Host time the first `SYS_CLOCK` was issued at.
+    started: Option<std::time::Instant>,
+}
+
+impl Semihosting {
+    pub(crate) fn call<B: BusReader + BusWriter>(
+        &mut self,
+        op: u32,
+        arg: u32,
+        bus: &mut B,
+    ) -> Result<Outcome, BusException> {
+        let ret = match op {
+            SYS_OPEN => {
+                let [name, mode, len] = params(bus, arg)?;
+                if len >= PATH_MAX {
+                    return Ok(Outcome::Return(self.fail(ENAMETOOLONG)));
+                }
+                let name = read_bytes(bus, name, len)?;
+                self.open(&String::from_utf8_lossy(&name), mode)
+            }
+            SYS_CLOSE => {
+                let [handle] = params(bus, arg)?;
+                match self.handles.remove(&handle) {
+                    Some(_) => 0,
+                    None => self.fail(EBADF),
+                }
+            }
+            SYS_WRITEC => {
+                let c = bus.read8(arg)?;
+                console_write(&mut std::io::stdout(), &[c]);
+                0
+            }
+            SYS_WRITE0 => {
+                let s = read_c_str(bus, arg)?;
+                console_write(&mut std::io::stdout(), &s);
+                0
+            }
+            SYS_WRITE => {
+                let [handle, buf, len] = params(bus, arg)?;
+                if !matches!(
+                    self.handles.get(&handle),
+                    Some(Handle::Stdout | Handle::Stderr | Handle::File(_))
+                ) {
+                    self.fail(EBADF);
+                    return Ok(Outcome::Return(len));
+                }
+                let mut written = 0;
+                while written < len {
+                    let data =
+                        read_bytes(bus, buf.wrapping_add(written), (len - written).min(CHUNK))?;
+                    let result = match self.handles.get_mut(&handle) {
+                        Some(Handle::Stdout) => {
+                            console_write(&mut std::io::stdout(), &data);
+                            Ok(())
+                        }
+                        Some(Handle::Stderr) => {
+                            console_write(&mut std::io::stderr(), &data);
+                            Ok(())
+                        }
+                        Some(Handle::File(f)) => f.write_all(&data),
+                        _ => unreachable!("checked above"),
+                    };
+                    if let Err(e) = result {
+                        return Ok(Outcome::Return(self.io_fail(e, len - written)));
+                    }
+                    written += data.len() as u32;
+                }
+                // The number of bytes *not* written.
+                0
+            }
+            SYS_READ => {
+                let [handle, buf, len] = params(bus, arg)?;
+                if !matches!(
+                    self.handles.get(&handle),
+                    Some(Handle::Stdin | Handle::File(_))
+                ) {
+                    return Ok(Outcome::Return(self.fail(EBADF)));
+                }
+                let mut data = vec![0u8; len.min(CHUNK) as usize];
+                let mut read = 0;
+                while read < len {
+                    let want = (len - read).min(CHUNK) as usize;
+                    let result = match self.handles.get_mut(&handle) {
+                        Some(Handle::Stdin) => std::io::stdin().read(&mut data[..want]),
+                        Some(Handle::File(f)) => f.read(&mut data[..want]),
+                        _ => unreachable!("checked above"),
+                    };
+                    let n = match result {
+                        Ok(n) => n,
+                        // What was read so far is still reported.
+                        Err(_) if read != 0 => break,
+                        Err(e) => return Ok(Outcome::Return(self.io_fail(e, FAILED))),
+                    };
+                    write_bytes(bus, buf.wrapping_add(read), &data[..n])?;
+                    read += n as u32;
+                    // A short read means the end of the file, or no more console input.
+                    if n < want {
+                        break;
+                    }
+                }
+                // The number of bytes *not* read.
+                len - read
+            }
+            SYS_ISERROR => {
+                let [status] = params(bus, arg)?;
+                ((status as i32) < 0) as u32
+            }
+            SYS_ISTTY => {
+                let [handle] = params(bus, arg)?;
+                match self.handles.get(&handle) {
+                    Some(Handle::File(_)) => 0,
+                    Some(_) => 1,
+                    None => self.fail(EBADF),
+                }
+            }
+            SYS_SEEK => {
+                let [handle, pos] = params(bus, arg)?;
+                match self.handles.get_mut(&handle) {
+                    Some(Handle::File(f)) => match f.seek(SeekFrom::Start(pos as u64)) {
+                        Ok(_) => 0,
+                        Err(e) => self.io_fail(e, FAILED),
+                    },
+                    _ => self.fail(EBADF),
+                }
+            }
+            SYS_FLEN => {
+                let [handle] = params(bus, arg)?;
+                match self.handles.get(&handle) {
+                    Some(Handle::File(f)) => match f.metadata() {
+                        Ok(m) => m.len() as u32,
+                        Err(e) => self.io_fail(e, FAILED),
+                    },
+                    _ => self.fail(EBADF),
+                }
+            }
+            SYS_REMOVE => {
+                let [name, len] = params(bus, arg)?;
+                if len >= PATH_MAX {
+                    return Ok(Outcome::Return(self.fail(ENAMETOOLONG)));
+                }
+                let name = read_bytes(bus, name, len)?;
+                match std::fs::remove_file(&*String::from_utf8_lossy(&name)) {
+                    Ok(()) => 0,
+                    Err(e) => self.io_fail(e, FAILED),
+                }
+            }
+            SYS_CLOCK => {
+                // Centiseconds since the program started.
+                let started = *self.started.get_or_insert_with(std::time::Instant::now);
+                (started.elapsed().as_millis() / 10) as u32
+            }
+            SYS_TIME => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0),
+            SYS_ERRNO => self.errno as u32,
+            SYS_GET_CMDLINE => {
+                // An empty command line: a NUL in the buffer and a length of 0.
+                let [buf, _len] = params(bus, arg)?;
+                bus.write8(buf, 0)?;
+                bus.write32(arg + 4, 0)?;
+                0
+            }
+            SYS_HEAPINFO => {
+                // Zeroes tell the C runtime to fall back to its linker-provided heap and stack.
+                let [block] = params(bus, arg)?;
+                for i in 0..4 {
+                    bus.write32(block + i * 4, 0)?;
+                }
+                0
+            }
+            SYS_EXIT => {
+                // On RV32 the reason code is passed directly rather than through a block.
+                let code = if arg == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 };
+                return Ok(Outcome::Exit(code));
+            }
+            SYS_EXIT_EXTENDED => {
+                let [reason, subcode] = params(bus, arg)?;
+                let code = if reason == ADP_STOPPED_APPLICATION_EXIT { subcode } else { 1 };
+                return Ok(Outcome::Exit(code));
+            }
+            _ => FAILED,
+        };
+        Ok(Outcome::Return(ret))
+    }
+
+    fn open(&mut self, name: &str, mode: u32) -> u32 {
+        // Modes are the fopen() strings "r", "rb", "r+", "r+b", "w", ..., "a+b" in order.
+        let handle = if name == CONSOLE {
+            match mode {
+                0..=3 => Handle::Stdin,
+                4..=7 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let mut options = OpenOptions::new();
+            match mode >> 2 {
+                0 => options.read(true).write(mode & 2 != 0),
+                1 => options
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .read(mode & 2 != 0),
+                2 => options.append(true).create(true).read(mode & 2 != 0),
+                _ => return self.fail(EINVAL),
+            };
+            match options.open(name) {
+                Ok(f) => Handle::File(f),
+                Err(e) => return self.io_fail(e, FAILED),
+            }
+        };
+        self.next_handle += 1;
+        self.handles.insert(self.next_handle, handle);
+        self.next_handle
+    }
+
+    fn fail(&mut self, errno: i32) -> u32 {
+        self.errno = errno;
+        FAILED
+    }
+
+    fn io_fail(&mut self, e: std::io::Error, ret: u32) -> u32 {
+        self.errno = e.raw_os_error().unwrap_or(EINVAL);
+        ret
+    }
+}
+
+fn params<B: BusReader, const N: usize>(bus: &B, addr: u32) -> Result<[u32; N], BusException> {
+    let mut out = [0; N];
+    for (i, v) in out.iter_mut().enumerate() {
+        *v = bus.read32(addr.wrapping_add(i as u32 * 4))?;
+    }
+    Ok(out)
+}
+
+fn read_bytes<B: BusReader>(bus: &B, addr: u32, len: u32) -> Result<Vec<u8>, BusException> {
+    (0..len).map(|i| bus.read8(addr.wrapping_add(i))).collect()
+}
+
+fn read_c_str<B: BusReader>(bus: &B, addr: u32) -> Result<Vec<u8>, BusException> {
+    let mut out = Vec::new();
+    loop {
+        match bus.read8(addr.wrapping_add(out.len() as u32))? {
+            0 => return Ok(out),
+            c => out.push(c),
+        }
+    }
+}
+
+fn write_bytes<B: BusWriter>(bus: &mut B, addr: u32, data: &[u8]) -> Result<(), BusException> {
+    for (i, b) in data.iter().enumerate() {
+        bus.write8(addr.wrapping_add(i as u32), *b)?;
+    }
+    Ok(())
+}
+
+/// Writes guest console output. The terminal is in raw mode while the emulator runs, so bare
+/// `\n` from C programs has to be expanded the way the tty driver normally would.
+fn console_write(out: &mut (impl Write + IsTerminal), data: &[u8]) {
+    let result = if out.is_terminal() {
+        let mut expanded = Vec::with_capacity(data.len());
+        for b in data {
+            if *b == b'\n' {
+                expanded.push(b'\r');
+            }
+            expanded.push(*b);
+        }
+        out.write_all(&expanded)
+    } else {
+        out.write_all(data)
+    };
+    result.and_then(|_| out.flush()).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Guest memory starting at address 0.
+    struct Ram(Vec<u8>);
+
+    impl BusReader for Ram {
+        fn read8(&self, addr: u32) -> Result<u8, BusException> {
+            self.0
+                .get(addr as usize)
+                .copied()
+                .ok_or(BusException::LoadAccessFault)
+        }
+
+        fn read16(&self, addr: u32) -> Result<u16, BusException> {
+            Ok(u16::from_le_bytes([
+                self.read8(addr)?,
+                self.read8(addr + 1)?,
+            ]))
+        }
+
+        fn read32(&self, addr: u32) -> Result<u32, BusException> {
+            Ok(self.read16(addr)? as u32 | (self.read16(addr + 2)? as u32) << 16)
+        }
+    }
+
+    impl BusWriter for Ram {
+        fn write8(&mut self, addr: u32, v: u8) -> Result<(), BusException> {
+            *self
+                .0
+                .get_mut(addr as usize)
+                .ok_or(BusException::StoreAccessFault)? = v;
+            Ok(())
+        }
+
+        fn write16(&mut self, addr: u32, v: u16) -> Result<(), BusException> {
+            self.write8(addr, v as u8)?;
+            self.write8(addr + 1, (v >> 8) as u8)
+        }
+
+        fn write32(&mut self, addr: u32, v: u32) -> Result<(), BusException> {
+            self.write16(addr, v as u16)?;
+            self.write16(addr + 2, (v >> 16) as u16)
+        }
+    }
+
+    /// Parameter blocks go at 0, file names at 0x100 and data at 0x1000.
+    const BLOCK: u32 = 0;
+    const NAME: u32 = 0x100;
+    const DATA: u32 = 0x1000;
+
+    fn call(host: &mut Semihosting, ram: &mut Ram, op: u32, params: &[u32]) -> Outcome {
+        for (i, v) in params.iter().enumerate() {
+            ram.write32(BLOCK + i as u32 * 4, *v).unwrap();
+        }
+        host.call(op, BLOCK, ram).unwrap()
+    }
+
+    fn open(host: &mut Semihosting, ram: &mut Ram, path: &str, mode: u32) -> u32 {
+        write_bytes(ram, NAME, path.as_bytes()).unwrap();
+        match call(host, ram, SYS_OPEN, &[NAME, mode, path.len() as u32]) {
+            Outcome::Return(handle) => handle,
+            outcome => panic!("{outcome:?}"),
+        }
+    }
+
+    #[test]
+    fn write_then_read_file() {
+        let path = std::env::temp_dir().join(format!("r2-semihosting-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut host = Semihosting::default();
+        let mut ram = Ram(vec![0; 0x2000]);
+
+        // "w"
+        let handle = open(&mut host, &mut ram, path, 4);
+        assert_ne!(handle, FAILED);
+        write_bytes(&mut ram, DATA, b"hello").unwrap();
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_WRITE, &[handle, DATA, 5]),
+            Outcome::Return(0)
+        );
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_CLOSE, &[handle]),
+            Outcome::Return(0)
+        );
+
+        // "r"
+        let handle = open(&mut host, &mut ram, path, 0);
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_FLEN, &[handle]),
+            Outcome::Return(5)
+        );
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_ISTTY, &[handle]),
+            Outcome::Return(0)
+        );
+        write_bytes(&mut ram, DATA, &[0; 5]).unwrap();
+        // The result is the number of bytes left unread.
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_READ, &[handle, DATA, 100]),
+            Outcome::Return(95)
+        );
+        assert_eq!(read_bytes(&ram, DATA, 5).unwrap(), b"hello");
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_SEEK, &[handle, 1]),
+            Outcome::Return(0)
+        );
+        // A length far beyond what the host would allocate.
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_READ, &[handle, DATA, u32::MAX]),
+            Outcome::Return(u32::MAX - 4)
+        );
+        assert_eq!(read_bytes(&ram, DATA, 4).unwrap(), b"ello");
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_CLOSE, &[handle]),
+            Outcome::Return(0)
+        );
+
+        write_bytes(&mut ram, NAME, path.as_bytes()).unwrap();
+        let remove = call(&mut host, &mut ram, SYS_REMOVE, &[NAME, path.len() as u32]);
+        assert_eq!(remove, Outcome::Return(0));
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[test]
+    fn bad_handles() {
+        let mut host = Semihosting::default();
+        let mut ram = Ram(vec![0; 0x2000]);
+        // Writes report every byte as not written.
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_WRITE, &[7, DATA, 3]),
+            Outcome::Return(3)
+        );
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_ERRNO, &[]),
+            Outcome::Return(EBADF as u32)
+        );
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_READ, &[7, DATA, 3]),
+            Outcome::Return(FAILED)
+        );
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_CLOSE, &[7]),
+            Outcome::Return(FAILED)
+        );
+        // The console is opened as ":tt"; "w" gives stdout.
+        let stdout = open(&mut host, &mut ram, CONSOLE, 4);
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_ISTTY, &[stdout]),
+            Outcome::Return(1)
+        );
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_READ, &[stdout, DATA, 3]),
+            Outcome::Return(FAILED)
+        );
+    }
+
+    #[test]
+    fn exit() {
+        let mut host = Semihosting::default();
+        let mut ram = Ram(vec![0; 0x100]);
+        let exit = host.call(SYS_EXIT, ADP_STOPPED_APPLICATION_EXIT, &mut ram);
+        assert_eq!(exit.unwrap(), Outcome::Exit(0));
+        assert_eq!(
+            host.call(SYS_EXIT, 0x20023, &mut ram).unwrap(),
+            Outcome::Exit(1)
+        );
+        let extended = [ADP_STOPPED_APPLICATION_EXIT, 3];
+        assert_eq!(
+            call(&mut host, &mut ram, SYS_EXIT_EXTENDED, &extended),
+            Outcome::Exit(3)
+        );
+    }
+}
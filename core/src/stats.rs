@@ -0,0 +1,196 @@
+//! Execution statistics gathered while the guest runs.
+//!
+//! Counters are cheap enough to keep enabled all the time, so the front ends only decide
+//! whether and how to report them once the guest powers off.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::profiler::Profiler;
+
+/// Coarse instruction classes, keyed by major opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeClass {
+    Lui,
+    Auipc,
+    Jal,
+    Jalr,
+    Branch,
+    Load,
+    Store,
+    Alu,
+    MulDiv,
+    Fence,
+    System,
+    Csr,
+    Atomic,
+    Illegal,
+}
+
+impl OpcodeClass {
+    pub const ALL: [OpcodeClass; 14] = [
+        Self::Lui,
+        Self::Auipc,
+        Self::Jal,
+        Self::Jalr,
+        Self::Branch,
+        Self::Load,
+        Self::Store,
+        Self::Alu,
+        Self::MulDiv,
+        Self::Fence,
+        Self::System,
+        Self::Csr,
+        Self::Atomic,
+        Self::Illegal,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lui => "lui",
+            Self::Auipc => "auipc",
+            Self::Jal => "jal",
+            Self::Jalr => "jalr",
+            Self::Branch => "branch",
+            Self::Load => "load",
+            Self::Store => "store",
+            Self::Alu => "alu",
+            Self::MulDiv => "muldiv",
+            Self::Fence => "fence",
+            Self::System => "system",
+            Self::Csr => "csr",
+            Self::Atomic => "atomic",
+            Self::Illegal => "illegal",
+        }
+    }
+}
+
+/// Number of accesses a guest made to one memory-mapped device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmioCount {
+    pub device: String,
+    /// Where the device is mapped, which tells apart devices of the same name.
+    pub base: u32,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    /// Instructions that completed without raising an exception.
+    pub instructions: u64,
+    /// Time the hart was parked in WFI, as requested from the host `sleep`.
+    pub idle: Duration,
+    /// Traps taken, keyed by the value written to `mcause`.
+    pub traps: BTreeMap<u32, u64>,
+    /// Number of traps that were interrupts rather than exceptions.
+    pub interrupts: u64,
+    /// Instructions fetched per [`OpcodeClass`], indexed by its discriminant.
+    pub opcodes: [u64; OpcodeClass::ALL.len()],
+    /// MMIO accesses per device, as reported by the bus.
+    pub mmio: Vec<MmioCount>,
+    /// PC samples, when the run was profiled.
+    pub profile: Option<Profiler>,
+}
+
+impl Stats {
+    pub(crate) fn record_opcode(&mut self, class: OpcodeClass) {
+        self.opcodes[class as usize] += 1;
+    }
+
+    pub(crate) fn record_trap(&mut self, mcause: u32) {
+        *self.traps.entry(mcause).or_default() += 1;
+        if mcause & 0x8000_0000 != 0 {
+            self.interrupts += 1;
+        }
+    }
+
+    /// Millions of instructions retired per second of wall-clock time.
+    pub fn mips(&self, elapsed: Duration) -> f64 {
+        let secs = elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.instructions as f64 / secs / 1_000_000.0
+    }
+
+    /// Human readable report. `elapsed` is the wall-clock time of the run, which only the
+    /// host can measure.
+    pub fn report(&self, elapsed: Duration) -> String {
+        let mut out = String::new();
+        out += &format!("instructions retired: {}\n", self.instructions);
+        out += &format!("elapsed:              {:.3}s\n", elapsed.as_secs_f64());
+        out += &format!("effective MIPS:       {:.2}\n", self.mips(elapsed));
+        out += &format!("idle (WFI):           {:.3}s\n", self.idle.as_secs_f64());
+        out += &format!("interrupts taken:     {}\n", self.interrupts);
+        out += "traps by mcause:\n";
+        for (mcause, count) in &self.traps {
+            out += &format!("  {mcause:#010x}: {count}\n");
+        }
+        out += "opcode classes:\n";
+        for class in OpcodeClass::ALL {
+            out += &format!("  {:<8} {}\n", class.name(), self.opcodes[class as usize]);
+        }
+        out += "mmio accesses:\n";
+        for m in &self.mmio {
+            out += &format!("  {:<8} reads {} writes {}\n", m.device, m.reads, m.writes);
+        }
+        out
+    }
+
+    /// Same content as [`Stats::report`] as a single JSON object.
+    pub fn to_json(&self, elapsed: Duration) -> String {
+        let traps = self
+            .traps
+            .iter()
+            .map(|(mcause, count)| format!("\"{mcause:#010x}\":{count}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        let opcodes = OpcodeClass::ALL
+            .iter()
+            .map(|c| format!("\"{}\":{}", c.name(), self.opcodes[*c as usize]))
+            .collect::<Vec<_>>()
+            .join(",");
+        // Several devices can share a name, so they are listed rather than keyed by it.
+        let mmio = self
+            .mmio
+            .iter()
+            .map(|m| {
+                format!(
+                    "{{\"device\":{},\"base\":{},\"reads\":{},\"writes\":{}}}",
+                    json_string(&m.device),
+                    m.base,
+                    m.reads,
+                    m.writes
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"instructions\":{},\"elapsed_secs\":{},\"mips\":{},\"idle_secs\":{},\"interrupts\":{},\"traps\":{{{}}},\"opcodes\":{{{}}},\"mmio\":[{}]}}",
+            self.instructions,
+            elapsed.as_secs_f64(),
+            self.mips(elapsed),
+            self.idle.as_secs_f64(),
+            self.interrupts,
+            traps,
+            opcodes,
+            mmio,
+        )
+    }
+}
+
+/// `s` as a JSON string, quoted and escaped.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,360 @@
+use std::collections::VecDeque;
+
+use device_interfaces::SerialInterface;
+
+/// Receiver buffer (read) and transmitter holding register (write).
+const RBR_THR: u32 = 0;
+/// Interrupt enable register.
+const IER: u32 = 1;
+/// Interrupt identification (read) and FIFO control (write) register.
+const IIR_FCR: u32 = 2;
+/// Line control register.
+const LCR: u32 = 3;
+/// Modem control register.
+const MCR: u32 = 4;
+/// Line status register.
+const LSR: u32 = 5;
+/// Modem status register.
+const MSR: u32 = 6;
+/// Scratch register.
+const SCR: u32 = 7;
+
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+const IER_RLSI: u8 = 0x04;
+const IER_MSI: u8 = 0x08;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_MSI: u8 = 0x00;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_RLSI: u8 = 0x06;
+const IIR_CTI: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RCVR: u8 = 0x02;
+
+const LCR_DLAB: u8 = 0x80;
+/// Writing this to LCR exposes the enhanced registers of later chips in place of the
+/// 16550A ones.
+const LCR_CONF_MODE_B: u8 = 0xbf;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_OE: u8 = 0x02;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+const MSR_DCTS: u8 = 0x01;
+const MSR_DDSR: u8 = 0x02;
+const MSR_TERI: u8 = 0x04;
+const MSR_DDCD: u8 = 0x08;
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+
+const FIFO_SIZE: usize = 16;
+/// Receive FIFO levels selected by FCR bits 7:6.
+const TRIGGER_LEVELS: [usize; 4] = [1, 4, 8, 14];
+/// Bus steps between two looks at the host for input. Asking the host is a system call
+/// natively, too slow to do on every instruction.
+const POLL_INTERVAL: u32 = 256;
+
+/// National Semiconductor 16550A UART.
+/// https://www.ti.com/lit/ds/symlink/pc16550d.pdf
+///
+/// Transmission takes no time: characters are handed to the host as soon as they are
+/// written, so the transmitter is always empty.
+#[derive(Debug)]
+pub struct Uart<S> {
+    /// Characters received and not read yet. It holds a single character while the FIFOs
+    /// are disabled.
+    rx: VecDeque<u8>,
+    /// Enables the received data, transmitter empty, line status and modem status
+    /// interrupts, in bits 0 to 3.
+    ier: u8,
+    /// Whether FCR enabled the FIFOs.
+    fifo_enabled: bool,
+    /// Receive FIFO level that raises the received data interrupt.
+    trigger_level: usize,
+    /// Line control register. Its top bit (DLAB) turns registers 0 and 1 into the baud
+    /// rate divisor latches, so it has to be tracked to keep the guest from mistaking a
+    /// divisor for a character.
+    lcr: u8,
+    divisor: u16,
+    /// Modem control register. Bit 4 loops the transmitter back into the receiver.
+    mcr: u8,
+    /// Set when a character arrived while the receiver was full, cleared by reading LSR.
+    overrun: bool,
+    /// Modem status register: line states in the top half and their changes since it was
+    /// last read in the bottom half.
+    msr: u8,
+    scr: u8,
+    /// Whether the transmitter empty interrupt is pending. Reading IIR while it is the one
+    /// reported acknowledges it.
+    thr_pending: bool,
+    /// Whether characters sat below the trigger level for a whole poll interval.
+    timeout_pending: bool,
+    /// Bus steps until the host is polled for input.
+    poll_countdown: u32,
+    serial: S,
+}
+
+impl<S> Uart<S> {
+    pub fn new(serial: S) -> Self {
+        Self {
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fifo_enabled: false,
+            trigger_level: 1,
+            lcr: 0,
+            divisor: 0,
+            mcr: 0,
+            overrun: false,
+            // With no modem attached the lines read as carrier detected, data set ready and
+            // clear to send.
+            msr: MSR_DCD | MSR_DSR | MSR_CTS,
+            scr: 0,
+            thr_pending: false,
+            timeout_pending: false,
+            poll_countdown: POLL_INTERVAL,
+            serial,
+        }
+    }
+
+    pub fn serial(&self) -> &S {
+        &self.serial
+    }
+
+    /// Puts the registers back to their power-on values. Input already received from the
+    /// host is dropped.
+    pub fn reset(&mut self) {
+        self.rx.clear();
+        self.ier = 0;
+        self.fifo_enabled = false;
+        self.trigger_level = 1;
+        self.lcr = 0;
+        self.divisor = 0;
+        self.mcr = 0;
+        self.overrun = false;
+        self.msr = MSR_DCD | MSR_DSR | MSR_CTS;
+        self.scr = 0;
+        self.thr_pending = false;
+        self.timeout_pending = false;
+        self.poll_countdown = POLL_INTERVAL;
+    }
+}
+
+impl<S: SerialInterface> Uart<S> {
+    /// Polls the host for input every [`POLL_INTERVAL`] calls.
+    pub fn step(&mut self) {
+        self.poll_countdown -= 1;
+        if self.poll_countdown == 0 {
+            self.poll_countdown = POLL_INTERVAL;
+            let received = self.receive_from_host();
+            self.timeout_pending = self.fifo_enabled
+                && !received
+                && !self.rx.is_empty()
+                && self.rx.len() < self.trigger_level;
+        }
+    }
+
+    /// Level of the interrupt output.
+    pub fn interrupt(&self) -> bool {
+        self.pending_interrupt() != IIR_NO_INT
+    }
+
+    /// Read register content.
+    pub fn read(&mut self, reg: u32) -> u8 {
+        match reg {
+            RBR_THR if self.dlab() => self.divisor as u8,
+            IER if self.dlab() => (self.divisor >> 8) as u8,
+            RBR_THR => {
+                if self.rx.is_empty() {
+                    self.receive_from_host();
+                }
+                self.timeout_pending = false;
+                self.rx.pop_front().unwrap_or(0)
+            }
+            IER => self.ier,
+            IIR_FCR if self.lcr == LCR_CONF_MODE_B => 0,
+            IIR_FCR => {
+                let iir = self.pending_interrupt();
+                if iir == IIR_THRI {
+                    self.thr_pending = false;
+                }
+                if self.fifo_enabled {
+                    iir | IIR_FIFO_ENABLED
+                } else {
+                    iir
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                if self.rx.is_empty() {
+                    self.receive_from_host();
+                }
+                let lsr = LSR_THRE | LSR_TEMT | if self.rx.is_empty() { 0 } else { LSR_DR };
+                if std::mem::take(&mut self.overrun) {
+                    lsr | LSR_OE
+                } else {
+                    lsr
+                }
+            }
+            MSR => {
+                let msr = self.msr;
+                self.msr &= !(MSR_DCTS | MSR_DDSR | MSR_TERI | MSR_DDCD);
+                msr
+            }
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    /// Write.
+    pub fn write(&mut self, reg: u32, v: u8) {
+        match reg {
+            RBR_THR if self.dlab() => self.divisor = (self.divisor & 0xff00) | v as u16,
+            IER if self.dlab() => self.divisor = (self.divisor & 0x00ff) | ((v as u16) << 8),
+            RBR_THR => {
+                self.transmit(v);
+                self.thr_pending = true;
+            }
+            IER => {
+                // Enabling the interrupt while the transmitter is empty raises it at once.
+                if v & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thr_pending = true;
+                }
+                self.ier = v & 0x0f;
+            }
+            IIR_FCR if self.lcr == LCR_CONF_MODE_B => {}
+            IIR_FCR => {
+                let enable = v & FCR_ENABLE != 0;
+                // Switching the FIFOs on or off empties them.
+                if enable != self.fifo_enabled || v & FCR_CLEAR_RCVR != 0 {
+                    self.rx.clear();
+                    self.timeout_pending = false;
+                }
+                self.fifo_enabled = enable;
+                self.trigger_level = if enable { TRIGGER_LEVELS[(v >> 6) as usize] } else { 1 };
+            }
+            LCR => self.lcr = v,
+            MCR => {
+                self.mcr = v & 0x1f;
+                self.update_msr();
+            }
+            MSR => {}
+            SCR => self.scr = v,
+            _ => {}
+        }
+    }
+
+    /// Sends a character as if it was written to THR.
+    pub fn transmit(&mut self, c: u8) {
+        if self.loopback() {
+            self.push(c);
+        } else {
+            self.serial.write(RBR_THR, c as u32);
+        }
+    }
+
+    /// Takes a received character, bypassing the registers.
+    pub fn receive(&mut self) -> Option<u8> {
+        if self.rx.is_empty() {
+            self.receive_from_host();
+        }
+        self.rx.pop_front()
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr & MCR_LOOP != 0
+    }
+
+    fn capacity(&self) -> usize {
+        if self.fifo_enabled {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// Moves characters the host has ready into the receiver, as far as there is room.
+    /// Returns whether any arrived. The receiver is disconnected from the host in loopback
+    /// mode.
+    fn receive_from_host(&mut self) -> bool {
+        let mut received = false;
+        while !self.loopback()
+            && self.rx.len() < self.capacity()
+            && self.serial.read(LSR) & LSR_DR != 0
+        {
+            self.rx.push_back(self.serial.read(RBR_THR));
+            received = true;
+        }
+        received
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.rx.len() < self.capacity() {
+            self.rx.push_back(c);
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    /// Recomputes the modem lines. In loopback mode they follow the modem control outputs,
+    /// otherwise nothing ever changes them.
+    fn update_msr(&mut self) {
+        let lines = if self.loopback() {
+            let mut lines = 0;
+            for (output, line) in [
+                (MCR_RTS, MSR_CTS),
+                (MCR_DTR, MSR_DSR),
+                (MCR_OUT1, MSR_RI),
+                (MCR_OUT2, MSR_DCD),
+            ] {
+                if self.mcr & output != 0 {
+                    lines |= line;
+                }
+            }
+            lines
+        } else {
+            MSR_DCD | MSR_DSR | MSR_CTS
+        };
+        let changed = (self.msr ^ lines) & 0xf0;
+        let mut delta = changed >> 4;
+        // Only the trailing edge of ring indicator is reported.
+        if lines & MSR_RI != 0 {
+            delta &= !MSR_TERI;
+        }
+        self.msr = lines | (self.msr & 0x0f) | delta;
+    }
+
+    /// Identification of the highest priority pending interrupt, as IIR reports it.
+    fn pending_interrupt(&self) -> u8 {
+        if self.ier & IER_RLSI != 0 && self.overrun {
+            IIR_RLSI
+        } else if self.ier & IER_RDI != 0 && self.rx.len() >= self.trigger_level {
+            IIR_RDI
+        } else if self.ier & IER_RDI != 0 && self.timeout_pending {
+            IIR_CTI
+        } else if self.ier & IER_THRI != 0 && self.thr_pending {
+            IIR_THRI
+        } else if self.ier & IER_MSI != 0 && self.msr & 0x0f != 0 {
+            IIR_MSI
+        } else {
+            IIR_NO_INT
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! virtio-mmio transport.
+//!
+//! [`VirtioMmio`] implements the register interface of a version 2 virtio-mmio device and the
+//! split virtqueues, and hands the buffers the driver makes available to a [`VirtioDevice`].
+//! @See https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+pub mod blk;
+pub mod console;
+pub mod input;
+pub mod net;
+pub mod p9;
+pub mod rng;
+pub mod vsock;
+
+use crate::mmio::{GuestMemory, MmioDevice, Width};
+
+const MAGIC: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+/// Vendor ID reported to the driver. Not a registered one.
+const VENDOR_ID: u32 = 0x5232;
+/// Largest number of descriptors a queue can have.
+const QUEUE_SIZE_MAX: u16 = 256;
+/// Size of the register block, configuration space included.
+const REGION_SIZE: u32 = 0x200;
+
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION_REG: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID_REG: u32 = 0x00c;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+const CONFIG_GENERATION: u32 = 0x0fc;
+const CONFIG: u32 = 0x100;
+
+/// The device follows the virtio 1.x specification rather than the legacy interface.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+
+/// The device used buffers.
+const INTERRUPT_USED_BUFFER: u32 = 1;
+/// The configuration space changed.
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// A device behind the virtio-mmio transport.
+pub trait VirtioDevice {
+    /// Short name used in statistics and error messages.
+    fn name(&self) -> &str;
+    /// Device type, 2 for block devices for example.
+    fn device_id(&self) -> u32;
+    /// Device-specific feature bits. `VIRTIO_F_VERSION_1` is offered by the transport.
+    fn features(&self) -> u64;
+    /// Number of virtqueues.
+    fn queues(&self) -> usize;
+    /// Contents of the configuration space.
+    fn config(&self) -> Vec<u8>;
+    /// Writes `data` to the configuration space at `offset`.
+    fn write_config(&mut self, _offset: u32, _data: &[u8]) {}
+    /// Services the buffers the driver made available on `queue`. Returns whether any
+    /// were used.
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool;
+    /// Called on every tick of the device while the driver is running, with the bus steps
+    /// since the previous tick, for devices that produce data on their own. Returns whether
+    /// buffers were used.
+    fn poll(&mut self, _steps: u32, _queues: &mut [Queue], _memory: &mut GuestMemory) -> bool {
+        false
+    }
+    /// Whether the configuration space changed since the last call.
+    fn config_changed(&mut self) -> bool {
+        false
+    }
+    /// Returns the device to its initial state when the driver resets it.
+    fn reset(&mut self) {}
+}
+
+/// A split virtqueue.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    /// Number of descriptors the driver chose.
+    pub size: u16,
+    pub ready: bool,
+    /// Guest addresses of the descriptor table, the available ring and the used ring.
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    /// Index in the available ring of the next chain to take.
+    next_avail: u16,
+    /// Index in the used ring of the next chain to return.
+    next_used: u16,
+    /// Whether the driver notified the queue since the last tick.
+    notified: bool,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            size: QUEUE_SIZE_MAX,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            next_avail: 0,
+            next_used: 0,
+            notified: false,
+        }
+    }
+}
+
+impl Queue {
+    /// Takes the next descriptor chain the driver made available. Descriptors pointing
+    /// outside RAM end the chain early.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Option<DescriptorChain> {
+        if !self.ready || self.size == 0 {
+            return None;
+        }
+        let avail_idx = memory.read_u16(self.driver + 2)?;
+        if avail_idx == self.next_avail {
+            return None;
+        }
+        let slot = (self.next_avail % self.size) as u64;
+        let head = memory.read_u16(self.driver + 4 + slot * 2)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        let mut chain = DescriptorChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let mut index = head;
+        // A chain cannot be longer than the table, which also stops descriptor loops.
+        for _ in 0..self.size {
+            if index >= self.size {
+                break;
+            }
+            let desc = self.desc + index as u64 * 16;
+            let (Some(addr), Some(len), Some(flags), Some(next)) = (
+                memory.read_u64(desc),
+                memory.read_u32(desc + 8),
+                memory.read_u16(desc + 12),
+                memory.read_u16(desc + 14),
+            ) else {
+                break;
+            };
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else {
+                chain.readable.push((addr, len));
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Some(chain)
+    }
+
+    /// Returns a chain to the driver, `len` being the number of bytes written to it.
+    pub fn push(&mut self, memory: &mut GuestMemory, head: u16, len: u32) {
+        let slot = (self.next_used % self.size) as u64;
+        let mut elem = [0u8; 8];
+        elem[..4].copy_from_slice(&(head as u32).to_le_bytes());
+        elem[4..].copy_from_slice(&len.to_le_bytes());
+        memory.write(self.device + 4 + slot * 8, &elem);
+        self.next_used = self.next_used.wrapping_add(1);
+        memory.write(self.device + 2, &self.next_used.to_le_bytes());
+    }
+}
+
+/// Buffers of one request: the ones the device reads, followed by the ones it writes.
+#[derive(Debug, Clone)]
+pub struct DescriptorChain {
+    pub head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+impl DescriptorChain {
+    /// Concatenated contents of the readable buffers.
+    pub fn read(&self, memory: &GuestMemory) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for (addr, len) in &self.readable {
+            data.extend_from_slice(memory.slice(*addr, *len as usize)?);
+        }
+        Some(data)
+    }
+
+    /// Total size of the writable buffers. The guest picks the lengths, so it can be more
+    /// than 4 GiB.
+    pub fn writable_len(&self) -> u64 {
+        self.writable.iter().map(|(_, len)| *len as u64).sum()
+    }
+
+    /// Fills the writable buffers with `data`, in order. Returns the number of bytes written.
+    pub fn write(&self, memory: &mut GuestMemory, mut data: &[u8]) -> u32 {
+        let mut written = 0;
+        for (addr, len) in &self.writable {
+            let n = data.len().min(*len as usize);
+            if n == 0 || memory.write(*addr, &data[..n]).is_none() {
+                break;
+            }
+            data = &data[n..];
+            written += n as u32;
+        }
+        written
+    }
+}
+
+/// Register interface of a virtio-mmio device.
+pub struct VirtioMmio<D> {
+    device: D,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+    status: u32,
+    config_generation: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> Self {
+        let queues = vec![Queue::default(); device.queues()];
+        Self {
+            device,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+            config_generation: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Features the driver accepted.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.fill(Queue::default());
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+}
+
+/// Replaces the low or high half of a 64-bit register.
+fn set_half(reg: &mut u64, high: bool, v: u32) {
+    *reg = if high {
+        (*reg & 0xffff_ffff) | (v as u64) << 32
+    } else {
+        (*reg & !0xffff_ffff) | v as u64
+    };
+}
+
+impl<D: VirtioDevice> MmioDevice for VirtioMmio<D> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn size(&self) -> u32 {
+        REGION_SIZE
+    }
+
+    fn read(&mut self, offset: u32, width: Width) -> u32 {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            let mut bytes = [0u8; 4];
+            for (i, byte) in bytes.iter_mut().take(width as usize).enumerate() {
+                *byte = config.get(start + i).copied().unwrap_or(0);
+            }
+            return u32::from_le_bytes(bytes);
+        }
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX as u32),
+            QUEUE_READY => queue.is_some_and(|q| q.ready) as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, v: u32, width: Width) {
+        if offset >= CONFIG {
+            let bytes = v.to_le_bytes();
+            self.device
+                .write_config(offset - CONFIG, &bytes[..width as usize]);
+            return;
+        }
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = v,
+            DRIVER_FEATURES => {
+                let features = self.features();
+                match self.driver_features_sel {
+                    0 => set_half(&mut self.driver_features, false, v),
+                    1 => set_half(&mut self.driver_features, true, v),
+                    _ => {}
+                }
+                // Features that were not offered cannot be accepted.
+                self.driver_features &= features;
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = v,
+            QUEUE_SEL => self.queue_sel = v,
+            QUEUE_NUM => {
+                if let Some(queue) = self.queue() {
+                    queue.size = (v as u16).min(QUEUE_SIZE_MAX);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = v & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => {
+                if let Some(queue) = self.queues.get_mut(v as usize) {
+                    queue.notified = true;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !v,
+            STATUS if v == 0 => self.reset(),
+            STATUS => self.status = v,
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.desc, offset == QUEUE_DESC_HIGH, v);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.driver, offset == QUEUE_DRIVER_HIGH, v);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.queue() {
+                    set_half(&mut queue.device, offset == QUEUE_DEVICE_HIGH, v);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, steps: u32, memory: &mut GuestMemory) {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+        let mut used = false;
+        for queue in 0..self.queues.len() {
+            if std::mem::take(&mut self.queues[queue].notified) {
+                used |= self.device.notify(queue, &mut self.queues, memory);
+            }
+        }
+        used |= self.device.poll(steps, &mut self.queues, memory);
+        if used {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        if self.device.config_changed() {
+            self.config_generation = self.config_generation.wrapping_add(1);
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn compatible(&self) -> Option<&str> {
+        Some("virtio,mmio")
+    }
+
+    /// Forgets the queues, so that nothing is written to RAM before the next driver sets
+    /// them up again.
+    fn reset(&mut self) {
+        VirtioMmio::reset(self);
+    }
+}
@@ -0,0 +1,179 @@
+//! virtio-blk device.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 2;
+
+/// The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The device accepts flush requests.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub const SECTOR_SIZE: u64 = 512;
+/// Size of the request header: type, reserved and sector.
+const HEADER_SIZE: usize = 16;
+/// Length of the serial number `GET_ID` returns.
+const ID_SIZE: usize = 20;
+
+/// Backing store of a block device.
+pub trait Disk: Read + Write + Seek {
+    /// Makes the data written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Disk for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl Disk for Cursor<Vec<u8>> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A disk image exposed as a virtio-blk device.
+pub struct Block<D> {
+    disk: D,
+    /// Capacity in 512-byte sectors. A partial sector at the end of the image is left out.
+    sectors: u64,
+    read_only: bool,
+    /// Whether every write is made durable before it completes.
+    sync: bool,
+}
+
+impl<D: Disk> Block<D> {
+    pub fn new(mut disk: D, read_only: bool, sync: bool) -> io::Result<Self> {
+        let len = disk.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            disk,
+            sectors: len / SECTOR_SIZE,
+            read_only,
+            sync,
+        })
+    }
+
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    /// Executes a request and returns its status and the data to hand back before it.
+    fn execute(&mut self, kind: u32, sector: u64, data: &[u8], read_len: usize) -> (u8, Vec<u8>) {
+        let fits = |len: usize| {
+            sector
+                .checked_add((len as u64).div_ceil(SECTOR_SIZE))
+                .is_some_and(|end| end <= self.sectors)
+        };
+        match kind {
+            VIRTIO_BLK_T_IN if fits(read_len) => {
+                let mut buf = vec![0u8; read_len];
+                match self.read_at(sector, &mut buf) {
+                    Ok(()) => (VIRTIO_BLK_S_OK, buf),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, Vec::new()),
+                }
+            }
+            VIRTIO_BLK_T_OUT if !self.read_only && fits(data.len()) => {
+                match self.write_at(sector, data) {
+                    Ok(()) => (VIRTIO_BLK_S_OK, Vec::new()),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, Vec::new()),
+                }
+            }
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => (VIRTIO_BLK_S_IOERR, Vec::new()),
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush().and_then(|_| self.disk.sync()) {
+                Ok(()) => (VIRTIO_BLK_S_OK, Vec::new()),
+                Err(_) => (VIRTIO_BLK_S_IOERR, Vec::new()),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = b"r2-virtio-blk".to_vec();
+                id.resize(ID_SIZE.min(read_len), 0);
+                (VIRTIO_BLK_S_OK, id)
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, Vec::new()),
+        }
+    }
+
+    fn read_at(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.disk.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.disk.read_exact(buf)
+    }
+
+    fn write_at(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.disk.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.disk.write_all(data)?;
+        if self.sync {
+            self.disk.flush()?;
+            self.disk.sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Disk> VirtioDevice for Block<D> {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        if self.read_only {
+            VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO
+        } else {
+            VIRTIO_BLK_F_FLUSH
+        }
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // Only the capacity: no optional fields are offered.
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(memory) {
+            // The last writable byte is the status, the ones before it receive read data.
+            // Requests with more than fits in the used ring's length are failed without one.
+            let Ok(writable_len) = u32::try_from(chain.writable_len()) else {
+                queues[queue].push(memory, chain.head, 0);
+                used = true;
+                continue;
+            };
+            let read_len = (writable_len as usize).saturating_sub(1);
+            let (status, mut reply) = match chain.read(memory) {
+                Some(request) if request.len() >= HEADER_SIZE && writable_len > 0 => {
+                    let kind = u32::from_le_bytes(request[0..4].try_into().unwrap());
+                    let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+                    self.execute(kind, sector, &request[HEADER_SIZE..], read_len)
+                }
+                _ => (VIRTIO_BLK_S_IOERR, Vec::new()),
+            };
+            // The status always goes in the last byte, however much data came before it.
+            reply.resize(read_len, 0);
+            reply.push(status);
+            let written = chain.write(memory, &reply);
+            queues[queue].push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
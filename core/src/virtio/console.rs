@@ -0,0 +1,283 @@
+//! virtio-console device.
+
+use std::collections::VecDeque;
+
+use device_interfaces::ConsolePort;
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 3;
+
+/// The configuration space holds the size of the console.
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+/// The device has more than one port, set up through the control queues.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Bus steps between two looks at the ports for input.
+const POLL_INTERVAL: u32 = 256;
+
+struct Port {
+    backend: Box<dyn ConsolePort>,
+    /// Shown to the guest in `/dev/virtio-ports/`. Port 0 is the console and has none.
+    name: Option<String>,
+    /// Input the guest has no buffers for yet.
+    pending: VecDeque<u8>,
+}
+
+/// A console whose port 0 is `hvc0` in the guest. Further ports are named and appear as
+/// `/dev/vportNpM`, which requires the guest driver to negotiate multiport support.
+pub struct Console {
+    ports: Vec<Port>,
+    /// Columns and rows of port 0.
+    size: Option<(u16, u16)>,
+    size_changed: bool,
+    /// Whether the driver set up the control queues.
+    multiport: bool,
+    /// Control messages the driver has no buffers for yet.
+    control: VecDeque<Vec<u8>>,
+    /// Bus steps until the ports are polled.
+    poll_countdown: u32,
+}
+
+impl Console {
+    pub fn new(console: Box<dyn ConsolePort>) -> Self {
+        let size = console.size();
+        Self {
+            ports: vec![Port {
+                backend: console,
+                name: None,
+                pending: VecDeque::new(),
+            }],
+            size,
+            // The driver reads the size when told the configuration changed.
+            size_changed: size.is_some(),
+            multiport: false,
+            control: VecDeque::new(),
+            poll_countdown: POLL_INTERVAL,
+        }
+    }
+
+    /// Adds a port named `name`.
+    pub fn add_port(&mut self, name: &str, backend: Box<dyn ConsolePort>) {
+        self.ports.push(Port {
+            backend,
+            name: Some(name.to_string()),
+            pending: VecDeque::new(),
+        });
+    }
+
+    /// Port a data queue belongs to. Port 0 has queues 0 and 1, and the control queues come
+    /// before those of the other ports.
+    fn port_of(queue: usize) -> Option<usize> {
+        match queue {
+            0 | 1 => Some(0),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some(queue / 2 - 1),
+        }
+    }
+
+    fn receive_queue(port: usize) -> usize {
+        if port == 0 {
+            0
+        } else {
+            (port + 1) * 2
+        }
+    }
+
+    fn send_control(&mut self, id: usize, event: u16, value: u16, extra: &[u8]) {
+        let mut message = Vec::with_capacity(8 + extra.len());
+        message.extend_from_slice(&(id as u32).to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(extra);
+        self.control.push_back(message);
+    }
+
+    fn send_resize(&mut self) {
+        if let Some((cols, rows)) = self.size {
+            let mut size = rows.to_le_bytes().to_vec();
+            size.extend_from_slice(&cols.to_le_bytes());
+            self.send_control(0, VIRTIO_CONSOLE_RESIZE, 0, &size);
+        }
+    }
+
+    /// Handles the messages the driver sent on the control queue.
+    fn control_tx(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory) {
+            if let Some(message) = chain.read(memory).filter(|m| m.len() >= 8) {
+                let id = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+                let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+                self.handle_control(id, event);
+            }
+            queue.push(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    fn handle_control(&mut self, id: usize, event: u16) {
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                self.multiport = true;
+                for id in 0..self.ports.len() {
+                    self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if id < self.ports.len() => {
+                match self.ports[id].name.clone() {
+                    None => {
+                        self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                        self.send_resize();
+                    }
+                    Some(name) => {
+                        self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 0, name.as_bytes())
+                    }
+                }
+                // The host end is always there.
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            // Whether the guest has the port open does not matter to the host ends.
+            _ => {}
+        }
+    }
+
+    /// Hands pending control messages to the driver.
+    fn control_rx(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while !self.control.is_empty() {
+            let Some(chain) = queue.pop(memory) else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap_or_default();
+            let written = chain.write(memory, &message);
+            queue.push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+
+    fn transmit(&mut self, port: usize, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory) {
+            if let (Some(data), Some(port)) = (chain.read(memory), self.ports.get_mut(port)) {
+                port.backend.write(&data);
+            }
+            queue.push(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    /// Fills the receive buffers of `port` with its pending input.
+    fn receive(&mut self, port: usize, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let Some(port) = self.ports.get_mut(port) else {
+            return false;
+        };
+        let mut used = false;
+        while !port.pending.is_empty() {
+            let Some(chain) = queue.pop(memory) else {
+                break;
+            };
+            let len = (port.pending.len() as u64).min(chain.writable_len()) as usize;
+            let data: Vec<u8> = port.pending.drain(..len).collect();
+            let written = chain.write(memory, &data);
+            queue.push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
+
+impl VirtioDevice for Console {
+    fn name(&self) -> &str {
+        "virtio-console"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        let size = if self.size.is_some() { VIRTIO_CONSOLE_F_SIZE } else { 0 };
+        size | VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn queues(&self) -> usize {
+        // Two for each port, and two control queues.
+        2 * (self.ports.len() + 1)
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let (cols, rows) = self.size.unwrap_or_default();
+        let mut config = cols.to_le_bytes().to_vec();
+        config.extend_from_slice(&rows.to_le_bytes());
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        let used = match (queue, Self::port_of(queue)) {
+            (CONTROL_TX, _) => self.control_tx(&mut queues[CONTROL_TX], memory),
+            (CONTROL_RX, _) => false,
+            // New receive buffers.
+            (queue, Some(port)) if queue % 2 == 0 => self.receive(port, &mut queues[queue], memory),
+            (queue, Some(port)) => self.transmit(port, &mut queues[queue], memory),
+            _ => false,
+        };
+        // Control messages may have been queued, or buffers for them made available.
+        self.control_rx(&mut queues[CONTROL_RX], memory) || used
+    }
+
+    fn poll(&mut self, steps: u32, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        self.poll_countdown = self.poll_countdown.saturating_sub(steps);
+        if self.poll_countdown != 0 {
+            return false;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+
+        if self.ports[0].backend.resized() {
+            self.size = self.ports[0].backend.size().or(self.size);
+            if self.multiport {
+                self.send_resize();
+            } else {
+                self.size_changed = true;
+            }
+        }
+        let mut used = false;
+        for port in 0..self.ports.len() {
+            if self.ports[port].pending.is_empty() {
+                if let Some(input) = self.ports[port].backend.read() {
+                    self.ports[port].pending.extend(input);
+                }
+            }
+            let queue = Self::receive_queue(port);
+            used |= self.receive(port, &mut queues[queue], memory);
+        }
+        self.control_rx(&mut queues[CONTROL_RX], memory) || used
+    }
+
+    fn config_changed(&mut self) -> bool {
+        std::mem::take(&mut self.size_changed)
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+        for port in &mut self.ports {
+            port.pending.clear();
+        }
+    }
+}
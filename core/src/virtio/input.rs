@@ -0,0 +1,287 @@
+//! virtio-input devices: a keyboard and a tablet whose events the guest sees through evdev.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use device_interfaces::{InputEvent, InputSource};
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 18;
+
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+/// Offset of the data in the configuration space, after select, subsel, size and padding.
+const CONFIG_DATA: usize = 8;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0;
+pub const REL_WHEEL: u16 = 0x08;
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+/// Highest key code the keyboard reports, `KEY_MICMUTE`.
+const KEY_LAST: u16 = 0xf8;
+
+/// Largest coordinate of the tablet on both axes. The guest scales it to its screen.
+pub const ABS_MAX: i32 = 0x7fff;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// Bus steps between two looks at the source for events.
+const POLL_INTERVAL: u32 = 256;
+
+/// Most events kept while the guest has no buffers for them. Older ones are dropped.
+const BACKLOG: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Keyboard,
+    Tablet,
+}
+
+/// An input device fed by `S`.
+pub struct Input<S> {
+    kind: Kind,
+    source: S,
+    /// Events the guest has no buffers for yet.
+    pending: VecDeque<InputEvent>,
+    /// What the driver asked to read from the configuration space.
+    select: u8,
+    subsel: u8,
+    /// Bus steps until the source is polled.
+    poll_countdown: u32,
+}
+
+impl<S: InputSource> Input<S> {
+    /// A keyboard with all the keys up to `KEY_MICMUTE`.
+    pub fn keyboard(source: S) -> Self {
+        Self::new(Kind::Keyboard, source)
+    }
+
+    /// An absolute pointer with three buttons and a wheel, ranging from 0 to [`ABS_MAX`].
+    pub fn tablet(source: S) -> Self {
+        Self::new(Kind::Tablet, source)
+    }
+
+    fn new(kind: Kind, source: S) -> Self {
+        Self {
+            kind,
+            source,
+            pending: VecDeque::new(),
+            select: 0,
+            subsel: 0,
+            poll_countdown: POLL_INTERVAL,
+        }
+    }
+
+    /// Answer to the current selection of the configuration space.
+    fn config_data(&self) -> Vec<u8> {
+        let (name, product) = match self.kind {
+            Kind::Keyboard => ("r2 keyboard", 1u16),
+            Kind::Tablet => ("r2 tablet", 2u16),
+        };
+        match (self.select, self.subsel) {
+            (VIRTIO_INPUT_CFG_ID_NAME, 0) => name.as_bytes().to_vec(),
+            (VIRTIO_INPUT_CFG_ID_SERIAL, 0) => b"0".to_vec(),
+            (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => [BUS_VIRTUAL, 0, product, 1]
+                .iter()
+                .flat_map(|id| id.to_le_bytes())
+                .collect(),
+            (VIRTIO_INPUT_CFG_EV_BITS, kind) => match (self.kind, kind as u16) {
+                (Kind::Keyboard, EV_KEY) => bitmap(1..=KEY_LAST),
+                (Kind::Tablet, EV_KEY) => bitmap(BTN_LEFT..=BTN_MIDDLE),
+                (Kind::Tablet, EV_REL) => bitmap(REL_WHEEL..=REL_WHEEL),
+                (Kind::Tablet, EV_ABS) => bitmap(ABS_X..=ABS_Y),
+                _ => Vec::new(),
+            },
+            (VIRTIO_INPUT_CFG_ABS_INFO, axis)
+                if self.kind == Kind::Tablet && matches!(axis as u16, ABS_X | ABS_Y) =>
+            {
+                // Minimum, maximum, fuzz, flat and resolution.
+                [0, ABS_MAX, 0, 0, 0]
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Hands pending events to the driver, one per buffer.
+    fn deliver(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Some(chain) = queue.pop(memory) else {
+                break;
+            };
+            let event = self.pending.pop_front().unwrap();
+            let mut bytes = event.kind.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&event.code.to_le_bytes());
+            bytes.extend_from_slice(&event.value.to_le_bytes());
+            let written = chain.write(memory, &bytes);
+            queue.push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
+
+/// A bitmap with the bits of `codes` set, as long as its last non-zero byte.
+fn bitmap(codes: std::ops::RangeInclusive<u16>) -> Vec<u8> {
+    let mut bitmap = vec![0u8; *codes.end() as usize / 8 + 1];
+    for code in codes {
+        bitmap[code as usize / 8] |= 1 << (code % 8);
+    }
+    bitmap
+}
+
+impl<S: InputSource> VirtioDevice for Input<S> {
+    fn name(&self) -> &str {
+        "virtio-input"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let data = self.config_data();
+        let mut config = vec![0u8; CONFIG_DATA];
+        config[0] = self.select;
+        config[1] = self.subsel;
+        config[2] = data.len() as u8;
+        config.extend_from_slice(&data);
+        config
+    }
+
+    fn write_config(&mut self, offset: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            match offset as usize + i {
+                0 => self.select = *byte,
+                1 => self.subsel = *byte,
+                // The rest is read-only.
+                _ => {}
+            }
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        match queue {
+            EVENT_QUEUE => self.deliver(&mut queues[EVENT_QUEUE], memory),
+            // LED changes; there are no LEDs to light.
+            STATUS_QUEUE => {
+                let mut used = false;
+                while let Some(chain) = queues[STATUS_QUEUE].pop(memory) {
+                    queues[STATUS_QUEUE].push(memory, chain.head, 0);
+                    used = true;
+                }
+                used
+            }
+            _ => false,
+        }
+    }
+
+    fn poll(&mut self, steps: u32, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        self.poll_countdown = self.poll_countdown.saturating_sub(steps);
+        if self.poll_countdown != 0 {
+            return false;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+
+        while let Some(event) = self.source.next_event() {
+            if self.pending.len() == BACKLOG {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(event);
+        }
+        self.deliver(&mut queues[EVENT_QUEUE], memory)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.select = 0;
+        self.subsel = 0;
+    }
+}
+
+/// Host-side handle to an input device: events pushed through any clone of it reach the
+/// device it was given to.
+#[derive(Debug, Clone, Default)]
+pub struct Events(Rc<RefCell<VecDeque<InputEvent>>>);
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, kind: u16, code: u16, value: i32) {
+        self.0
+            .borrow_mut()
+            .push_back(InputEvent { kind, code, value });
+    }
+
+    fn sync(&self) {
+        self.push(EV_SYN, SYN_REPORT, 0);
+    }
+
+    /// Presses or releases the key with the evdev code `code`, e.g. `KEY_A` (30).
+    pub fn key(&self, code: u16, pressed: bool) {
+        self.push(EV_KEY, code, pressed as i32);
+        self.sync();
+    }
+
+    /// Repeats a key held down, as keyboards do after a while.
+    pub fn repeat(&self, code: u16) {
+        self.push(EV_KEY, code, 2);
+        self.sync();
+    }
+
+    /// Presses or releases a button of the tablet, e.g. [`BTN_LEFT`].
+    pub fn button(&self, code: u16, pressed: bool) {
+        self.key(code, pressed);
+    }
+
+    /// Moves the pointer of the tablet, with both coordinates from 0 to [`ABS_MAX`].
+    pub fn move_to(&self, x: i32, y: i32) {
+        self.push(EV_ABS, ABS_X, x.clamp(0, ABS_MAX));
+        self.push(EV_ABS, ABS_Y, y.clamp(0, ABS_MAX));
+        self.sync();
+    }
+
+    /// Turns the wheel by `clicks`, positive away from the user.
+    pub fn wheel(&self, clicks: i32) {
+        self.push(EV_REL, REL_WHEEL, clicks);
+        self.sync();
+    }
+}
+
+impl InputSource for Events {
+    fn next_event(&mut self) -> Option<InputEvent> {
+        self.0.borrow_mut().pop_front()
+    }
+}
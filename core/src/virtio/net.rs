@@ -0,0 +1,136 @@
+//! virtio-net device.
+
+use std::collections::VecDeque;
+
+use device_interfaces::NetworkInterface;
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 1;
+
+/// The device has a MAC address in its configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+/// `virtio_net_hdr` preceding every frame. No offloads are offered, so it is all zeros but
+/// for `num_buffers`.
+const HEADER_SIZE: usize = 12;
+/// Frames taken from the backend while waiting for receive buffers. Further ones are left
+/// with the backend until the guest catches up.
+const BACKLOG: usize = 64;
+/// Bus steps between two looks at the backend for frames.
+const POLL_INTERVAL: u32 = 256;
+
+/// QEMU's default MAC address.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// A network card whose frames go to and come from `N`.
+pub struct Net<N> {
+    backend: N,
+    mac: [u8; 6],
+    /// Frames received from the backend the guest has no buffers for yet.
+    pending: VecDeque<Vec<u8>>,
+    /// Bus steps until the backend is polled.
+    poll_countdown: u32,
+}
+
+impl<N: NetworkInterface> Net<N> {
+    pub fn new(backend: N, mac: [u8; 6]) -> Self {
+        Self {
+            backend,
+            mac,
+            pending: VecDeque::new(),
+            poll_countdown: POLL_INTERVAL,
+        }
+    }
+
+    pub fn backend(&self) -> &N {
+        &self.backend
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory) {
+            if let Some(packet) = chain.read(memory) {
+                if packet.len() > HEADER_SIZE {
+                    self.backend.send(&packet[HEADER_SIZE..]);
+                }
+            }
+            queue.push(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    /// Hands pending frames to the guest for as long as it has buffers.
+    fn receive(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        while self.pending.len() < BACKLOG {
+            let Some(frame) = self.backend.recv() else {
+                break;
+            };
+            self.pending.push_back(frame);
+        }
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Some(chain) = queue.pop(memory) else {
+                break;
+            };
+            let frame = self.pending.pop_front().unwrap_or_default();
+            let mut packet = vec![0u8; HEADER_SIZE];
+            // num_buffers: the frame fits in the one chain.
+            packet[10] = 1;
+            packet.extend_from_slice(&frame);
+            let written = chain.write(memory, &packet);
+            queue.push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
+
+impl<N: NetworkInterface> VirtioDevice for Net<N> {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.mac.to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        match queue {
+            // New receive buffers.
+            RX => self.receive(&mut queues[RX], memory),
+            TX => self.transmit(&mut queues[TX], memory),
+            _ => false,
+        }
+    }
+
+    fn poll(&mut self, steps: u32, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        self.poll_countdown = self.poll_countdown.saturating_sub(steps);
+        if self.poll_countdown != 0 {
+            return false;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+        self.receive(&mut queues[RX], memory)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,66 @@
+//! virtio-9p device.
+
+use device_interfaces::FileServer;
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 9;
+
+/// The configuration space holds the tag the guest mounts the share by.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+/// A directory shared with the guest, served by `S`. The guest mounts it with
+/// `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>`.
+pub struct P9<S> {
+    server: S,
+    tag: String,
+}
+
+impl<S: FileServer> P9<S> {
+    pub fn new(server: S, tag: &str) -> Self {
+        Self {
+            server,
+            tag: tag.to_string(),
+        }
+    }
+}
+
+impl<S: FileServer> VirtioDevice for P9<S> {
+    fn name(&self) -> &str {
+        "virtio-9p"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(memory) {
+            // Replies fit in the buffers of their request, as the driver sized them for the
+            // negotiated message size.
+            let written = match chain.read(memory) {
+                Some(request) => chain.write(memory, &self.server.handle(&request)),
+                None => 0,
+            };
+            queues[queue].push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
@@ -0,0 +1,87 @@
+//! virtio-rng device.
+
+use device_interfaces::EntropySource;
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 4;
+
+/// Most bytes handed out for one request. Drivers ask for far less.
+const MAX_REQUEST: usize = 64 * 1024;
+
+/// An entropy device whose bytes come from `E`.
+pub struct Rng<E> {
+    source: E,
+}
+
+impl<E: EntropySource> Rng<E> {
+    pub fn new(source: E) -> Self {
+        Self { source }
+    }
+}
+
+impl<E: EntropySource> VirtioDevice for Rng<E> {
+    fn name(&self) -> &str {
+        "virtio-rng"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(memory) {
+            let mut bytes = vec![0u8; chain.writable_len().min(MAX_REQUEST as u64) as usize];
+            self.source.fill(&mut bytes);
+            let written = chain.write(memory, &bytes);
+            queues[queue].push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+}
+
+/// Deterministic bytes for reproducible runs: the same seed always gives the same sequence.
+/// Not fit for anything that needs actual secrets.
+/// @See https://prng.di.unimi.it/splitmix64.c
+#[derive(Debug, Clone)]
+pub struct Seeded {
+    state: u64,
+}
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl EntropySource for Seeded {
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! virtio-vsock device.
+//!
+//! Only stream sockets between the guest and the host exist. Every packet carries the credit
+//! of its sender: how much buffer space it has and how much data it has consumed, and
+//! neither side sends more than the other can take.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+
+use device_interfaces::{VsockHost, VsockStream};
+
+use super::{Queue, VirtioDevice};
+use crate::mmio::GuestMemory;
+
+const DEVICE_ID: u32 = 19;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+/// Context ID of the host.
+pub const HOST_CID: u64 = 2;
+/// Context ID given to the guest unless configured otherwise.
+pub const DEFAULT_GUEST_CID: u64 = 3;
+
+const HEADER_SIZE: usize = 44;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The sender will receive no more data.
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+/// The sender will send no more data.
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+/// Buffer space for the data of each connection the host has not taken yet.
+const BUF_ALLOC: u32 = 256 * 1024;
+/// Largest payload of a packet sent to the guest.
+const MAX_PAYLOAD: usize = 4096;
+/// Packets waiting for receive buffers. Host streams are not read beyond this.
+const BACKLOG: usize = 64;
+/// Bus steps between two looks at the host for data and connections.
+const POLL_INTERVAL: u32 = 256;
+/// First port given to the host end of connections the host opens.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    kind: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let u16_at = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        (bytes.len() >= HEADER_SIZE).then(|| Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            kind: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(&self.src_cid.to_le_bytes());
+        bytes.extend_from_slice(&self.dst_cid.to_le_bytes());
+        bytes.extend_from_slice(&self.src_port.to_le_bytes());
+        bytes.extend_from_slice(&self.dst_port.to_le_bytes());
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.kind.to_le_bytes());
+        bytes.extend_from_slice(&self.op.to_le_bytes());
+        bytes.extend_from_slice(&self.flags.to_le_bytes());
+        bytes.extend_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes.extend_from_slice(&self.fwd_cnt.to_le_bytes());
+        bytes
+    }
+}
+
+struct Connection {
+    stream: Box<dyn VsockStream>,
+    /// Opened by the host and not yet accepted by the guest.
+    connecting: bool,
+    /// Credit of the guest.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Bytes sent to the guest.
+    tx_cnt: u32,
+    /// Bytes of guest data handed to the host, and the count the guest last heard of.
+    fwd_cnt: u32,
+    advertised_fwd_cnt: u32,
+    /// Guest data the host has not taken yet.
+    to_host: Vec<u8>,
+    /// The guest will send no more data.
+    guest_done: bool,
+    /// The host end closed, and the guest was told so.
+    host_done: bool,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn VsockStream>, connecting: bool, header: &Header) -> Self {
+        Self {
+            stream,
+            connecting,
+            peer_buf_alloc: header.buf_alloc,
+            peer_fwd_cnt: header.fwd_cnt,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            advertised_fwd_cnt: 0,
+            to_host: Vec::new(),
+            guest_done: false,
+            host_done: false,
+        }
+    }
+
+    /// How much more the guest can take.
+    fn credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    /// Hands guest data to the host as far as it takes it.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.to_host.is_empty() {
+            match self.stream.write(&self.to_host) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.to_host.drain(..len);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(len as u32);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+        if self.guest_done && self.to_host.is_empty() {
+            self.stream.close_write();
+        }
+        Ok(())
+    }
+}
+
+/// A vsock device connecting guest sockets to the streams of `H`. Connections are keyed by
+/// host port, then guest port.
+pub struct Vsock<H> {
+    host: H,
+    guest_cid: u64,
+    connections: HashMap<(u32, u32), Connection>,
+    /// Packets the guest has no receive buffers for yet.
+    pending: VecDeque<Vec<u8>>,
+    /// Host port of the next connection the host opens.
+    next_host_port: u32,
+    /// Bus steps until the host is polled.
+    poll_countdown: u32,
+}
+
+impl<H: VsockHost> Vsock<H> {
+    pub fn new(host: H, guest_cid: u64) -> Self {
+        Self {
+            host,
+            guest_cid,
+            connections: HashMap::new(),
+            pending: VecDeque::new(),
+            next_host_port: FIRST_HOST_PORT,
+            poll_countdown: POLL_INTERVAL,
+        }
+    }
+
+    /// Queues a packet for the guest on connection `key`, with the credit of its host end.
+    fn send(&mut self, key: (u32, u32), op: u16, flags: u32, data: &[u8]) {
+        let fwd_cnt = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                connection.advertised_fwd_cnt = connection.fwd_cnt;
+                connection.tx_cnt = connection.tx_cnt.wrapping_add(data.len() as u32);
+                connection.fwd_cnt
+            }
+            None => 0,
+        };
+        let header = Header {
+            src_cid: HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.0,
+            dst_port: key.1,
+            len: data.len() as u32,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt,
+        };
+        let mut packet = header.to_bytes();
+        packet.extend_from_slice(data);
+        self.pending.push_back(packet);
+    }
+
+    fn reset(&mut self, key: (u32, u32)) {
+        self.connections.remove(&key);
+        self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+    }
+
+    /// Handles a packet from the guest.
+    fn handle(&mut self, header: Header, data: &[u8]) {
+        let key = (header.dst_port, header.src_port);
+        if header.op == VIRTIO_VSOCK_OP_RST {
+            self.connections.remove(&key);
+            return;
+        }
+        // Only streams to the host exist.
+        if header.dst_cid != HOST_CID
+            || header.src_cid != self.guest_cid
+            || header.kind != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+            return;
+        }
+        if header.op == VIRTIO_VSOCK_OP_REQUEST {
+            let stream = match self.connections.contains_key(&key) {
+                true => None,
+                false => self.host.connect(header.dst_port),
+            };
+            match stream {
+                Some(stream) => {
+                    let connection = Connection::new(stream, false, &header);
+                    self.connections.insert(key, connection);
+                    self.send(key, VIRTIO_VSOCK_OP_RESPONSE, 0, &[]);
+                }
+                None => self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]),
+            }
+            return;
+        }
+        let Some(connection) = self.connections.get_mut(&key) else {
+            self.send(key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+            return;
+        };
+        connection.peer_buf_alloc = header.buf_alloc;
+        connection.peer_fwd_cnt = header.fwd_cnt;
+        match header.op {
+            VIRTIO_VSOCK_OP_RESPONSE if connection.connecting => {
+                connection.connecting = false;
+                connection.stream.established(key.0);
+            }
+            VIRTIO_VSOCK_OP_RW => {
+                // More than the credit given means a broken driver.
+                if connection.to_host.len() + data.len() > BUF_ALLOC as usize {
+                    self.reset(key);
+                    return;
+                }
+                connection.to_host.extend_from_slice(data);
+                if connection.flush().is_err() {
+                    self.reset(key);
+                }
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                let both = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+                if header.flags & both == both {
+                    // Closed: the guest waits for the reset.
+                    self.reset(key);
+                } else if header.flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+                    connection.guest_done = true;
+                    if connection.flush().is_err() {
+                        self.reset(key);
+                    }
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => self.send(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]),
+            // Credit updates only carry what was already taken from the header.
+            _ => {}
+        }
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory) {
+            if let Some(packet) = chain.read(memory) {
+                if let Some(header) = Header::parse(&packet) {
+                    let end = (HEADER_SIZE + header.len as usize).min(packet.len());
+                    self.handle(header, &packet[HEADER_SIZE..end]);
+                }
+            }
+            queue.push(memory, chain.head, 0);
+            used = true;
+        }
+        used
+    }
+
+    /// Hands pending packets to the guest for as long as it has buffers.
+    fn receive(&mut self, queue: &mut Queue, memory: &mut GuestMemory) -> bool {
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let Some(chain) = queue.pop(memory) else {
+                break;
+            };
+            let packet = self.pending.pop_front().unwrap_or_default();
+            let written = chain.write(memory, &packet);
+            queue.push(memory, chain.head, written);
+            used = true;
+        }
+        used
+    }
+
+    /// Takes new connections and data from the host.
+    fn poll_host(&mut self) {
+        while let Some((port, stream)) = self.host.accept() {
+            let key = (self.next_host_port, port);
+            self.next_host_port = self.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+            self.connections
+                .insert(key, Connection::new(stream, true, &Header::default()));
+            self.send(key, VIRTIO_VSOCK_OP_REQUEST, 0, &[]);
+        }
+
+        let keys: Vec<_> = self.connections.keys().copied().collect();
+        let mut buffer = [0u8; MAX_PAYLOAD];
+        for key in keys {
+            let Some(connection) = self.connections.get_mut(&key) else {
+                continue;
+            };
+            if connection.flush().is_err() {
+                self.reset(key);
+                continue;
+            }
+            // The guest knows how much was consumed once it has half of its buffer back.
+            if connection
+                .fwd_cnt
+                .wrapping_sub(connection.advertised_fwd_cnt)
+                >= BUF_ALLOC / 2
+            {
+                self.send(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+            }
+            while self.pending.len() < BACKLOG {
+                let Some(connection) = self.connections.get_mut(&key) else {
+                    break;
+                };
+                let len = (connection.credit() as usize).min(MAX_PAYLOAD);
+                if connection.connecting || connection.host_done || len == 0 {
+                    break;
+                }
+                match connection.stream.read(&mut buffer[..len]) {
+                    Ok(0) => {
+                        connection.host_done = true;
+                        let flags = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+                        self.send(key, VIRTIO_VSOCK_OP_SHUTDOWN, flags, &[]);
+                    }
+                    Ok(len) => self.send(key, VIRTIO_VSOCK_OP_RW, 0, &buffer[..len]),
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => self.reset(key),
+                }
+            }
+        }
+    }
+}
+
+impl<H: VsockHost> VirtioDevice for Vsock<H> {
+    fn name(&self) -> &str {
+        "virtio-vsock"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        // Receive, transmit, and events, of which none are sent.
+        3
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.guest_cid.to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        let used = match queue {
+            TX => self.transmit(&mut queues[TX], memory),
+            _ => false,
+        };
+        // Replies, or new receive buffers for what was waiting.
+        self.receive(&mut queues[RX], memory) || used
+    }
+
+    fn poll(&mut self, steps: u32, queues: &mut [Queue], memory: &mut GuestMemory) -> bool {
+        self.poll_countdown = self.poll_countdown.saturating_sub(steps);
+        if self.poll_countdown != 0 {
+            return false;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+        self.poll_host();
+        self.receive(&mut queues[RX], memory)
+    }
+
+    fn reset(&mut self) {
+        self.connections.clear();
+        self.pending.clear();
+    }
+}
@@ -1,5 +1,2050 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn baremetal() {}
+//! Tests of the emulator core: hand-assembled programs, the devices on the bus and the ISA
+//! compliance suites.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use core::{
+    bus::{Bus, Layout, Region, FRAMEBUFFER, PLIC, RAM_START, RTC, RTC_IRQ, UART, UART_IRQ},
+    bus_interface::{BusController, BusReader, BusWriter},
+    clint::Clint,
+    cpu::{Cpu, Fault},
+    elf::{Elf, ElfError, Segment, Symbols},
+    fdt::{DeviceTree, FdtError, Node, Reservation},
+    framebuffer::{Framebuffer, FramebufferError},
+    htif::Htif,
+    machine::{ExitReason, Machine},
+    mmio::{MapError, MmioDevice, Width},
+    overlay::{self, Overlay},
+    rtc::{self, Rtc},
+    stats::{MmioCount, Stats},
+    virtio::{
+        blk::Block,
+        console::Console,
+        input::{Events, Input},
+        net::{Net, DEFAULT_MAC},
+        p9::P9,
+        rng::{Rng, Seeded},
+        vsock::Vsock,
+        VirtioMmio,
+    },
+    Options,
+};
+
+const RAM_SIZE: usize = 16 * 1024 * 1024;
+const MAX_STEPS: u64 = 10_000_000;
+
+struct StoppedTimer;
+
+impl device_interfaces::TimerDriver for StoppedTimer {
+    fn as_micros(&self) -> u64 {
+        0
+    }
+}
+
+struct NoSerial;
+
+impl device_interfaces::SerialInterface for NoSerial {
+    fn read(&self, _addr: u32) -> u8 {
+        0
+    }
+
+    fn write(&self, _addr: u32, _v: u32) {}
+}
+
+type TestBus = Bus<StoppedTimer, NoSerial>;
+
+/// Runs until the program reports through HTIF and returns its exit code, or `None` if it
+/// did not finish within [`MAX_STEPS`].
+fn run(cpu: &mut Cpu<TestBus>) -> Option<u32> {
+    for _ in 0..MAX_STEPS {
+        cpu.step();
+        if let Some(code) = cpu.exit_code() {
+            return Some(code);
+        }
+    }
+    None
+}
+
+fn program(words: &[u32], tohost: u32) -> Cpu<TestBus> {
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let mut bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    bus.attach_htif(Htif::new(tohost, None));
+    let mut cpu = Cpu::new(bus);
+    cpu.pc(RAM_START);
+    cpu
+}
+
+/// A program passing through HTIF ends with exit code 0.
+#[test]
+fn htif_pass() {
+    let mut cpu = program(
+        &[
+            0x800012b7, // lui t0, 0x80001
+            0x00100193, // li gp, 1
+            0x0032a023, // sw gp, 0(t0)
+            0x0002a223, // sw zero, 4(t0)
+            0x0000006f, // j .
+        ],
+        0x8000_1000,
+    );
+    assert_eq!(run(&mut cpu), Some(0));
+}
+
+/// A program failing through HTIF ends with its exit code.
+#[test]
+fn htif_fail_code() {
+    let mut cpu = program(
+        &[
+            0x800012b7, // lui t0, 0x80001
+            0x00700193, // li gp, 7
+            0x0032a023, // sw gp, 0(t0)
+            0x0002a223, // sw zero, 4(t0)
+            0x0000006f, // j .
+        ],
+        0x8000_1000,
+    );
+    assert_eq!(run(&mut cpu), Some(3));
+}
+
+/// A failure written to the `sifive,test` finisher ends the run with its code.
+#[test]
+fn syscon_fail_code() {
+    let words = [
+        0x111002b7u32, // lui t0, 0x11100
+        0x000731b7,    // lui gp, 0x73
+        0x33318193,    // addi gp, gp, 0x333
+        0x0032a023,    // sw gp, 0(t0)
+        0x0000006f,    // j .
+    ];
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    let exit = core::start(bus, RAM_START, 0, Default::default(), &|_| {});
+    assert_eq!(exit.code, 7);
+}
+
+/// Only the low half of a syscon write says what to do, as on a `sifive,test`.
+#[test]
+fn syscon_status() {
+    let syscon = Layout::default().syscon.base;
+    let bus = || Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let mut off = bus();
+    off.write32(syscon, 0x0001_5555).unwrap();
+    assert!(off.power_off && !off.reboot);
+    let mut reboot = bus();
+    reboot.write32(syscon, 0x0002_7777).unwrap();
+    assert!(reboot.reboot && !reboot.power_off);
+    let mut other = bus();
+    other.write32(syscon, 0x5555_0000).unwrap();
+    other.write32(syscon, 0x0000_5556).unwrap();
+    assert!(!other.power_off && !other.reboot);
+}
+
+/// A [`Machine`] stops at breakpoints, when its budget runs out, when the hart waits for an
+/// interrupt and when it gets stuck.
+#[test]
+fn machine_exit_reasons() {
+    let words = [
+        0x00300293u32, // li t0, 3
+        0xfff28293,    // loop: addi t0, t0, -1
+        0xfe029ee3,    // bnez t0, loop
+        0x08000313,    // li t1, 0x80
+        0x30431073,    // csrw mie, t1
+        0x10500073,    // wfi
+        0x00000067,    // jr zero
+    ];
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    let mut machine = Machine::new(bus, RAM_START, 0, Default::default());
+    assert_eq!(
+        machine.run_until(Instant::now()),
+        ExitReason::BudgetExhausted
+    );
+
+    let looped = RAM_START + 4;
+    machine.add_breakpoint(looped);
+    assert_eq!(machine.run_for(100), ExitReason::Breakpoint(looped));
+    assert_eq!(machine.stats().instructions, 1);
+    assert_eq!(machine.run_for(100), ExitReason::Breakpoint(looped));
+    assert_eq!(machine.stats().instructions, 3);
+    machine.remove_breakpoint(looped);
+    assert_eq!(machine.run_for(1), ExitReason::BudgetExhausted);
+
+    // The hart sleeps in WFI until the host raises a timer interrupt.
+    assert_eq!(machine.run_for(100), ExitReason::Idle);
+    assert_eq!(machine.run_for(100), ExitReason::Idle);
+    machine.bus_mut().clint.mtimecmp = 1;
+    machine.bus_mut().clint.mtime = 1;
+
+    // Nothing is mapped at 0, where the trap vector points too.
+    let fault = Fault {
+        cause: 1,
+        pc: 0,
+        tval: 0,
+    };
+    assert_eq!(machine.run_for(100), ExitReason::Fault(fault));
+    assert_eq!(machine.cpu().fault(), Some(fault));
+}
+
+/// A power-off requested while the hart is parked in WFI is reported as such.
+#[test]
+fn machine_power_off_while_idle() {
+    let words = [
+        0x08000313u32, // li t1, 0x80
+        0x30431073,    // csrw mie, t1
+        0x10500073,    // wfi
+        0x0000006f,    // j .
+    ];
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    let mut machine = Machine::new(bus, RAM_START, 0, Default::default());
+    assert_eq!(machine.run_for(100), ExitReason::Idle);
+    machine.bus_mut().power_off = true;
+    assert_eq!(machine.run_for(100), ExitReason::PowerOff(0));
+}
+
+/// A machine running `words` from the start of RAM.
+fn boot(words: &[u32], options: Options) -> Machine<TestBus> {
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    Machine::new(bus, RAM_START, 0, options)
+}
+
+/// The `len` words the program logged at `addr`.
+fn logged(bus: &TestBus, addr: u32, len: u32) -> Vec<u32> {
+    (0..len)
+        .map(|i| bus.read32(addr + i * 4).unwrap())
+        .collect()
+}
+
+/// S-mode and U-mode code cannot reach the CSRs and returns of more privileged modes, and
+/// nobody writes read-only CSRs.
+#[test]
+fn csr_privilege() {
+    // The M-mode handler logs mcause and mtval, skips the trapping instruction and powers
+    // off on the U-mode ecall.
+    let words = [
+        0x00000297u32, // auipc t0, 0
+        0x06428293,    // addi t0, t0, 0x64 (handler)
+        0x30529073,    // csrw mtvec, t0
+        0x80001437,    // lui s0, 0x80001
+        0x000012b7,    // lui t0, 1
+        0x80028293,    // addi t0, t0, -0x800
+        0x30029073,    // csrw mstatus, t0
+        0x00000297,    // auipc t0, 0
+        0x01028293,    // addi t0, t0, 0x10 (smode)
+        0x34129073,    // csrw mepc, t0
+        0x30200073,    // mret
+        0x34002573,    // smode: csrr a0, mscratch
+        0xc0051073,    // csrw cycle, a0
+        0xc00025f3,    // rdcycle a1
+        0x14059073,    // csrw sscratch, a1
+        0x30200073,    // mret
+        0x10000293,    // li t0, 0x100
+        0x1002b073,    // csrc sstatus, t0
+        0x00000297,    // auipc t0, 0
+        0x01028293,    // addi t0, t0, 0x10 (umode)
+        0x14129073,    // csrw sepc, t0
+        0x10200073,    // sret
+        0x14002573,    // umode: csrr a0, sscratch
+        0x10200073,    // sret
+        0x00000073,    // ecall
+        0x342022f3,    // handler: csrr t0, mcause
+        0x00542023,    // sw t0, 0(s0)
+        0x34302373,    // csrr t1, mtval
+        0x00642223,    // sw t1, 4(s0)
+        0x00840413,    // addi s0, s0, 8
+        0x00800313,    // li t1, 8
+        0x00628a63,    // beq t0, t1, off
+        0x341022f3,    // csrr t0, mepc
+        0x00428293,    // addi t0, t0, 4
+        0x34129073,    // csrw mepc, t0
+        0x30200073,    // mret
+        0x111002b7,    // off: lui t0, 0x11100
+        0x00005337,    // lui t1, 5
+        0x55530313,    // addi t1, t1, 0x555
+        0x0062a023,    // sw t1, 0(t0)
+        0x0000006f,    // j .
+    ];
+    let mut machine = boot(&words, Options::default());
+    assert_eq!(machine.run_for(1000), ExitReason::PowerOff(0));
+    let illegal = 2;
+    assert_eq!(
+        logged(machine.bus(), 0x8000_1000, 12),
+        [
+            illegal, 0x34002573, illegal, 0xc0051073, illegal, 0x30200073, // S-mode
+            illegal, 0x14002573, illegal, 0x10200073, 8, 0, // U-mode
+        ]
+    );
+}
+
+/// Division by zero and the signed overflow give the results the M extension specifies
+/// instead of trapping.
+#[test]
+fn divide_edge_cases() {
+    let words = [
+        0xff900513u32, // li a0, -7
+        0x80001437,    // lui s0, 0x80001
+        0x020542b3,    // div t0, a0, zero
+        0x02055333,    // divu t1, a0, zero
+        0x020563b3,    // rem t2, a0, zero
+        0x02057e33,    // remu t3, a0, zero
+        0x800006b7,    // lui a3, 0x80000
+        0xfff00713,    // li a4, -1
+        0x02e6ceb3,    // div t4, a3, a4
+        0x02e6ef33,    // rem t5, a3, a4
+        0x00542023,    // sw t0, 0(s0)
+        0x00642223,    // sw t1, 4(s0)
+        0x00742423,    // sw t2, 8(s0)
+        0x01c42623,    // sw t3, 12(s0)
+        0x01d42823,    // sw t4, 16(s0)
+        0x01e42a23,    // sw t5, 20(s0)
+        0x0000006f,    // j .
+    ];
+    let mut machine = boot(&words, Options::default());
+    assert_eq!(machine.run_for(100), ExitReason::BudgetExhausted);
+    // Dividing by zero gives all ones and the remainder is the dividend.
+    let minus_seven = -7i32 as u32;
+    assert_eq!(
+        logged(machine.bus(), 0x8000_1000, 6),
+        [u32::MAX, u32::MAX, minus_seven, minus_seven, 0x8000_0000, 0]
+    );
+}
+
+/// Sv32 translation through 4 KiB pages and a superpage, with permission checks and the
+/// accessed and dirty bits kept up to date.
+#[test]
+fn sv32_translation() {
+    // S-mode code runs from an identity-mapped superpage and logs what it read through
+    // two pages sharing a frame, one of them read-only. The M-mode handler logs mcause
+    // and mtval, skips the faulting instruction and powers off on the ecall.
+    let words = [
+        0x00000297u32, // auipc t0, 0
+        0x06c28293,    // addi t0, t0, 0x6c (handler)
+        0x30529073,    // csrw mtvec, t0
+        0x80004437,    // lui s0, 0x80004
+        0x800802b7,    // lui t0, 0x80080
+        0x00128293,    // addi t0, t0, 1
+        0x18029073,    // csrw satp, t0
+        0x000012b7,    // lui t0, 1
+        0x80028293,    // addi t0, t0, -0x800
+        0x30029073,    // csrw mstatus, t0
+        0x00000297,    // auipc t0, 0
+        0x01028293,    // addi t0, t0, 0x10 (smode)
+        0x34129073,    // csrw mepc, t0
+        0x30200073,    // mret
+        0x40000537,    // smode: lui a0, 0x40000
+        0x12300593,    // li a1, 0x123
+        0x00b52023,    // sw a1, 0(a0)
+        0x400016b7,    // lui a3, 0x40001
+        0x0006a603,    // lw a2, 0(a3)
+        0x00c42023,    // sw a2, 0(s0)
+        0x00440413,    // addi s0, s0, 4
+        0x00b6a023,    // sw a1, 0(a3)
+        0x40002737,    // lui a4, 0x40002
+        0x00072783,    // lw a5, 0(a4)
+        0x50000737,    // lui a4, 0x50000
+        0x00072783,    // lw a5, 0(a4)
+        0x00000073,    // ecall
+        0x342022f3,    // handler: csrr t0, mcause
+        0x00542023,    // sw t0, 0(s0)
+        0x34302373,    // csrr t1, mtval
+        0x00642223,    // sw t1, 4(s0)
+        0x00840413,    // addi s0, s0, 8
+        0x00900313,    // li t1, 9
+        0x00628a63,    // beq t0, t1, off
+        0x341022f3,    // csrr t0, mepc
+        0x00428293,    // addi t0, t0, 4
+        0x34129073,    // csrw mepc, t0
+        0x30200073,    // mret
+        0x111002b7,    // off: lui t0, 0x11100
+        0x00005337,    // lui t1, 5
+        0x55530313,    // addi t1, t1, 0x555
+        0x0062a023,    // sw t1, 0(t0)
+        0x0000006f,    // j .
+    ];
+    let mut machine = boot(&words, Options::default());
+    let (v, r, w, x, u, a, d) = (1, 2, 4, 8, 0x10, 0x40, 0x80);
+    let pte = |addr: u32, flags: u32| (addr >> 12) << 10 | flags;
+    let page_tables = [
+        (
+            0x8000_1000 + 0x200 * 4,
+            pte(RAM_START, v | r | w | x | a | d),
+        ),
+        (0x8000_1000 + 0x100 * 4, pte(0x8000_2000, v)),
+        (0x8000_2000, pte(0x8000_3000, v | r | w)),
+        (0x8000_2004, pte(0x8000_3000, v | r | a)),
+        (0x8000_2008, pte(0x8000_3000, v | r | w | u | a | d)),
+    ];
+    for (addr, pte) in page_tables {
+        machine.bus_mut().write32(addr, pte).unwrap();
+    }
+    assert_eq!(machine.run_for(1000), ExitReason::PowerOff(0));
+    let (load_fault, store_fault) = (13, 15);
+    assert_eq!(
+        logged(machine.bus(), 0x8000_4000, 9),
+        [
+            0x123,
+            store_fault,
+            0x4000_1000,
+            load_fault, // S-mode without SUM cannot read user pages.
+            0x4000_2000,
+            load_fault,
+            0x5000_0000,
+            9,
+            0,
+        ]
+    );
+    assert_eq!(
+        machine.bus().read32(0x8000_2000).unwrap(),
+        pte(0x8000_3000, v | r | w | a | d)
+    );
+}
+
+/// Delegated exceptions and interrupts go to S-mode, except those raised in M-mode.
+#[test]
+fn trap_delegation() {
+    // Illegal instructions and the supervisor timer interrupt are delegated. Both handlers
+    // log the cause and the mode they run in; the M-mode one raises the timer interrupt
+    // on the S-mode ecall and the S-mode one powers off when it takes it.
+    let words = [
+        0x00000297u32, // auipc t0, 0
+        0x06028293,    // addi t0, t0, 0x60 (mhandler)
+        0x30529073,    // csrw mtvec, t0
+        0x00000297,    // auipc t0, 0
+        0x08828293,    // addi t0, t0, 0x88 (shandler)
+        0x10529073,    // csrw stvec, t0
+        0x00400293,    // li t0, 4
+        0x30229073,    // csrw medeleg, t0
+        0x02000293,    // li t0, 0x20
+        0x30329073,    // csrw mideleg, t0
+        0x30429073,    // csrw mie, t0
+        0x80001437,    // lui s0, 0x80001
+        0xc0001073,    // unimp
+        0x000012b7,    // lui t0, 1
+        0x80028293,    // addi t0, t0, -0x800
+        0x30029073,    // csrw mstatus, t0
+        0x00000297,    // auipc t0, 0
+        0x01028293,    // addi t0, t0, 0x10 (smode)
+        0x34129073,    // csrw mepc, t0
+        0x30200073,    // mret
+        0xc0001073,    // smode: unimp
+        0x00000073,    // ecall
+        0x10016073,    // csrsi sstatus, 2
+        0x0000006f,    // j .
+        0x342022f3,    // mhandler: csrr t0, mcause
+        0x00542023,    // sw t0, 0(s0)
+        0x04d00313,    // li t1, 'M'
+        0x00642223,    // sw t1, 4(s0)
+        0x00840413,    // addi s0, s0, 8
+        0x00900313,    // li t1, 9
+        0x00629663,    // bne t0, t1, mskip
+        0x02000313,    // li t1, 0x20
+        0x34432073,    // csrs mip, t1
+        0x341022f3,    // mskip: csrr t0, mepc
+        0x00428293,    // addi t0, t0, 4
+        0x34129073,    // csrw mepc, t0
+        0x30200073,    // mret
+        0x142022f3,    // shandler: csrr t0, scause
+        0x00542023,    // sw t0, 0(s0)
+        0x05300313,    // li t1, 'S'
+        0x00642223,    // sw t1, 4(s0)
+        0x00840413,    // addi s0, s0, 8
+        0x0002ca63,    // bltz t0, off
+        0x141022f3,    // csrr t0, sepc
+        0x00428293,    // addi t0, t0, 4
+        0x14129073,    // csrw sepc, t0
+        0x10200073,    // sret
+        0x111002b7,    // off: lui t0, 0x11100
+        0x00005337,    // lui t1, 5
+        0x55530313,    // addi t1, t1, 0x555
+        0x0062a023,    // sw t1, 0(t0)
+        0x0000006f,    // j .
+    ];
+    let mut machine = boot(&words, Options::default());
+    assert_eq!(machine.run_for(1000), ExitReason::PowerOff(0));
+    let (m, s) = (b'M' as u32, b'S' as u32);
+    assert_eq!(
+        logged(machine.bus(), 0x8000_1000, 8),
+        [2, m, 2, s, 9, m, 0x8000_0005, s]
+    );
+}
+
+/// With `--sbi`, S-mode ecalls reach the built-in SBI implementation.
+#[test]
+fn sbi_calls() {
+    // Logs the base extension's answers, the hart status and an unknown extension's
+    // error, then sets a timer that has expired already and shuts down once it fires.
+    let words = [
+        0x80001437u32, // lui s0, 0x80001
+        0x01000893,    // li a7, 0x10
+        0x00000813,    // li a6, 0
+        0x00000073,    // ecall
+        0x00a42023,    // sw a0, 0(s0)
+        0x00b42223,    // sw a1, 4(s0)
+        0x00300813,    // li a6, 3
+        0x54495537,    // lui a0, 0x54495
+        0xd4550513,    // addi a0, a0, -0x2bb
+        0x00000073,    // ecall
+        0x00a42423,    // sw a0, 8(s0)
+        0x00b42623,    // sw a1, 12(s0)
+        0x12345537,    // lui a0, 0x12345
+        0x67850513,    // addi a0, a0, 0x678
+        0x00000073,    // ecall
+        0x00a42823,    // sw a0, 16(s0)
+        0x00b42a23,    // sw a1, 20(s0)
+        0x004858b7,    // lui a7, 0x485
+        0x34d88893,    // addi a7, a7, 0x34d
+        0x00200813,    // li a6, 2
+        0x00000513,    // li a0, 0
+        0x00000073,    // ecall
+        0x00a42c23,    // sw a0, 24(s0)
+        0x00b42e23,    // sw a1, 28(s0)
+        0x0a0008b7,    // lui a7, 0xa000
+        0x00000073,    // ecall
+        0x02a42023,    // sw a0, 32(s0)
+        0x00000297,    // auipc t0, 0
+        0x03428293,    // addi t0, t0, 0x34 (shandler)
+        0x10529073,    // csrw stvec, t0
+        0x02000293,    // li t0, 0x20
+        0x10429073,    // csrw sie, t0
+        0x544958b7,    // lui a7, 0x54495
+        0xd4588893,    // addi a7, a7, -0x2bb
+        0x00000813,    // li a6, 0
+        0x00000513,    // li a0, 0
+        0x00000593,    // li a1, 0
+        0x00000073,    // ecall
+        0x10016073,    // csrsi sstatus, 2
+        0x0000006f,    // j .
+        0x142022f3,    // shandler: csrr t0, scause
+        0x02542223,    // sw t0, 36(s0)
+        0x535258b7,    // lui a7, 0x53525
+        0x35488893,    // addi a7, a7, 0x354
+        0x00000813,    // li a6, 0
+        0x00000513,    // li a0, 0
+        0x00000593,    // li a1, 0
+        0x00000073,    // ecall
+        0x0000006f,    // j .
+    ];
+    let options = Options {
+        sbi: true,
+        ..Default::default()
+    };
+    let mut machine = boot(&words, options);
+    assert_eq!(machine.run_for(1000), ExitReason::PowerOff(0));
+    let not_supported = -2i32 as u32;
+    assert_eq!(
+        logged(machine.bus(), 0x8000_1000, 10),
+        [
+            0,
+            0x0200_0000, // Version 2.0 of the specification.
+            0,
+            1, // The TIME extension is there.
+            0,
+            0, // This one is not.
+            0,
+            0, // Hart 0 is started.
+            not_supported,
+            0x8000_0005,
+        ]
+    );
+}
+
+/// Counts the resets it went through and reads back the count.
+struct Boots(Rc<Cell<u32>>);
+
+impl MmioDevice for Boots {
+    fn name(&self) -> &str {
+        "boots"
+    }
+
+    fn size(&self) -> u32 {
+        0x1000
+    }
+
+    fn read(&mut self, _offset: u32, _width: Width) -> u32 {
+        self.0.get()
+    }
+
+    fn write(&mut self, _offset: u32, _v: u32, _width: Width) {}
+
+    fn reset(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+/// A reboot resets the devices and restores RAM before booting again.
+#[test]
+fn syscon_reboot() {
+    // Reboots on the first boot and powers off on the second. A flag it leaves in RAM
+    // fails the run if it is still there after the reboot.
+    let words = [
+        0x00000297u32, // auipc t0, 0
+        0x1002a303,    // lw t1, 0x100(t0)
+        0x02031e63,    // bnez t1, fail
+        0x00100313,    // li t1, 1
+        0x1062a023,    // sw t1, 0x100(t0)
+        0x200003b7,    // lui t2, 0x20000
+        0x0003ae03,    // lw t3, 0(t2)
+        0x11100eb7,    // lui t4, 0x11100
+        0x000e1a63,    // bnez t3, off
+        0x00007f37,    // lui t5, 0x7
+        0x777f0f13,    // addi t5, t5, 0x777
+        0x01eea023,    // sw t5, 0(t4)
+        0x0000006f,    // j .
+        0x00005f37,    // off: lui t5, 0x5
+        0x555f0f13,    // addi t5, t5, 0x555
+        0x01eea023,    // sw t5, 0(t4)
+        0x0000006f,    // j .
+        0x00073f37,    // fail: lui t5, 0x73
+        0x333f0f13,    // addi t5, t5, 0x333
+        0x01eea023,    // sw t5, 0(t4)
+        0x0000006f,    // j .
+    ];
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let mut bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    let boots = Rc::new(Cell::new(0));
+    bus.map_device(0x2000_0000, Box::new(Boots(boots.clone())), None)
+        .unwrap();
+    let exit = core::start(bus, RAM_START, 0, Default::default(), &|_| {});
+    assert_eq!(exit.code, 0);
+    assert_eq!(boots.get(), 1);
+    // Statistics cover both boots, 12 instructions each.
+    assert_eq!(exit.stats.instructions, 24);
+}
+
+/// Execution statistics are counted and reported as text and JSON.
+#[test]
+fn stats_report() {
+    let words = [
+        0x111002b7u32, // lui t0, 0x11100
+        0x000051b7,    // lui gp, 0x5
+        0x55518193,    // addi gp, gp, 0x555
+        0x0032a023,    // sw gp, 0(t0)
+        0x0000006f,    // j .
+    ];
+    let mut ram = vec![0u8; RAM_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        ram[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    let bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    let exit = core::start(bus, RAM_START, 0, Default::default(), &|_| {});
+    assert_eq!(exit.code, 0);
+
+    let report = exit.stats.report(Duration::from_secs(1));
+    assert!(report.contains("instructions retired: 4\n"), "{report}");
+    assert!(report.contains("  lui      2\n"), "{report}");
+    assert!(report.contains("  store    1\n"), "{report}");
+    assert!(report.contains("  syscon   reads 0 writes 1\n"), "{report}");
+
+    let json = exit.stats.to_json(Duration::from_secs(1));
+    assert!(
+        json.starts_with("{\"instructions\":4,\"elapsed_secs\":1,"),
+        "{json}"
+    );
+    assert!(json.contains("\"alu\":1"), "{json}");
+    assert!(
+        json.contains("{\"device\":\"syscon\",\"base\":286261248,\"reads\":0,\"writes\":1}"),
+        "{json}"
+    );
+
+    // Devices of the same name are both listed, and names are escaped.
+    let count = |device: &str, base| MmioCount {
+        device: device.to_string(),
+        base,
+        reads: 1,
+        writes: 2,
+    };
+    let stats = Stats {
+        mmio: vec![count("virtio", 0x1000_1000), count("a\"b\\\n", 0x1000_2000)],
+        ..Default::default()
+    };
+    let json = stats.to_json(Duration::from_secs(1));
+    assert!(
+        json.ends_with(concat!(
+            "\"mmio\":[{\"device\":\"virtio\",\"base\":268439552,\"reads\":1,\"writes\":2},",
+            "{\"device\":\"a\\\"b\\\\\\u000a\",\"base\":268443648,\"reads\":1,\"writes\":2}]}"
+        )),
+        "{json}"
+    );
+}
+
+const BAREMETAL_ELF: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../fixtures/baremetal/baremetal.elf"
+);
+
+/// The bare-metal example program runs to its power-off.
+#[test]
+fn baremetal() {
+    let bytes = std::fs::read(BAREMETAL_ELF).unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    let mut ram = vec![0u8; RAM_SIZE];
+    elf.load(&mut ram, RAM_START).unwrap();
+    let bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    let exit = core::start(bus, elf.entry, 0, Default::default(), &|_| {});
+    assert_eq!(exit.code, 0);
+    assert!(exit.fault.is_none());
+}
+
+/// ELF segments are copied to their physical addresses with `.bss` cleared.
+#[test]
+fn elf_load() {
+    let bytes = std::fs::read(BAREMETAL_ELF).unwrap();
+    let elf = Elf::parse(&bytes).unwrap();
+    assert_eq!(elf.entry, RAM_START);
+    assert_eq!(
+        elf.segments(),
+        [Segment {
+            paddr: RAM_START,
+            offset: 0x1000,
+            filesz: 0x1ca,
+            memsz: 0x21d0,
+        }]
+    );
+    assert_eq!(elf.end(), Some(RAM_START + 0x21d0));
+
+    // `.bss` is cleared whatever RAM held before.
+    let mut ram = vec![0xffu8; 0x4000];
+    elf.load(&mut ram, RAM_START).unwrap();
+    assert_eq!(ram[..0x1ca], bytes[0x1000..0x11ca]);
+    assert!(ram[0x1ca..0x21d0].iter().all(|b| *b == 0));
+    assert_eq!(ram[0x21d0], 0xff);
+
+    let mut small = vec![0u8; 0x2000];
+    assert_eq!(
+        elf.load(&mut small, RAM_START),
+        Err(ElfError::SegmentOutOfRam {
+            addr: RAM_START,
+            size: 0x21d0,
+        })
+    );
+    assert!(small.iter().all(|b| *b == 0));
+}
+
+/// Symbols come from the ELF symbol table or a `System.map`.
+#[test]
+fn elf_symbols() {
+    let bytes = std::fs::read(BAREMETAL_ELF).unwrap();
+    let symbols = Elf::parse(&bytes).unwrap().symbols().unwrap();
+    assert_eq!(symbols.addr_of("_start"), Some(RAM_START));
+    assert_eq!(symbols.lookup(RAM_START + 0x50).unwrap().name, "main");
+    // Mapping symbols are left out.
+    assert!(symbols.iter().all(|s| !s.name.starts_with('$')));
+
+    let map = Symbols::from_system_map("80000000 T _start\n80000010 t helper\n80000020 D data\n");
+    assert_eq!(map.lookup(RAM_START + 4).unwrap().name, "_start");
+    // The last unsized symbol does not cover the rest of the address space.
+    assert!(map.lookup(RAM_START + 0x14).is_none());
+    assert!(map.addr_of("data").is_none());
+}
+
+/// Malformed ELF files are reported as errors instead of panicking.
+#[test]
+fn elf_malformed() {
+    let bytes = std::fs::read(BAREMETAL_ELF).unwrap();
+    assert_eq!(Elf::parse(&bytes[..40]).unwrap_err(), ElfError::Truncated);
+
+    let patched = |offset: usize, value: u32| {
+        let mut bytes = bytes.clone();
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        bytes
+    };
+    // Program and section header tables at the very end of the address space.
+    let far = patched(28, u32::MAX);
+    assert_eq!(Elf::parse(&far).unwrap_err(), ElfError::Truncated);
+    let far = patched(32, u32::MAX);
+    assert_eq!(Elf::parse(&far).unwrap_err(), ElfError::Truncated);
+    // Segment contents past the end of the file.
+    assert_eq!(
+        Elf::parse(&bytes[..0x1000]).unwrap_err(),
+        ElfError::Truncated
+    );
+}
+
+/// A generated device tree maps the bus the same way when read back.
+#[test]
+fn device_tree_layout() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let layout = Layout {
+        uart: Region::new(0x1001_0000, 0x100),
+        clint: Region::new(0x0200_0000, 0x1_0000),
+        poweroff_value: 0x1234,
+        ..Layout::default()
+    };
+    bus.set_layout(layout).unwrap();
+
+    let tree = DeviceTree::parse(&bus.platform().to_dtb()).unwrap();
+    assert_eq!(tree.to_dtb(), bus.platform().to_dtb());
+    assert_eq!(Layout::from_device_tree(&tree), (layout, Vec::new()));
+
+    // Layouts that overlap the other devices or RAM are refused.
+    let overlapping = Layout {
+        uart: PLIC,
+        ..layout
+    };
+    assert_eq!(
+        bus.set_layout(overlapping),
+        Err(MapError::Overlap {
+            device: "uart".to_string(),
+            other: "plic".to_string()
+        })
+    );
+    let rtc = Rtc::new(rtc::Fixed::new(0));
+    bus.map_device(RTC.base, Box::new(rtc), None).unwrap();
+    let overlapping = Layout {
+        syscon: RTC,
+        ..layout
+    };
+    assert_eq!(
+        bus.set_layout(overlapping),
+        Err(MapError::Overlap {
+            device: "rtc".to_string(),
+            other: "syscon".to_string()
+        })
+    );
+    let in_ram = Layout {
+        clint: Region::new(RAM_START, 0x1_0000),
+        ..layout
+    };
+    assert_eq!(
+        bus.set_layout(in_ram),
+        Err(MapError::Overlap {
+            device: "clint".to_string(),
+            other: "RAM".to_string()
+        })
+    );
+}
+
+/// Addresses are translated through the `ranges` of the buses, and what r2 cannot emulate
+/// as described is warned about.
+#[test]
+fn device_tree_ranges() {
+    let cells = |cells: &[u32]| -> Vec<u8> { cells.iter().flat_map(|c| c.to_be_bytes()).collect() };
+    let device = |name: &str, compatible: &str, reg: &[u32]| {
+        let mut node = Node::new(name);
+        node.set_property("compatible", [compatible.as_bytes(), &[0]].concat());
+        node.set_property("reg", cells(reg));
+        node
+    };
+    let mut soc = Node::new("soc");
+    soc.set_property("compatible", b"simple-bus\0".to_vec());
+    soc.set_property("#address-cells", cells(&[1]));
+    soc.set_property("#size-cells", cells(&[1]));
+    soc.set_property("ranges", cells(&[0, 0x1000_0000, 0x10_0000]));
+    let mut uart = device("serial@0", "ns16550a", &[0, 0x100]);
+    uart.set_property("reg-shift", cells(&[2]));
+    soc.children.push(uart);
+    soc.children
+        .push(device("virtio_mmio@1000", "virtio,mmio", &[0x1000, 0x1000]));
+    soc.children.push(device(
+        "virtio_mmio@18000",
+        "virtio,mmio",
+        &[0x1_8000, 0x1000],
+    ));
+    // Beyond what the bus forwards.
+    soc.children.push(device(
+        "clint@2000000",
+        "riscv,clint0",
+        &[0x200_0000, 0x1_0000],
+    ));
+    let mut root = Node::new("");
+    root.set_property("#address-cells", cells(&[1]));
+    root.set_property("#size-cells", cells(&[1]));
+    root.children.push(soc);
+    let tree = DeviceTree {
+        reservations: Vec::new(),
+        boot_cpuid: 0,
+        root,
+    };
+
+    assert_eq!(tree.translate("/soc/serial@0", 0x10), Some(0x1000_0010));
+    assert_eq!(tree.translate("/soc/clint@2000000", 0x200_0000), None);
+    let (layout, warnings) = Layout::from_device_tree(&tree);
+    assert_eq!(layout.uart, Region::new(0x1000_0000, 0x100));
+    assert_eq!(layout.clint, Layout::default().clint);
+    assert_eq!(
+        warnings,
+        [
+            "/soc/serial@0: the UART has byte registers one apart, not reg-shift 2 and \
+             reg-io-width 1",
+            "/soc/virtio_mmio@18000: virtio-mmio slots are at 0x10001000 onward, a page \
+             apart, and interrupt from line 1 onward",
+            "/soc/clint@2000000: unusable reg, keeping the CLINT at 0x11000000",
+        ]
+    );
+}
+
+/// Big-endian word `i` of a device tree blob.
+fn fdt_word(dtb: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(dtb[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+/// Generated blobs have a version 17 header, an empty reservation map and a strings block
+/// holding each property name once.
+#[test]
+fn device_tree_blob() {
+    let bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let dtb = bus.platform().to_dtb();
+    let [magic, total, off_struct, off_strings, off_rsvmap, version, last_comp, boot_cpuid, strings_len, struct_len] =
+        std::array::from_fn(|i| fdt_word(&dtb, i));
+    assert_eq!(magic, 0xd00dfeed);
+    assert_eq!(total as usize, dtb.len());
+    assert_eq!((version, last_comp, boot_cpuid), (17, 16, 0));
+    // The header is followed by the terminating entry of the reservation map.
+    assert_eq!((off_rsvmap, off_struct), (40, 56));
+    assert!(dtb[40..56].iter().all(|b| *b == 0));
+    assert_eq!(off_strings, off_struct + struct_len);
+    assert_eq!(off_strings + strings_len, total);
+
+    // The structure block opens the unnamed root, padded to a word, and ends with FDT_END
+    // right after the root is closed.
+    let structure = &dtb[off_struct as usize..off_strings as usize];
+    assert_eq!(structure[..8], [0, 0, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(structure[structure.len() - 8..], [0, 0, 0, 2, 0, 0, 0, 9]);
+
+    let strings = &dtb[off_strings as usize..];
+    let names: Vec<&[u8]> = strings
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .collect();
+    for name in &names {
+        assert_eq!(names.iter().filter(|n| *n == name).count(), 1);
+    }
+    assert!(names.contains(&&b"#address-cells"[..]));
+
+    let tree = DeviceTree::parse(&dtb).unwrap();
+    let memory = tree
+        .nodes()
+        .into_iter()
+        .find(|node| node.path == format!("/memory@{RAM_START:x}"))
+        .unwrap();
+    assert_eq!(
+        memory.reg(),
+        Some(vec![(RAM_START as u64, RAM_SIZE as u64)])
+    );
+    assert_eq!(memory.strings("device_type"), ["memory"]);
+}
+
+/// `/chosen` gets the command line and initramfs patched in, and the blob survives the
+/// trip through the parser, reservations included.
+#[test]
+fn device_tree_chosen() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let mut tree = DeviceTree::parse(&bus.platform().to_dtb()).unwrap();
+    let initrd = bus.load_initrd(b"initramfs").unwrap();
+    assert_eq!(initrd, Region::new(RAM_START + RAM_SIZE as u32 / 2, 9));
+    let offset = (initrd.base - RAM_START) as usize;
+    let ram = bus.replace_ram(vec![0u8; 8]);
+    assert_eq!(ram[offset..offset + 9], *b"initramfs");
+    // Images that do not fit are refused.
+    assert_eq!(bus.load_initrd(b"initramfs"), None);
+
+    tree.reservations.push(Reservation {
+        address: 0x8040_0000,
+        size: 0x1000,
+    });
+    tree.set_bootargs("console=ttyS0");
+    tree.set_initrd(initrd);
+    // Setting the command line again replaces it.
+    tree.set_bootargs("console=hvc0 earlycon=sbi");
+
+    let patched = DeviceTree::parse(&tree.to_dtb()).unwrap();
+    assert_eq!(patched, tree);
+    let chosen = patched.root.child("chosen").unwrap();
+    assert_eq!(chosen.strings("bootargs"), ["console=hvc0 earlycon=sbi"]);
+    let start = (RAM_START as u64 + RAM_SIZE as u64 / 2).to_be_bytes();
+    let end = (RAM_START as u64 + RAM_SIZE as u64 / 2 + 9).to_be_bytes();
+    assert_eq!(chosen.property("linux,initrd-start"), Some(&start[..]));
+    assert_eq!(chosen.property("linux,initrd-end"), Some(&end[..]));
+    assert_eq!(
+        chosen
+            .properties
+            .iter()
+            .filter(|(name, _)| name == "bootargs")
+            .count(),
+        1
+    );
+
+    // A tree without /chosen gets one.
+    let mut bare = DeviceTree {
+        reservations: Vec::new(),
+        boot_cpuid: 0,
+        root: Node::new(""),
+    };
+    bare.set_bootargs("quiet");
+    let bare = DeviceTree::parse(&bare.to_dtb()).unwrap();
+    assert_eq!(
+        bare.root.child("chosen").unwrap().strings("bootargs"),
+        ["quiet"]
+    );
+
+    let dtb = tree.to_dtb();
+    assert_eq!(
+        DeviceTree::parse(&dtb[..dtb.len() - 8]),
+        Err(FdtError::Truncated)
+    );
+    assert_eq!(DeviceTree::parse(&[0u8; 64]), Err(FdtError::BadMagic));
+}
+
+/// A register that raises its interrupt while it holds a nonzero value.
+struct Latch(u32);
+
+impl MmioDevice for Latch {
+    fn name(&self) -> &str {
+        "latch"
+    }
+
+    fn size(&self) -> u32 {
+        0x10
+    }
+
+    fn read(&mut self, _offset: u32, _width: Width) -> u32 {
+        self.0
+    }
+
+    fn write(&mut self, _offset: u32, v: u32, _width: Width) {
+        self.0 = v;
+    }
+
+    fn interrupt(&self) -> bool {
+        self.0 != 0
+    }
+}
+
+/// Display keeping every frame it was shown.
+#[derive(Default, Clone)]
+struct Frames(Rc<RefCell<Vec<Vec<u8>>>>);
+
+impl device_interfaces::Display for Frames {
+    fn refresh(&mut self, _width: u32, _height: u32, rgba: &[u8]) {
+        self.0.borrow_mut().push(rgba.to_vec());
+    }
+}
+
+/// The framebuffer is described in the device tree and shown once drawn to.
+#[test]
+fn framebuffer() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let frames = Frames::default();
+    assert_eq!(
+        Framebuffer::new(0x8000, 0x8000, frames.clone()).err(),
+        Some(FramebufferError::TooLarge {
+            width: 0x8000,
+            height: 0x8000
+        })
+    );
+    assert!(Framebuffer::new(0x4000_0000, 1, frames.clone()).is_err());
+    // Fits, but not once rounded up to a page.
+    assert!(Framebuffer::new(0x3fff_fc01, 1, frames.clone()).is_err());
+    let framebuffer = Framebuffer::new(4, 2, frames.clone()).unwrap();
+    bus.map_device(FRAMEBUFFER, Box::new(framebuffer), None)
+        .unwrap();
+
+    let tree = DeviceTree::parse(&bus.platform().to_dtb()).unwrap();
+    let node = tree
+        .nodes()
+        .into_iter()
+        .find(|node| node.strings("compatible") == ["simple-framebuffer"])
+        .unwrap();
+    assert_eq!(node.reg(), Some(vec![(FRAMEBUFFER as u64, 0x1000)]));
+    assert_eq!(node.u32_property("stride"), Some(16));
+    assert_eq!(node.strings("format"), ["x8r8g8b8"]);
+
+    // The second pixel of the first row.
+    bus.write32(FRAMEBUFFER + 4, 0x0011_2233).unwrap();
+    assert_eq!(bus.read32(FRAMEBUFFER + 4).unwrap(), 0x0011_2233);
+    bus.step(&mut 0);
+    let frames = frames.0.borrow();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].len(), 4 * 2 * 4);
+    assert_eq!(frames[0][4..8], [0x11, 0x22, 0x33, 0xff]);
+}
+
+/// The RTC reports the clock and raises its alarm.
+#[test]
+fn goldfish_rtc() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let rtc = Rtc::new(rtc::Fixed::new(1_700_000_000));
+    bus.map_device(RTC.base, Box::new(rtc), Some(RTC_IRQ))
+        .unwrap();
+    let time = |bus: &Bus<StoppedTimer, NoSerial>| {
+        let low = bus.read32(RTC.base).unwrap() as u64;
+        (bus.read32(RTC.base + 0x04).unwrap() as u64) << 32 | low
+    };
+    assert_eq!(time(&bus), 1_700_000_000_000_000_000);
+
+    // The guest sets the clock a second back, then an alarm a millisecond ahead.
+    bus.write32(RTC.base + 0x04, (1_699_999_999_000_000_000u64 >> 32) as u32)
+        .unwrap();
+    bus.write32(RTC.base, 1_699_999_999_000_000_000u64 as u32)
+        .unwrap();
+    let alarm = time(&bus) + 1_000_000;
+    bus.write32(RTC.base + 0x0c, (alarm >> 32) as u32).unwrap();
+    bus.write32(RTC.base + 0x08, alarm as u32).unwrap();
+    bus.write32(RTC.base + 0x10, 1).unwrap();
+    assert_eq!(bus.read32(RTC.base + 0x18).unwrap(), 1);
+
+    // About one microsecond per step.
+    for _ in 0..900 {
+        bus.step(&mut 0);
+    }
+    assert_eq!(bus.interrupts().count(), 0);
+    for _ in 0..200 {
+        bus.step(&mut 0);
+    }
+    assert_eq!(bus.interrupts().collect::<Vec<_>>(), [RTC_IRQ]);
+    assert_eq!(bus.read32(RTC.base + 0x18).unwrap(), 0);
+    assert!(time(&bus) >= alarm && time(&bus) < 1_700_000_000_000_000_000);
+    bus.write32(RTC.base + 0x1c, 1).unwrap();
+    assert_eq!(bus.interrupts().count(), 0);
+}
+
+/// A fixed clock moves on a microsecond per instruction, however often the guest touches
+/// devices.
+#[test]
+fn rtc_fixed_instructions() {
+    // Reads the time every 74 instructions, 50 times, then logs it.
+    let words = [
+        0x101002b7u32, // lui t0, 0x10100
+        0x03200313,    // li t1, 50
+        0x0002a383,    // loop: lw t2, 0(t0)
+        0x02300e13,    // li t3, 35
+        0xfffe0e13,    // delay: addi t3, t3, -1
+        0xfe0e1ee3,    // bnez t3, delay
+        0xfff30313,    // addi t1, t1, -1
+        0xfe0316e3,    // bnez t1, loop
+        0x0002a503,    // lw a0, 0(t0)
+        0x0042a583,    // lw a1, 4(t0)
+        0x80001437,    // lui s0, 0x80001
+        0x00a42023,    // sw a0, 0(s0)
+        0x00b42223,    // sw a1, 4(s0)
+        0x0000006f,    // j .
+    ];
+    let mut machine = boot(&words, Options::default());
+    let epoch = 1_700_000_000_000_000_000u64;
+    let rtc = Rtc::new(rtc::Fixed::new(epoch / 1_000_000_000));
+    machine
+        .bus_mut()
+        .map_device(RTC.base, Box::new(rtc), Some(RTC_IRQ))
+        .unwrap();
+    assert_eq!(machine.run_for(4000), ExitReason::BudgetExhausted);
+
+    // The final read is the 3703rd instruction, and the guest sees the clock as of the last
+    // look at it, at most 256 steps and a tick before.
+    let time = logged(machine.bus(), 0x8000_1000, 2);
+    let elapsed = ((time[1] as u64) << 32 | time[0] as u64) - epoch;
+    assert!(
+        (3703 - 256 - 64) * 1000 < elapsed && elapsed <= 3703 * 1000,
+        "{elapsed}"
+    );
+}
+
+/// Plugged-in peripherals are reachable and interrupt through the PLIC.
+#[test]
+fn mmio_device() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    bus.map_device(0x1000_1000, Box::new(Latch(0)), Some(3))
+        .unwrap();
+    assert_eq!(
+        bus.map_device(0x1000_0080, Box::new(Latch(0)), None),
+        Err(MapError::Overlap {
+            device: "latch".to_string(),
+            other: "uart".to_string()
+        })
+    );
+
+    assert_eq!(bus.interrupts().count(), 0);
+    bus.write32(0x1000_1004, 0xabcd).unwrap();
+    assert_eq!(bus.read32(0x1000_1000).unwrap(), 0xabcd);
+    assert_eq!(bus.interrupts().collect::<Vec<_>>(), [3]);
+
+    let latch = bus.mmio_stats().into_iter().find(|m| m.device == "latch");
+    assert_eq!(latch.map(|m| (m.reads, m.writes)), Some((1, 1)));
+}
+
+/// The UART's FIFO and interrupt identification, through loopback mode.
+#[test]
+fn uart_loopback() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let reg = |r: u32| UART.base + r;
+    // Loopback, FIFOs enabled with a trigger level of 4, received data interrupt enabled.
+    bus.write8(reg(4), 0x10).unwrap();
+    bus.write8(reg(2), 0x41).unwrap();
+    bus.write8(reg(1), 0x01).unwrap();
+    bus.write8(reg(7), 0x5a).unwrap();
+    assert_eq!(bus.read8(reg(7)).unwrap(), 0x5a);
+
+    for c in b"abc" {
+        bus.write8(reg(0), *c).unwrap();
+    }
+    assert_eq!(bus.read8(reg(5)).unwrap() & 0x01, 0x01);
+    assert_eq!(bus.read8(reg(2)).unwrap(), 0xc1);
+    assert_eq!(bus.interrupts().count(), 0);
+
+    bus.write8(reg(0), b'd').unwrap();
+    assert_eq!(bus.read8(reg(2)).unwrap(), 0xc4);
+    assert_eq!(bus.interrupts().collect::<Vec<_>>(), [UART_IRQ]);
+    let received: Vec<u8> = (0..4).map(|_| bus.read8(reg(0)).unwrap()).collect();
+    assert_eq!(received, b"abcd");
+    assert_eq!(bus.read8(reg(5)).unwrap(), 0x60);
+
+    // The transmitter empty interrupt is raised on enabling and acknowledged by IIR.
+    bus.write8(reg(1), 0x02).unwrap();
+    assert_eq!(bus.read8(reg(2)).unwrap(), 0xc2);
+    assert_eq!(bus.read8(reg(2)).unwrap(), 0xc1);
+
+    // Modem control outputs are looped back to the modem status lines. Entering loopback
+    // dropped the lines that were asserted before.
+    assert_eq!(bus.read8(reg(6)).unwrap(), 0x0b);
+    bus.write8(reg(4), 0x13).unwrap();
+    assert_eq!(bus.read8(reg(6)).unwrap(), 0x33);
+    assert_eq!(bus.read8(reg(6)).unwrap(), 0x30);
+}
+
+/// The PLIC routes interrupts by priority and threshold through claim and complete.
+#[test]
+fn plic_claim() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    bus.map_device(0x1000_1000, Box::new(Latch(1)), Some(3))
+        .unwrap();
+    bus.map_device(0x1000_2000, Box::new(Latch(1)), Some(5))
+        .unwrap();
+    let (meip, seip) = (0x800, 0x200);
+    let mut mip = 0;
+    bus.step(&mut mip);
+    assert_eq!(mip, 0);
+
+    // Priorities of sources 3 and 5, both enabled for the S-mode context.
+    bus.write32(PLIC.base + 3 * 4, 1).unwrap();
+    bus.write32(PLIC.base + 5 * 4, 2).unwrap();
+    bus.write32(PLIC.base + 0x2080, 1 << 3 | 1 << 5).unwrap();
+    bus.step(&mut mip);
+    assert_eq!(mip, seip);
+    assert_eq!(bus.read32(PLIC.base + 0x1000).unwrap(), 1 << 3 | 1 << 5);
+
+    // The threshold masks source 3, and claiming source 5 takes it out of the pending set.
+    bus.write32(PLIC.base + 0x20_1000, 1).unwrap();
+    assert_eq!(bus.read32(PLIC.base + 0x20_1004).unwrap(), 5);
+    bus.step(&mut mip);
+    assert_eq!(mip, 0);
+    assert_eq!(bus.read32(PLIC.base + 0x20_1004).unwrap(), 0);
+
+    // Completing it lets the still asserted line interrupt again.
+    bus.write32(PLIC.base + 0x20_1004, 5).unwrap();
+    bus.step(&mut mip);
+    assert_eq!(mip, seip);
+    bus.write32(0x1000_2000, 0).unwrap();
+    bus.step(&mut mip);
+    assert_eq!(mip & (meip | seip), 0);
+}
+
+/// Sends one request through the single queue of the virtio-blk device at `base`, whose
+/// rings are set up at the start of RAM, and returns the status byte.
+fn blk_request(
+    bus: &mut Bus<StoppedTimer, NoSerial>,
+    base: u32,
+    kind: u32,
+    sector: u64,
+    data: &mut [u8],
+) -> u8 {
+    let (desc, avail, used) = (RAM_START, RAM_START + 0x1000, RAM_START + 0x2000);
+    let (header, buffer, status) = (RAM_START + 0x3000, RAM_START + 0x4000, RAM_START + 0x5000);
+    let write_desc = |bus: &mut Bus<_, _>, i: u32, addr: u32, len: u32, flags: u16| {
+        let d = desc + i * 16;
+        bus.write32(d, addr).unwrap();
+        bus.write32(d + 4, 0).unwrap();
+        bus.write32(d + 8, len).unwrap();
+        bus.write32(d + 12, flags as u32 | (i + 1) << 16).unwrap();
+    };
+    let device_writes = kind == 0;
+    for (i, b) in data.iter().enumerate() {
+        bus.write8(buffer + i as u32, *b).unwrap();
+    }
+    bus.write32(header, kind).unwrap();
+    bus.write32(header + 8, sector as u32).unwrap();
+    bus.write32(header + 12, (sector >> 32) as u32).unwrap();
+    write_desc(bus, 0, header, 16, 1);
+    write_desc(
+        bus,
+        1,
+        buffer,
+        data.len() as u32,
+        1 | if device_writes { 2 } else { 0 },
+    );
+    write_desc(bus, 2, status, 1, 2);
+
+    let idx = bus.read16(avail + 2).unwrap();
+    bus.write16(avail + 4 + (idx as u32 % 8) * 2, 0).unwrap();
+    bus.write16(avail + 2, idx + 1).unwrap();
+    bus.write32(base + 0x050, 0).unwrap();
+    let mut mip = 0;
+    bus.step(&mut mip);
+    assert_eq!(bus.read16(used + 2).unwrap(), idx + 1);
+    assert_eq!(mip, 0x800);
+    bus.write32(base + 0x064, bus.read32(base + 0x060).unwrap())
+        .unwrap();
+
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = bus.read8(buffer + i as u32).unwrap();
+    }
+    bus.read8(status).unwrap()
+}
+
+/// virtio-blk requests go through a virtqueue.
+#[test]
+fn virtio_blk() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let mut image = vec![0u8; 4 * 512];
+    image[512..517].copy_from_slice(b"hello");
+    let block = Block::new(Cursor::new(image), false, false).unwrap();
+    let base = bus
+        .map_virtio(Box::new(VirtioMmio::new(block)))
+        .unwrap()
+        .base;
+
+    assert_eq!(bus.read32(base).unwrap(), 0x7472_6976);
+    assert_eq!(bus.read32(base + 0x008).unwrap(), 2);
+    // The capacity in sectors is the first field of the configuration space.
+    assert_eq!(bus.read32(base + 0x100).unwrap(), 4);
+
+    // Driver initialization, with the PLIC routing the device to the M-mode context.
+    let plic = 0x0c00_0000;
+    bus.write32(plic + 4, 1).unwrap();
+    bus.write32(plic + 0x2000, 1 << 1).unwrap();
+    bus.write32(base + 0x070, 0x7).unwrap();
+    bus.write32(base + 0x024, 1).unwrap();
+    bus.write32(base + 0x020, 1).unwrap();
+    bus.write32(base + 0x038, 8).unwrap();
+    bus.write32(base + 0x080, RAM_START).unwrap();
+    bus.write32(base + 0x090, RAM_START + 0x1000).unwrap();
+    bus.write32(base + 0x0a0, RAM_START + 0x2000).unwrap();
+    bus.write32(base + 0x044, 1).unwrap();
+    bus.write32(base + 0x070, 0xf).unwrap();
+
+    let mut sector = [0u8; 512];
+    assert_eq!(blk_request(&mut bus, base, 0, 1, &mut sector), 0);
+    assert_eq!(&sector[..5], b"hello");
+
+    sector[..5].copy_from_slice(b"world");
+    assert_eq!(blk_request(&mut bus, base, 1, 3, &mut sector), 0);
+    let mut read_back = [0u8; 512];
+    assert_eq!(blk_request(&mut bus, base, 0, 3, &mut read_back), 0);
+    assert_eq!(&read_back[..5], b"world");
+
+    // Past the end of the disk.
+    assert_eq!(blk_request(&mut bus, base, 0, 4, &mut read_back), 1);
+}
+
+/// Sets up `queues` virtqueues of the virtio device at `base`, each with its rings in its
+/// own 16 KiB from the start of RAM, and completes driver initialization.
+fn virtq_setup(bus: &mut Bus<StoppedTimer, NoSerial>, base: u32, queues: u32) {
+    let plic = 0x0c00_0000;
+    bus.write32(plic + 4, 1).unwrap();
+    bus.write32(plic + 0x2000, 1 << 1).unwrap();
+    bus.write32(base + 0x070, 0x7).unwrap();
+    bus.write32(base + 0x024, 1).unwrap();
+    bus.write32(base + 0x020, 1).unwrap();
+    for queue in 0..queues {
+        let rings = RAM_START + queue * 0x4000;
+        bus.write32(base + 0x030, queue).unwrap();
+        bus.write32(base + 0x038, 8).unwrap();
+        bus.write32(base + 0x080, rings).unwrap();
+        bus.write32(base + 0x090, rings + 0x1000).unwrap();
+        bus.write32(base + 0x0a0, rings + 0x2000).unwrap();
+        bus.write32(base + 0x044, 1).unwrap();
+    }
+    bus.write32(base + 0x070, 0xf).unwrap();
+}
+
+/// Makes `len` bytes at `buffer` available on a queue set up by [`virtq_setup`], using the
+/// descriptor of the same index as the ring slot, and notifies the device.
+fn virtq_post(
+    bus: &mut Bus<StoppedTimer, NoSerial>,
+    base: u32,
+    queue: u32,
+    buffer: u32,
+    len: u32,
+    writable: bool,
+) {
+    let rings = RAM_START + queue * 0x4000;
+    let idx = bus.read16(rings + 0x1002).unwrap();
+    let desc = rings + (idx as u32 % 8) * 16;
+    bus.write32(desc, buffer).unwrap();
+    bus.write32(desc + 4, 0).unwrap();
+    bus.write32(desc + 8, len).unwrap();
+    bus.write32(desc + 12, if writable { 2 } else { 0 })
+        .unwrap();
+    bus.write16(rings + 0x1004 + (idx as u32 % 8) * 2, idx % 8)
+        .unwrap();
+    bus.write16(rings + 0x1002, idx + 1).unwrap();
+    bus.write32(base + 0x050, queue).unwrap();
+}
+
+/// Network backend whose frames the test sends and inspects.
+#[derive(Clone, Default)]
+struct Wire {
+    sent: Rc<RefCell<Vec<Vec<u8>>>>,
+    inbox: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl device_interfaces::NetworkInterface for Wire {
+    fn send(&mut self, frame: &[u8]) {
+        self.sent.borrow_mut().push(frame.to_vec());
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inbox.borrow_mut().pop_front()
+    }
+}
+
+/// virtio-net frames go both ways between the queues and the backend.
+#[test]
+fn virtio_net() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let wire = Wire::default();
+    let net = Net::new(wire.clone(), DEFAULT_MAC);
+    let base = bus.map_virtio(Box::new(VirtioMmio::new(net))).unwrap().base;
+
+    assert_eq!(bus.read32(base + 0x008).unwrap(), 1);
+    let mac: Vec<u8> = (0..6)
+        .map(|i| bus.read8(base + 0x100 + i).unwrap())
+        .collect();
+    assert_eq!(mac, DEFAULT_MAC);
+
+    virtq_setup(&mut bus, base, 2);
+
+    // Transmit: the virtio-net header is stripped off.
+    let buffer = RAM_START + 0x8000;
+    for (i, b) in b"frame".iter().enumerate() {
+        bus.write8(buffer + 12 + i as u32, *b).unwrap();
+    }
+    virtq_post(&mut bus, base, 1, buffer, 17, false);
+    let mut mip = 0;
+    bus.step(&mut mip);
+    assert_eq!(*wire.sent.borrow(), [b"frame".to_vec()]);
+    assert_eq!(bus.read16(RAM_START + 0x6002).unwrap(), 1);
+    assert_eq!(mip, 0x800);
+    bus.write32(base + 0x064, bus.read32(base + 0x060).unwrap())
+        .unwrap();
+
+    // Receive: nothing arrives until the backend has a frame, which is picked up by
+    // polling.
+    let buffer = RAM_START + 0x9000;
+    virtq_post(&mut bus, base, 0, buffer, 2048, true);
+    let mut mip = 0;
+    bus.step(&mut mip);
+    assert_eq!(bus.read16(RAM_START + 0x2002).unwrap(), 0);
+    wire.inbox.borrow_mut().push_back(b"reply".to_vec());
+    for _ in 0..256 {
+        bus.step(&mut mip);
+    }
+    assert_eq!(bus.read16(RAM_START + 0x2002).unwrap(), 1);
+    assert_eq!(bus.read32(RAM_START + 0x2008).unwrap(), 17);
+    assert_eq!(mip, 0x800);
+    // `num_buffers`, then the frame.
+    assert_eq!(bus.read16(buffer + 10).unwrap(), 1);
+    let frame: Vec<u8> = (0..5)
+        .map(|i| bus.read8(buffer + 12 + i).unwrap())
+        .collect();
+    assert_eq!(frame, b"reply");
+}
+
+/// Console port whose traffic and size the test controls.
+#[derive(Clone, Default)]
+struct Pipe {
+    output: Rc<RefCell<Vec<u8>>>,
+    size: Rc<RefCell<(u16, u16)>>,
+    resized: Rc<RefCell<bool>>,
+}
+
+impl device_interfaces::ConsolePort for Pipe {
+    fn write(&mut self, data: &[u8]) {
+        self.output.borrow_mut().extend_from_slice(data);
+    }
+
+    fn read(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn size(&self) -> Option<(u16, u16)> {
+        Some(*self.size.borrow())
+    }
+
+    fn resized(&mut self) -> bool {
+        std::mem::take(&mut *self.resized.borrow_mut())
+    }
+}
+
+/// virtio-console ports carry data and report their size.
+#[test]
+fn virtio_console() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let (terminal, agent) = (Pipe::default(), Pipe::default());
+    *terminal.size.borrow_mut() = (80, 24);
+    let mut console = Console::new(Box::new(terminal.clone()));
+    console.add_port("agent", Box::new(agent.clone()));
+    let base = bus
+        .map_virtio(Box::new(VirtioMmio::new(console)))
+        .unwrap()
+        .base;
+
+    assert_eq!(bus.read32(base + 0x008).unwrap(), 3);
+    // Columns, rows and the number of ports.
+    assert_eq!(bus.read32(base + 0x100).unwrap(), 24 << 16 | 80);
+    assert_eq!(bus.read32(base + 0x104).unwrap(), 2);
+
+    virtq_setup(&mut bus, base, 6);
+    // The driver is told to read the initial size.
+    let mut mip = 0;
+    bus.step(&mut mip);
+    assert_eq!(bus.read32(base + 0x060).unwrap(), 2);
+    bus.write32(base + 0x064, 2).unwrap();
+
+    let buffers = RAM_START + 0x20000;
+    bus.write16(buffers, u16::from_le_bytes(*b"hi")).unwrap();
+    virtq_post(&mut bus, base, 1, buffers, 2, false);
+    bus.step(&mut mip);
+    assert_eq!(*terminal.output.borrow(), b"hi");
+
+    // Multiport: the device announces its ports once the driver is ready.
+    for i in 0..4 {
+        virtq_post(&mut bus, base, 2, buffers + 0x100 * (i + 1), 64, true);
+    }
+    let device_ready = buffers + 0x1000;
+    bus.write32(device_ready, 0xffff_ffff).unwrap();
+    bus.write32(device_ready + 4, 1 << 16).unwrap();
+    virtq_post(&mut bus, base, 3, device_ready, 8, false);
+    bus.step(&mut mip);
+    let control_used = RAM_START + 2 * 0x4000 + 0x2000;
+    assert_eq!(bus.read16(control_used + 2).unwrap(), 2);
+    // DEVICE_ADD for port 0, then port 1.
+    assert_eq!(bus.read32(buffers + 0x100).unwrap(), 0);
+    assert_eq!(bus.read32(buffers + 0x104).unwrap(), 1);
+    assert_eq!(bus.read32(buffers + 0x200).unwrap(), 1);
+    assert_eq!(bus.read32(buffers + 0x204).unwrap(), 1);
+
+    // Now resizes go through the control queue, as rows then columns.
+    *terminal.size.borrow_mut() = (100, 40);
+    *terminal.resized.borrow_mut() = true;
+    for _ in 0..256 {
+        bus.step(&mut mip);
+    }
+    assert_eq!(bus.read16(control_used + 2).unwrap(), 3);
+    assert_eq!(bus.read32(buffers + 0x304).unwrap(), 5);
+    assert_eq!(bus.read32(buffers + 0x308).unwrap(), 100 << 16 | 40);
+    assert_eq!(bus.read32(base + 0x100).unwrap(), 40 << 16 | 100);
+    assert!(agent.output.borrow().is_empty());
+}
+
+/// Ports beyond the 15th, whose queues come after the 32nd, are reachable too.
+#[test]
+fn virtio_console_ports() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let mut console = Console::new(Box::new(Pipe::default()));
+    let last = Pipe::default();
+    for i in 1..15 {
+        console.add_port(&format!("port{i}"), Box::new(Pipe::default()));
+    }
+    console.add_port("port15", Box::new(last.clone()));
+    let base = bus
+        .map_virtio(Box::new(VirtioMmio::new(console)))
+        .unwrap()
+        .base;
+    assert_eq!(bus.read32(base + 0x104).unwrap(), 16);
+
+    virtq_setup(&mut bus, base, 34);
+    let buffer = RAM_START + 0x100000;
+    bus.write16(buffer, u16::from_le_bytes(*b"hi")).unwrap();
+    // The transmit queue of port 15.
+    virtq_post(&mut bus, base, 33, buffer, 2, false);
+    bus.step(&mut 0);
+    assert_eq!(*last.output.borrow(), b"hi");
+
+    // Notifications for queues the device does not have are ignored.
+    bus.write32(base + 0x050, 34).unwrap();
+    bus.write32(base + 0x050, u32::MAX).unwrap();
+    bus.step(&mut 0);
+}
+
+/// virtio-rng fills the buffers it is given.
+#[test]
+fn virtio_rng() {
+    // Two machines with the same seed see the same bytes.
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+        let rng = Rng::new(Seeded::new(42));
+        let base = bus.map_virtio(Box::new(VirtioMmio::new(rng))).unwrap().base;
+        assert_eq!(bus.read32(base + 0x008).unwrap(), 4);
+        virtq_setup(&mut bus, base, 1);
+
+        let buffer = RAM_START + 0x20000;
+        virtq_post(&mut bus, base, 0, buffer, 13, true);
+        let mut mip = 0;
+        bus.step(&mut mip);
+        // The used length of the first used ring entry.
+        assert_eq!(bus.read32(RAM_START + 0x2008).unwrap(), 13);
+        let bytes: Vec<u8> = (0..14).map(|i| bus.read8(buffer + i).unwrap()).collect();
+        assert_eq!(bytes[13], 0);
+        outputs.push(bytes);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_ne!(outputs[0][..13], [0; 13]);
+
+    // Buffers adding up to more than 4 GiB get as much as a request is given.
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let rng = Rng::new(Seeded::new(42));
+    let base = bus.map_virtio(Box::new(VirtioMmio::new(rng))).unwrap().base;
+    virtq_setup(&mut bus, base, 1);
+    let buffer = RAM_START + 0x20000;
+    virtq_post(&mut bus, base, 0, buffer, u32::MAX, true);
+    // Chain the posted descriptor to the last one, as big.
+    bus.write32(RAM_START + 12, 3 | 7 << 16).unwrap();
+    bus.write32(RAM_START + 7 * 16, buffer).unwrap();
+    bus.write32(RAM_START + 7 * 16 + 8, u32::MAX).unwrap();
+    bus.write32(RAM_START + 7 * 16 + 12, 2).unwrap();
+    bus.step(&mut 0);
+    assert_eq!(bus.read32(RAM_START + 0x2008).unwrap(), 64 * 1024);
+}
+
+/// virtio-input describes its devices and delivers their events.
+#[test]
+fn virtio_input() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let events = Events::new();
+    let tablet = Input::tablet(events.clone());
+    let base = bus
+        .map_virtio(Box::new(VirtioMmio::new(tablet)))
+        .unwrap()
+        .base;
+    assert_eq!(bus.read32(base + 0x008).unwrap(), 18);
+
+    // The name, selected through the select byte.
+    bus.write8(base + 0x100, 1).unwrap();
+    let size = bus.read8(base + 0x102).unwrap() as u32;
+    let name: Vec<u8> = (0..size)
+        .map(|i| bus.read8(base + 0x108 + i).unwrap())
+        .collect();
+    assert_eq!(name, b"r2 tablet");
+    // The range of ABS_Y.
+    bus.write8(base + 0x100, 0x12).unwrap();
+    bus.write8(base + 0x101, 1).unwrap();
+    assert_eq!(bus.read8(base + 0x102).unwrap(), 20);
+    assert_eq!(bus.read32(base + 0x10c).unwrap(), 0x7fff);
+
+    virtq_setup(&mut bus, base, 2);
+    for i in 0..3 {
+        virtq_post(&mut bus, base, 0, RAM_START + 0x20000 + i * 8, 8, true);
+    }
+    events.move_to(100, 0x10000);
+    // Accessing the device ticks it sooner, but doesn't make it look at the events sooner.
+    for _ in 0..200 {
+        bus.read32(base + 0x070).unwrap();
+        bus.step(&mut 0);
+    }
+    assert_eq!(bus.read16(RAM_START + 0x2002).unwrap(), 0);
+    // The events are picked up on the first tick 256 steps on.
+    for _ in 0..64 + 64 {
+        bus.step(&mut 0);
+    }
+    // ABS_X, ABS_Y clamped to the range, and the report.
+    let expected = [(3, 0, 100), (3, 1, 0x7fff), (0, 0, 0)];
+    for (i, (kind, code, value)) in expected.iter().enumerate() {
+        let event = RAM_START + 0x20000 + i as u32 * 8;
+        assert_eq!(bus.read32(RAM_START + 0x2008 + i as u32 * 8).unwrap(), 8);
+        assert_eq!(bus.read16(event).unwrap(), *kind);
+        assert_eq!(bus.read16(event + 2).unwrap(), *code);
+        assert_eq!(bus.read32(event + 4).unwrap(), *value);
+    }
+}
+
+/// File server answering every request with an empty reply of the matching type.
+struct Acknowledge;
+
+impl device_interfaces::FileServer for Acknowledge {
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reply = request[..7].to_vec();
+        reply[0..4].copy_from_slice(&7u32.to_le_bytes());
+        reply[4] += 1;
+        reply
+    }
+}
+
+/// 9P messages go through the virtio-9p queue to the file server.
+#[test]
+fn virtio_9p() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let p9 = P9::new(Acknowledge, "share");
+    let base = bus.map_virtio(Box::new(VirtioMmio::new(p9))).unwrap().base;
+    assert_eq!(bus.read32(base + 0x008).unwrap(), 9);
+    // The tag, prefixed with its length.
+    assert_eq!(bus.read16(base + 0x100).unwrap(), 5);
+    let tag: Vec<u8> = (0..5)
+        .map(|i| bus.read8(base + 0x102 + i).unwrap())
+        .collect();
+    assert_eq!(tag, b"share");
+
+    virtq_setup(&mut bus, base, 1);
+    // Tclunk of fid 0 with tag 0x1234, followed by the buffer for the reply.
+    let (request, reply) = (RAM_START + 0x20000, RAM_START + 0x21000);
+    for (i, b) in [11, 0, 0, 0, 120, 0x34, 0x12, 0, 0, 0, 0]
+        .iter()
+        .enumerate()
+    {
+        bus.write8(request + i as u32, *b).unwrap();
+    }
+    let desc = RAM_START;
+    bus.write32(desc, request).unwrap();
+    bus.write32(desc + 8, 11).unwrap();
+    bus.write32(desc + 12, 1 | 1 << 16).unwrap();
+    bus.write32(desc + 16, reply).unwrap();
+    bus.write32(desc + 24, 64).unwrap();
+    bus.write32(desc + 28, 2).unwrap();
+    bus.write16(RAM_START + 0x1004, 0).unwrap();
+    bus.write16(RAM_START + 0x1002, 1).unwrap();
+    bus.write32(base + 0x050, 0).unwrap();
+    let mut mip = 0;
+    bus.step(&mut mip);
+
+    assert_eq!(bus.read32(RAM_START + 0x2008).unwrap(), 7);
+    assert_eq!(bus.read32(reply).unwrap(), 7);
+    assert_eq!(bus.read8(reply + 4).unwrap(), 121);
+    assert_eq!(bus.read8(reply + 5).unwrap(), 0x34);
+    assert_eq!(bus.read8(reply + 6).unwrap(), 0x12);
+}
+
+/// Host end of vsock connections, shared with the test.
+#[derive(Clone, Default)]
+struct VsockPeer {
+    received: Rc<RefCell<Vec<u8>>>,
+    to_send: Rc<RefCell<VecDeque<u8>>>,
+}
+
+impl Read for VsockPeer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut to_send = self.to_send.borrow_mut();
+        if to_send.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(to_send.len());
+        for (b, byte) in buf.iter_mut().zip(to_send.drain(..len)) {
+            *b = byte;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for VsockPeer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.received.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl device_interfaces::VsockStream for VsockPeer {}
+
+/// Accepts guest connections to port 1234 only.
+struct VsockHostPort(VsockPeer);
+
+impl device_interfaces::VsockHost for VsockHostPort {
+    fn connect(&mut self, port: u32) -> Option<Box<dyn device_interfaces::VsockStream>> {
+        (port == 1234).then(|| Box::new(self.0.clone()) as _)
+    }
+
+    fn accept(&mut self) -> Option<(u32, Box<dyn device_interfaces::VsockStream>)> {
+        None
+    }
+}
+
+/// Builds a packet from guest port 5000 to host `port`.
+fn vsock_packet(port: u32, op: u16, flags: u32, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&3u64.to_le_bytes());
+    packet.extend_from_slice(&2u64.to_le_bytes());
+    packet.extend_from_slice(&5000u32.to_le_bytes());
+    packet.extend_from_slice(&port.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(&1u16.to_le_bytes());
+    packet.extend_from_slice(&op.to_le_bytes());
+    packet.extend_from_slice(&flags.to_le_bytes());
+    // Buffer space and consumed bytes.
+    packet.extend_from_slice(&65536u32.to_le_bytes());
+    packet.extend_from_slice(&0u32.to_le_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// virtio-vsock connects guest streams to the host.
+#[test]
+fn virtio_vsock() {
+    let mut bus = Bus::new(vec![0u8; RAM_SIZE], Clint::new(StoppedTimer), NoSerial);
+    let peer = VsockPeer::default();
+    let vsock = Vsock::new(VsockHostPort(peer.clone()), 3);
+    let base = bus
+        .map_virtio(Box::new(VirtioMmio::new(vsock)))
+        .unwrap()
+        .base;
+    assert_eq!(bus.read32(base + 0x008).unwrap(), 19);
+    assert_eq!(bus.read32(base + 0x100).unwrap(), 3);
+    virtq_setup(&mut bus, base, 3);
+
+    let rx_buffers = RAM_START + 0x20000;
+    for i in 0..4 {
+        virtq_post(&mut bus, base, 0, rx_buffers + i * 0x1000, 0x1000, true);
+    }
+    let tx_buffer = RAM_START + 0x30000;
+    let send = |bus: &mut Bus<_, _>, packet: &[u8]| {
+        for (i, b) in packet.iter().enumerate() {
+            bus.write8(tx_buffer + i as u32, *b).unwrap();
+        }
+        virtq_post(bus, base, 1, tx_buffer, packet.len() as u32, false);
+        bus.step(&mut 0);
+    };
+    // The op of the `n`th packet the device sent.
+    let op = |bus: &mut Bus<_, _>, n: u32| bus.read16(rx_buffers + n * 0x1000 + 30).unwrap();
+
+    // Refused, then accepted.
+    send(&mut bus, &vsock_packet(80, 1, 0, &[]));
+    assert_eq!(op(&mut bus, 0), 3);
+    send(&mut bus, &vsock_packet(1234, 1, 0, &[]));
+    assert_eq!(op(&mut bus, 1), 2);
+
+    send(&mut bus, &vsock_packet(1234, 5, 0, b"ping"));
+    assert_eq!(*peer.received.borrow(), b"ping");
+
+    peer.to_send.borrow_mut().extend(b"pong");
+    for _ in 0..256 {
+        bus.step(&mut 0);
+    }
+    let reply = rx_buffers + 2 * 0x1000;
+    assert_eq!(op(&mut bus, 2), 5);
+    assert_eq!(bus.read32(reply + 24).unwrap(), 4);
+    // The data the guest sent is acknowledged as consumed.
+    assert_eq!(bus.read32(reply + 40).unwrap(), 4);
+    let data: Vec<u8> = (0..4).map(|i| bus.read8(reply + 44 + i).unwrap()).collect();
+    assert_eq!(data, b"pong");
+
+    // A full shutdown is answered with a reset.
+    send(&mut bus, &vsock_packet(1234, 4, 3, &[]));
+    assert_eq!(op(&mut bus, 3), 3);
+}
+
+/// Copy-on-write overlays keep writes apart from the base until committed.
+#[test]
+fn overlay_commit() {
+    let dir = std::env::temp_dir().join(format!("r2-overlay-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (base, path) = (dir.join("base.img"), dir.join("base.ovl"));
+    // Not a whole number of clusters, so the last one is partial.
+    let mut image = vec![0u8; 10_000];
+    image[5000..5004].copy_from_slice(b"base");
+    std::fs::write(&base, &image).unwrap();
+
+    let read_at = |disk: &mut dyn ReadSeek, pos: u64, len: usize| {
+        let mut buf = vec![0u8; len];
+        disk.seek(SeekFrom::Start(pos)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    };
+
+    let mut disk = Overlay::open(File::open(&base).unwrap(), &base, &path).unwrap();
+    assert_eq!(read_at(&mut disk, 5000, 4), b"base");
+    disk.seek(SeekFrom::Start(4094)).unwrap();
+    disk.write_all(b"over").unwrap();
+    disk.seek(SeekFrom::Start(9998)).unwrap();
+    assert!(disk.write_all(b"end").is_err());
+    drop(disk);
+    assert_eq!(std::fs::read(&base).unwrap(), image);
+
+    let mut disk = Overlay::open(File::open(&base).unwrap(), &base, &path).unwrap();
+    assert_eq!(read_at(&mut disk, 4094, 4), b"over");
+    assert_eq!(read_at(&mut disk, 5000, 4), b"base");
+    drop(disk);
+
+    // Only the clusters written to are copied back: the two the first write spanned and
+    // the last one, which got what fitted of the second.
+    assert_eq!(overlay::commit(&path).unwrap(), 3);
+    image[4094..4098].copy_from_slice(b"over");
+    image[9998..10_000].copy_from_slice(b"en");
+    assert_eq!(std::fs::read(&base).unwrap(), image);
+
+    let mut disk = Overlay::in_memory(File::open(&base).unwrap()).unwrap();
+    disk.seek(SeekFrom::Start(0)).unwrap();
+    disk.write_all(b"lost").unwrap();
+    assert_eq!(read_at(&mut disk, 0, 4), b"lost");
+    assert_eq!(std::fs::read(&base).unwrap(), image);
+
+    let mut disk = Overlay::open(File::open(&base).unwrap(), &base, &path).unwrap();
+    disk.write_all(b"gone").unwrap();
+    drop(disk);
+    overlay::discard(&path).unwrap();
+    let mut disk = Overlay::open(File::open(&base).unwrap(), &base, &path).unwrap();
+    assert_eq!(read_at(&mut disk, 0, 4), [0; 4]);
+    drop(disk);
+
+    // The overlay only goes with the image it was made for, even one of the same size, but
+    // it does not matter how that image is named.
+    let other = dir.join("other.img");
+    std::fs::write(&other, &image).unwrap();
+    let refused = Overlay::open(File::open(&other).unwrap(), &other, &path);
+    assert_eq!(
+        refused.err().unwrap().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    let renamed = dir.join(".").join("base.img");
+    assert!(Overlay::open(File::open(&base).unwrap(), &renamed, &path).is_ok());
+
+    // Base path lengths are bounded before anything is allocated for them.
+    let mut header = std::fs::read(&path).unwrap();
+    header[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&path, &header).unwrap();
+    assert!(Overlay::open(File::open(&base).unwrap(), &base, &path).is_err());
+    assert!(overlay::base_path(&path).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A snapshot on top of an overlay sees what was written through the overlay, and changes
+/// neither.
+#[test]
+fn overlay_snapshot() {
+    let dir = std::env::temp_dir().join(format!("r2-snapshot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (base, path) = (dir.join("base.img"), dir.join("base.ovl"));
+    std::fs::write(&base, vec![0u8; 8192]).unwrap();
+    let mut disk = Overlay::open(File::open(&base).unwrap(), &base, &path).unwrap();
+    disk.seek(SeekFrom::Start(100)).unwrap();
+    disk.write_all(b"kept").unwrap();
+    drop(disk);
+    let overlay = std::fs::read(&path).unwrap();
+
+    let under = Overlay::open_read_only(File::open(&base).unwrap(), &base, &path).unwrap();
+    let mut disk = Overlay::in_memory(under).unwrap();
+    let mut buf = [0u8; 4];
+    disk.seek(SeekFrom::Start(100)).unwrap();
+    disk.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"kept");
+    disk.seek(SeekFrom::Start(100)).unwrap();
+    disk.write_all(b"temp").unwrap();
+    disk.seek(SeekFrom::Start(100)).unwrap();
+    disk.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"temp");
+    drop(disk);
+
+    assert_eq!(std::fs::read(&path).unwrap(), overlay);
+    assert_eq!(std::fs::read(&base).unwrap(), vec![0u8; 8192]);
+    let mut under = Overlay::open_read_only(File::open(&base).unwrap(), &base, &path).unwrap();
+    assert!(under.write_all(b"fail").is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+fn run_elf(path: &Path) -> Result<(), String> {
+    let image = std::fs::read(path).map_err(|e| e.to_string())?;
+    let elf = Elf::parse(&image).map_err(|e| e.to_string())?;
+    let symbols = elf.symbols().map_err(|e| e.to_string())?;
+    let tohost = symbols.addr_of("tohost").ok_or("no tohost symbol")?;
+
+    let mut ram = vec![0u8; RAM_SIZE];
+    elf.load(&mut ram, RAM_START).map_err(|e| e.to_string())?;
+    let mut bus = Bus::new(ram, Clint::new(StoppedTimer), NoSerial);
+    bus.attach_htif(Htif::new(tohost, symbols.addr_of("fromhost")));
+    let mut cpu = Cpu::new(bus);
+    cpu.pc(elf.entry);
+
+    match run(&mut cpu) {
+        Some(0) => {}
+        Some(code) => return Err(format!("failed test case {code}")),
+        None => return Err("timed out".to_string()),
+    }
+
+    let reference = path.with_extension("reference_output");
+    let (Some(begin), Some(end), true) = (
+        symbols.addr_of("begin_signature"),
+        symbols.addr_of("end_signature"),
+        reference.exists(),
+    ) else {
+        return Ok(());
+    };
+    let expected = std::fs::read_to_string(reference).map_err(|e| e.to_string())?;
+    for (i, line) in expected.lines().enumerate() {
+        let addr = begin + i as u32 * 4;
+        if addr >= end {
+            return Err("reference is longer than the signature".to_string());
+        }
+        let expected = u32::from_str_radix(line.trim(), 16).map_err(|e| e.to_string())?;
+        let actual = cpu.bus().read32(addr).map_err(|e| e.to_string())?;
+        if actual != expected {
+            return Err(format!(
+                "signature mismatch at {addr:#010x}: expected {expected:08x}, got {actual:08x}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs every ELF found in the directory named by `RISCV_TESTS_DIR`, which is where prebuilt
+/// `riscv-tests` (`rv32ui-p-*`, `rv32um-p-*`, `rv32ua-p-*`) or `riscv-arch-test` binaries
+/// are expected. Binaries with `begin_signature`/`end_signature` symbols and a
+/// `<name>.reference_output` file next to them get their signature region compared as well.
+#[test]
+#[ignore = "needs prebuilt binaries in RISCV_TESTS_DIR"]
+fn riscv_tests() {
+    let dir = std::env::var("RISCV_TESTS_DIR").expect("RISCV_TESTS_DIR is not set");
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .expect("failed to read RISCV_TESTS_DIR")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            std::fs::read(path)
+                .map(|bytes| core::elf::is_elf(&bytes))
+                .unwrap_or(false)
+        })
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no ELF in RISCV_TESTS_DIR");
+
+    let failures: Vec<_> = paths
+        .iter()
+        .filter_map(|path| {
+            run_elf(path)
+                .err()
+                .map(|e| format!("{}: {e}", path.display()))
+        })
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} of {} tests failed:\n{}",
+        failures.len(),
+        paths.len(),
+        failures.join("\n")
+    );
 }
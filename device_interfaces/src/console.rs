@@ -0,0 +1,18 @@
+/// Host end of a port of a virtio console.
+pub trait ConsolePort {
+    /// Writes what the guest sent to the port.
+    fn write(&mut self, data: &[u8]);
+
+    /// Takes input for the guest, if any is waiting. Called often, so it must not block.
+    fn read(&mut self) -> Option<Vec<u8>>;
+
+    /// Size of the host terminal as columns and rows, for ports connected to one.
+    fn size(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Whether the size changed since the last call.
+    fn resized(&mut self) -> bool {
+        false
+    }
+}
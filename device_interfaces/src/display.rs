@@ -0,0 +1,12 @@
+/// Host end of the framebuffer.
+pub trait Display {
+    /// Shows a frame of `width` by `height` pixels, given as RGBA rows from the top. Only
+    /// called when the guest drew something since the previous frame.
+    fn refresh(&mut self, width: u32, height: u32, rgba: &[u8]);
+}
+
+impl<D: Display + ?Sized> Display for Box<D> {
+    fn refresh(&mut self, width: u32, height: u32, rgba: &[u8]) {
+        (**self).refresh(width, height, rgba)
+    }
+}
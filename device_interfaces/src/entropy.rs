@@ -0,0 +1,5 @@
+/// Source of the random bytes handed to the guest.
+pub trait EntropySource {
+    /// Fills `buf` entirely.
+    fn fill(&mut self, buf: &mut [u8]);
+}
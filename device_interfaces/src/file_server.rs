@@ -0,0 +1,6 @@
+/// Host end of a shared directory. Speaks 9P2000.L, one message at a time.
+/// @See https://github.com/chaos/diod/blob/master/protocol.md
+pub trait FileServer {
+    /// Answers a request, both as whole messages with their size prefix.
+    fn handle(&mut self, request: &[u8]) -> Vec<u8>;
+}
@@ -0,0 +1,15 @@
+/// An evdev event, as defined in `linux/input.h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    /// `EV_KEY`, `EV_ABS`, ...
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// Host end of an input device of the guest.
+pub trait InputSource {
+    /// Takes the next event for the guest, if any is waiting. Called often, so it must not
+    /// block. A group of events is only acted upon once an `EV_SYN` follows it.
+    fn next_event(&mut self) -> Option<InputEvent>;
+}
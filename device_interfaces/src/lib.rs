@@ -1,5 +1,21 @@
+mod console;
+mod display;
+mod entropy;
+mod file_server;
+mod input;
+mod network;
 mod serial;
 mod timer;
+mod vsock;
+mod wall_clock;
 
+pub use console::*;
+pub use display::*;
+pub use entropy::*;
+pub use file_server::*;
+pub use input::*;
+pub use network::*;
 pub use serial::*;
 pub use timer::*;
+pub use vsock::*;
+pub use wall_clock::*;
@@ -0,0 +1,19 @@
+/// Host end of a network card. Frames are raw Ethernet frames without FCS.
+pub trait NetworkInterface {
+    /// Sends a frame the guest transmitted.
+    fn send(&mut self, frame: &[u8]);
+
+    /// Takes the next frame for the guest, if one is waiting. Called often, so it must not
+    /// block.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+impl<N: NetworkInterface + ?Sized> NetworkInterface for Box<N> {
+    fn send(&mut self, frame: &[u8]) {
+        (**self).send(frame)
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        (**self).recv()
+    }
+}
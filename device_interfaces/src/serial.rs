@@ -1,5 +1,12 @@
+/// Host end of the UART. The emulated 16550A only reads characters from register 0 while
+/// bit 0 of register 5 reports one ready, and writes the characters it sends to register 0.
 pub trait SerialInterface {
     fn read(&self, addr: u32) -> u8;
 
     fn write(&self, addr: u32, v: u32);
+
+    /// Whether the host side asked to stop the emulator.
+    fn quit_requested(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,22 @@
+use std::io::{Read, Write};
+
+/// Host end of a vsock device, which connects guest streams to host programs.
+pub trait VsockHost {
+    /// Opens a stream to host `port` for the guest, or refuses the connection.
+    fn connect(&mut self, port: u32) -> Option<Box<dyn VsockStream>>;
+
+    /// Takes a stream a host program opened to guest `port`, if one is waiting. Called
+    /// often, so it must not block.
+    fn accept(&mut self) -> Option<(u32, Box<dyn VsockStream>)>;
+}
+
+/// A connection between a guest port and a host program. Reads and writes must not block:
+/// they fail with `WouldBlock` instead, and a read of 0 bytes means the host end closed.
+pub trait VsockStream: Read + Write {
+    /// The guest accepted a stream from [`VsockHost::accept`], which it sees coming from
+    /// host `port`.
+    fn established(&mut self, _port: u32) {}
+
+    /// The guest will send no more data.
+    fn close_write(&mut self) {}
+}
@@ -0,0 +1,9 @@
+/// Source of the date and time the guest sees.
+pub trait WallClock {
+    /// Nanoseconds since the UNIX epoch.
+    fn now(&mut self) -> u64;
+
+    /// Called with the bus steps that went by, for clocks that count them rather than
+    /// follow the host.
+    fn advance(&mut self, _steps: u64) {}
+}
@@ -0,0 +1,15 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The date and time of the host.
+#[derive(Debug, Default)]
+pub struct HostClock;
+
+impl device_interfaces::WallClock for HostClock {
+    fn now(&mut self) -> u64 {
+        // A host clock set before 1970 gives the epoch.
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        since_epoch.as_nanos() as u64
+    }
+}
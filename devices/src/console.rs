@@ -0,0 +1,237 @@
+//! Host ends of the ports of the guest virtio console.
+
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use crate::keyboard::{is_readable, read_kb_bytes};
+use crate::terminal::{self, RawMode};
+
+/// Binds a console port to the host terminal, size included.
+#[derive(Debug)]
+pub struct Terminal {
+    _raw_mode: RawMode,
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        terminal::watch_resize();
+        Self {
+            _raw_mode: RawMode::enable(),
+        }
+    }
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl device_interfaces::ConsolePort for Terminal {
+    fn write(&mut self, data: &[u8]) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(data).expect("failed to write stdout.");
+        stdout.flush().expect("failed to flush stdout.");
+    }
+
+    fn read(&mut self) -> Option<Vec<u8>> {
+        Some(read_kb_bytes()).filter(|input| !input.is_empty())
+    }
+
+    fn size(&self) -> Option<(u16, u16)> {
+        terminal::window_size()
+    }
+
+    fn resized(&mut self) -> bool {
+        terminal::take_resized()
+    }
+}
+
+/// A port nothing is connected to, for a console that only has named ports.
+#[derive(Debug, Default)]
+pub struct Unconnected;
+
+impl device_interfaces::ConsolePort for Unconnected {
+    fn write(&mut self, _data: &[u8]) {}
+
+    fn read(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Output kept for a client that is not reading. A client that falls further behind is
+/// disconnected, so that it cannot stall the guest.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Binds a console port to a Unix socket, with one client at a time. Output is dropped while
+/// no client is connected.
+#[derive(Debug)]
+pub struct Socket {
+    listener: UnixListener,
+    path: PathBuf,
+    client: Option<UnixStream>,
+    /// Output the client has not taken yet.
+    pending: Vec<u8>,
+}
+
+impl Socket {
+    /// Listens on `path`. Only a socket may already be there, which an earlier run left.
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        crate::remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            client: None,
+            pending: Vec::new(),
+        })
+    }
+
+    fn client(&mut self) -> Option<&mut UnixStream> {
+        if self.client.is_none() {
+            // Accepted sockets do not inherit non-blocking mode.
+            self.client = self
+                .listener
+                .accept()
+                .ok()
+                .map(|(stream, _)| stream)
+                .filter(|stream| stream.set_nonblocking(true).is_ok());
+        }
+        self.client.as_mut()
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.pending.clear();
+    }
+
+    /// Writes pending output as far as the client takes it.
+    fn flush(&mut self) {
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        while !self.pending.is_empty() {
+            match client.write(&self.pending) {
+                Ok(0) => return self.disconnect(),
+                Ok(len) => {
+                    self.pending.drain(..len);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return self.disconnect(),
+            }
+        }
+        if self.pending.len() > MAX_PENDING {
+            self.disconnect();
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl device_interfaces::ConsolePort for Socket {
+    fn write(&mut self, data: &[u8]) {
+        if self.client().is_some() {
+            self.pending.extend_from_slice(data);
+            self.flush();
+        }
+    }
+
+    fn read(&mut self) -> Option<Vec<u8>> {
+        // Output left over from earlier writes goes out as the client catches up.
+        self.flush();
+        let client = self.client()?;
+        if !is_readable(client.as_raw_fd()) {
+            return None;
+        }
+        let mut buffer = [0u8; 4096];
+        match client.read(&mut buffer) {
+            Ok(len) if len > 0 => Some(buffer[..len].to_vec()),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => None,
+            // The client went away, the next one may connect.
+            _ => {
+                self.disconnect();
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::ConsolePort;
+
+    /// A socket port talks to one client at a time and takes the next once it leaves.
+    #[test]
+    fn socket_clients() {
+        let path = std::env::temp_dir().join(format!("r2-console-{}", std::process::id()));
+        let mut port = Socket::listen(&path).unwrap();
+        // Nobody is listening yet.
+        port.write(b"lost");
+        assert_eq!(port.read(), None);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        port.write(b"hello");
+        let mut buffer = [0u8; 5];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(port.read(), None);
+        client.write_all(b"typed").unwrap();
+        assert_eq!(port.read().unwrap(), b"typed");
+
+        drop(client);
+        assert_eq!(port.read(), None);
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"again").unwrap();
+        assert_eq!(port.read().unwrap(), b"again");
+
+        drop(port);
+        assert!(!path.exists());
+    }
+
+    /// A client that stops reading is disconnected instead of blocking the guest.
+    #[test]
+    fn socket_slow_client() {
+        let path = std::env::temp_dir().join(format!("r2-console-slow-{}", std::process::id()));
+        let mut port = Socket::listen(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        port.write(b"start");
+        assert!(port.client.is_some());
+        for _ in 0..1024 {
+            port.write(&[b'x'; 1024]);
+        }
+        assert!(port.client.is_none());
+        assert!(port.pending.is_empty());
+        // What the socket took arrives in order, then the end of the stream.
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        assert!(output.starts_with(b"startxxx"));
+        assert!(output.len() < 5 + 1024 * 1024);
+
+        // The next client is served.
+        let mut client = UnixStream::connect(&path).unwrap();
+        port.write(b"next");
+        let mut buffer = [0u8; 4];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"next");
+    }
+
+    /// A file that is not a socket is not replaced.
+    #[test]
+    fn socket_path_taken() {
+        let path = std::env::temp_dir().join(format!("r2-console-file-{}", std::process::id()));
+        std::fs::write(&path, b"notes").unwrap();
+        let error = Socket::listen(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"notes");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
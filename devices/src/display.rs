@@ -0,0 +1,244 @@
+//! Host ends of the framebuffer.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Shows the screen nowhere, for guests that need a framebuffer to start.
+#[derive(Debug)]
+pub struct Headless;
+
+impl device_interfaces::Display for Headless {
+    fn refresh(&mut self, _width: u32, _height: u32, _rgba: &[u8]) {}
+}
+
+/// Saves the screen as PNG files: to `path` when the emulator exits and, if an interval is
+/// given, as numbered files next to it while the guest runs (`screen.png` gives
+/// `screen-0001.png`, `screen-0002.png`, ...).
+#[derive(Debug)]
+pub struct Screenshots {
+    path: PathBuf,
+    every: Option<Duration>,
+    last: Instant,
+    taken: u32,
+    /// Latest frame as width, height and RGBA pixels.
+    frame: Option<(u32, u32, Vec<u8>)>,
+}
+
+impl Screenshots {
+    pub fn new(path: &Path, every: Option<Duration>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            every,
+            last: Instant::now(),
+            taken: 0,
+            frame: None,
+        }
+    }
+
+    fn numbered_path(&self) -> PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(extension) => format!("{stem}-{:04}.{}", self.taken, extension.to_string_lossy()),
+            None => format!("{stem}-{:04}", self.taken),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let Some((width, height, rgba)) = &self.frame else {
+            return Ok(());
+        };
+        std::fs::write(path, encode_png(*width, *height, rgba))
+    }
+}
+
+impl device_interfaces::Display for Screenshots {
+    fn refresh(&mut self, width: u32, height: u32, rgba: &[u8]) {
+        self.frame = Some((width, height, rgba.to_vec()));
+        let Some(every) = self.every else {
+            return;
+        };
+        if self.last.elapsed() >= every {
+            self.last = Instant::now();
+            self.taken += 1;
+            if let Err(error) = self.save(&self.numbered_path()) {
+                eprintln!("failed to save a screenshot: {error}");
+            }
+        }
+    }
+}
+
+impl Drop for Screenshots {
+    fn drop(&mut self) {
+        if let Err(error) = self.save(&self.path) {
+            eprintln!("failed to save {}: {error}", self.path.display());
+        }
+    }
+}
+
+/// Encodes RGBA pixels as a PNG image. The data is stored rather than compressed, which
+/// keeps this short at the cost of larger files.
+/// @See https://www.w3.org/TR/png/
+fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    // Each row starts with its filter type, 0 for none.
+    let mut raw = Vec::with_capacity(rgba.len() + height as usize);
+    for row in rgba.chunks_exact(width as usize * 4) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut ihdr = width.to_be_bytes().to_vec();
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, RGBA, deflate, adaptive filtering and no interlacing.
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &ihdr);
+    chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no dictionary.
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::Display;
+
+    /// Splits a PNG file into its chunks, checking their CRCs.
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        let mut rest = &png[8..];
+        let mut chunks = Vec::new();
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc32(&rest[4..8 + len]), crc);
+            chunks.push((rest[4..8].try_into().unwrap(), rest[8..8 + len].to_vec()));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    /// Reads back a zlib stream of stored blocks.
+    fn unstore(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        let mut rest = &zlib[2..];
+        let mut data = Vec::new();
+        loop {
+            let last = rest[0] == 1;
+            let len = u16::from_le_bytes([rest[1], rest[2]]);
+            assert_eq!(u16::from_le_bytes([rest[3], rest[4]]), !len);
+            data.extend_from_slice(&rest[5..5 + len as usize]);
+            rest = &rest[5 + len as usize..];
+            if last {
+                break;
+            }
+        }
+        assert_eq!(rest, adler32(&data).to_be_bytes());
+        data
+    }
+
+    /// CRC-32 and Adler-32 give the published check values.
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // Long enough for the sums to be reduced along the way.
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+    }
+
+    /// An image large enough to take several stored blocks comes back row by row.
+    #[test]
+    fn png() {
+        let (width, height) = (200, 100);
+        let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i % 251) as u8).collect();
+        let chunks = chunks(&encode_png(width, height, &rgba));
+        let kinds: Vec<_> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        let ihdr = &chunks[0].1;
+        assert_eq!(ihdr[..8], [0, 0, 0, 200, 0, 0, 0, 100]);
+        assert_eq!(ihdr[8..], [8, 6, 0, 0, 0]);
+        let raw = unstore(&chunks[1].1);
+        assert!(raw.len() > 0xffff);
+        let rows: Vec<_> = raw.chunks(1 + width as usize * 4).collect();
+        assert_eq!(rows.len(), height as usize);
+        for (row, pixels) in rows.iter().zip(rgba.chunks(width as usize * 4)) {
+            assert_eq!(row[0], 0);
+            assert_eq!(row[1..], *pixels);
+        }
+        assert!(chunks[2].1.is_empty());
+    }
+
+    /// Frames are saved as numbered files while the guest runs, and the last one on exit.
+    #[test]
+    fn screenshots() {
+        let dir = std::env::temp_dir().join(format!("r2-screenshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut screenshots = Screenshots::new(&dir.join("screen.png"), Some(Duration::ZERO));
+        screenshots.refresh(1, 1, &[1, 2, 3, 255]);
+        screenshots.refresh(1, 1, &[4, 5, 6, 255]);
+        drop(screenshots);
+        let pixel = |name: &str| {
+            let png = std::fs::read(dir.join(name)).unwrap();
+            unstore(&chunks(&png)[1].1)
+        };
+        assert_eq!(pixel("screen-0001.png"), [0, 1, 2, 3, 255]);
+        assert_eq!(pixel("screen-0002.png"), [0, 4, 5, 6, 255]);
+        assert_eq!(pixel("screen.png"), [0, 4, 5, 6, 255]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Entropy from the host kernel.
+#[derive(Debug)]
+pub struct HostEntropy {
+    urandom: File,
+}
+
+impl HostEntropy {
+    pub fn open() -> io::Result<Self> {
+        Ok(Self {
+            urandom: File::open("/dev/urandom")?,
+        })
+    }
+}
+
+impl device_interfaces::EntropySource for HostEntropy {
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.urandom
+            .read_exact(buf)
+            .expect("failed to read /dev/urandom.");
+    }
+}
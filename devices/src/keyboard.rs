@@ -2,22 +2,31 @@ use libc::{poll, pollfd, read, POLLIN};
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::os::fd::AsRawFd;
-
-use crate::terminal;
+use std::time::{Duration, Instant};
 
 /// Prefix that introduces an emulator command instead of being sent to the guest.
 /// The terminal runs in raw mode, so this is the only way to leave the emulator
 /// from the keyboard.
 const COMMAND_PREFIX: u8 = 0x01; // Ctrl-A
 
-/// Terminates the emulator when it follows [`COMMAND_PREFIX`].
+/// Asks the emulator to stop when it follows [`COMMAND_PREFIX`].
 const QUIT_COMMAND: u8 = b'x';
 
+/// Calls of [`quit_requested`] between two looks at the clock. It is called on every bus
+/// step, so the clock is only read now and then.
+const QUIT_CHECK_CALLS: u32 = 1024;
+
+/// How often [`quit_requested`] reads the terminal, whether or not the guest does.
+const QUIT_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
 thread_local! {
     static INPUT: RefCell<Input> = const {
         RefCell::new(Input {
             pending: VecDeque::new(),
             prefixed: false,
+            quit: false,
+            calls: 0,
+            last_check: None,
         })
     };
 }
@@ -27,6 +36,13 @@ struct Input {
     pending: VecDeque<u8>,
     /// Whether the previous byte was [`COMMAND_PREFIX`].
     prefixed: bool,
+    /// Whether [`QUIT_COMMAND`] was typed. The emulator is left to shut down on its own so
+    /// that it can restore the terminal and report on the run.
+    quit: bool,
+    /// Calls of [`quit_requested`] since the clock was last read.
+    calls: u32,
+    /// When [`quit_requested`] last read the terminal.
+    last_check: Option<Instant>,
 }
 
 impl Input {
@@ -53,10 +69,7 @@ impl Input {
     fn push(&mut self, byte: u8) {
         if std::mem::take(&mut self.prefixed) {
             match byte {
-                QUIT_COMMAND => {
-                    terminal::restore();
-                    std::process::exit(0);
-                }
+                QUIT_COMMAND => self.quit = true,
                 // Ctrl-A Ctrl-A sends a literal Ctrl-A.
                 COMMAND_PREFIX => self.pending.push_back(COMMAND_PREFIX),
                 _ => self.pending.push_back(byte),
@@ -69,7 +82,7 @@ impl Input {
     }
 }
 
-fn is_readable(fd: i32) -> bool {
+pub(crate) fn is_readable(fd: i32) -> bool {
     let mut fds = pollfd {
         fd,
         events: POLLIN,
@@ -89,9 +102,70 @@ pub fn read_kb_byte() -> u32 {
     })
 }
 
+/// Takes all the input typed so far.
+pub fn read_kb_bytes() -> Vec<u8> {
+    INPUT.with_borrow_mut(|input| {
+        input.fill();
+        input.pending.drain(..).collect()
+    })
+}
+
 pub fn is_kb_hit() -> bool {
     INPUT.with_borrow_mut(|input| {
         input.fill();
         !input.pending.is_empty()
     })
 }
+
+/// Whether [`QUIT_COMMAND`] was typed. The terminal is read here too, so that a guest that
+/// hangs or never reads its console can still be quit.
+pub fn quit_requested() -> bool {
+    INPUT.with_borrow_mut(|input| {
+        input.calls += 1;
+        if input.calls >= QUIT_CHECK_CALLS {
+            input.calls = 0;
+            let now = Instant::now();
+            if input
+                .last_check
+                .is_none_or(|last| now - last >= QUIT_CHECK_INTERVAL)
+            {
+                input.last_check = Some(now);
+                input.fill();
+            }
+        }
+        input.quit
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(bytes: &[u8]) -> Input {
+        let mut input = Input {
+            pending: VecDeque::new(),
+            prefixed: false,
+            quit: false,
+            calls: 0,
+            last_check: None,
+        };
+        for byte in bytes {
+            input.push(*byte);
+        }
+        input
+    }
+
+    #[test]
+    fn quit_command() {
+        let input = typed(b"ls\x01x");
+        assert!(input.quit);
+        assert_eq!(input.pending, b"ls");
+    }
+
+    #[test]
+    fn literal_prefix() {
+        let input = typed(b"\x01\x01\x01a");
+        assert!(!input.quit);
+        assert_eq!(input.pending, b"\x01a");
+    }
+}
@@ -1,4 +1,29 @@
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+pub mod clock;
+pub mod console;
+pub mod display;
+pub mod entropy;
 pub mod keyboard;
+pub mod net;
+pub mod p9;
 pub mod terminal;
 pub mod timer;
 pub mod uart;
+pub mod vsock;
+
+/// Makes room to bind a Unix socket at `path`. A socket left behind by an earlier run is
+/// removed, but anything else found there is an error rather than deleted.
+pub(crate) fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
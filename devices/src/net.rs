@@ -0,0 +1,21 @@
+//! Host backends of the guest network card.
+
+mod nat;
+mod pcap;
+mod socket;
+
+pub use nat::Nat;
+pub use pcap::Pcap;
+pub use socket::Socket;
+
+/// A backend with no cable plugged in: frames are dropped and none arrive.
+#[derive(Debug, Default)]
+pub struct Unplugged;
+
+impl device_interfaces::NetworkInterface for Unplugged {
+    fn send(&mut self, _frame: &[u8]) {}
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
@@ -0,0 +1,955 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// The guest's network is 10.0.2.0/24, like QEMU's user networking.
+pub const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+/// Address standing for the host's loopback interface.
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER: usize = 14;
+const IPV4_HEADER: usize = 20;
+const TCP_HEADER: usize = 20;
+const UDP_HEADER: usize = 8;
+
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Largest IPv4 packet in a frame to the guest.
+const MTU: usize = 1500;
+/// Largest segment sent to the guest, and the MSS announced to it.
+const MSS: usize = MTU - IPV4_HEADER - TCP_HEADER;
+/// Largest UDP payload sent to the guest. Larger datagrams from the host are dropped, as a
+/// router would if it could not fragment them.
+const UDP_PAYLOAD: usize = MTU - IPV4_HEADER - UDP_HEADER;
+/// A UDP socket neither side has used for this long is closed.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// Data from the guest waiting for the host to take it. The window shrinks as it fills.
+const WINDOW: usize = 65535;
+/// Unacknowledged data is sent again after this long. Frames are only lost when the guest
+/// drops them, so there is no need for anything smarter.
+const RETRANSMIT: Duration = Duration::from_millis(500);
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_LEASE: u32 = 86400;
+
+/// Gives the guest access to the host's localhost ports: TCP and UDP sent to the gateway
+/// address go to the same port on 127.0.0.1. The gateway also answers ARP, ping and DHCP, so
+/// a guest configured with `ip=dhcp` just works. Nothing goes beyond the host.
+pub struct Nat {
+    /// Learned from the guest's frames.
+    guest_mac: [u8; 6],
+    /// Frames for the guest.
+    outbox: VecDeque<Vec<u8>>,
+    /// Keyed by guest port and host port.
+    udp: HashMap<(u16, u16), UdpFlow>,
+    tcp: HashMap<(u16, u16), Connection>,
+    ip_id: u16,
+    /// Initial sequence number of the next connection.
+    isn: u32,
+}
+
+/// A guest UDP port talking to a host port through a socket of its own.
+struct UdpFlow {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+/// A guest TCP connection proxied to a host socket. Sequence numbers are ours unless named
+/// after the guest.
+struct Connection {
+    stream: TcpStream,
+    /// Next sequence number to send.
+    seq: u32,
+    /// Oldest sequence number the guest has not acknowledged.
+    acked: u32,
+    /// Next sequence number expected from the guest.
+    guest_seq: u32,
+    /// Receive window the guest last announced.
+    guest_window: u32,
+    /// Sent data the guest has not acknowledged, starting at `acked`.
+    unacked: Vec<u8>,
+    /// Guest data the host has not taken yet.
+    to_host: Vec<u8>,
+    /// The SYN-ACK is not acknowledged yet.
+    handshake: bool,
+    /// The host closed its side and a FIN went to the guest.
+    fin_sent: bool,
+    /// The guest closed its side.
+    fin_received: bool,
+    last_progress: Instant,
+}
+
+impl Default for Nat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Nat {
+    pub fn new() -> Self {
+        Self {
+            guest_mac: BROADCAST_MAC,
+            outbox: VecDeque::new(),
+            udp: HashMap::new(),
+            tcp: HashMap::new(),
+            ip_id: 0,
+            isn: 0x5232_0000,
+        }
+    }
+
+    fn arp(&mut self, packet: &[u8]) {
+        // Only Ethernet/IPv4 requests.
+        if packet.len() < 28 || packet[0..8] != [0, 1, 8, 0, 6, 4, 0, 1] {
+            return;
+        }
+        let target = &packet[24..28];
+        // Every other address of the network lives on the gateway.
+        if target == GUEST.octets() || target[..3] != GATEWAY.octets()[..3] {
+            return;
+        }
+        let mut reply = ethernet_header(self.guest_mac, ETHERTYPE_ARP);
+        reply.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 2]);
+        reply.extend_from_slice(&GATEWAY_MAC);
+        reply.extend_from_slice(target);
+        reply.extend_from_slice(&packet[8..18]);
+        self.outbox.push_back(reply);
+    }
+
+    fn ipv4(&mut self, packet: &[u8]) {
+        if packet.len() < IPV4_HEADER || packet[0] >> 4 != 4 {
+            return;
+        }
+        let header_len = ((packet[0] & 0xf) as usize) * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        // Fragments are not reassembled: the guest has no reason to send any.
+        let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+        if header_len < IPV4_HEADER || total_len < header_len || total_len > packet.len() {
+            return;
+        }
+        if fragmented {
+            return;
+        }
+        let protocol = packet[9];
+        let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let payload = &packet[header_len..total_len];
+        match protocol {
+            PROTOCOL_UDP if payload.len() >= UDP_HEADER => {
+                let source_port = u16::from_be_bytes([payload[0], payload[1]]);
+                let port = u16::from_be_bytes([payload[2], payload[3]]);
+                if port == DHCP_SERVER_PORT {
+                    self.dhcp(&payload[UDP_HEADER..]);
+                } else if destination == GATEWAY {
+                    self.udp_send(source_port, port, &payload[UDP_HEADER..]);
+                }
+            }
+            _ if destination != GATEWAY => {}
+            PROTOCOL_ICMP => self.icmp(payload),
+            PROTOCOL_TCP if payload.len() >= TCP_HEADER => self.tcp(payload),
+            _ => {}
+        }
+    }
+
+    /// Answers echo requests sent to the gateway.
+    fn icmp(&mut self, message: &[u8]) {
+        if message.len() < 8 || message[0] != 8 {
+            return;
+        }
+        let mut reply = message.to_vec();
+        reply[0] = 0;
+        reply[2..4].fill(0);
+        let sum = checksum(&reply, 0);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+        self.send_ipv4(PROTOCOL_ICMP, GATEWAY, GUEST, &reply);
+    }
+
+    /// Hands out the guest address: offers it to any discover and acknowledges any request.
+    /// @See https://datatracker.ietf.org/doc/html/rfc2131
+    fn dhcp(&mut self, message: &[u8]) {
+        if message.len() < 240 || message[0] != 1 || message[236..240] != DHCP_MAGIC {
+            return;
+        }
+        let mut kind = None;
+        let mut options = &message[240..];
+        while let [code, rest @ ..] = options {
+            match code {
+                0 => options = rest,
+                255 => break,
+                _ => {
+                    let Some((&len, rest)) = rest.split_first() else {
+                        break;
+                    };
+                    let Some(value) = rest.get(..len as usize) else {
+                        break;
+                    };
+                    if *code == 53 && len == 1 {
+                        kind = Some(value[0]);
+                    }
+                    options = &rest[len as usize..];
+                }
+            }
+        }
+        let reply_kind = match kind {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => return,
+        };
+        let mut reply = vec![0u8; 236];
+        // BOOTREPLY over Ethernet, same transaction and client.
+        reply[0..3].copy_from_slice(&[2, 1, 6]);
+        reply[4..8].copy_from_slice(&message[4..8]);
+        reply[16..20].copy_from_slice(&GUEST.octets());
+        reply[20..24].copy_from_slice(&GATEWAY.octets());
+        reply[28..44].copy_from_slice(&message[28..44]);
+        reply.extend_from_slice(&DHCP_MAGIC);
+        reply.extend_from_slice(&[53, 1, reply_kind]);
+        reply.extend_from_slice(&[54, 4]);
+        reply.extend_from_slice(&GATEWAY.octets());
+        reply.extend_from_slice(&[51, 4]);
+        reply.extend_from_slice(&DHCP_LEASE.to_be_bytes());
+        reply.extend_from_slice(&[1, 4]);
+        reply.extend_from_slice(&NETMASK.octets());
+        reply.extend_from_slice(&[3, 4]);
+        reply.extend_from_slice(&GATEWAY.octets());
+        reply.push(255);
+        let Some(datagram) =
+            udp_datagram(DHCP_SERVER_PORT, DHCP_CLIENT_PORT, GATEWAY, GUEST, &reply)
+        else {
+            return;
+        };
+        // The client has no address yet, so the reply is broadcast.
+        let broadcast = Ipv4Addr::BROADCAST;
+        let frame = self.ipv4_frame(BROADCAST_MAC, PROTOCOL_UDP, GATEWAY, broadcast, &datagram);
+        self.outbox.extend(frame);
+    }
+
+    fn udp_send(&mut self, guest_port: u16, port: u16, data: &[u8]) {
+        let flow = match self.udp.entry((guest_port, port)) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let Ok(socket) = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)) else {
+                    return;
+                };
+                if socket.connect((Ipv4Addr::LOCALHOST, port)).is_err()
+                    || socket.set_nonblocking(true).is_err()
+                {
+                    return;
+                }
+                entry.insert(UdpFlow {
+                    socket,
+                    last_used: Instant::now(),
+                })
+            }
+        };
+        flow.last_used = Instant::now();
+        // UDP promises nothing, so neither does the NAT.
+        let _ = flow.socket.send(data);
+    }
+
+    fn udp_poll(&mut self) {
+        // One byte more than fits, to tell the datagrams that don't from the ones that do.
+        let mut buffer = [0u8; UDP_PAYLOAD + 1];
+        let mut datagrams = Vec::new();
+        for (&(guest_port, port), flow) in &mut self.udp {
+            // Errors are mostly ICMP port unreachable from an earlier send.
+            while let Ok(len) = flow.socket.recv(&mut buffer) {
+                flow.last_used = Instant::now();
+                if len <= UDP_PAYLOAD {
+                    datagrams.extend(udp_datagram(
+                        port,
+                        guest_port,
+                        GATEWAY,
+                        GUEST,
+                        &buffer[..len],
+                    ));
+                }
+            }
+        }
+        self.udp
+            .retain(|_, flow| flow.last_used.elapsed() < UDP_TIMEOUT);
+        for datagram in datagrams {
+            self.send_ipv4(PROTOCOL_UDP, GATEWAY, GUEST, &datagram);
+        }
+    }
+
+    fn tcp(&mut self, segment: &[u8]) {
+        let guest_port = u16::from_be_bytes([segment[0], segment[1]]);
+        let port = u16::from_be_bytes([segment[2], segment[3]]);
+        let seq = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+        let ack = u32::from_be_bytes(segment[8..12].try_into().unwrap());
+        let data_offset = ((segment[12] >> 4) as usize) * 4;
+        let flags = segment[13];
+        let window = u16::from_be_bytes([segment[14], segment[15]]) as u32;
+        if data_offset < TCP_HEADER || data_offset > segment.len() {
+            return;
+        }
+        let data = &segment[data_offset..];
+        let key = (guest_port, port);
+
+        if flags & TCP_RST != 0 {
+            self.tcp.remove(&key);
+            return;
+        }
+        if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+            match self.tcp.get(&key) {
+                // A retransmitted SYN: the SYN-ACK got lost.
+                Some(connection) if connection.handshake => {
+                    let isn = connection.acked;
+                    let guest_seq = connection.guest_seq;
+                    self.send_syn_ack(key, isn, guest_seq);
+                }
+                Some(_) => {}
+                None => self.tcp_connect(key, seq, window),
+            }
+            return;
+        }
+        let Some(connection) = self.tcp.get_mut(&key) else {
+            // Nothing to talk to, like after a restart of the emulator.
+            if flags & TCP_ACK != 0 {
+                self.send_tcp(key, ack, 0, TCP_RST, &[]);
+            }
+            return;
+        };
+        if flags & TCP_ACK != 0 {
+            connection.acknowledge(ack, window);
+        }
+        if !data.is_empty() || flags & TCP_FIN != 0 {
+            // Out of order or more than fits: the guest retransmits what was not
+            // acknowledged.
+            let room = WINDOW.saturating_sub(connection.to_host.len());
+            if seq == connection.guest_seq && data.len() <= room && !connection.fin_received {
+                connection.to_host.extend_from_slice(data);
+                connection.guest_seq = connection.guest_seq.wrapping_add(data.len() as u32);
+                if flags & TCP_FIN != 0 {
+                    connection.guest_seq = connection.guest_seq.wrapping_add(1);
+                    connection.fin_received = true;
+                }
+                // A failure resets the connection on the next poll.
+                let _ = connection.flush();
+            }
+            let (seq, ack) = (connection.seq, connection.guest_seq);
+            let window = connection.window();
+            self.send_segment(key, seq, ack, TCP_ACK, window, &[]);
+        }
+        self.tcp_close_if_done(key);
+    }
+
+    fn tcp_connect(&mut self, key: (u16, u16), guest_isn: u32, window: u32) {
+        let guest_seq = guest_isn.wrapping_add(1);
+        // Connections to localhost complete or fail right away.
+        let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, key.1)).and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        });
+        let Ok(stream) = stream else {
+            self.send_tcp(key, 0, guest_seq, TCP_RST | TCP_ACK, &[]);
+            return;
+        };
+        let isn = self.isn;
+        self.isn = self.isn.wrapping_add(0x0001_0000);
+        self.tcp.insert(
+            key,
+            Connection {
+                stream,
+                seq: isn.wrapping_add(1),
+                acked: isn,
+                guest_seq,
+                guest_window: window,
+                unacked: Vec::new(),
+                to_host: Vec::new(),
+                handshake: true,
+                fin_sent: false,
+                fin_received: false,
+                last_progress: Instant::now(),
+            },
+        );
+        self.send_syn_ack(key, isn, guest_seq);
+    }
+
+    fn send_syn_ack(&mut self, key: (u16, u16), isn: u32, guest_seq: u32) {
+        // The MSS option, so the guest keeps its segments within one frame.
+        let mut options = vec![2, 4];
+        options.extend_from_slice(&(MSS as u16).to_be_bytes());
+        let segment = tcp_segment(
+            key,
+            isn,
+            guest_seq,
+            TCP_SYN | TCP_ACK,
+            WINDOW as u16,
+            &options,
+            &[],
+        );
+        self.send_ipv4(PROTOCOL_TCP, GATEWAY, GUEST, &segment);
+    }
+
+    fn send_tcp(&mut self, key: (u16, u16), seq: u32, ack: u32, flags: u8, data: &[u8]) {
+        self.send_segment(key, seq, ack, flags, WINDOW as u16, data);
+    }
+
+    fn send_segment(
+        &mut self,
+        key: (u16, u16),
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        window: u16,
+        data: &[u8],
+    ) {
+        let segment = tcp_segment(key, seq, ack, flags, window, &[], data);
+        self.send_ipv4(PROTOCOL_TCP, GATEWAY, GUEST, &segment);
+    }
+
+    /// Moves data between the host sockets and the guest.
+    fn tcp_poll(&mut self) {
+        let keys: Vec<_> = self.tcp.keys().copied().collect();
+        for key in keys {
+            let Some(connection) = self.tcp.get_mut(&key) else {
+                continue;
+            };
+            let segments = match connection.flush().and_then(|_| connection.pull()) {
+                Ok(segments) => segments,
+                Err(_) => {
+                    let (seq, ack) = (connection.seq, connection.guest_seq);
+                    self.tcp.remove(&key);
+                    self.send_tcp(key, seq, ack, TCP_RST | TCP_ACK, &[]);
+                    continue;
+                }
+            };
+            for (seq, flags, data) in segments {
+                let connection = &self.tcp[&key];
+                let (ack, window) = (connection.guest_seq, connection.window());
+                self.send_segment(key, seq, ack, flags, window, &data);
+            }
+            self.tcp_close_if_done(key);
+        }
+    }
+
+    /// Forgets a connection both sides have closed.
+    fn tcp_close_if_done(&mut self, key: (u16, u16)) {
+        let done = self.tcp.get(&key).is_some_and(|connection| {
+            connection.fin_sent && connection.fin_received && connection.acked == connection.seq
+        });
+        if done {
+            self.tcp.remove(&key);
+        }
+    }
+
+    fn send_ipv4(&mut self, protocol: u8, source: Ipv4Addr, destination: Ipv4Addr, data: &[u8]) {
+        let frame = self.ipv4_frame(self.guest_mac, protocol, source, destination, data);
+        self.outbox.extend(frame);
+    }
+
+    /// Wraps `data` in an IPv4 packet and an Ethernet frame, or returns `None` if it does not
+    /// fit in a packet.
+    fn ipv4_frame(
+        &mut self,
+        mac: [u8; 6],
+        protocol: u8,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let len = u16::try_from(IPV4_HEADER + data.len()).ok()?;
+        self.ip_id = self.ip_id.wrapping_add(1);
+        let mut header = [0u8; IPV4_HEADER];
+        header[0] = 0x45;
+        header[2..4].copy_from_slice(&len.to_be_bytes());
+        header[4..6].copy_from_slice(&self.ip_id.to_be_bytes());
+        // Don't fragment.
+        header[6] = 0x40;
+        header[8] = 64;
+        header[9] = protocol;
+        header[12..16].copy_from_slice(&source.octets());
+        header[16..20].copy_from_slice(&destination.octets());
+        let sum = checksum(&header, 0);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+        let mut frame = ethernet_header(mac, ETHERTYPE_IPV4);
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(data);
+        Some(frame)
+    }
+}
+
+impl Connection {
+    /// Window announced to the guest.
+    fn window(&self) -> u16 {
+        WINDOW.saturating_sub(self.to_host.len()) as u16
+    }
+
+    fn in_flight(&self) -> u32 {
+        self.seq.wrapping_sub(self.acked)
+    }
+
+    fn acknowledge(&mut self, ack: u32, window: u32) {
+        self.guest_window = window;
+        let advance = ack.wrapping_sub(self.acked);
+        // Ignore duplicates and acknowledgements of data never sent.
+        if advance == 0 || advance > self.in_flight() {
+            return;
+        }
+        if self.handshake {
+            self.handshake = false;
+        } else {
+            // A FIN is acknowledged like a byte but never was in `unacked`.
+            let len = (advance as usize).min(self.unacked.len());
+            self.unacked.drain(..len);
+        }
+        self.acked = ack;
+        self.last_progress = Instant::now();
+    }
+
+    /// Writes the guest's data to the host as far as it takes it.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.to_host.is_empty() {
+            match self.stream.write(&self.to_host) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.to_host.drain(..len);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+        if self.fin_received {
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+
+    /// Reads what the guest's window allows from the host and returns the segments to send,
+    /// as sequence number, flags and data. Unacknowledged data is sent again when stale.
+    fn pull(&mut self) -> io::Result<Vec<(u32, u8, Vec<u8>)>> {
+        let mut segments = Vec::new();
+        if self.handshake {
+            return Ok(segments);
+        }
+        if self.in_flight() != 0 && self.last_progress.elapsed() >= RETRANSMIT {
+            self.last_progress = Instant::now();
+            let mut seq = self.acked;
+            for chunk in self.unacked.chunks(MSS) {
+                segments.push((seq, TCP_ACK | TCP_PSH, chunk.to_vec()));
+                seq = seq.wrapping_add(chunk.len() as u32);
+            }
+            if self.fin_sent {
+                segments.push((seq, TCP_FIN | TCP_ACK, Vec::new()));
+            }
+        }
+        let mut buffer = [0u8; MSS];
+        while !self.fin_sent {
+            let room = self.guest_window.saturating_sub(self.in_flight()) as usize;
+            if room == 0 {
+                break;
+            }
+            let len = room.min(MSS);
+            match self.stream.read(&mut buffer[..len]) {
+                Ok(0) => {
+                    segments.push((self.seq, TCP_FIN | TCP_ACK, Vec::new()));
+                    self.seq = self.seq.wrapping_add(1);
+                    self.fin_sent = true;
+                }
+                Ok(len) => {
+                    if self.in_flight() == 0 {
+                        self.last_progress = Instant::now();
+                    }
+                    segments.push((self.seq, TCP_ACK | TCP_PSH, buffer[..len].to_vec()));
+                    self.unacked.extend_from_slice(&buffer[..len]);
+                    self.seq = self.seq.wrapping_add(len as u32);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(segments)
+    }
+}
+
+impl device_interfaces::NetworkInterface for Nat {
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER {
+            return;
+        }
+        self.guest_mac.copy_from_slice(&frame[6..12]);
+        let payload = &frame[ETHERNET_HEADER..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.arp(payload),
+            ETHERTYPE_IPV4 => self.ipv4(payload),
+            _ => {}
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        if self.outbox.is_empty() {
+            self.udp_poll();
+            self.tcp_poll();
+        }
+        self.outbox.pop_front()
+    }
+}
+
+fn ethernet_header(destination: [u8; 6], ethertype: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(1514);
+    header.extend_from_slice(&destination);
+    header.extend_from_slice(&GATEWAY_MAC);
+    header.extend_from_slice(&ethertype.to_be_bytes());
+    header
+}
+
+/// Builds a datagram, or returns `None` if `data` is too long for one.
+fn udp_datagram(
+    source_port: u16,
+    port: u16,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    let len = u16::try_from(UDP_HEADER + data.len()).ok()?;
+    let mut datagram = Vec::with_capacity(len as usize);
+    datagram.extend_from_slice(&source_port.to_be_bytes());
+    datagram.extend_from_slice(&port.to_be_bytes());
+    datagram.extend_from_slice(&len.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let sum = checksum(
+        &datagram,
+        pseudo_header(source, destination, PROTOCOL_UDP, len),
+    );
+    // Zero means no checksum, so a computed zero is sent as its complement.
+    let sum = if sum == 0 { 0xffff } else { sum };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    Some(datagram)
+}
+
+/// Builds a segment from the gateway port `key.1` to the guest port `key.0`.
+fn tcp_segment(
+    key: (u16, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    options: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let header_len = TCP_HEADER + options.len();
+    let mut segment = Vec::with_capacity(header_len + data.len());
+    segment.extend_from_slice(&key.1.to_be_bytes());
+    segment.extend_from_slice(&key.0.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push(((header_len / 4) as u8) << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&window.to_be_bytes());
+    // Checksum and urgent pointer.
+    segment.extend_from_slice(&[0; 4]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(data);
+    let len = segment.len() as u16;
+    let sum = checksum(&segment, pseudo_header(GATEWAY, GUEST, PROTOCOL_TCP, len));
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+/// Sum of the IPv4 pseudo header covered by TCP and UDP checksums.
+fn pseudo_header(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, len: u16) -> u32 {
+    let [a, b, c, d] = source.octets();
+    let [e, f, g, h] = destination.octets();
+    [[a, b], [c, d], [e, f], [g, h], [0, protocol]]
+        .iter()
+        .map(|word| u16::from_be_bytes(*word) as u32)
+        .sum::<u32>()
+        + len as u32
+}
+
+/// Internet checksum of `data`, starting from the partial sum `initial`.
+/// @See https://datatracker.ietf.org/doc/html/rfc1071
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    for word in data.chunks(2) {
+        let high = word[0] as u32;
+        let low = word.get(1).copied().unwrap_or(0) as u32;
+        sum += (high << 8) | low;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::NetworkInterface;
+    use std::net::TcpListener;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    /// Frame from the guest carrying `payload`.
+    fn frame(ethertype: u16, destination: [u8; 6], payload: &[u8]) -> Vec<u8> {
+        let mut frame = destination.to_vec();
+        frame.extend_from_slice(&GUEST_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// IPv4 frame from the guest to `destination`. The NAT ignores checksums, so they are
+    /// left out.
+    fn ipv4(protocol: u8, destination: Ipv4Addr, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; IPV4_HEADER];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&((IPV4_HEADER + data.len()) as u16).to_be_bytes());
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&GUEST.octets());
+        packet[16..20].copy_from_slice(&destination.octets());
+        packet.extend_from_slice(data);
+        frame(ETHERTYPE_IPV4, GATEWAY_MAC, &packet)
+    }
+
+    fn udp(guest_port: u16, port: u16, data: &[u8]) -> Vec<u8> {
+        let mut datagram = guest_port.to_be_bytes().to_vec();
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&((UDP_HEADER + data.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        ipv4(PROTOCOL_UDP, GATEWAY, &datagram)
+    }
+
+    fn tcp(guest_port: u16, port: u16, seq: u32, ack: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = guest_port.to_be_bytes().to_vec();
+        segment.extend_from_slice(&port.to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.extend_from_slice(&[(TCP_HEADER as u8 / 4) << 4, flags, 0xff, 0xff]);
+        segment.extend_from_slice(&[0; 4]);
+        segment.extend_from_slice(data);
+        ipv4(PROTOCOL_TCP, GATEWAY, &segment)
+    }
+
+    /// Polls until the NAT has a frame for the guest, which must be IPv4 from the gateway,
+    /// and returns the protocol and the payload of the packet.
+    fn receive(nat: &mut Nat) -> (u8, Vec<u8>) {
+        let start = Instant::now();
+        let frame = loop {
+            if let Some(frame) = nat.recv() {
+                break frame;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no frame");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(frame[0..6], GUEST_MAC);
+        assert_eq!(frame[6..12], GATEWAY_MAC);
+        assert_eq!(frame[12..14], ETHERTYPE_IPV4.to_be_bytes());
+        let packet = &frame[ETHERNET_HEADER..];
+        assert_eq!(checksum(&packet[..IPV4_HEADER], 0), 0);
+        let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        assert_eq!(len, packet.len());
+        assert!(len <= MTU);
+        assert_eq!(packet[12..16], GATEWAY.octets());
+        (packet[9], packet[IPV4_HEADER..].to_vec())
+    }
+
+    /// Checksum of a TCP or UDP payload from the gateway to the guest, zero when right.
+    fn transport_checksum(protocol: u8, data: &[u8]) -> u16 {
+        let pseudo = pseudo_header(GATEWAY, GUEST, protocol, data.len() as u16);
+        checksum(data, pseudo)
+    }
+
+    /// ARP requests are answered for addresses of the network other than the guest's.
+    #[test]
+    fn arp() {
+        let mut nat = Nat::new();
+        let request = |target: Ipv4Addr| {
+            let mut packet = vec![0, 1, 8, 0, 6, 4, 0, 1];
+            packet.extend_from_slice(&GUEST_MAC);
+            packet.extend_from_slice(&GUEST.octets());
+            packet.extend_from_slice(&[0; 6]);
+            packet.extend_from_slice(&target.octets());
+            frame(ETHERTYPE_ARP, BROADCAST_MAC, &packet)
+        };
+        nat.send(&request(GUEST));
+        assert_eq!(nat.recv(), None);
+        nat.send(&request(GATEWAY));
+        let reply = nat.recv().unwrap();
+        assert_eq!(reply[0..6], GUEST_MAC);
+        assert_eq!(reply[12..14], ETHERTYPE_ARP.to_be_bytes());
+        assert_eq!(reply[20..22], [0, 2]);
+        assert_eq!(reply[22..28], GATEWAY_MAC);
+        assert_eq!(reply[28..32], GATEWAY.octets());
+        assert_eq!(reply[32..38], GUEST_MAC);
+        assert_eq!(reply[38..42], GUEST.octets());
+    }
+
+    /// The gateway answers pings.
+    #[test]
+    fn ping() {
+        let mut nat = Nat::new();
+        let mut request = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1];
+        request.extend_from_slice(b"ping");
+        nat.send(&ipv4(PROTOCOL_ICMP, GATEWAY, &request));
+        let (protocol, reply) = receive(&mut nat);
+        assert_eq!(protocol, PROTOCOL_ICMP);
+        assert_eq!(reply[0], 0);
+        assert_eq!(checksum(&reply, 0), 0);
+        assert_eq!(reply[4..], request[4..]);
+        assert_eq!(nat.recv(), None);
+    }
+
+    /// A DHCP discover is offered the guest address, broadcast.
+    #[test]
+    fn dhcp() {
+        let mut nat = Nat::new();
+        let mut message = vec![0u8; 240];
+        message[0..3].copy_from_slice(&[1, 1, 6]);
+        message[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        message[28..34].copy_from_slice(&GUEST_MAC);
+        message[236..240].copy_from_slice(&DHCP_MAGIC);
+        message.extend_from_slice(&[53, 1, DHCP_DISCOVER, 255]);
+        let mut datagram = DHCP_CLIENT_PORT.to_be_bytes().to_vec();
+        datagram.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+        datagram.extend_from_slice(&((UDP_HEADER + message.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(&message);
+        nat.send(&ipv4(PROTOCOL_UDP, Ipv4Addr::BROADCAST, &datagram));
+
+        let frame = nat.recv().unwrap();
+        assert_eq!(frame[0..6], BROADCAST_MAC);
+        let packet = &frame[ETHERNET_HEADER..];
+        assert_eq!(packet[16..20], Ipv4Addr::BROADCAST.octets());
+        let reply = &packet[IPV4_HEADER + UDP_HEADER..];
+        assert_eq!(reply[0], 2);
+        assert_eq!(reply[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(reply[16..20], GUEST.octets());
+        assert_eq!(reply[240..243], [53, 1, DHCP_OFFER]);
+    }
+
+    /// UDP to the gateway reaches localhost and the answer comes back.
+    #[test]
+    fn udp_round_trip() {
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let mut nat = Nat::new();
+        nat.send(&udp(5000, port, b"question"));
+        let mut buffer = [0u8; 64];
+        let (len, peer) = host.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"question");
+
+        host.send_to(b"answer", peer).unwrap();
+        let (protocol, datagram) = receive(&mut nat);
+        assert_eq!(protocol, PROTOCOL_UDP);
+        assert_eq!(transport_checksum(PROTOCOL_UDP, &datagram), 0);
+        assert_eq!(datagram[0..2], port.to_be_bytes());
+        assert_eq!(datagram[2..4], 5000u16.to_be_bytes());
+        assert_eq!(&datagram[UDP_HEADER..], b"answer");
+    }
+
+    /// Datagrams from the host that don't fit in a frame are dropped.
+    #[test]
+    fn udp_too_large() {
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let mut nat = Nat::new();
+        nat.send(&udp(5000, port, b"question"));
+        let (_, peer) = host.recv_from(&mut [0u8; 64]).unwrap();
+
+        host.send_to(&[0x55; UDP_PAYLOAD + 1], peer).unwrap();
+        host.send_to(&[0xaa; UDP_PAYLOAD], peer).unwrap();
+        let (_, datagram) = receive(&mut nat);
+        assert_eq!(datagram[UDP_HEADER..], [0xaa; UDP_PAYLOAD]);
+        assert_eq!(nat.recv(), None);
+    }
+
+    /// Lengths that don't fit in the headers are refused instead of wrapping around.
+    #[test]
+    fn too_long_for_headers() {
+        let data = vec![0u8; 65535 - UDP_HEADER + 1];
+        assert_eq!(udp_datagram(1, 2, GATEWAY, GUEST, &data), None);
+        assert!(udp_datagram(1, 2, GATEWAY, GUEST, &data[1..]).is_some());
+        let mut nat = Nat::new();
+        let data = vec![0u8; 65535 - IPV4_HEADER + 1];
+        assert_eq!(
+            nat.ipv4_frame(GUEST_MAC, PROTOCOL_UDP, GATEWAY, GUEST, &data),
+            None
+        );
+    }
+
+    /// Sockets neither side has used for a while are closed.
+    #[test]
+    fn udp_timeout() {
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = host.local_addr().unwrap().port();
+        let mut nat = Nat::new();
+        nat.send(&udp(5000, port, b"question"));
+        assert_eq!(nat.recv(), None);
+        assert_eq!(nat.udp.len(), 1);
+
+        let flow = nat.udp.get_mut(&(5000, port)).unwrap();
+        flow.last_used = Instant::now().checked_sub(UDP_TIMEOUT).unwrap();
+        assert_eq!(nat.recv(), None);
+        assert!(nat.udp.is_empty());
+    }
+
+    /// A TCP connection to the gateway is proxied to localhost both ways, and one to a
+    /// closed port is reset.
+    #[test]
+    fn tcp_connection() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut nat = Nat::new();
+        nat.send(&tcp(5000, port, 100, 0, TCP_SYN, &[]));
+        let (protocol, syn_ack) = receive(&mut nat);
+        assert_eq!(protocol, PROTOCOL_TCP);
+        assert_eq!(transport_checksum(PROTOCOL_TCP, &syn_ack), 0);
+        assert_eq!(syn_ack[13], TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack[8..12], 101u32.to_be_bytes());
+        // The MSS option.
+        assert_eq!(syn_ack[20..24], [2, 4, 0x05, 0xb4]);
+        let isn = u32::from_be_bytes(syn_ack[4..8].try_into().unwrap());
+        nat.send(&tcp(5000, port, 101, isn + 1, TCP_ACK, &[]));
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        nat.send(&tcp(5000, port, 101, isn + 1, TCP_ACK | TCP_PSH, b"hello"));
+        let (_, ack) = receive(&mut nat);
+        assert_eq!(ack[13], TCP_ACK);
+        assert_eq!(ack[8..12], 106u32.to_be_bytes());
+        let mut buffer = [0u8; 5];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        stream.write_all(b"world").unwrap();
+        let (_, segment) = receive(&mut nat);
+        assert_eq!(transport_checksum(PROTOCOL_TCP, &segment), 0);
+        assert_eq!(segment[4..8], (isn + 1).to_be_bytes());
+        assert_eq!(&segment[TCP_HEADER..], b"world");
+
+        drop(listener);
+        nat.send(&tcp(5001, port, 100, 0, TCP_SYN, &[]));
+        let (_, reset) = receive(&mut nat);
+        assert_eq!(reset[13], TCP_RST | TCP_ACK);
+        assert_eq!(reset[8..12], 101u32.to_be_bytes());
+    }
+}
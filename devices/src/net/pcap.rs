@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Records the frames going through another backend in a pcap file, readable by
+/// tcpdump and Wireshark.
+/// @See https://wiki.wireshark.org/Development/LibpcapFileFormat
+#[derive(Debug)]
+pub struct Pcap<N> {
+    inner: N,
+    file: BufWriter<File>,
+}
+
+impl<N> Pcap<N> {
+    pub fn create(path: &Path, inner: N) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&MAGIC.to_le_bytes())?;
+        // Version 2.4.
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        // Timezone offset and timestamp accuracy, always 0.
+        file.write_all(&[0u8; 8])?;
+        file.write_all(&SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self { inner, file })
+    }
+
+    /// Appends a frame. Records are flushed right away so that the capture can be followed
+    /// while the guest runs.
+    fn record(&mut self, frame: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len().min(SNAPLEN as usize) as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame[..frame.len().min(SNAPLEN as usize)]);
+        // A full disk should not take the guest's network down.
+        let _ = self.file.write_all(&record).and_then(|_| self.file.flush());
+    }
+}
+
+impl<N: device_interfaces::NetworkInterface> device_interfaces::NetworkInterface for Pcap<N> {
+    fn send(&mut self, frame: &[u8]) {
+        self.record(frame);
+        self.inner.send(frame);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let frame = self.inner.recv()?;
+        self.record(&frame);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::NetworkInterface;
+    use std::collections::VecDeque;
+
+    /// Backend that sends frames back.
+    #[derive(Default)]
+    struct Echo(VecDeque<Vec<u8>>);
+
+    impl NetworkInterface for Echo {
+        fn send(&mut self, frame: &[u8]) {
+            self.0.push_back(frame.to_vec());
+        }
+
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.0.pop_front()
+        }
+    }
+
+    /// Frames both ways are recorded, after a header for Ethernet.
+    #[test]
+    fn capture() {
+        let path = std::env::temp_dir().join(format!("r2-pcap-{}", std::process::id()));
+        let mut pcap = Pcap::create(&path, Echo::default()).unwrap();
+        pcap.send(b"frame");
+        assert_eq!(pcap.recv().unwrap(), b"frame");
+        assert_eq!(pcap.recv(), None);
+        let capture = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let word = |i: usize| u32::from_le_bytes(capture[i..i + 4].try_into().unwrap());
+        assert_eq!(capture.len(), 24 + 2 * (16 + 5));
+        assert_eq!(word(0), MAGIC);
+        assert_eq!(capture[4..8], [2, 0, 4, 0]);
+        assert_eq!(word(16), SNAPLEN);
+        assert_eq!(word(20), LINKTYPE_ETHERNET);
+        for record in [24, 24 + 16 + 5] {
+            assert_eq!(word(record + 8), 5);
+            assert_eq!(word(record + 12), 5);
+            assert_eq!(&capture[record + 16..record + 21], b"frame");
+        }
+    }
+}
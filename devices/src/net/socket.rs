@@ -0,0 +1,90 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Exchanges frames with a peer over Unix datagram sockets, one frame per datagram. Two
+/// emulators pointed at each other's socket share a link.
+#[derive(Debug)]
+pub struct Socket {
+    socket: UnixDatagram,
+    path: PathBuf,
+    peer: PathBuf,
+}
+
+impl Socket {
+    /// Binds `path` and sends to `peer`. A stale socket at `path` is replaced, any other
+    /// file is left alone and refused.
+    pub fn bind(path: &Path, peer: &Path) -> io::Result<Self> {
+        crate::remove_stale_socket(path)?;
+        let socket = UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            path: path.to_path_buf(),
+            peer: peer.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl device_interfaces::NetworkInterface for Socket {
+    fn send(&mut self, frame: &[u8]) {
+        // Until the peer is up, frames are lost as on an unplugged cable.
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 65536];
+        let len = self.socket.recv(&mut buffer).ok()?;
+        Some(buffer[..len].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::NetworkInterface;
+
+    /// Two sockets pointed at each other exchange frames, and a socket left behind is
+    /// replaced.
+    #[test]
+    fn link() {
+        let dir = std::env::temp_dir();
+        let a = dir.join(format!("r2-socket-a-{}", std::process::id()));
+        let b = dir.join(format!("r2-socket-b-{}", std::process::id()));
+        // Left behind by an earlier run.
+        drop(UnixDatagram::bind(&a).unwrap());
+        let mut first = Socket::bind(&a, &b).unwrap();
+        // The peer is not up yet.
+        first.send(b"lost");
+        let mut second = Socket::bind(&b, &a).unwrap();
+        assert_eq!(second.recv(), None);
+
+        first.send(b"ping");
+        assert_eq!(second.recv().unwrap(), b"ping");
+        assert_eq!(second.recv(), None);
+        second.send(b"pong");
+        assert_eq!(first.recv().unwrap(), b"pong");
+
+        drop(first);
+        drop(second);
+        assert!(!a.exists());
+        assert!(!b.exists());
+    }
+
+    /// A file that is not a socket is not taken for one left behind.
+    #[test]
+    fn not_a_socket() {
+        let path = std::env::temp_dir().join(format!("r2-socket-file-{}", std::process::id()));
+        std::fs::write(&path, b"notes").unwrap();
+        let error = Socket::bind(&path, &path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"notes");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
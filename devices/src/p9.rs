@@ -0,0 +1,1124 @@
+//! 9P2000.L server exporting a host directory to the guest.
+//!
+//! The guest kernel resolves symbolic links itself, so walks never go through one here. The
+//! guest can still make links that point anywhere and hold fids for paths that have since
+//! become links, so the paths of fids are checked not to go through any before they are
+//! used, and files are opened without following links.
+//! @See https://github.com/chaos/diod/blob/master/protocol.md
+
+use std::collections::HashMap;
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TLERROR: u8 = 6;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION: &str = "9P2000.L";
+/// Largest message size offered to the guest.
+const MAX_MSIZE: u32 = 512 * 1024;
+/// Size of the header of every message: size, type and tag.
+const HEADER_SIZE: usize = 7;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+const DT_UNKNOWN: u8 = 0;
+
+/// Linux open flags, which 9P2000.L uses whatever the host.
+const L_O_ACCMODE: u32 = 0o3;
+const L_O_WRONLY: u32 = 0o1;
+const L_O_RDWR: u32 = 0o2;
+const L_O_TRUNC: u32 = 0o1000;
+const L_O_APPEND: u32 = 0o2000;
+
+/// The fields of `Rgetattr` that are filled.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// `AT_REMOVEDIR` of `unlinkat`.
+const AT_REMOVEDIR: u32 = 0x200;
+
+const LOCK_SUCCESS: u8 = 0;
+const F_UNLCK: u8 = 2;
+
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EEXIST: u32 = 17;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const ENOSYS: u32 = 38;
+const ELOOP: u32 = 40;
+const EOPNOTSUPP: u32 = 95;
+
+/// A file the guest refers to by number.
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+    /// Entries of an open directory, read when the guest starts listing it.
+    entries: Option<Vec<Entry>>,
+}
+
+struct Entry {
+    qid: Qid,
+    kind: u8,
+    name: String,
+}
+
+#[derive(Clone, Copy)]
+struct Qid {
+    kind: u8,
+    version: u32,
+    path: u64,
+}
+
+/// Serves the directory tree under a host directory.
+pub struct Share {
+    root: PathBuf,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Share {
+    pub fn new(root: &Path, read_only: bool) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self {
+            root,
+            read_only,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        match kind {
+            TVERSION => self.version(r),
+            TATTACH => self.attach(r),
+            TWALK => self.walk(r),
+            TCLUNK => {
+                self.fids.remove(&r.u32()?).ok_or(EBADF)?;
+                Ok(Vec::new())
+            }
+            TREMOVE => self.remove(r),
+            TFLUSH => Ok(Vec::new()),
+            TGETATTR => self.getattr(r),
+            TSETATTR => self.setattr(r),
+            TSTATFS => self.statfs(r),
+            TLOPEN => self.lopen(r),
+            TLCREATE => self.lcreate(r),
+            TREAD => self.read(r),
+            TWRITE => self.write(r),
+            TREADDIR => self.readdir(r),
+            TFSYNC => {
+                let fid = self.fid(r.u32()?)?;
+                match &fid.file {
+                    Some(file) => file.sync_all().map_err(errno)?,
+                    None => return Err(EBADF),
+                }
+                Ok(Vec::new())
+            }
+            TMKDIR => self.mkdir(r),
+            TSYMLINK => self.symlink(r),
+            TREADLINK => {
+                let path = self.path(r.u32()?)?;
+                let target = fs::read_link(path).map_err(errno)?;
+                let mut w = Writer::default();
+                w.str(&target.to_string_lossy());
+                Ok(w.0)
+            }
+            TLINK => self.link(r),
+            TRENAMEAT => self.renameat(r),
+            TRENAME => self.rename(r),
+            TUNLINKAT => self.unlinkat(r),
+            // Locks only matter between guests, and there is one.
+            TLOCK => Ok(vec![LOCK_SUCCESS]),
+            TGETLOCK => self.getlock(r),
+            TXATTRWALK | TXATTRCREATE => Err(EOPNOTSUPP),
+            TMKNOD => Err(EPERM),
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn version(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let msize = r.u32()?;
+        let version = r.str()?;
+        // A new session: everything from the last one is forgotten.
+        self.fids.clear();
+        self.msize = msize.min(MAX_MSIZE);
+        let mut w = Writer::default();
+        w.u32(self.msize);
+        w.str(if version == VERSION { VERSION } else { "unknown" });
+        Ok(w.0)
+    }
+
+    fn attach(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let qid = qid(&self.root)?;
+        self.insert(fid, self.root.clone())?;
+        let mut w = Writer::default();
+        w.qid(qid);
+        Ok(w.0)
+    }
+
+    fn walk(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let count = r.u16()?;
+        // Cloning a fid is fine whatever it is.
+        let mut path = match count {
+            0 => self.fid(fid)?.path.clone(),
+            _ => self.real_path(fid)?,
+        };
+        let mut qids = Vec::new();
+        for i in 0..count {
+            let name = r.str()?;
+            let next = match name.as_str() {
+                // The root is its own parent, as on any file system.
+                ".." if path == self.root => path.clone(),
+                ".." => path.parent().map_or(path.clone(), Path::to_path_buf),
+                "." => path.clone(),
+                _ => path.join(check_name(&name)?),
+            };
+            let result =
+                fs::symlink_metadata(&path)
+                    .map_err(errno)
+                    .and_then(|meta| match meta.is_dir() {
+                        true => qid(&next),
+                        false => Err(ENOTDIR),
+                    });
+            match result {
+                Ok(qid) => qids.push(qid),
+                // Only the first element failing is an error, otherwise the qids of the
+                // elements that could be walked are returned.
+                Err(error) if i == 0 => return Err(error),
+                Err(_) => break,
+            }
+            path = next;
+        }
+        if qids.len() == count as usize {
+            if newfid != fid && self.fids.contains_key(&newfid) {
+                return Err(EEXIST);
+            }
+            self.fids.insert(
+                newfid,
+                Fid {
+                    path,
+                    file: None,
+                    entries: None,
+                },
+            );
+        }
+        let mut w = Writer::default();
+        w.u16(qids.len() as u16);
+        for qid in qids {
+            w.qid(qid);
+        }
+        Ok(w.0)
+    }
+
+    fn getattr(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let path = self.path(r.u32()?)?;
+        let meta = fs::symlink_metadata(path).map_err(errno)?;
+        let mut w = Writer::default();
+        w.u64(GETATTR_BASIC);
+        w.qid(qid_of(&meta));
+        w.u32(meta.mode());
+        w.u32(meta.uid());
+        w.u32(meta.gid());
+        w.u64(meta.nlink());
+        w.u64(meta.rdev());
+        w.u64(meta.size());
+        w.u64(meta.blksize());
+        w.u64(meta.blocks());
+        for (sec, nsec) in [
+            (meta.atime(), meta.atime_nsec()),
+            (meta.mtime(), meta.mtime_nsec()),
+            (meta.ctime(), meta.ctime_nsec()),
+            // Birth time, generation and data version are not reported.
+            (0, 0),
+        ] {
+            w.u64(sec as u64);
+            w.u64(nsec as u64);
+        }
+        w.u64(0);
+        w.u64(0);
+        Ok(w.0)
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime = time(r.u64()?, r.u64()?);
+        let mtime = time(r.u64()?, r.u64()?);
+        self.writable()?;
+        // Links cannot have their own mode or size, and following them could leave the share.
+        let path = self.real_path(fid)?;
+        if valid & SETATTR_MODE != 0 {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            let uid = (valid & SETATTR_UID != 0).then_some(uid);
+            let gid = (valid & SETATTR_GID != 0).then_some(gid);
+            std::os::unix::fs::chown(&path, uid, gid).map_err(errno)?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            let file = OpenOptions::new().write(true).open(&path).map_err(errno)?;
+            file.set_len(size).map_err(errno)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let now = SystemTime::now();
+            let mut times = FileTimes::new();
+            if valid & SETATTR_ATIME != 0 {
+                times = times.set_accessed(match valid & SETATTR_ATIME_SET {
+                    0 => now,
+                    _ => atime,
+                });
+            }
+            if valid & SETATTR_MTIME != 0 {
+                times = times.set_modified(match valid & SETATTR_MTIME_SET {
+                    0 => now,
+                    _ => mtime,
+                });
+            }
+            File::open(&path)
+                .and_then(|file| file.set_times(times))
+                .map_err(errno)?;
+        }
+        Ok(Vec::new())
+    }
+
+    // The types of the `statvfs` fields vary between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    fn statfs(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let path = self.real_path(r.u32()?)?;
+        let path =
+            std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).map_err(|_| EINVAL)?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(errno(io::Error::last_os_error()));
+        }
+        let stat = unsafe { stat.assume_init() };
+        let mut w = Writer::default();
+        // V9FS_MAGIC as the file system type.
+        w.u32(0x0102_1997);
+        w.u32(stat.f_bsize as u32);
+        w.u64(stat.f_blocks as u64);
+        w.u64(stat.f_bfree as u64);
+        w.u64(stat.f_bavail as u64);
+        w.u64(stat.f_files as u64);
+        w.u64(stat.f_ffree as u64);
+        w.u64(stat.f_fsid as u64);
+        w.u32(stat.f_namemax as u32);
+        Ok(w.0)
+    }
+
+    fn lopen(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let flags = r.u32()?;
+        let writes = flags & L_O_ACCMODE != 0 || flags & L_O_TRUNC != 0;
+        if writes {
+            self.writable()?;
+        }
+        let iounit = self.iounit();
+        self.real_path(fid)?;
+        let fid = self.fids.get_mut(&fid).ok_or(EBADF)?;
+        let meta = fs::symlink_metadata(&fid.path).map_err(errno)?;
+        // Directories are listed with `fs::read_dir`, so there is nothing to open.
+        if !meta.is_dir() {
+            fid.file = Some(open(&fid.path, flags, false, 0).map_err(errno)?);
+        }
+        fid.entries = None;
+        let mut w = Writer::default();
+        w.qid(qid_of(&meta));
+        w.u32(iounit);
+        Ok(w.0)
+    }
+
+    fn lcreate(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let name = r.str()?;
+        let flags = r.u32()?;
+        let mode = r.u32()?;
+        self.writable()?;
+        let iounit = self.iounit();
+        let path = self.real_path(fid)?.join(check_name(&name)?);
+        let fid = self.fids.get_mut(&fid).ok_or(EBADF)?;
+        let file = open(&path, flags, true, mode).map_err(errno)?;
+        let qid = qid_of(&file.metadata().map_err(errno)?);
+        // The fid now stands for the new file, open.
+        fid.path = path;
+        fid.file = Some(file);
+        let mut w = Writer::default();
+        w.qid(qid);
+        w.u32(iounit);
+        Ok(w.0)
+    }
+
+    fn read(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.iounit()) as usize;
+        let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+        let mut data = vec![0u8; count];
+        let mut len = 0;
+        // Short reads only mean the end of the file to the guest.
+        while len < count {
+            match file.read_at(&mut data[len..], offset + len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(errno(error)),
+            }
+        }
+        let mut w = Writer::default();
+        w.u32(len as u32);
+        w.0.extend_from_slice(&data[..len]);
+        Ok(w.0)
+    }
+
+    fn write(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()? as usize;
+        let data = r.bytes(count)?;
+        self.writable()?;
+        let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+        file.write_all_at(data, offset).map_err(errno)?;
+        let mut w = Writer::default();
+        w.u32(count as u32);
+        Ok(w.0)
+    }
+
+    fn readdir(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()?.min(self.iounit()) as usize;
+        let path = self.real_path(fid)?;
+        let fid = self.fids.get_mut(&fid).ok_or(EBADF)?;
+        // Listed once, when the guest starts reading, so offsets stay stable.
+        if offset == 0 || fid.entries.is_none() {
+            fid.entries = Some(list(&self.root, &path)?);
+        }
+        let entries = fid.entries.as_deref().unwrap_or_default();
+        let mut data = Writer::default();
+        // Each entry points at the next through its offset.
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            let size = 13 + 8 + 1 + 2 + entry.name.len();
+            if data.0.len() + size > count {
+                break;
+            }
+            data.qid(entry.qid);
+            data.u64(index as u64 + 1);
+            data.u8(entry.kind);
+            data.str(&entry.name);
+        }
+        let mut w = Writer::default();
+        w.u32(data.0.len() as u32);
+        w.0.extend_from_slice(&data.0);
+        Ok(w.0)
+    }
+
+    fn mkdir(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let dfid = r.u32()?;
+        let name = r.str()?;
+        let mode = r.u32()?;
+        self.writable()?;
+        let path = self.real_path(dfid)?.join(check_name(&name)?);
+        fs::DirBuilder::new()
+            .mode(mode & 0o7777)
+            .create(&path)
+            .map_err(errno)?;
+        let mut w = Writer::default();
+        w.qid(qid(&path)?);
+        Ok(w.0)
+    }
+
+    fn symlink(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let dfid = r.u32()?;
+        let name = r.str()?;
+        let target = r.str()?;
+        self.writable()?;
+        let path = self.real_path(dfid)?.join(check_name(&name)?);
+        std::os::unix::fs::symlink(target, &path).map_err(errno)?;
+        let mut w = Writer::default();
+        w.qid(qid(&path)?);
+        Ok(w.0)
+    }
+
+    fn link(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let dfid = r.u32()?;
+        let fid = r.u32()?;
+        let name = r.str()?;
+        self.writable()?;
+        let original = self.path(fid)?;
+        let path = self.real_path(dfid)?.join(check_name(&name)?);
+        fs::hard_link(original, path).map_err(errno)?;
+        Ok(Vec::new())
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let old_dfid = r.u32()?;
+        let old_name = r.str()?;
+        let new_dfid = r.u32()?;
+        let new_name = r.str()?;
+        self.writable()?;
+        let from = self.real_path(old_dfid)?.join(check_name(&old_name)?);
+        let to = self.real_path(new_dfid)?.join(check_name(&new_name)?);
+        self.rename_path(&from, &to)
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let dfid = r.u32()?;
+        let name = r.str()?;
+        self.writable()?;
+        let from = self.path(fid)?;
+        let to = self.real_path(dfid)?.join(check_name(&name)?);
+        self.rename_path(&from, &to)
+    }
+
+    /// Renames a file and every fid under it along.
+    fn rename_path(&mut self, from: &Path, to: &Path) -> Result<Vec<u8>, u32> {
+        if from == self.root {
+            return Err(EINVAL);
+        }
+        fs::rename(from, to).map_err(errno)?;
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.path.strip_prefix(from) {
+                fid.path = to.join(rest);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let dfid = r.u32()?;
+        let name = r.str()?;
+        let flags = r.u32()?;
+        self.writable()?;
+        let path = self.real_path(dfid)?.join(check_name(&name)?);
+        if flags & AT_REMOVEDIR != 0 {
+            fs::remove_dir(path).map_err(errno)?;
+        } else {
+            fs::remove_file(path).map_err(errno)?;
+        }
+        Ok(Vec::new())
+    }
+
+    /// Removes a file and forgets its fid, even when the removal fails.
+    fn remove(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let fid = r.u32()?;
+        let checked = self.path(fid);
+        let fid = self.fids.remove(&fid).ok_or(EBADF)?;
+        self.writable()?;
+        checked?;
+        if fid.path == self.root {
+            return Err(EINVAL);
+        }
+        match fs::symlink_metadata(&fid.path).map_err(errno)?.is_dir() {
+            true => fs::remove_dir(&fid.path).map_err(errno)?,
+            false => fs::remove_file(&fid.path).map_err(errno)?,
+        }
+        Ok(Vec::new())
+    }
+
+    fn getlock(&mut self, r: &mut Reader) -> Result<Vec<u8>, u32> {
+        let _fid = r.u32()?;
+        let _kind = r.u8()?;
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let proc_id = r.u32()?;
+        let client_id = r.str()?;
+        // Nothing else ever holds a lock.
+        let mut w = Writer::default();
+        w.u8(F_UNLCK);
+        w.u64(start);
+        w.u64(length);
+        w.u32(proc_id);
+        w.str(&client_id);
+        Ok(w.0)
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid, u32> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    /// The path of `fid`, for operations on the file itself that don't follow it if it is a
+    /// link. The directories leading to it must not be links: the guest can make links that
+    /// point anywhere, and going through one would leave the share.
+    fn path(&self, fid: u32) -> Result<PathBuf, u32> {
+        let path = &self.fid(fid)?.path;
+        match path.parent() {
+            Some(parent) if *path != self.root => no_links(parent)?,
+            _ => no_links(path)?,
+        }
+        Ok(path.clone())
+    }
+
+    /// The path of `fid`, which must not be a link either, for operations that follow it or
+    /// work on files in it.
+    fn real_path(&self, fid: u32) -> Result<PathBuf, u32> {
+        let path = &self.fid(fid)?.path;
+        no_links(path)?;
+        Ok(path.clone())
+    }
+
+    fn insert(&mut self, fid: u32, path: PathBuf) -> Result<(), u32> {
+        if self.fids.contains_key(&fid) {
+            return Err(EEXIST);
+        }
+        self.fids.insert(
+            fid,
+            Fid {
+                path,
+                file: None,
+                entries: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn writable(&self) -> Result<(), u32> {
+        match self.read_only {
+            true => Err(EROFS),
+            false => Ok(()),
+        }
+    }
+
+    /// Most data a read or write can move: what fits in a message after its header.
+    fn iounit(&self) -> u32 {
+        self.msize.saturating_sub(HEADER_SIZE as u32 + 4)
+    }
+}
+
+impl device_interfaces::FileServer for Share {
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut r = Reader(request);
+        let header = (r.u32(), r.u8(), r.u16());
+        let (Ok(_), Ok(kind), Ok(tag)) = header else {
+            return Vec::new();
+        };
+        let (kind, body) = match self.dispatch(kind, &mut r) {
+            Ok(body) => (kind + 1, body),
+            Err(code) => (TLERROR + 1, code.to_le_bytes().to_vec()),
+        };
+        let mut reply = Vec::with_capacity(HEADER_SIZE + body.len());
+        reply.extend_from_slice(&((HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        reply.push(kind);
+        reply.extend_from_slice(&tag.to_le_bytes());
+        reply.extend_from_slice(&body);
+        reply
+    }
+}
+
+/// Opens `path` with Linux open flags. Links are never followed.
+fn open(path: &Path, flags: u32, create: bool, mode: u32) -> io::Result<File> {
+    let access = flags & L_O_ACCMODE;
+    OpenOptions::new()
+        .read(access != L_O_WRONLY)
+        .write(access == L_O_WRONLY || access == L_O_RDWR)
+        .append(flags & L_O_APPEND != 0)
+        .truncate(flags & L_O_TRUNC != 0)
+        .create_new(create)
+        .mode(mode & 0o7777)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// Lists a directory, `.` and `..` first.
+fn list(root: &Path, path: &Path) -> Result<Vec<Entry>, u32> {
+    let parent = match path == root {
+        true => path,
+        false => path.parent().unwrap_or(path),
+    };
+    let mut entries = Vec::new();
+    for (name, path) in [(".", path), ("..", parent)] {
+        entries.push(Entry {
+            qid: qid(path)?,
+            kind: DT_DIR,
+            name: name.to_string(),
+        });
+    }
+    for entry in fs::read_dir(path).map_err(errno)? {
+        let entry = entry.map_err(errno)?;
+        // Entries removed since the listing started are skipped.
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        let kind = match meta.file_type() {
+            t if t.is_dir() => DT_DIR,
+            t if t.is_file() => DT_REG,
+            t if t.is_symlink() => DT_LNK,
+            _ => DT_UNKNOWN,
+        };
+        entries.push(Entry {
+            qid: qid_of(&meta),
+            kind,
+            name: entry.file_name().to_string_lossy().into_owned(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Rejects a path that goes through a link. Paths of fids are built from the canonical root
+/// and single components, so any link makes them differ from their canonical form.
+fn no_links(path: &Path) -> Result<(), u32> {
+    match fs::canonicalize(path).map_err(errno)? == path {
+        true => Ok(()),
+        false => Err(ELOOP),
+    }
+}
+
+/// Rejects names that are not a single path component.
+fn check_name(name: &str) -> Result<&str, u32> {
+    match name {
+        "" | "." | ".." => Err(EINVAL),
+        _ if name.contains('/') => Err(EINVAL),
+        _ => Ok(name),
+    }
+}
+
+fn qid(path: &Path) -> Result<Qid, u32> {
+    Ok(qid_of(&fs::symlink_metadata(path).map_err(errno)?))
+}
+
+fn qid_of(meta: &fs::Metadata) -> Qid {
+    let kind = match meta.file_type() {
+        t if t.is_dir() => QTDIR,
+        t if t.is_symlink() => QTSYMLINK,
+        _ => QTFILE,
+    };
+    Qid {
+        kind,
+        // Changes when the file does, so the guest can tell its cache is stale.
+        version: meta.mtime() as u32 ^ meta.mtime_nsec() as u32,
+        path: meta.ino(),
+    }
+}
+
+fn time(sec: u64, nsec: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(sec, nsec as u32)
+}
+
+fn errno(error: io::Error) -> u32 {
+    match error.raw_os_error() {
+        Some(code) => code as u32,
+        None if error.kind() == io::ErrorKind::NotFound => ENOENT,
+        None => EIO,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], u32> {
+        if self.0.len() < len {
+            return Err(EINVAL);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, u32> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, u32> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn qid(&mut self, qid: Qid) {
+        self.u8(qid.kind);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::FileServer;
+
+    /// A share of a fresh directory next to one outside of it, with the root attached as
+    /// fid 0.
+    struct Setup {
+        share: Share,
+        dir: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Setup {
+        fn new(name: &str, read_only: bool) -> Self {
+            let base = std::env::temp_dir().join(format!("r2-p9-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&base);
+            let dir = base.join("share");
+            let outside = base.join("outside");
+            fs::create_dir_all(&dir).unwrap();
+            fs::create_dir_all(&outside).unwrap();
+            fs::write(dir.join("file"), b"inside").unwrap();
+            fs::write(outside.join("secret"), b"outside").unwrap();
+            let mut setup = Self {
+                share: Share::new(&dir, read_only).unwrap(),
+                dir: fs::canonicalize(dir).unwrap(),
+                outside: fs::canonicalize(outside).unwrap(),
+            };
+            setup.call(TVERSION, |w| {
+                w.u32(8192);
+                w.str(VERSION);
+            });
+            setup.call(TATTACH, |w| {
+                w.u32(0);
+                w.u32(!0);
+                w.str("root");
+                w.str("");
+            });
+            setup
+        }
+
+        /// Sends a request and returns its reply body, or the error code.
+        fn request(&mut self, kind: u8, body: impl FnOnce(&mut Writer)) -> Result<Vec<u8>, u32> {
+            let mut w = Writer::default();
+            body(&mut w);
+            let mut request = ((HEADER_SIZE + w.0.len()) as u32).to_le_bytes().to_vec();
+            request.push(kind);
+            request.extend_from_slice(&1u16.to_le_bytes());
+            request.extend_from_slice(&w.0);
+            let reply = self.share.handle(&request);
+            assert_eq!(
+                reply.len(),
+                u32::from_le_bytes(reply[..4].try_into().unwrap()) as usize
+            );
+            let body = reply[HEADER_SIZE..].to_vec();
+            match reply[4] {
+                TLERROR_REPLY => Err(u32::from_le_bytes(body.try_into().unwrap())),
+                reply => {
+                    assert_eq!(reply, kind + 1);
+                    Ok(body)
+                }
+            }
+        }
+
+        fn call(&mut self, kind: u8, body: impl FnOnce(&mut Writer)) -> Vec<u8> {
+            self.request(kind, body).unwrap()
+        }
+
+        fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Result<Vec<u8>, u32> {
+            self.request(TWALK, |w| {
+                w.u32(fid);
+                w.u32(newfid);
+                w.u16(names.len() as u16);
+                for name in names {
+                    w.str(name);
+                }
+            })
+        }
+
+        fn lcreate(&mut self, fid: u32, name: &str) -> Result<Vec<u8>, u32> {
+            self.request(TLCREATE, |w| {
+                w.u32(fid);
+                w.str(name);
+                w.u32(L_O_RDWR);
+                w.u32(0o644);
+                w.u32(0);
+            })
+        }
+
+        fn mkdir(&mut self, dfid: u32, name: &str) -> Result<Vec<u8>, u32> {
+            self.request(TMKDIR, |w| {
+                w.u32(dfid);
+                w.str(name);
+                w.u32(0o755);
+                w.u32(0);
+            })
+        }
+
+        fn symlink(&mut self, dfid: u32, name: &str, target: &Path) {
+            self.call(TSYMLINK, |w| {
+                w.u32(dfid);
+                w.str(name);
+                w.str(target.to_str().unwrap());
+                w.u32(0);
+            });
+        }
+
+        fn unlinkat(&mut self, dfid: u32, name: &str, flags: u32) -> Result<Vec<u8>, u32> {
+            self.request(TUNLINKAT, |w| {
+                w.u32(dfid);
+                w.str(name);
+                w.u32(flags);
+            })
+        }
+    }
+
+    impl Drop for Setup {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.dir.parent().unwrap());
+        }
+    }
+
+    const TLERROR_REPLY: u8 = TLERROR + 1;
+
+    /// Files are walked to, read, created, written and listed.
+    #[test]
+    fn files() {
+        let mut setup = Setup::new("files", false);
+        let qids = setup.walk(0, 1, &["file"]).unwrap();
+        assert_eq!(qids[..2], [1, 0]);
+        assert_eq!(qids[2], QTFILE);
+        setup.call(TLOPEN, |w| {
+            w.u32(1);
+            w.u32(0);
+        });
+        let data = setup.call(TREAD, |w| {
+            w.u32(1);
+            w.u64(2);
+            w.u32(100);
+        });
+        assert_eq!(data, b"\x04\0\0\0side");
+
+        setup.walk(0, 2, &[]).unwrap();
+        setup.lcreate(2, "new").unwrap();
+        setup.call(TWRITE, |w| {
+            w.u32(2);
+            w.u64(0);
+            w.u32(4);
+            w.0.extend_from_slice(b"data");
+        });
+        assert_eq!(fs::read(setup.dir.join("new")).unwrap(), b"data");
+        assert_eq!(setup.lcreate(0, "new"), Err(EEXIST));
+
+        setup.walk(0, 3, &[]).unwrap();
+        setup.call(TLOPEN, |w| {
+            w.u32(3);
+            w.u32(0);
+        });
+        let listing = setup.call(TREADDIR, |w| {
+            w.u32(3);
+            w.u64(0);
+            w.u32(4096);
+        });
+        let mut names = Vec::new();
+        let mut r = Reader(&listing[4..]);
+        while !r.0.is_empty() {
+            r.bytes(13 + 8 + 1).unwrap();
+            names.push(r.str().unwrap());
+        }
+        names.sort();
+        assert_eq!(names, [".", "..", "file", "new"]);
+    }
+
+    /// Walks stay in the share and only take single names.
+    #[test]
+    fn walk_bounds() {
+        let mut setup = Setup::new("walk", false);
+        let root = setup.walk(0, 1, &[]).unwrap();
+        assert_eq!(root, [0, 0]);
+        let up = setup.walk(0, 1, &[".."]);
+        assert_eq!(up, Err(EEXIST));
+        let up = setup.walk(0, 2, &["..", ".."]).unwrap();
+        let attr = |setup: &mut Setup, fid| {
+            setup.call(TGETATTR, |w| {
+                w.u32(fid);
+                w.u64(GETATTR_BASIC);
+            })[8..21]
+                .to_vec()
+        };
+        assert_eq!(up[..2], [2, 0]);
+        assert_eq!(attr(&mut setup, 2), attr(&mut setup, 0));
+        assert_eq!(setup.walk(0, 3, &["../outside"]), Err(EINVAL));
+        // Only the elements before the one that fails are walked, and no fid is made.
+        assert_eq!(setup.walk(0, 3, &["file", "x"]).unwrap()[..2], [1, 0]);
+        assert_eq!(setup.walk(3, 4, &[]), Err(EBADF));
+        assert_eq!(setup.walk(0, 3, &["missing"]), Err(ENOENT));
+    }
+
+    /// Links the guest made don't lead out of the share, whether a fid is the link or goes
+    /// through one.
+    #[test]
+    fn links_stay_inside() {
+        let mut setup = Setup::new("links", false);
+        let outside = setup.outside.clone();
+        setup.symlink(0, "escape", &outside);
+        let qids = setup.walk(0, 1, &["escape"]).unwrap();
+        assert_eq!(qids[2], QTSYMLINK);
+        let target = setup.call(TREADLINK, |w| w.u32(1));
+        assert_eq!(target[2..], *outside.to_str().unwrap().as_bytes());
+
+        assert_eq!(setup.walk(1, 2, &["secret"]), Err(ELOOP));
+        assert_eq!(setup.lcreate(1, "planted"), Err(ELOOP));
+        assert_eq!(setup.mkdir(1, "planted"), Err(ELOOP));
+        assert_eq!(setup.unlinkat(1, "secret", 0), Err(ELOOP));
+        let lopen = setup.request(TLOPEN, |w| {
+            w.u32(1);
+            w.u32(0);
+        });
+        assert_eq!(lopen, Err(ELOOP));
+        assert_eq!(setup.request(TSTATFS, |w| w.u32(1)), Err(ELOOP));
+        let renameat = setup.request(TRENAMEAT, |w| {
+            w.u32(1);
+            w.str("secret");
+            w.u32(0);
+            w.str("stolen");
+        });
+        assert_eq!(renameat, Err(ELOOP));
+        let setattr = setup.request(TSETATTR, |w| {
+            w.u32(1);
+            w.u32(SETATTR_MODE);
+            w.u32(0o777);
+            w.0.extend_from_slice(&[0; 4 + 4 + 8 + 32]);
+        });
+        assert_eq!(setattr, Err(ELOOP));
+
+        // A directory replaced by a link after it was walked to, with a fid on a file in it.
+        setup.mkdir(0, "dir").unwrap();
+        setup.mkdir(0, "other").unwrap();
+        fs::write(setup.dir.join("other/secret"), b"inside").unwrap();
+        setup.walk(0, 3, &["dir"]).unwrap();
+        setup.walk(0, 4, &["other"]).unwrap();
+        setup.walk(0, 5, &["other", "secret"]).unwrap();
+        setup.unlinkat(4, "secret", 0).unwrap();
+        setup.unlinkat(0, "dir", AT_REMOVEDIR).unwrap();
+        setup.unlinkat(0, "other", AT_REMOVEDIR).unwrap();
+        setup.symlink(0, "dir", &outside);
+        setup.symlink(0, "other", &outside);
+        assert_eq!(setup.lcreate(3, "planted"), Err(ELOOP));
+        assert_eq!(setup.walk(3, 6, &["secret"]), Err(ELOOP));
+        let getattr = setup.request(TGETATTR, |w| {
+            w.u32(5);
+            w.u64(GETATTR_BASIC);
+        });
+        assert_eq!(getattr, Err(ELOOP));
+        let link = setup.request(TLINK, |w| {
+            w.u32(0);
+            w.u32(5);
+            w.str("stolen");
+        });
+        assert_eq!(link, Err(ELOOP));
+        let rename = setup.request(TRENAME, |w| {
+            w.u32(5);
+            w.u32(0);
+            w.str("stolen");
+        });
+        assert_eq!(rename, Err(ELOOP));
+        assert_eq!(setup.request(TREMOVE, |w| w.u32(5)), Err(ELOOP));
+
+        let mut outside_names: Vec<_> = fs::read_dir(&outside)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        outside_names.sort();
+        assert_eq!(outside_names, ["secret"]);
+        assert_eq!(fs::read(outside.join("secret")).unwrap(), b"outside");
+        assert!(!setup.dir.join("stolen").exists());
+    }
+
+    /// A read-only share refuses every change.
+    #[test]
+    fn read_only() {
+        let mut setup = Setup::new("read-only", true);
+        assert_eq!(setup.lcreate(0, "new"), Err(EROFS));
+        assert_eq!(setup.mkdir(0, "new"), Err(EROFS));
+        assert_eq!(setup.unlinkat(0, "file", 0), Err(EROFS));
+        setup.walk(0, 1, &["file"]).unwrap();
+        let lopen = setup.request(TLOPEN, |w| {
+            w.u32(1);
+            w.u32(L_O_WRONLY);
+        });
+        assert_eq!(lopen, Err(EROFS));
+        assert_eq!(fs::read(setup.dir.join("file")).unwrap(), b"inside");
+    }
+}
@@ -3,33 +3,56 @@
 //! Without this the host line discipline keeps canonical mode, echo and signal generation
 //! enabled, so keystrokes are only handed to the guest once a newline is typed and control
 //! characters such as `Ctrl-C` terminate the emulator instead of reaching the guest.
+//!
+//! The size of the terminal is also tracked here, for consoles that can tell the guest.
 
 #[cfg(unix)]
 mod imp {
     use std::os::fd::AsRawFd;
-    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Mutex, Once};
+
+    static RAW: Mutex<Raw> = Mutex::new(Raw {
+        users: 0,
+        saved: None,
+    });
+    static PANIC_HOOK: Once = Once::new();
+    static RESIZED: AtomicBool = AtomicBool::new(false);
 
-    static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+    struct Raw {
+        /// Live [`RawMode`] guards. The terminal goes back to cooked mode with the last one.
+        users: usize,
+        /// Settings to restore, while the terminal is in raw mode.
+        saved: Option<libc::termios>,
+    }
 
-    /// Puts the controlling terminal into raw mode and restores it when dropped.
+    /// Puts the controlling terminal into raw mode and restores it when dropped. Devices
+    /// sharing the terminal each hold a guard, and it stays raw until all of them are gone.
     #[derive(Debug)]
     pub struct RawMode;
 
     impl RawMode {
         pub fn enable() -> Self {
+            let mut raw = RAW.lock().unwrap();
+            raw.users += 1;
+            if raw.users > 1 {
+                return Self;
+            }
             if let Some(saved) = get_termios() {
-                *SAVED.lock().unwrap() = Some(saved);
+                raw.saved = Some(saved);
 
-                let mut raw = saved;
-                unsafe { libc::cfmakeraw(&mut raw) };
-                set_termios(&raw);
+                let mut termios = saved;
+                unsafe { libc::cfmakeraw(&mut termios) };
+                set_termios(&termios);
 
                 // A panic would otherwise leave the terminal unusable.
-                let previous_hook = std::panic::take_hook();
-                std::panic::set_hook(Box::new(move |info| {
-                    restore();
-                    previous_hook(info);
-                }));
+                PANIC_HOOK.call_once(|| {
+                    let previous_hook = std::panic::take_hook();
+                    std::panic::set_hook(Box::new(move |info| {
+                        restore();
+                        previous_hook(info);
+                    }));
+                });
             }
             Self
         }
@@ -37,17 +60,57 @@ mod imp {
 
     impl Drop for RawMode {
         fn drop(&mut self) {
-            restore();
+            let mut raw = RAW.lock().unwrap();
+            raw.users -= 1;
+            if raw.users == 0 {
+                if let Some(saved) = raw.saved.take() {
+                    set_termios(&saved);
+                }
+            }
         }
     }
 
-    /// Restores the settings captured by [`RawMode::enable`], if any.
+    /// Restores the settings captured by [`RawMode::enable`], if any, whatever guards are
+    /// left.
     pub fn restore() {
-        if let Some(saved) = SAVED.lock().unwrap().take() {
+        let mut raw = RAW.lock().unwrap();
+        if let Some(saved) = raw.saved.take() {
             set_termios(&saved);
         }
     }
 
+    extern "C" fn on_sigwinch(_: libc::c_int) {
+        RESIZED.store(true, Ordering::Relaxed);
+    }
+
+    /// Starts noting `SIGWINCH`, which the terminal sends when its size changes.
+    pub fn watch_resize() {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigwinch as *const () as libc::sighandler_t;
+            // Reads from stdin must not fail because the window was resized.
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut());
+        }
+    }
+
+    /// Whether the terminal was resized since the last call.
+    pub fn take_resized() -> bool {
+        RESIZED.swap(false, Ordering::Relaxed)
+    }
+
+    /// Columns and rows of the terminal on stdout.
+    pub fn window_size() -> Option<(u16, u16)> {
+        let fd = std::io::stdout().as_raw_fd();
+        let mut size = std::mem::MaybeUninit::<libc::winsize>::uninit();
+        if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, size.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let size = unsafe { size.assume_init() };
+        (size.ws_col != 0 && size.ws_row != 0).then_some((size.ws_col, size.ws_row))
+    }
+
     fn get_termios() -> Option<libc::termios> {
         let fd = std::io::stdin().as_raw_fd();
         if unsafe { libc::isatty(fd) } != 1 {
@@ -64,6 +127,26 @@ mod imp {
         let fd = std::io::stdin().as_raw_fd();
         unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) };
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// The terminal stays raw until the last guard is dropped.
+        #[test]
+        fn raw_mode_guards() {
+            let tty = get_termios().is_some();
+            let first = RawMode::enable();
+            let second = RawMode::enable();
+            assert_eq!(RAW.lock().unwrap().users, 2);
+            drop(first);
+            assert_eq!(RAW.lock().unwrap().users, 1);
+            assert_eq!(RAW.lock().unwrap().saved.is_some(), tty);
+            drop(second);
+            assert_eq!(RAW.lock().unwrap().users, 0);
+            assert!(RAW.lock().unwrap().saved.is_none());
+        }
+    }
 }
 
 #[cfg(not(unix))]
@@ -78,6 +161,16 @@ mod imp {
     }
 
     pub fn restore() {}
+
+    pub fn watch_resize() {}
+
+    pub fn take_resized() -> bool {
+        false
+    }
+
+    pub fn window_size() -> Option<(u16, u16)> {
+        None
+    }
 }
 
-pub use imp::{restore, RawMode};
+pub use imp::{restore, take_resized, watch_resize, window_size, RawMode};
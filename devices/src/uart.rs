@@ -1,18 +1,29 @@
 use std::io::Write;
 
-use crate::keyboard::{is_kb_hit, read_kb_byte};
+use crate::keyboard::{is_kb_hit, quit_requested, read_kb_byte};
 use crate::terminal::RawMode;
 
 /// Binds the guest serial port to the host terminal.
 #[derive(Debug)]
 pub struct Uart {
     _raw_mode: RawMode,
+    /// Whether keystrokes go to the guest through this port.
+    input: bool,
 }
 
 impl Uart {
     pub fn new() -> Self {
         Self {
             _raw_mode: RawMode::enable(),
+            input: true,
+        }
+    }
+
+    /// Only shows what the guest prints, leaving keystrokes to another console.
+    pub fn output_only() -> Self {
+        Self {
+            input: false,
+            ..Self::new()
         }
     }
 }
@@ -26,8 +37,8 @@ impl Default for Uart {
 impl device_interfaces::SerialInterface for Uart {
     fn read(&self, addr: u32) -> u8 {
         match addr {
-            0x0005 => 0x60 | if is_kb_hit() { 1 } else { 0 },
-            0x0000 if is_kb_hit() => read_kb_byte() as u8,
+            0x0005 => 0x60 | if self.input && is_kb_hit() { 1 } else { 0 },
+            0x0000 if self.input && is_kb_hit() => read_kb_byte() as u8,
             _ => 0,
         }
     }
@@ -43,4 +54,8 @@ impl device_interfaces::SerialInterface for Uart {
             stdout.flush().expect("failed to flush stdout.");
         }
     }
+
+    fn quit_requested(&self) -> bool {
+        quit_requested()
+    }
 }
@@ -0,0 +1,201 @@
+//! Host end of the vsock device over Unix sockets, following the conventions of Firecracker
+//! so that existing tools work:
+//!
+//! - A guest connecting to host port `P` reaches the program listening on `<path>_P`.
+//! - A host program connects to `<path>` and sends `CONNECT <port>\n` to reach guest port
+//!   `<port>`. Once the guest accepts, it receives `OK <host port>\n` and the stream follows.
+//!
+//! @See https://github.com/firecracker-microvm/firecracker/blob/main/docs/vsock.md
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Longest `CONNECT` line accepted.
+const MAX_LINE: usize = 32;
+
+#[derive(Debug)]
+pub struct UnixVsock {
+    path: PathBuf,
+    listener: UnixListener,
+    /// Host connections that have not sent their whole `CONNECT` line yet.
+    handshakes: Vec<(UnixStream, Vec<u8>)>,
+}
+
+impl UnixVsock {
+    /// Listens for host programs on `path`, where nothing but a stale socket may be.
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        crate::remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+            handshakes: Vec::new(),
+        })
+    }
+}
+
+impl Drop for UnixVsock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Parses `CONNECT <port>`.
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = std::str::from_utf8(line).ok()?;
+    line.strip_prefix("CONNECT ")?.trim().parse().ok()
+}
+
+impl device_interfaces::VsockHost for UnixVsock {
+    fn connect(&mut self, port: u32) -> Option<Box<dyn device_interfaces::VsockStream>> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!("_{port}"));
+        let stream = UnixStream::connect(path).ok()?;
+        stream.set_nonblocking(true).ok()?;
+        Some(Box::new(Stream(stream)))
+    }
+
+    fn accept(&mut self) -> Option<(u32, Box<dyn device_interfaces::VsockStream>)> {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.handshakes.push((stream, Vec::new()));
+            }
+        }
+        // Read a byte at a time, so that nothing after the line is taken from the stream.
+        let mut index = 0;
+        while index < self.handshakes.len() {
+            let (stream, line) = &mut self.handshakes[index];
+            let mut byte = [0u8];
+            let done = loop {
+                match stream.read(&mut byte) {
+                    Ok(1) if byte[0] == b'\n' => break Some(parse_connect(line)),
+                    Ok(1) if line.len() < MAX_LINE => line.push(byte[0]),
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => break None,
+                    // Closed, failed or garbage.
+                    _ => break Some(None),
+                }
+            };
+            match done {
+                None => index += 1,
+                Some(port) => {
+                    let (stream, _) = self.handshakes.swap_remove(index);
+                    if let Some(port) = port {
+                        return Some((port, Box::new(Stream(stream))));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+struct Stream(UnixStream);
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl device_interfaces::VsockStream for Stream {
+    fn established(&mut self, port: u32) {
+        // Nothing was written to the socket yet, so the line fits in its buffer.
+        let _ = self.0.write_all(format!("OK {port}\n").as_bytes());
+    }
+
+    fn close_write(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Write);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_interfaces::VsockHost;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("r2-vsock-{name}-{}", std::process::id()))
+    }
+
+    /// Guest connections reach the program listening on `<path>_<port>`.
+    #[test]
+    fn guest_connects() {
+        let path = socket_path("guest");
+        let mut host = UnixVsock::listen(&path).unwrap();
+        assert!(host.connect(1234).is_none());
+        let program_path = PathBuf::from(format!("{}_1234", path.display()));
+        let program = UnixListener::bind(&program_path).unwrap();
+        let mut stream = host.connect(1234).unwrap();
+        let (mut program, _) = program.accept().unwrap();
+
+        stream.write_all(b"from guest").unwrap();
+        let mut buffer = [0u8; 10];
+        program.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"from guest");
+        let mut buffer = [0u8; 16];
+        let error = stream.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        program.write_all(b"from host").unwrap();
+        assert_eq!(stream.read(&mut buffer).unwrap(), 9);
+        assert_eq!(&buffer[..9], b"from host");
+        stream.close_write();
+        assert_eq!(program.read(&mut buffer).unwrap(), 0);
+
+        std::fs::remove_file(program_path).unwrap();
+        drop(host);
+        assert!(!path.exists());
+    }
+
+    /// Host programs pick a guest port with a `CONNECT` line, which may come in pieces, and
+    /// are told the port they come from once the guest accepts.
+    #[test]
+    fn host_connects() {
+        let path = socket_path("host");
+        let mut host = UnixVsock::listen(&path).unwrap();
+        assert!(host.accept().is_none());
+
+        let mut program = UnixStream::connect(&path).unwrap();
+        program.write_all(b"CONN").unwrap();
+        assert!(host.accept().is_none());
+        program.write_all(b"ECT 52\nhello").unwrap();
+        let (port, mut stream) = host.accept().unwrap();
+        assert_eq!(port, 52);
+        let mut buffer = [0u8; 16];
+        assert_eq!(stream.read(&mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"hello");
+        stream.established(1025);
+        let mut reply = [0u8; 8];
+        program.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"OK 1025\n");
+
+        // Anything but a `CONNECT` line closes the connection.
+        let mut garbage = UnixStream::connect(&path).unwrap();
+        garbage.write_all(b"HELLO\n").unwrap();
+        assert!(host.accept().is_none());
+        assert_eq!(garbage.read(&mut buffer).unwrap(), 0);
+    }
+
+    /// A file that is not a socket is not replaced.
+    #[test]
+    fn path_taken() {
+        let path = socket_path("file");
+        std::fs::write(&path, b"notes").unwrap();
+        let error = UnixVsock::listen(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"notes");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
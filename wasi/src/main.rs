@@ -1,4 +1,5 @@
-use core::bus::RAM_START;
+use core::bus::{RAM_START, RTC, RTC_IRQ};
+use core::rtc::Rtc;
 
 fn main() {
     let ram_size = 640 * 1024 * 1024;
@@ -6,21 +7,23 @@ fn main() {
     let mut ram = vec![0u8; ram_size];
 
     let bin = include_bytes!("../../fixtures/linux.bin");
-    let dtb = include_bytes!("../../fixtures/default.dtb");
 
     ram[0..bin.len()].copy_from_slice(bin);
 
-    let dtb_ref = ram_size - dtb.len();
-    ram[dtb_ref..dtb_ref + dtb.len()].copy_from_slice(dtb);
-
     let clint = core::clint::Clint::new(devices::timer::Timer::default());
     let uart = devices::uart::Uart::new();
-    let bus = core::bus::Bus::new(ram, clint, uart);
+    let mut bus = core::bus::Bus::new(ram, clint, uart);
+    let rtc = Rtc::new(devices::clock::HostClock);
+    bus.map_device(RTC.base, Box::new(rtc), Some(RTC_IRQ))
+        .expect("the RTC overlaps another device");
+    let dtb = bus.platform().to_dtb();
+    let dtb_ref = bus.load_dtb(&dtb).expect("RAM is too small for the DTB");
 
     core::start(
         bus,
         RAM_START,
-        dtb_ref as u32 + RAM_START,
+        dtb_ref,
+        Default::default(),
         &std::thread::sleep,
     );
 }
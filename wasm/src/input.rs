@@ -0,0 +1,148 @@
+//! Keyboard and pointer events of the page, handed to the virtio-input devices.
+//!
+//! worker.js queues each event as three words: a kind, then two arguments.
+
+use core::virtio::input::{Events, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT};
+use device_interfaces::{InputEvent, InputSource};
+
+#[link(wasm_import_module = "env")]
+extern "C" {
+    fn input_pending() -> bool;
+    fn input_read() -> u32;
+}
+
+/// `keyCode` and `down | repeat << 1 | location << 2` of a `KeyboardEvent`.
+const KEY: u32 = 0;
+/// Coordinates already scaled to the range of the tablet.
+const MOVE: u32 = 1;
+/// `button` and whether it went down, of a `MouseEvent`.
+const BUTTON: u32 = 2;
+/// Wheel clicks, positive away from the user, and an unused word.
+const WHEEL: u32 = 3;
+
+/// `KeyboardEvent.location` of the keys on the right of the keyboard and of the keypad.
+const DOM_KEY_LOCATION_RIGHT: u32 = 2;
+const DOM_KEY_LOCATION_NUMPAD: u32 = 3;
+
+thread_local! {
+    static KEYBOARD: Events = Events::new();
+    static TABLET: Events = Events::new();
+}
+
+/// Source of the keyboard or the tablet device.
+pub struct Dom(Events);
+
+impl Dom {
+    pub fn keyboard() -> Self {
+        Self(KEYBOARD.with(Clone::clone))
+    }
+
+    pub fn tablet() -> Self {
+        Self(TABLET.with(Clone::clone))
+    }
+}
+
+impl InputSource for Dom {
+    fn next_event(&mut self) -> Option<InputEvent> {
+        pump();
+        self.0.next_event()
+    }
+}
+
+/// Sorts the events queued by the page between the two devices.
+fn pump() {
+    while unsafe { input_pending() } {
+        let (kind, a, b) = unsafe { (input_read(), input_read(), input_read()) };
+        match kind {
+            KEY => {
+                let Some(code) = evdev_key(a, b >> 2) else {
+                    continue;
+                };
+                KEYBOARD.with(|keyboard| match (b & 1 != 0, b & 2 != 0) {
+                    (true, true) => keyboard.repeat(code),
+                    (down, _) => keyboard.key(code, down),
+                });
+            }
+            MOVE => TABLET.with(|tablet| tablet.move_to(a as i32, b as i32)),
+            BUTTON => {
+                let code = match a {
+                    0 => BTN_LEFT,
+                    1 => BTN_MIDDLE,
+                    2 => BTN_RIGHT,
+                    _ => continue,
+                };
+                TABLET.with(|tablet| tablet.button(code, b != 0));
+            }
+            WHEEL => TABLET.with(|tablet| tablet.wheel(a as i32)),
+            _ => {}
+        }
+    }
+}
+
+/// Linux key code of a `KeyboardEvent.keyCode`, for a US layout.
+fn evdev_key(key_code: u32, location: u32) -> Option<u16> {
+    const LETTERS: [u16; 26] = [
+        30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17,
+        45, 21, 44,
+    ];
+    const KEYPAD: [u16; 10] = [82, 79, 80, 81, 75, 76, 77, 71, 72, 73];
+    let right = location == DOM_KEY_LOCATION_RIGHT;
+    let code = match key_code {
+        8 => 14,
+        9 => 15,
+        13 if location == DOM_KEY_LOCATION_NUMPAD => 96,
+        13 => 28,
+        16 if right => 54,
+        16 => 42,
+        17 if right => 97,
+        17 => 29,
+        18 if right => 100,
+        18 => 56,
+        19 => 119,
+        20 => 58,
+        27 => 1,
+        32 => 57,
+        33 => 104,
+        34 => 109,
+        35 => 107,
+        36 => 102,
+        37 => 105,
+        38 => 103,
+        39 => 106,
+        40 => 108,
+        45 => 110,
+        46 => 111,
+        48 => 11,
+        49..=57 => key_code as u16 - 47,
+        65..=90 => LETTERS[key_code as usize - 65],
+        91 if right => 126,
+        91 => 125,
+        92 => 126,
+        93 => 127,
+        96..=105 => KEYPAD[key_code as usize - 96],
+        106 => 55,
+        107 => 78,
+        109 => 74,
+        110 => 83,
+        111 => 98,
+        112..=121 => key_code as u16 - 53,
+        122 => 87,
+        123 => 88,
+        144 => 69,
+        145 => 70,
+        // Firefox differs from the others for these three.
+        59 | 186 => 39,
+        61 | 187 => 13,
+        173 | 189 => 12,
+        188 => 51,
+        190 => 52,
+        191 => 53,
+        192 => 41,
+        219 => 26,
+        220 => 43,
+        221 => 27,
+        222 => 40,
+        _ => return None,
+    };
+    Some(code)
+}
@@ -1,4 +1,8 @@
-use core::bus::RAM_START;
+use core::bus::{FRAMEBUFFER, RAM_START};
+use core::framebuffer::Framebuffer;
+use core::virtio::{input::Input, VirtioMmio};
+
+mod input;
 
 // Rust 1.82 stopped turning undefined symbols into `env` imports implicitly,
 // so the module has to be named for the host functions worker.js provides.
@@ -8,6 +12,7 @@ extern "C" {
     fn tx(s: u32);
     fn rx() -> u32;
     fn keydown() -> bool;
+    fn present(width: u32, height: u32, rgba: *const u8, len: usize);
 }
 
 struct Elapsed;
@@ -38,6 +43,15 @@ impl device_interfaces::SerialInterface for Term {
     }
 }
 
+/// Paints the framebuffer on the canvas of the page.
+struct Canvas;
+
+impl device_interfaces::Display for Canvas {
+    fn refresh(&mut self, width: u32, height: u32, rgba: &[u8]) {
+        unsafe { present(width, height, rgba.as_ptr(), rgba.len()) }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn start() {
     let ram_size = 640 * 1024 * 1024;
@@ -45,18 +59,29 @@ pub extern "C" fn start() {
     let mut ram = vec![0u8; ram_size];
 
     let bin = include_bytes!("../../fixtures/linux.bin");
-    let dtb = include_bytes!("../../fixtures/default.dtb");
 
     ram[0..bin.len()].copy_from_slice(bin);
 
-    let dtb_ref = ram_size - dtb.len();
-    ram[dtb_ref..dtb_ref + dtb.len()].copy_from_slice(dtb);
-
     let clint = core::clint::Clint::new(Elapsed);
     let term = Term;
-    let bus = core::bus::Bus::new(ram, clint, term);
+    let mut bus = core::bus::Bus::new(ram, clint, term);
+    bus.map_device(
+        FRAMEBUFFER,
+        Box::new(Framebuffer::new(640, 480, Canvas).expect("640x480 fits in memory")),
+        None,
+    )
+    .expect("the framebuffer overlaps another device");
+    for input in [
+        Input::keyboard(input::Dom::keyboard()),
+        Input::tablet(input::Dom::tablet()),
+    ] {
+        bus.map_virtio(Box::new(VirtioMmio::new(input)))
+            .expect("no virtio slot left");
+    }
+    let dtb = bus.platform().to_dtb();
+    let dtb_ref = bus.load_dtb(&dtb).expect("RAM is too small for the DTB");
 
     let sleep = |_u: std::time::Duration| {};
 
-    core::start(bus, RAM_START, dtb_ref as u32 + RAM_START, &sleep);
+    core::start(bus, RAM_START, dtb_ref, Default::default(), &sleep);
 }
@@ -21,8 +21,17 @@ const rx = () => {
   return d.charCodeAt(0);
 };
 
+// Keyboard and pointer events for the virtio-input devices, three words each.
+const inputbuf = [];
+const input_pending = () => !!inputbuf.length;
+const input_read = () => inputbuf.shift();
+
 self.addEventListener("message", (event) => {
-  keybuf.push(event.data);
+  if (typeof event.data === "string") {
+    keybuf.push(event.data);
+  } else {
+    inputbuf.push(...event.data.input);
+  }
 });
 
 let wasm;
@@ -68,6 +77,12 @@ function getImports() {
   };
   imports.env.keydown = keydown;
   imports.env.rx = rx;
+  imports.env.input_pending = input_pending;
+  imports.env.input_read = input_read;
+  imports.env.present = (width, height, rgba, len) => {
+    const data = new Uint8ClampedArray(wasm.memory.buffer, rgba, len).slice();
+    postMessage({ frame: { width, height, data } }, [data.buffer]);
+  };
 
   return imports;
 }